@@ -1,9 +1,10 @@
 use crate::error::WalletError;
-use bitcoincash_addr::{AddressCodec, CashAddrCodec};
+use bitcoincash_addr::{AddressCodec, CashAddrCodec, HashType};
 use bitcoinsuite_core::{
     hash::{Hashed, ShaRmd160},
     script::Script,
 };
+use bytes::Bytes;
 /// 20 bit hash160 of public key
 pub fn address_to_pubkey_hash(address: &str) -> Result<Vec<u8>, WalletError> {
     let address_content = CashAddrCodec::decode(address);
@@ -26,6 +27,72 @@ pub fn address_to_p2pkh(address: &str) -> Result<Script, WalletError> {
     let hash160 = &ShaRmd160::from_le_hex(&script_hash?)?;
     Ok(Script::p2pkh(hash160))
 }
+/// Locking script of any cash address: P2PKH, P2SH or P2SH32.
+pub fn address_to_script(address: &str) -> Result<Script, WalletError> {
+    let decoded = match CashAddrCodec::decode(address) {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            return Err(WalletError::AddresssDecodeError {
+                reason: e.to_string(),
+            })
+        }
+    };
+    let hash = decoded.body;
+    let bytecode = match (decoded.hash_type, hash.len()) {
+        (HashType::Key, 20) => return address_to_p2pkh(address),
+        // OP_HASH160 <hash> OP_EQUAL
+        (HashType::Script, 20) => [&[0xa9, 0x14], &hash[..], &[0x87]].concat(),
+        // OP_HASH256 <hash> OP_EQUAL
+        (HashType::Script, 32) => [&[0xaa, 0x20], &hash[..], &[0x87]].concat(),
+        (_, len) => {
+            return Err(WalletError::AddresssDecodeError {
+                reason: format!("unsupported {} byte hash", len),
+            })
+        }
+    };
+    Ok(Script::new(Bytes::from(bytecode)))
+}
+
+/// Hash carried by the cash address of a P2PKH, P2SH or P2SH32 locking script, the inverse
+/// of [address_to_script].
+pub fn script_address_hash(script: &Script) -> Option<Vec<u8>> {
+    let bytecode = script.bytecode();
+    match (bytecode.len(), &bytecode[..]) {
+        // OP_DUP OP_HASH160 <hash> OP_EQUALVERIFY OP_CHECKSIG
+        (25, [0x76, 0xa9, 0x14, hash @ .., 0x88, 0xac]) => Some(hash.to_vec()),
+        (23, [0xa9, 0x14, hash @ .., 0x87]) => Some(hash.to_vec()),
+        (35, [0xaa, 0x20, hash @ .., 0x87]) => Some(hash.to_vec()),
+        _ => None,
+    }
+}
+
+/// Token outputs must go to a token-aware (`z...`) cash address. A plain address is only
+/// re-encoded when `convert` is set, otherwise [WalletError::NonTokenAwareAddress] is returned.
+pub fn require_token_address(address: &str, convert: bool) -> Result<String, WalletError> {
+    let decoded = match CashAddrCodec::decode(address) {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            return Err(WalletError::AddresssDecodeError {
+                reason: e.to_string(),
+            })
+        }
+    };
+    if decoded.token_support {
+        return Ok(address.to_string());
+    }
+    if !convert {
+        return Err(WalletError::NonTokenAwareAddress {
+            address: address.to_string(),
+        });
+    }
+    match CashAddrCodec::encode(&decoded.body, decoded.hash_type, decoded.network, true) {
+        Ok(token_address) => Ok(token_address),
+        Err(e) => Err(WalletError::AddresssDecodeError {
+            reason: e.to_string(),
+        }),
+    }
+}
+
 /*
 mod test {
     use super::*;
@@ -41,3 +108,50 @@ mod test {
     }
 }
 */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_address_required() {
+        let plain = "bchtest:qzxu4ynqdgyjr2hvt5xcx7x35ncdz8zffsf2hgn9mp";
+        let token_aware = "bchtest:zzxu4ynqdgyjr2hvt5xcx7x35ncdz8zffswqykaryj";
+        assert_eq!(
+            require_token_address(plain, false),
+            Err(WalletError::NonTokenAwareAddress {
+                address: plain.to_string()
+            })
+        );
+        assert_eq!(require_token_address(plain, true).unwrap(), token_aware);
+        assert_eq!(require_token_address(token_aware, false).unwrap(), token_aware);
+    }
+
+    #[test]
+    fn locking_scripts() {
+        let p2pkh = address_to_script("bchtest:qptnz3u8atavszhaqk037v0fjrtahxmsl5mm45u3pf");
+        assert_eq!(
+            hex::encode(p2pkh.unwrap().bytecode()),
+            "76a91457314787eafac80afd059f1f31e990d7db9b70fd88ac"
+        );
+        let p2sh = address_to_script("bchtest:pqaf54jyp7xlx5dah84gqnry6n786evj55zwnnalcq");
+        assert_eq!(
+            hex::encode(p2sh.unwrap().bytecode()),
+            "a9143a9a56440f8df351bdb9ea804c64d4fc7d6592a587"
+        );
+        assert!(address_to_script("bchtest:invalid").is_err());
+
+        for address in [
+            "bchtest:qptnz3u8atavszhaqk037v0fjrtahxmsl5mm45u3pf",
+            "bchtest:pqaf54jyp7xlx5dah84gqnry6n786evj55zwnnalcq",
+        ] {
+            let script = address_to_script(address).unwrap();
+            assert_eq!(
+                script_address_hash(&script),
+                Some(address_to_pubkey_hash(address).unwrap())
+            );
+        }
+        let op_return = Script::new(Bytes::from(vec![0x6a]));
+        assert_eq!(script_address_hash(&op_return), None);
+    }
+}
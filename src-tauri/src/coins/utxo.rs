@@ -1,7 +1,8 @@
 use crate::address::address_to_pubkey_hash;
 use crate::error::WalletError;
 
-use crate::store::storage::KEY_PATH;
+use crate::store::pending::{apply_pending, pending_spends};
+use crate::store::schema::address_utxos;
 use bitcoinsuite_core::{
     hash::{Hashed, Sha256d, ShaRmd160},
     script::Script,
@@ -11,7 +12,7 @@ use bitcoinsuite_core::{
     },
 };
 use bytes::Bytes;
-use serde_json::Value;
+use serde_json::{json, Value};
 
 #[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct Utxo {
@@ -32,29 +33,20 @@ pub struct UnspentUtxos {
     pub non_token: Vec<UnspentOutput>,
 }
 
-pub fn get_utxos_for_address(address: &str) -> Result<UnspentUtxos, WalletError> {
-    serde_json_to_utxo(get_db_utxo_unspent(address)?, address)
+pub fn get_utxos_for_address(db: &sled::Db, address: &str) -> Result<UnspentUtxos, WalletError> {
+    serde_json_to_utxo(get_db_utxo_unspent(db, address)?, address)
 }
 
-pub fn get_db_utxo_unspent(address: &str) -> Result<Value, WalletError> {
-    let path = dirs::home_dir().unwrap().join(KEY_PATH);
-    let db = sled::open(path)?;
+/// Stored utxos of `address` as a `listunspent` array, without the ones our sent
+/// transactions spend and with their unconfirmed change.
+pub fn get_db_utxo_unspent(db: &sled::Db, address: &str) -> Result<Value, WalletError> {
     if address_to_pubkey_hash(address).is_ok() {
         let pubkey_hash = address_to_pubkey_hash(address)?;
-        let res = match db.get(pubkey_hash) {
-            Ok(val) => match val {
-                Some(utxo_bytes) => {
-                    let utxo_serde_json =
-                        serde_json::from_slice::<serde_json::Value>(utxo_bytes.to_vec().as_ref());
-                    utxo_serde_json
-                }
-                None => Ok(Value::Array([].to_vec())),
-            },
-            Err(e) => Err(serde_json::Error::from(WalletError::Generic {
-                reason: e.to_string(),
-            })),
-        };
-        Ok(res?)
+        let utxos = address_utxos(db, &pubkey_hash)?;
+        let utxos = apply_pending(&pending_spends(db)?, &pubkey_hash, utxos);
+        Ok(Value::Array(
+            utxos.iter().map(|utxo| utxo.to_json()).collect(),
+        ))
     } else {
         Err(WalletError::AddresssDecodeError {
             reason: address_to_pubkey_hash(address).unwrap_err().to_string(),
@@ -62,6 +54,26 @@ pub fn get_db_utxo_unspent(address: &str) -> Result<Value, WalletError> {
     }
 }
 
+/// Fulcrum `token_data` object of a token output.
+pub fn token_json(token: &CashToken) -> Value {
+    let mut token_data = json!({
+        "amount": token.amount.0.to_string(),
+        "category": token.category.to_string(),
+    });
+    if let Some(nft) = token.nft.as_ref() {
+        let capability = match nft.capability.0 {
+            Capability::None => "none",
+            Capability::Mutable => "mutable",
+            Capability::Minting => "minting",
+        };
+        token_data["nft"] = json!({
+            "capability": capability,
+            "commitment": hex::encode(&nft.commitment.0),
+        });
+    }
+    token_data
+}
+
 /// Converts serde json values to bitcoinsuite types and structures them to [UnspentUtxos]
 pub fn serde_json_to_utxo(utxos: Value, src_addr: &str) -> Result<UnspentUtxos, WalletError> {
     let pubkey_hash = address_to_pubkey_hash(src_addr)?;
@@ -356,7 +368,8 @@ mod test {
         // let y = CashAddrCodec::encode(&x.as_ref().unwrap().body, hashtype, network);
         // println!("{:?} {:?}", x, y);
         // let x = get_db_utxo_unspent(sample_addr2);
-        let x = get_db_utxo_unspent(sample_addr);
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let x = get_db_utxo_unspent(&db, sample_addr);
         let y = serde_json_to_utxo(x.unwrap(), sample_addr);
         // println!("{:#?}", x);
         println!("{:#?}", y);
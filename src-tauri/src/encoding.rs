@@ -0,0 +1,126 @@
+//! Bitcoin style serialization shared by the p2p messages, double-spend proofs and the
+//! wallet's stored records: little endian integers, compact size varints and length prefixed
+//! bytes.
+
+pub fn write_varint(out: &mut Vec<u8>, n: u64) {
+    match n {
+        0..=0xfc => out.push(n as u8),
+        0xfd..=0xffff => {
+            out.push(0xfd);
+            out.extend_from_slice(&(n as u16).to_le_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(0xfe);
+            out.extend_from_slice(&(n as u32).to_le_bytes());
+        }
+        _ => {
+            out.push(0xff);
+            out.extend_from_slice(&n.to_le_bytes());
+        }
+    }
+}
+
+pub fn write_varbytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+/// Length prefixed utf-8 text.
+pub fn write_str(out: &mut Vec<u8>, text: &str) {
+    write_varbytes(out, text.as_bytes());
+}
+
+/// Presence byte, then the text when there is one.
+pub fn write_optional_str(out: &mut Vec<u8>, text: Option<&str>) {
+    match text {
+        Some(text) => {
+            out.push(1);
+            write_str(out, text);
+        }
+        None => out.push(0),
+    }
+}
+
+/// Cursor over a payload, every read fails on truncated data.
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(8)?.try_into().ok()?))
+    }
+
+    pub fn hash(&mut self) -> Option<[u8; 32]> {
+        self.bytes(32)?.try_into().ok()
+    }
+
+    pub fn varint(&mut self) -> Option<u64> {
+        match self.u8()? {
+            0xfd => Some(u16::from_le_bytes(self.bytes(2)?.try_into().ok()?) as u64),
+            0xfe => Some(self.u32()? as u64),
+            0xff => self.u64(),
+            n => Some(n as u64),
+        }
+    }
+
+    pub fn varbytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.varint()?;
+        self.bytes(usize::try_from(len).ok()?)
+    }
+
+    /// Text written by `write_str`, fails on invalid utf-8.
+    pub fn string(&mut self) -> Option<String> {
+        String::from_utf8(self.varbytes()?.to_vec()).ok()
+    }
+
+    /// Text written by `write_optional_str`, `Some(None)` when there was none.
+    pub fn optional_string(&mut self) -> Option<Option<String>> {
+        match self.u8()? {
+            0 => Some(None),
+            1 => Some(Some(self.string()?)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varints() {
+        for n in [
+            0u64,
+            0xfc,
+            0xfd,
+            0xffff,
+            0x10000,
+            0xffff_ffff,
+            0x1_0000_0000,
+        ] {
+            let mut out = vec![];
+            write_varint(&mut out, n);
+            assert_eq!(Reader::new(&out).varint(), Some(n));
+        }
+    }
+}
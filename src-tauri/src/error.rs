@@ -26,6 +26,14 @@ pub enum WalletError {
     NetworkError { reason: String },
     #[error("Only BCH testnet and mainnet cointype are supported")]
     CoinType { reason: String },
+    #[error("{address} is not a token-aware cash address")]
+    NonTokenAwareAddress { address: String },
+    #[error("transaction would implicitly burn tokens of category {category}")]
+    ImplicitTokenBurn { category: String },
+    #[error("token burn requires confirmation: {summary}")]
+    BurnNotConfirmed { summary: String },
+    #[error("spv verification failed: {reason}")]
+    SpvError { reason: String },
     #[error("{reason}")]
     Generic { reason: String },
 }
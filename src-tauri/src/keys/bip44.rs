@@ -7,7 +7,7 @@ use std::fs::File;
 use std::io::Read;
 
 use crate::encryption;
-use crate::store::storage::KEY_PATH;
+use crate::store::storage::wallet_dir;
 
 // use super::address::get_address;
 
@@ -64,7 +64,7 @@ pub fn get_hd_node_from_db_seed(
 }
 
 pub fn load_seed(password: Option<&str>) -> Result<Vec<u8>, String> {
-    let path = dirs::home_dir().unwrap().join(KEY_PATH);
+    let path = wallet_dir();
     let mnemonic_path = path.join("seed");
     let file = File::open(mnemonic_path);
     let mut buffer = vec![];
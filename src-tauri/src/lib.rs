@@ -1,11 +1,14 @@
 pub mod address;
 #[allow(clippy::module_inception)]
 pub mod coins;
+pub mod encoding;
 pub mod encryption;
 pub mod error;
 pub mod keys;
 pub mod network;
+pub mod spv;
 pub mod store;
+pub mod tokens;
 pub mod transaction;
 
 pub mod wallet;
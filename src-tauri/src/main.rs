@@ -1,8 +1,8 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
-use cashcaster::address::address_to_p2pkh;
+use cashcaster::address::{address_to_p2pkh, address_to_script, require_token_address};
 use cashcaster::coins::utxo::{
-    get_db_utxo_unspent, get_utxos_for_address, serde_json_to_utxo, UnspentUtxos,
+    get_db_utxo_unspent, get_utxos_for_address, serde_json_to_utxo, UnspentUtxos, Utxo,
 };
 use cashcaster::keys::address::get_address;
 use cashcaster::keys::bip44::{
@@ -18,22 +18,43 @@ use bitcoinsuite_core::tx::{
 use bytes::Bytes;
 use electrum_client::bitcoin::network::constants::ParseMagicError;
 use num_bigint::{BigUint, ToBigUint};
+use std::collections::BTreeSet;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 use tauri::{utils::config::AppUrl, window::WindowBuilder, WindowUrl};
-use tauri::{Manager, Window};
+use tauri::{AppHandle, Manager, Window};
 use url::Url;
 
 use cashcaster::encryption;
 // use cashcaster::keys::bip32::ExtendedPrivateKey;
-use cashcaster::network::electrum::{
-    get_address_history, get_mempool, get_unspent_utxos, send_raw_transaction, subscribe,
-};
-use cashcaster::store::storage::{store_utxos, KEY_PATH};
+use cashcaster::coins::selection::FeeRate;
+use cashcaster::network::backend::{BackendSelection, ChainBackend};
+use cashcaster::network::bchn::BchnBackend;
+use cashcaster::network::connection::ConnectionManager;
+use cashcaster::network::fees::{FeeCache, FeePreset};
+use cashcaster::network::http::http_get;
+use cashcaster::network::p2p::{message::Network as ChainNetwork, PeerBackend};
+use cashcaster::network::pool::ServerPool;
+use cashcaster::network::proxy::ProxyConfig;
+use cashcaster::network::subscription::SubscriptionService;
+use cashcaster::network::tls::{self, TlsMode};
+use cashcaster::network::electrum::{get_address_history, get_mempool, subscribe};
+use cashcaster::spv::headers::{sync_headers, HeaderStore};
+use cashcaster::store::history::{self, HistoryFilter};
+use cashcaster::store::schema::{self, AddressRecord};
+use cashcaster::store::storage::{sync_address_utxos, sync_addresses_utxos, wallet_db, KEY_PATH};
+use cashcaster::store::transactions::{fetch_transaction, fetch_transactions, spent_outputs};
+use cashcaster::tokens::bcmr;
+use cashcaster::tokens::portfolio::TokenPortfolio;
+use cashcaster::tokens::supply;
+use cashcaster::transaction::broadcast::{self, BroadcastQueue, BroadcastState};
 use cashcaster::transaction::build::{
-    build_transaction_p2pkh, create_tx_for_destination_output, RawTransactionHex, TokenOptions,
+    build_transaction_p2pkh, create_burn_transaction, create_tx_for_destination_output,
+    RawTransactionHex, TokenOptions,
 };
 use cashcaster::{error::WalletError, network::electrum};
 use bip39::{Language, Mnemonic, MnemonicType, Seed};
@@ -45,7 +66,7 @@ use electrum_client::bitcoin::{bip32, secp256k1};
 use secp256k1::SecretKey;
 use serde_json::{json, Value};
 use sled::{self, Error};
-// use tauri::State;
+use tauri::State;
 
 /**
  * Network functions
@@ -53,9 +74,17 @@ use sled::{self, Error};
 
 ///get all the unspet utxos for this address
 #[tauri::command]
-async fn network_unspent_utxos(address: String, network_url: String) -> Result<String, String> {
-    let res = electrum::get_unspent_utxos(address.as_str(), network_url.as_str());
-    match res.await {
+async fn network_unspent_utxos(
+    address: String,
+    network_url: String,
+    backends: State<'_, BackendSelection>,
+) -> Result<String, String> {
+    let script = match address_to_script(&address) {
+        Ok(script) => script,
+        Err(e) => return Err(e.to_string()),
+    };
+    let backend = backends.backend(&network_url);
+    match backend.unspent(&script).await {
         Ok(unspent_utxos) => Ok(unspent_utxos),
         Err(e) => Err(e.to_string()),
     }
@@ -65,8 +94,13 @@ async fn network_unspent_utxos(address: String, network_url: String) -> Result<S
 async fn network_unspent_balance_no_tokens(
     address: String,
     network_url: String,
+    connections: State<'_, ConnectionManager>,
+    pool: State<'_, ServerPool>,
 ) -> Result<String, String> {
-    let res = electrum::get_utxos_balance_no_tokens(address.as_str(), network_url.as_str());
+    let address = address.as_str();
+    let res = pool.with_failover(&connections, &network_url, |connection| async move {
+        electrum::get_utxos_balance_no_tokens(address, &connection).await
+    });
     match res.await {
         Ok(balance) => Ok(balance),
         Err(e) => Err(e.to_string()),
@@ -77,8 +111,13 @@ async fn network_unspent_balance_no_tokens(
 async fn network_unspent_balance_include_tokens(
     address: String,
     network_url: String,
+    connections: State<'_, ConnectionManager>,
+    pool: State<'_, ServerPool>,
 ) -> Result<String, String> {
-    let res = electrum::get_utxos_balance_include_tokens(address.as_str(), network_url.as_str());
+    let address = address.as_str();
+    let res = pool.with_failover(&connections, &network_url, |connection| async move {
+        electrum::get_utxos_balance_include_tokens(address, &connection).await
+    });
     match res.await {
         Ok(balance) => Ok(balance),
         Err(e) => Err(e.to_string()),
@@ -86,8 +125,12 @@ async fn network_unspent_balance_include_tokens(
 }
 
 #[tauri::command]
-async fn network_ping(network_url: String) -> Result<String, String> {
-    let res = electrum::ping(network_url.as_str());
+async fn network_ping(
+    network_url: String,
+    connections: State<'_, ConnectionManager>,
+) -> Result<String, String> {
+    let connection = connections.get(network_url.as_str());
+    let res = electrum::ping(&connection);
     match res.await {
         Ok(res) => Ok(res),
         Err(e) => Err(e.to_string()),
@@ -99,17 +142,127 @@ async fn address_history(
     address: String,
     network_url: String,
     // from_height: u32,
+    connections: State<'_, ConnectionManager>,
+    pool: State<'_, ServerPool>,
 ) -> Result<String, String> {
-    let res = electrum::get_address_history(address.as_str(), network_url.as_str());
+    let address = address.as_str();
+    let res = pool.with_failover(&connections, &network_url, |connection| async move {
+        electrum::get_address_history(address, &connection).await
+    });
     match res.await {
         Ok(h) => Ok(h),
         Err(e) => Err(e.to_string()),
     }
 }
 
+/// History of `address` with the raw transactions, from the transaction cache where possible.
+#[tauri::command]
+async fn address_transactions(
+    address: &str,
+    network_url: &str,
+    connections: State<'_, ConnectionManager>,
+    db: State<'_, sled::Db>,
+) -> Result<Value, String> {
+    let connection = connections.get(network_url);
+    let history = match electrum::get_address_history(address, &connection).await {
+        Ok(history) => history,
+        Err(e) => return Err(e.to_string()),
+    };
+    let mut history: Value = match serde_json::from_str(&history) {
+        Ok(history) => history,
+        Err(e) => return Err(e.to_string()),
+    };
+    let entries = history.as_array_mut().unwrap();
+    let txids: Vec<String> = entries
+        .iter()
+        .filter_map(|entry| entry["tx_hash"].as_str().map(String::from))
+        .collect();
+    let raw_txs = match fetch_transactions(&db, &txids, &connection).await {
+        Ok(raw_txs) => raw_txs,
+        Err(e) => return Err(e.to_string()),
+    };
+    for (entry, raw_tx) in entries.iter_mut().zip(raw_txs) {
+        entry["hex"] = Value::String(raw_tx);
+    }
+    Ok(history)
+}
+
+/// Updates the stored history of every wallet address from the server, returns the changed
+/// txids.
+#[tauri::command]
+async fn sync_wallet_history(
+    addresses: Vec<String>,
+    network_url: &str,
+    connections: State<'_, ConnectionManager>,
+    db: State<'_, sled::Db>,
+) -> Result<Vec<String>, String> {
+    let connection = connections.get(network_url);
+    match history::sync_history(&db, &addresses, &addresses, &connection).await {
+        Ok(changed) => Ok(changed),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// A page of the stored wallet history, newest first. `direction` is `incoming` or
+/// `outgoing`, heights bound confirmed transactions.
+#[tauri::command]
+fn get_wallet_history(
+    offset: usize,
+    limit: usize,
+    category: Option<&str>,
+    direction: Option<&str>,
+    min_height: Option<u32>,
+    max_height: Option<u32>,
+    db: State<'_, sled::Db>,
+) -> Result<Value, String> {
+    let category = match category.map(TxId::from_str) {
+        Some(Ok(category)) => Some(category),
+        Some(Err(e)) => return Err(e.to_string()),
+        None => None,
+    };
+    let incoming = match direction {
+        Some("incoming") => Some(true),
+        Some("outgoing") => Some(false),
+        Some(direction) => return Err(format!("unknown direction {}", direction)),
+        None => None,
+    };
+    let filter = HistoryFilter {
+        category,
+        incoming,
+        min_height,
+        max_height,
+    };
+    match history::history_page(&db, &filter, offset, limit) {
+        Ok((entries, total, tip)) => {
+            let entries: Vec<Value> = entries.iter().map(|entry| entry.to_json(tip)).collect();
+            Ok(json!({"entries":entries,"total":total,"tip":tip}))
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Raw transaction hex, from the transaction cache or the server.
 #[tauri::command]
-async fn subscribe_to_address(address: &str, network_url: &str) -> Result<String, String> {
-    let res = subscribe(address, network_url);
+async fn get_raw_transaction(
+    txid: &str,
+    network_url: &str,
+    connections: State<'_, ConnectionManager>,
+    db: State<'_, sled::Db>,
+) -> Result<String, String> {
+    match fetch_transaction(&db, txid, &connections.get(network_url)).await {
+        Ok(raw_tx) => Ok(raw_tx),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[tauri::command]
+async fn subscribe_to_address(
+    address: &str,
+    network_url: &str,
+    connections: State<'_, ConnectionManager>,
+) -> Result<String, String> {
+    let connection = connections.get(network_url);
+    let res = subscribe(address, &connection);
     match res.await {
         Ok(hash) => Ok(hash),
         Err(e) => Err(e.to_string()),
@@ -117,8 +270,13 @@ async fn subscribe_to_address(address: &str, network_url: &str) -> Result<String
 }
 
 #[tauri::command]
-async fn get_mempool_address(address: &str, network_url: &str) -> Result<String, String> {
-    let res = get_mempool(address, network_url);
+async fn get_mempool_address(
+    address: &str,
+    network_url: &str,
+    connections: State<'_, ConnectionManager>,
+) -> Result<String, String> {
+    let connection = connections.get(network_url);
+    let res = get_mempool(address, &connection);
     match res.await {
         Ok(data) => Ok(data),
         Err(e) => Err(e.to_string()),
@@ -126,15 +284,70 @@ async fn get_mempool_address(address: &str, network_url: &str) -> Result<String,
 }
 
 #[tauri::command]
-async fn unsubscribe_to_address(address: &str, network_url: &str) -> Result<bool, String> {
+async fn unsubscribe_to_address(
+    address: &str,
+    network_url: &str,
+    connections: State<'_, ConnectionManager>,
+) -> Result<bool, String> {
     use cashcaster::network::electrum::unsubscribe;
-    let res = unsubscribe(address, network_url);
+    let connection = connections.get(network_url);
+    let res = unsubscribe(address, &connection);
     match res.await {
         Ok(val) => Ok(val),
         Err(e) => Err(e.to_string()),
     }
 }
 
+/// Keeps the addresses and the chain tip subscribed in the background and emits
+/// `balance-changed`, `new-transaction`, `history-changed`, `new-block` and `double-spend`
+/// events. The broadcast queue runs alongside and emits `broadcast-status`.
+#[tauri::command]
+fn start_wallet_subscriptions(
+    addresses: Vec<String>,
+    network_url: &str,
+    app: AppHandle,
+    connections: State<'_, ConnectionManager>,
+    backends: State<'_, BackendSelection>,
+    subscriptions: State<'_, SubscriptionService>,
+    queue: State<'_, BroadcastQueue>,
+    db: State<'_, sled::Db>,
+) -> Result<(), String> {
+    subscriptions.watch(addresses);
+    let queue_app = app.clone();
+    let db = db.inner();
+    subscriptions.start(
+        connections.get(network_url),
+        backends.inner().clone(),
+        db.clone(),
+        move |event, payload| {
+            _ = app.emit_all(event, payload);
+        },
+    );
+    queue.start(
+        connections.get(network_url),
+        db.clone(),
+        move |event, payload| {
+            _ = queue_app.emit_all(event, payload);
+        },
+    );
+    Ok(())
+}
+
+#[tauri::command]
+fn stop_wallet_subscriptions(
+    subscriptions: State<'_, SubscriptionService>,
+    queue: State<'_, BroadcastQueue>,
+) {
+    subscriptions.stop();
+    queue.stop();
+}
+
+/// Double-spend proofs received for unconfirmed wallet transactions, with their verification.
+#[tauri::command]
+fn get_double_spend_alerts(subscriptions: State<'_, SubscriptionService>) -> Vec<Value> {
+    subscriptions.double_spend_alerts()
+}
+
 /**
  * Key CRUD
  */
@@ -472,7 +685,7 @@ fn does_db_exist() -> bool {
 
 #[tauri::command]
 fn create_db() {
-    match sled::open(dirs::home_dir().unwrap().join(KEY_PATH)) {
+    match wallet_db() {
         Ok(_) => {}
         Err(_) => {}
     }
@@ -506,7 +719,9 @@ fn get_master_key<P: AsRef<Path>>(password: &str) {
 
 //TODO implement in UI
 #[tauri::command]
-fn create_change_pubkeyhash_store(/* x_privkey: ExtendedPrivKey */) -> Result<(), String> {
+fn create_change_pubkeyhash_store(
+    db: State<'_, sled::Db>, /* x_privkey: ExtendedPrivKey */
+) -> Result<(), String> {
     let path = dirs::home_dir().unwrap().join(KEY_PATH);
     let master_key_path = path.join("master_key");
     let secp = secp256k1::Secp256k1::new();
@@ -533,7 +748,6 @@ fn create_change_pubkeyhash_store(/* x_privkey: ExtendedPrivKey */) -> Result<()
         };
 
         let mut index = 0;
-        let db = sled::open(path).unwrap();
         if intern_public_key.is_ok() && xtern_public_key.is_ok() {
             while index < 100 {
                 let external_pubkey = xtern_public_key
@@ -545,9 +759,13 @@ fn create_change_pubkeyhash_store(/* x_privkey: ExtendedPrivKey */) -> Result<()
                     .serialize();
                 let hash = Sha256::digest(&external_pubkey);
                 let hash = Ripemd160::digest(&hash);
-                _ = db.insert(
-                    format!("change-extern-{index}").to_string(),
-                    hash.as_le_bytes(),
+                _ = schema::put_address(
+                    &db,
+                    &AddressRecord {
+                        change: false,
+                        index,
+                        pubkey_hash: hash.as_le_bytes().to_vec(),
+                    },
                 );
 
                 let internal_pubkey = intern_public_key
@@ -559,9 +777,13 @@ fn create_change_pubkeyhash_store(/* x_privkey: ExtendedPrivKey */) -> Result<()
                     .serialize();
                 let hash = Sha256::digest(&internal_pubkey);
                 let hash = Ripemd160::digest(&hash);
-                _ = db.insert(
-                    format!("change-intern-{index}").to_string(),
-                    hash.as_le_bytes(),
+                _ = schema::put_address(
+                    &db,
+                    &AddressRecord {
+                        change: true,
+                        index,
+                        pubkey_hash: hash.as_le_bytes().to_vec(),
+                    },
                 );
 
                 index += 1;
@@ -575,11 +797,45 @@ fn create_change_pubkeyhash_store(/* x_privkey: ExtendedPrivKey */) -> Result<()
 
 //TODO will be used for hdkey stragety. need KV,
 #[tauri::command]
-fn get_pkh(key: &str) -> Result<String, String> {
-    let path = dirs::home_dir().unwrap().join(KEY_PATH);
-    let db = sled::open(path).unwrap();
-    let x = db.get(key.as_bytes());
-    Ok(hex::encode(x.unwrap().unwrap().to_vec()))
+fn get_pkh(key: &str, db: State<'_, sled::Db>) -> Result<String, String> {
+    let (change, index) = match schema::legacy_address_key(key) {
+        Some(chain_index) => chain_index,
+        None => return Err(format!("bad address key {}", key)),
+    };
+    match schema::get_address(&db, change, index) {
+        Ok(Some(record)) => Ok(hex::encode(record.pubkey_hash)),
+        Ok(None) => Err(format!("no address stored for {}", key)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Labels of txids and addresses.
+#[tauri::command]
+fn get_labels(db: State<'_, sled::Db>) -> Result<Value, String> {
+    match schema::labels(&db) {
+        Ok(labels) => Ok(labels
+            .into_iter()
+            .map(|(key, label)| json!({"key":key,"label":label}))
+            .collect()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Labels a txid or address, an empty label removes it.
+#[tauri::command]
+fn set_label(key: &str, label: &str, db: State<'_, sled::Db>) -> Result<(), String> {
+    match schema::set_label(&db, key, label) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// sat/byte, never below the 1 sat/byte relay floor
+fn fee_rate_or_floor(fee_rate: Option<f32>) -> FeeRate {
+    match fee_rate {
+        Some(rate) if rate.is_finite() && rate > 1.0 => FeeRate::from_sat_per_vb(rate),
+        _ => FeeRate::default_min_relay_fee(),
+    }
 }
 
 /**
@@ -597,6 +853,8 @@ fn build_p2pkh_transaction(
     capability: Option<&str>,
     utxos: Value,
     required_utxos: Option<Value>,
+    convert_to_token_address: Option<bool>,
+    fee_rate: Option<f32>,
 ) -> Result<RawTransactionHex, String> {
     println!("UTXOS JSON {:#?}", utxos);
     println!("REQUIRED JSON {:#?}", required_utxos);
@@ -645,8 +903,23 @@ fn build_p2pkh_transaction(
     {
         return Err("invalid cash address".to_string());
     }
-    let destination_script = address_to_p2pkh(destination_address).unwrap();
+    let destination_address = match token_amount.is_some() || nft.is_some() {
+        true => {
+            let convert = convert_to_token_address.unwrap_or(false);
+            match require_token_address(destination_address, convert) {
+                Ok(address) => address,
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        false => destination_address.to_string(),
+    };
+    let destination_script = address_to_p2pkh(&destination_address).unwrap();
     let src_script = address_to_p2pkh(source_address).unwrap();
+    let fee_rate = fee_rate_or_floor(fee_rate);
+    let xpriv = match get_hd_node_from_db_seed(None, Network::Testnet) {
+        Ok(xpriv) => xpriv,
+        Err(e) => return Err(e),
+    };
 
     let raw_tx = if let Some(token_amount) = token_amount {
         let token_amount = BigUint::parse_bytes(token_amount.as_bytes(), 10);
@@ -661,6 +934,7 @@ fn build_p2pkh_transaction(
         let token_data = create_token_options(category, token_amount, nft, token_available_amount);
 
         match create_tx_for_destination_output(
+            &xpriv,
             derivation_path,
             token_data,
             &destination_script,
@@ -668,6 +942,7 @@ fn build_p2pkh_transaction(
             amount,
             available_utxos.unwrap(),
             req_utxos,
+            fee_rate,
         ) {
             Ok(data) => Ok(data),
             Err(e) => Err(e.to_string()),
@@ -675,6 +950,7 @@ fn build_p2pkh_transaction(
         }
     } else {
         match create_tx_for_destination_output(
+            &xpriv,
             derivation_path,
             None,
             &destination_script,
@@ -682,6 +958,7 @@ fn build_p2pkh_transaction(
             amount,
             available_utxos.unwrap(),
             req_utxos,
+            fee_rate,
         ) {
             Ok(data) => Ok(data),
             Err(e) => Err(e.to_string()),
@@ -689,22 +966,154 @@ fn build_p2pkh_transaction(
     };
     println!("build p2pkh res\n{:?}\n", raw_tx);
     match raw_tx {
-        Ok(res) => Ok(json!({"rawTx":res.raw_tx,"dust":res.dust}).to_string()),
+        Ok(res) => Ok(
+            json!({"rawTx":res.raw_tx,"dust":res.dust,"destination":destination_address})
+                .to_string(),
+        ),
         Err(e) => Err(e.to_string()),
     }
     // json!({})raw_tx
 }
+/// Destroys the tokens held by `burn_utxos`. `keep_amount` fungible tokens of their category
+/// go back to `source_address` as token change. Without `confirm_burn` nothing is built and
+/// the error lists what would be burned.
+#[tauri::command]
+fn build_burn_transaction(
+    derivation_path: &str,
+    source_address: &str,
+    burn_utxos: Value,
+    utxos: Value,
+    keep_amount: Option<&str>,
+    memo: Option<&str>,
+    fee_rate: Option<f32>,
+    confirm_burn: bool,
+) -> Result<String, String> {
+    if validate_cash_address(source_address).is_err() {
+        return Err("invalid cash address".to_string());
+    }
+    let burn_utxos: Vec<Utxo> = match serde_json_to_utxo(burn_utxos, source_address) {
+        Ok(utxos) => utxos.with_token.into_iter().map(|u| u.0).collect(),
+        Err(e) => return Err(e.to_string()),
+    };
+    let keep = match keep_amount {
+        Some(amount) => {
+            let amount: u64 = match amount.parse() {
+                Ok(amount) => amount,
+                Err(e) => return Err(format!("bad token amount {}: {}", amount, e)),
+            };
+            let categories: BTreeSet<TxId> = burn_utxos
+                .iter()
+                .filter_map(|utxo| utxo.output.token.as_ref())
+                .map(|token| token.category)
+                .collect();
+            if categories.len() != 1 {
+                return Err("token change needs burn utxos of a single category".to_string());
+            }
+            vec![CashToken {
+                amount: CompactUint(amount),
+                category: *categories.iter().next().unwrap(),
+                nft: None,
+            }]
+        }
+        None => vec![],
+    };
+    let funding_utxos = match serde_json_to_utxo(utxos, source_address) {
+        Ok(utxos) => utxos.non_token.into_iter().map(|u| u.0).collect(),
+        Err(e) => return Err(e.to_string()),
+    };
+    let change_script = address_to_p2pkh(source_address).unwrap();
+    let xpriv = match get_hd_node_from_db_seed(None, Network::Testnet) {
+        Ok(xpriv) => xpriv,
+        Err(e) => return Err(e),
+    };
+
+    match create_burn_transaction(
+        &xpriv,
+        derivation_path,
+        burn_utxos,
+        funding_utxos,
+        &change_script,
+        keep,
+        memo,
+        fee_rate_or_floor(fee_rate),
+        confirm_burn,
+    ) {
+        Ok(burn) => {
+            let burned: Vec<Value> = burn
+                .burned
+                .iter()
+                .map(|(category, tally)| {
+                    json!({"category":category.to_string(),"amount":tally.fungible.to_string(),"nfts":tally.nfts})
+                })
+                .collect();
+            Ok(json!({"rawTx":burn.raw_tx,"fee":burn.fee,"burned":burned}).to_string())
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
 #[tauri::command]
-async fn broadcast_transaction(transaction: &str, network_url: &str) -> Result<String, String> {
-    match send_raw_transaction(transaction, network_url).await {
-        Ok(txid) => Ok(txid.to_string()),
+async fn broadcast_transaction(
+    transaction: &str,
+    network_url: &str,
+    connections: State<'_, ConnectionManager>,
+    backends: State<'_, BackendSelection>,
+    db: State<'_, sled::Db>,
+) -> Result<String, String> {
+    let mut outgoing = match broadcast::enqueue(&db, transaction) {
+        Ok(outgoing) => outgoing,
+        Err(e) => return Err(e.to_string()),
+    };
+    // the queue and the ui expect the txid as a json string, like Electrum answers it
+    let res = match backends.backend(network_url).broadcast(transaction).await {
+        Ok(txid) => Ok(json!(txid).to_string()),
+        Err(e) => Err(e),
+    };
+    let connection = connections.get(network_url);
+    if let Err(e) = broadcast::record_send(&db, &mut outgoing, &res, &connection).await {
+        log::warn!("outgoing {} not updated {}", outgoing.txid, e);
+    }
+    if let Err(e) = broadcast::save_outgoing(&db, &outgoing) {
+        return Err(e.to_string());
+    }
+    // the stored utxos still list the inputs until the next refresh
+    if let Err(e) = broadcast::track_pending(&db, &outgoing) {
+        log::warn!("pending spends of {} not updated {}", outgoing.txid, e);
+    }
+    match (res, &outgoing.state) {
+        (Ok(txid), _) => Ok(txid),
+        (Err(_), BroadcastState::InMempool) => Ok(json!(outgoing.txid).to_string()),
+        (Err(e), BroadcastState::Queued) => Err(format!("{}, queued for retry", e)),
+        (Err(e), _) => Err(e.to_string()),
+    }
+}
+
+/// Transactions sent through `broadcast_transaction`, with their broadcast state.
+#[tauri::command]
+fn get_outgoing_transactions(db: State<'_, sled::Db>) -> Result<Vec<Value>, String> {
+    match broadcast::outgoing_transactions(&db) {
+        Ok(txs) => Ok(txs.iter().map(|tx| tx.to_json()).collect()),
         Err(e) => Err(e.to_string()),
     }
 }
-//TODO add source outputs to transaction. instead of showing tx hash and index. show actual
-//source outputs
+
+/// Drops a transaction from the broadcast queue, it is no longer retried.
 #[tauri::command]
-async fn decode_transaction(transaction: &str) -> Result<Value, String> {
+fn forget_outgoing_transaction(txid: &str, db: State<'_, sled::Db>) -> Result<bool, String> {
+    match broadcast::forget_outgoing(&db, txid) {
+        Ok(removed) => Ok(removed),
+        Err(e) => Err(e.to_string()),
+    }
+}
+/// Inputs and outputs of a raw transaction. With `network_url` the spent outputs are looked up
+/// through the transaction cache, adding input values and the fee.
+#[tauri::command]
+async fn decode_transaction(
+    transaction: &str,
+    network_url: Option<String>,
+    connections: State<'_, ConnectionManager>,
+    db: State<'_, sled::Db>,
+) -> Result<Value, String> {
     let mut inputs = /* : Vec<Value> =  */Vec::new();
     let mut outputs = /* : Vec<Value> =  */Vec::new();
 
@@ -750,6 +1159,11 @@ async fn decode_transaction(transaction: &str) -> Result<Value, String> {
                 Ok(txid) => {
                     let category = Value::String(hex::encode(txid.ser()));
                     let amount = Value::Number(o.token.as_ref().unwrap().amount.0.into());
+                    let metadata = bcmr::get_metadata(&txid.to_string()).unwrap_or(None);
+                    let symbol = metadata.as_ref().map(|m| m.symbol.clone());
+                    let display = metadata.as_ref().map(|m| {
+                        bcmr::format_token_amount(o.token.as_ref().unwrap().amount.0 as u128, m.decimals)
+                    });
                     if o.token.as_ref().unwrap().nft.is_some() {
                         let capability =
                             match o.token.as_ref().unwrap().nft.as_ref().unwrap().capability.0 {
@@ -761,10 +1175,13 @@ async fn decode_transaction(transaction: &str) -> Result<Value, String> {
                         // let commitment =  hex::encode(o.token.as_ref().unwrap().commitment().0) ;
                         let commitment = o.token.as_ref().unwrap().commitment().0;
                             let commitment = hex::encode(commitment);
-                        json!({"category":category,"amount":amount, "nft":{"capability": capability,"commitment":*commitment} })
+                        let nft_type = metadata
+                            .as_ref()
+                            .and_then(|m| m.nft_type(&commitment).map(|t| t.name.clone()));
+                        json!({"category":category,"amount":amount,"display":display,"symbol":symbol, "nft":{"capability": capability,"commitment":*commitment,"nftType":nft_type} })
                         // json!("")
                     } else {
-                        json!({"category":category,"amount":amount, "nft":Value::Null})
+                        json!({"category":category,"amount":amount,"display":display,"symbol":symbol, "nft":Value::Null})
                     }
                 }
                 Err(_) => Value::Null,
@@ -777,7 +1194,24 @@ async fn decode_transaction(transaction: &str) -> Result<Value, String> {
         outputs.push(res)
     });
 
-    Ok(json!({"inputs":inputs,"outputs":outputs,"txid":txid}))
+    let mut fee = Value::Null;
+    if let Some(network_url) = network_url {
+        let tx = tx.as_ref().unwrap();
+        match spent_outputs(&db, tx, &connections.get(&network_url)).await {
+            Ok(spent) => {
+                for (input, output) in inputs.iter_mut().zip(spent.iter()) {
+                    input["value"] = json!(output.value);
+                    input["script"] = json!(hex::encode(output.script.bytecode()));
+                }
+                let input_total: u64 = spent.iter().map(|o| o.value).sum();
+                let output_total: u64 = tx.outputs.iter().map(|o| o.value).sum();
+                fee = json!(input_total.saturating_sub(output_total));
+            }
+            Err(e) => log::warn!("spent outputs unavailable {}", e),
+        }
+    }
+
+    Ok(json!({"inputs":inputs,"outputs":outputs,"txid":txid,"fee":fee}))
 }
 
 //TODO ADD NETWORK ARG
@@ -805,50 +1239,310 @@ fn lockscript_to_cash_address(script: &str) -> Result<String, String> {
 }
 
 #[tauri::command]
-async fn update_utxo_store(address: &str, network_url: &str) -> Result<(), String> {
-    let db_utxos = match get_db_utxo_unspent(address) {
-        Ok(utxo) => Ok(utxo),
+async fn update_utxo_store(
+    address: &str,
+    network_url: &str,
+    connections: State<'_, ConnectionManager>,
+    backends: State<'_, BackendSelection>,
+    db: State<'_, sled::Db>,
+) -> Result<(), String> {
+    let backend = backends.backend(network_url);
+    let connection = connections.get(network_url);
+    match sync_address_utxos(&db, address, backend.as_ref(), &connection).await {
+        Ok(_) => Ok(()),
         Err(e) => Err(e.to_string()),
-    };
-    let network_utxos = get_unspent_utxos(address, network_url).await;
+    }
+}
+
+/// Refreshes the stored utxos of many addresses in batched requests, returns the addresses
+/// that changed.
+#[tauri::command]
+async fn update_utxo_stores(
+    addresses: Vec<String>,
+    network_url: &str,
+    connections: State<'_, ConnectionManager>,
+    backends: State<'_, BackendSelection>,
+    db: State<'_, sled::Db>,
+) -> Result<Vec<String>, String> {
+    let backend = backends.backend(network_url);
+    let connection = connections.get(network_url);
+    match sync_addresses_utxos(&db, &addresses, backend.as_ref(), &connection).await {
+        Ok(changed) => Ok(changed),
+        Err(e) => Err(e.to_string()),
+    }
+}
 
-    let network_utxos = match network_utxos {
-        Ok(v) => Ok(v),
+/// Downloads and validates block headers up to the server tip, returns the tip height.
+#[tauri::command]
+async fn sync_block_headers(
+    network_url: &str,
+    connections: State<'_, ConnectionManager>,
+    db: State<'_, sled::Db>,
+) -> Result<u32, String> {
+    let store = match HeaderStore::wallet(&db) {
+        Ok(store) => store,
+        Err(e) => return Err(e.to_string()),
+    };
+    match sync_headers(&store, &connections.get(network_url)).await {
+        Ok(tip) => Ok(tip),
         Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Sends all server traffic through a SOCKS5 proxy such as Tor at `127.0.0.1:9050`, or
+/// directly when `address` is empty. `isolation` gives the wallet its own Tor circuits. A
+/// selected node or peer was set up with the previous proxy, the wallet goes back to the
+/// Electrum servers until it is selected again.
+#[tauri::command]
+fn set_network_proxy(
+    address: Option<String>,
+    isolation: Option<String>,
+    connections: State<'_, ConnectionManager>,
+    backends: State<'_, BackendSelection>,
+) -> Result<(), String> {
+    backends.select(None);
+    let proxy = match address {
+        Some(address) if !address.is_empty() => Some(ProxyConfig {
+            address,
+            isolation: isolation.filter(|i| !i.is_empty()),
+        }),
+        _ => None,
     };
-    if network_utxos.is_err() {
-        return Err(network_utxos.unwrap_err().to_string());
+    match connections.set_proxy(proxy) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(e.to_string()),
     }
-    if db_utxos.is_err() {
-        return Err(db_utxos.unwrap_err().to_string());
+}
+
+#[tauri::command]
+fn get_network_proxy(connections: State<'_, ConnectionManager>) -> Result<Value, String> {
+    match connections.proxy() {
+        Ok(Some(proxy)) => Ok(json!({"address":proxy.address,"isolation":proxy.isolation})),
+        Ok(None) => Ok(Value::Null),
+        Err(e) => Err(e.to_string()),
     }
+}
 
-    let network_res = serde_json::from_slice::<serde_json::Value>(
-        network_utxos.clone().unwrap().as_bytes().to_vec().as_ref(),
-    );
-    let network_res = serde_json_to_utxo(
-        serde_json::Value::from(network_res.expect("serde_json_to_utxo error")),
-        address,
-    );
+/// Minimum, normal and fast fee rates in sat/byte from the server, cached for a few minutes.
+/// Falls back to 1 sat/byte when the server can't be reached.
+#[tauri::command]
+async fn get_fee_presets(
+    network_url: String,
+    connections: State<'_, ConnectionManager>,
+    fees: State<'_, FeeCache>,
+) -> Result<Value, String> {
+    let connection = connections.get(&network_url);
+    Ok(fees.estimates(&connection).await.to_json())
+}
 
-    let mut ismatch =
-        network_res.unwrap() == serde_json_to_utxo(db_utxos.unwrap(), address).unwrap();
-    let res = if ismatch {
-        Ok(())
-    } else {
-        let updated = store_utxos(address.to_string(), network_utxos.clone().unwrap());
-        match updated.is_ok() {
-            // true => Ok("utxo store update success".to_string()),
-            true => {
-                ismatch = true;
+/// Rate in sat/byte of the `minimum`, `normal` or `fast` preset, to pass as `fee_rate` when
+/// building a transaction.
+#[tauri::command]
+async fn get_fee_rate(
+    network_url: String,
+    preset: String,
+    connections: State<'_, ConnectionManager>,
+    fees: State<'_, FeeCache>,
+) -> Result<f32, String> {
+    let preset = FeePreset::from_name(&preset)?;
+    let connection = connections.get(&network_url);
+    Ok(fees.rate(preset, &connection).await.as_sat_per_vb())
+}
 
-                Ok(())
-            }
+/// Replaces the configured server list used for failover.
+#[tauri::command]
+fn set_servers(servers: Vec<String>, pool: State<'_, ServerPool>) -> Result<(), String> {
+    for url in servers.iter() {
+        check_url(url)?;
+    }
+    match pool.set_servers(servers) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[tauri::command]
+fn set_server_cross_check(enabled: bool, pool: State<'_, ServerPool>) -> Result<(), String> {
+    match pool.set_cross_check(enabled) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Pings every configured server and returns the servers ranked with their scores.
+#[tauri::command]
+async fn check_servers(
+    connections: State<'_, ConnectionManager>,
+    pool: State<'_, ServerPool>,
+) -> Result<Value, String> {
+    pool.check_health(&connections).await;
+    Ok(pool.to_json())
+}
+
+#[tauri::command]
+fn server_status(pool: State<'_, ServerPool>) -> Value {
+    pool.to_json()
+}
+
+/// Adds the peers announced by the servers to the list, returns the new urls.
+#[tauri::command]
+async fn discover_servers(
+    network_url: &str,
+    connections: State<'_, ConnectionManager>,
+    pool: State<'_, ServerPool>,
+) -> Result<Vec<String>, String> {
+    match pool.discover(&connections, network_url).await {
+        Ok(added) => Ok(added),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Checks a BCHN node rpc endpoint through the configured proxy and returns its tip height.
+#[tauri::command]
+async fn check_bchn_node(
+    url: &str,
+    user: &str,
+    password: &str,
+    connections: State<'_, ConnectionManager>,
+) -> Result<u32, String> {
+    let timeout = Duration::from_secs(15);
+    let proxy = match connections.proxy() {
+        Ok(proxy) => proxy,
+        Err(e) => return Err(e.to_string()),
+    };
+    let backend = match BchnBackend::new(url, user, password, timeout, proxy) {
+        Ok(backend) => backend,
+        Err(e) => return Err(e.to_string()),
+    };
+    match backend.tip().await {
+        Ok((height, _)) => Ok(height),
+        Err(e) => Err(e.to_string()),
+    }
+}
 
-            _ => Err("utxo store update error".to_string()),
+/// Connects to a peer over the p2p protocol and returns the height it reports. Nothing is
+/// synced or scanned.
+#[tauri::command]
+async fn check_p2p_peer(
+    host: &str,
+    port: Option<u16>,
+    network: &str,
+    connections: State<'_, ConnectionManager>,
+    db: State<'_, sled::Db>,
+) -> Result<u32, String> {
+    let network = ChainNetwork::from_name(network)?;
+    let proxy = match connections.proxy() {
+        Ok(proxy) => proxy,
+        Err(e) => return Err(e.to_string()),
+    };
+    let backend = PeerBackend::new(
+        host,
+        port,
+        network,
+        0,
+        Duration::from_secs(30),
+        proxy,
+        db.inner().clone(),
+    );
+    match backend.handshake().await {
+        Ok(version) => Ok(version.start_height),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Sends and syncs utxos through a BCHN node from now on, returns its tip height. The node
+/// is checked first and stays unselected when it can't be reached.
+#[tauri::command]
+async fn use_bchn_backend(
+    url: &str,
+    user: &str,
+    password: &str,
+    connections: State<'_, ConnectionManager>,
+    backends: State<'_, BackendSelection>,
+) -> Result<u32, String> {
+    let timeout = Duration::from_secs(15);
+    let proxy = match connections.proxy() {
+        Ok(proxy) => proxy,
+        Err(e) => return Err(e.to_string()),
+    };
+    let backend = match BchnBackend::new(url, user, password, timeout, proxy) {
+        Ok(backend) => backend,
+        Err(e) => return Err(e.to_string()),
+    };
+    match backend.tip().await {
+        Ok((height, _)) => {
+            backends.select(Some(Arc::new(backend)));
+            Ok(height)
         }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Sends and syncs utxos through a peer over the p2p protocol from now on, returns the height
+/// it reports. Blocks before `scan_from`, the wallet birthday, are never scanned.
+#[tauri::command]
+async fn use_p2p_backend(
+    host: &str,
+    port: Option<u16>,
+    network: &str,
+    scan_from: u32,
+    connections: State<'_, ConnectionManager>,
+    backends: State<'_, BackendSelection>,
+    db: State<'_, sled::Db>,
+) -> Result<u32, String> {
+    let network = ChainNetwork::from_name(network)?;
+    let proxy = match connections.proxy() {
+        Ok(proxy) => proxy,
+        Err(e) => return Err(e.to_string()),
     };
-    res
+    let backend = PeerBackend::new(
+        host,
+        port,
+        network,
+        scan_from,
+        Duration::from_secs(30),
+        proxy,
+        db.inner().clone(),
+    );
+    match backend.handshake().await {
+        Ok(version) => {
+            backends.select(Some(Arc::new(backend)));
+            Ok(version.start_height)
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Sends and syncs utxos through the Electrum servers of the pool again.
+#[tauri::command]
+fn use_electrum_backend(backends: State<'_, BackendSelection>) {
+    backends.select(None);
+}
+
+/// Chain of the wallet: `mainnet`, `testnet3`, `testnet4`, `chipnet` or `regtest`. Servers on
+/// another chain are refused from then on.
+#[tauri::command]
+fn set_wallet_network(
+    network: &str,
+    connections: State<'_, ConnectionManager>,
+) -> Result<(), String> {
+    let network = ChainNetwork::from_name(network)?;
+    match connections.set_network(network) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Protocol version and features of the server, connecting first when needed.
+#[tauri::command]
+async fn server_capabilities(
+    network_url: &str,
+    connections: State<'_, ConnectionManager>,
+) -> Result<Value, String> {
+    match connections.get(network_url).capabilities().await {
+        Ok(capabilities) => Ok(capabilities.to_json()),
+        Err(e) => Err(e.to_string()),
+    }
 }
 
 #[tauri::command]
@@ -901,8 +1595,8 @@ fn bch_to_satoshi(value: f64) -> Result<u64, String> {
     }
 }
 #[tauri::command]
-fn non_token_utxo_balance_db(address: &str) -> Result<u64, u64> {
-    let utxo_from_db = get_db_utxo_unspent(&address);
+fn non_token_utxo_balance_db(address: &str, db: State<'_, sled::Db>) -> Result<u64, u64> {
+    let utxo_from_db = get_db_utxo_unspent(&db, &address);
     if utxo_from_db.is_ok() {
         let utxo_data = serde_json_to_utxo(utxo_from_db.unwrap(), address);
         let mut sum = 0;
@@ -918,8 +1612,8 @@ fn non_token_utxo_balance_db(address: &str) -> Result<u64, u64> {
 }
 
 #[tauri::command]
-fn utxo_balance_with_tokens_db(address: &str) -> Result<u64, u64> {
-    let utxo_from_db = get_db_utxo_unspent(&address);
+fn utxo_balance_with_tokens_db(address: &str, db: State<'_, sled::Db>) -> Result<u64, u64> {
+    let utxo_from_db = get_db_utxo_unspent(&db, &address);
     if utxo_from_db.is_ok() {
         let utxo_data = serde_json_to_utxo(utxo_from_db.unwrap(), address);
         let mut sum = 0;
@@ -937,19 +1631,19 @@ fn utxo_balance_with_tokens_db(address: &str) -> Result<u64, u64> {
 }
 
 #[tauri::command]
-fn get_db_unspent_utxos(address: &str) -> Result<Value, String> {
-    match get_db_utxo_unspent(&address) {
+fn get_db_unspent_utxos(address: &str, db: State<'_, sled::Db>) -> Result<Value, String> {
+    match get_db_utxo_unspent(&db, &address) {
         Ok(utxos) => Ok(utxos),
         Err(e) => Err(e.to_string()),
     }
 }
 
 #[tauri::command]
-fn get_token_utxo_data(address: &str) -> Result<Vec<Value>, u64> {
+fn get_token_utxo_data(address: &str, db: State<'_, sled::Db>) -> Result<Vec<Value>, u64> {
     let mut tokens = vec![];
 
-    let res = if get_db_utxo_unspent(&address).is_ok() {
-        let utxos = get_db_utxo_unspent(&address).unwrap();
+    let res = if get_db_utxo_unspent(&db, &address).is_ok() {
+        let utxos = get_db_utxo_unspent(&db, &address).unwrap();
 
         for utxo in utxos.as_array().unwrap() {
             if !utxo["token_data"].is_null() {
@@ -964,6 +1658,105 @@ fn get_token_utxo_data(address: &str) -> Result<Vec<Value>, u64> {
     res
 }
 
+/// Token holdings of every wallet address grouped by category.
+#[tauri::command]
+fn get_token_portfolio(addresses: Vec<String>, db: State<'_, sled::Db>) -> Result<Value, String> {
+    match TokenPortfolio::for_addresses(&db, &addresses) {
+        Ok(portfolio) => Ok(portfolio.to_json()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn token_metadata_json(tokens: Vec<bcmr::TokenMetadata>) -> Value {
+    Value::Array(tokens.iter().map(|t| t.to_json()).collect())
+}
+
+/// Imports a BCMR registry from a local file into the metadata cache.
+#[tauri::command]
+fn import_bcmr_file(path: &str) -> Result<Value, String> {
+    match bcmr::import_registry_file(path) {
+        Ok(tokens) => Ok(token_metadata_json(tokens)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Fetches a BCMR registry, through the proxy when one is set, and imports it into the
+/// metadata cache.
+#[tauri::command]
+async fn import_bcmr_url(
+    url: String,
+    connections: State<'_, ConnectionManager>,
+) -> Result<Value, String> {
+    let proxy = match connections.proxy() {
+        Ok(proxy) => proxy,
+        Err(e) => return Err(e.to_string()),
+    };
+    let fetch_url = url.clone();
+    let fetched = tauri::async_runtime::spawn_blocking(move || {
+        http_get(&fetch_url, Duration::from_secs(30), proxy.as_ref())
+    })
+    .await;
+    let body = match fetched {
+        Ok(Ok(body)) => body,
+        Ok(Err(e)) => return Err(e.to_string()),
+        Err(e) => return Err(e.to_string()),
+    };
+    match bcmr::import_registry_url(url.as_str(), |_| Ok(body)) {
+        Ok(tokens) => Ok(token_metadata_json(tokens)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[tauri::command]
+fn get_token_metadata(category: &str) -> Result<Value, String> {
+    match bcmr::get_metadata(category) {
+        Ok(Some(metadata)) => Ok(metadata.to_json()),
+        Ok(None) => Ok(Value::Null),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Supply report for a category we issued. Served from the cache unless `refresh` is set.
+#[tauri::command]
+async fn token_supply(
+    category: &str,
+    genesis_txid: Option<&str>,
+    addresses: Vec<String>,
+    network_url: &str,
+    refresh: Option<bool>,
+    connections: State<'_, ConnectionManager>,
+    db: State<'_, sled::Db>,
+) -> Result<Value, String> {
+    let category: TxId = match Sha256d::from_be_hex(category) {
+        Ok(txid) => txid.into(),
+        Err(e) => return Err(e.to_string()),
+    };
+    if !refresh.unwrap_or(false) {
+        match supply::cached_supply(&db, &category) {
+            Ok(Some(cached)) => return Ok(cached),
+            Ok(None) => {}
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+    let genesis_txid: Option<TxId> = match genesis_txid.map(Sha256d::from_be_hex) {
+        Some(Ok(txid)) => Some(txid.into()),
+        Some(Err(e)) => return Err(e.to_string()),
+        None => None,
+    };
+    let mut our_scripts = vec![];
+    for address in addresses.iter() {
+        match address_to_p2pkh(address) {
+            Ok(script) => our_scripts.push(script),
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+    let connection = connections.get(network_url);
+    match supply::compute_supply(&db, category, genesis_txid, &our_scripts, &connection).await {
+        Ok(report) => Ok(report.to_json()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
 pub fn create_nft(commitment: &str, capability: &str) -> Option<NFT> {
     // let commitment = match hex::decode(hex::encode(commitment.as_bytes())) {
     let commitment = match hex::decode(commitment) {
@@ -1063,11 +1856,38 @@ async fn close_splash(window: Window) {
         .unwrap();
 }
 
+/// `mode` is "system" to require a certificate from a system root or "pinned" for
+/// trust-on-first-use.
+#[tauri::command]
+fn set_server_tls_mode(
+    network_url: &str,
+    mode: &str,
+    connections: State<'_, ConnectionManager>,
+) -> Result<(), String> {
+    connections.set_tls_mode(network_url, TlsMode::from_name(mode)?);
+    Ok(())
+}
+
+/// Accepts a changed certificate, the next connection pins whatever the server presents.
+#[tauri::command]
+fn forget_server_certificate(
+    network_url: &str,
+    connections: State<'_, ConnectionManager>,
+) -> Result<(), String> {
+    match tls::forget_certificate(network_url) {
+        Ok(()) => {
+            connections.get(network_url).disconnect();
+            Ok(())
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
 #[tauri::command]
 fn check_url(url: &str) -> Result<(), String> {
     match Url::parse(url) {
         Ok(res) => match res.scheme() {
-            "tcp" => Ok(()),
+            "tcp" | "ssl" | "ws" | "wss" => Ok(()),
             _ => Err(format!("invalid scheme {}", res.scheme())),
         },
         Err(e) => Err(e.to_string()),
@@ -1078,6 +1898,7 @@ fn check_url(url: &str) -> Result<(), String> {
 /* async  */
 fn main() {
     // tauri::async_runtime::spawn(start_server());
+    env_logger::init();
     match does_db_exist() {
         true => {}
         false => {
@@ -1085,16 +1906,60 @@ fn main() {
             create_db()
         }
     }
+    let db = match wallet_db() {
+        Ok(db) => db,
+        Err(e) => panic!("Database open failed {}", e),
+    };
+    // wallet commands must never read a database this release can't understand
+    match schema::migrate(&db) {
+        Ok(version) => log::info!("Database schema {}", version),
+        Err(e) => panic!("Database migration failed {}", e),
+    }
+    let connections = ConnectionManager::load();
+    let pool = ServerPool::load();
+    let backends = BackendSelection::new(pool.clone(), connections.clone());
     tauri::Builder::default()
         .plugin(tauri_plugin_websocket::init())
+        .manage(db)
+        .manage(connections)
+        .manage(pool)
+        .manage(backends)
+        .manage(SubscriptionService::default())
+        .manage(BroadcastQueue::default())
+        .manage(FeeCache::default())
         .invoke_handler(tauri::generate_handler![
             check_url,
+            set_server_tls_mode,
+            forget_server_certificate,
+            set_servers,
+            set_server_cross_check,
+            check_servers,
+            server_status,
+            discover_servers,
+            server_capabilities,
+            set_wallet_network,
+            check_bchn_node,
+            check_p2p_peer,
+            use_bchn_backend,
+            use_p2p_backend,
+            use_electrum_backend,
+            get_double_spend_alerts,
+            sync_block_headers,
+            set_network_proxy,
+            get_network_proxy,
+            get_fee_presets,
+            get_fee_rate,
             create_db,
             does_db_exist,
             get_db_unspent_utxos,
             validate_token_cash_address,
             token_cash_address,
             get_token_utxo_data,
+            get_token_portfolio,
+            import_bcmr_file,
+            import_bcmr_url,
+            get_token_metadata,
+            token_supply,
             valid_nft,
             valid_token_amount,
             bch_to_satoshi,
@@ -1113,20 +1978,32 @@ fn main() {
             // get_pkh, //TODO wait for HD key stragety
             valid_xpriv_base58_check,
             create_change_pubkeyhash_store,
+            get_labels,
+            set_label,
             save_base58_xpriv,
             does_master_key_exist,
             validate_cash_address,
             address_history,
+            address_transactions,
+            get_raw_transaction,
+            sync_wallet_history,
+            get_wallet_history,
             broadcast_transaction,
+            get_outgoing_transactions,
+            forget_outgoing_transaction,
             build_p2pkh_transaction,
+            build_burn_transaction,
             network_unspent_utxos,
             non_token_utxo_balance_db,
             utxo_balance_with_tokens_db,
             subscribe_to_address,
             unsubscribe_to_address,
+            start_wallet_subscriptions,
+            stop_wallet_subscriptions,
             get_mempool_address,
             cashcaster::store::storage::store_utxos, /* db_utxos */
             update_utxo_store,
+            update_utxo_stores,
             network_unspent_balance_no_tokens,
             network_unspent_balance_include_tokens,
             network_ping,
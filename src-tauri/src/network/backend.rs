@@ -0,0 +1,298 @@
+//! Common api over the chain data sources: Electrum servers, a BCHN node over JSON-RPC (see
+//! `network::bchn`) and a peer-to-peer light client (see `network::p2p`). Responses use the
+//! Electrum shapes the rest of the wallet already stores, e.g. utxos are `listunspent` entries
+//! with Fulcrum `token_data`. The utxo sync and send commands go through the backend of
+//! [BackendSelection], Electrum through the server pool until a node or peer is selected.
+//! The peer lists the history of an address with bloom filter scans, a BCHN node can't list
+//! it. Address history, subscriptions, merkle proofs and the broadcast queue's retries still
+//! talk to Electrum through `network::electrum` whichever backend is selected.
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bitcoinsuite_core::script::Script;
+use tokio::sync::mpsc;
+
+use super::connection::{ConnectionManager, ElectrumConnection};
+use super::electrum::{
+    estimate_fee, get_script_history, get_script_unspent, get_scripts_unspent_batch,
+    get_tip_header, get_transaction, send_raw_transaction,
+};
+use super::error::NetworkError;
+use super::pool::{same_utxo_set, same_utxo_sets, ServerPool};
+
+pub type BackendFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, NetworkError>> + Send + 'a>>;
+
+/// Target used for `fee_estimate`
+pub const FEE_TARGET_BLOCKS: u32 = 6;
+
+pub trait ChainBackend: Send + Sync {
+    /// Name shown in the ui, the server url for Electrum
+    fn name(&self) -> String;
+    /// Unspent outputs locked by `script` as a `listunspent` json array, tokens included.
+    fn unspent<'a>(&'a self, script: &'a Script) -> BackendFuture<'a, String>;
+    /// `unspent` of every script, in the order of `scripts`. One request at a time unless the
+    /// source can batch them.
+    fn unspent_batch<'a>(&'a self, scripts: &'a [Script]) -> BackendFuture<'a, Vec<String>> {
+        Box::pin(async move {
+            let mut utxos = Vec::with_capacity(scripts.len());
+            for script in scripts {
+                utxos.push(self.unspent(script).await?);
+            }
+            Ok(utxos)
+        })
+    }
+    /// `[{"tx_hash", "height"}]` json array of the transactions touching `script`. Sources
+    /// without an address index return `Unsupported`.
+    fn history<'a>(&'a self, script: &'a Script) -> BackendFuture<'a, String>;
+    /// Raw transaction hex.
+    fn transaction<'a>(&'a self, txid: &'a str) -> BackendFuture<'a, String>;
+    /// Broadcasts `raw_tx` and returns its txid.
+    fn broadcast<'a>(&'a self, raw_tx: &'a str) -> BackendFuture<'a, String>;
+    /// Fee in BCH per kB, negative when unknown.
+    fn fee_estimate(&self) -> BackendFuture<'_, f64>;
+    /// Chain tip as `(height, raw header)`, polled by `subscribe_tip` for new tips.
+    fn tip(&self) -> BackendFuture<'_, (u32, Vec<u8>)>;
+}
+
+/// Polls `tip` every `interval` and sends each new tip. Stops when the receiver is dropped.
+pub fn subscribe_tip(
+    backend: Arc<dyn ChainBackend>,
+    interval: Duration,
+) -> mpsc::Receiver<(u32, Vec<u8>)> {
+    let (sender, receiver) = mpsc::channel(16);
+    tokio::spawn(async move {
+        let mut last = None;
+        loop {
+            match backend.tip().await {
+                Ok(tip) if last.as_ref() != Some(&tip) => {
+                    last = Some(tip.clone());
+                    if sender.send(tip).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => log::warn!("{} tip failed: {}", backend.name(), e),
+            }
+            if sender.is_closed() {
+                return;
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+    receiver
+}
+
+pub struct ElectrumBackend {
+    connection: Arc<ElectrumConnection>,
+}
+
+impl ElectrumBackend {
+    pub fn new(connection: Arc<ElectrumConnection>) -> Self {
+        ElectrumBackend { connection }
+    }
+}
+
+impl ChainBackend for ElectrumBackend {
+    fn name(&self) -> String {
+        self.connection.url().to_string()
+    }
+
+    fn unspent<'a>(&'a self, script: &'a Script) -> BackendFuture<'a, String> {
+        Box::pin(get_script_unspent(script, Some("include_tokens"), &self.connection))
+    }
+
+    fn history<'a>(&'a self, script: &'a Script) -> BackendFuture<'a, String> {
+        Box::pin(get_script_history(script, &self.connection))
+    }
+
+    fn transaction<'a>(&'a self, txid: &'a str) -> BackendFuture<'a, String> {
+        Box::pin(get_transaction(txid, &self.connection))
+    }
+
+    fn broadcast<'a>(&'a self, raw_tx: &'a str) -> BackendFuture<'a, String> {
+        Box::pin(async move {
+            // send_raw_transaction returns the txid as a json string
+            let txid = send_raw_transaction(raw_tx, &self.connection).await?;
+            Ok(txid.trim_matches('"').to_string())
+        })
+    }
+
+    fn fee_estimate(&self) -> BackendFuture<'_, f64> {
+        Box::pin(estimate_fee(FEE_TARGET_BLOCKS, &self.connection))
+    }
+
+    fn tip(&self) -> BackendFuture<'_, (u32, Vec<u8>)> {
+        Box::pin(get_tip_header(&self.connection))
+    }
+}
+
+/// Electrum through the server pool: requests fail over to the next ranked server and, with
+/// cross-checking on, utxos and broadcasts must agree between two servers.
+pub struct PoolBackend {
+    pool: ServerPool,
+    connections: ConnectionManager,
+    preferred: String,
+}
+
+impl PoolBackend {
+    /// `preferred` is asked first, the other servers of `pool` when it fails.
+    pub fn new(pool: ServerPool, connections: ConnectionManager, preferred: &str) -> Self {
+        PoolBackend {
+            pool,
+            connections,
+            preferred: preferred.to_string(),
+        }
+    }
+}
+
+impl ChainBackend for PoolBackend {
+    fn name(&self) -> String {
+        self.preferred.clone()
+    }
+
+    fn unspent<'a>(&'a self, script: &'a Script) -> BackendFuture<'a, String> {
+        Box::pin(self.pool.cross_checked(
+            &self.connections,
+            &self.preferred,
+            move |connection| async move {
+                get_script_unspent(script, Some("include_tokens"), &connection).await
+            },
+            |a: &String, b: &String| same_utxo_set(a, b),
+        ))
+    }
+
+    fn unspent_batch<'a>(&'a self, scripts: &'a [Script]) -> BackendFuture<'a, Vec<String>> {
+        Box::pin(self.pool.cross_checked(
+            &self.connections,
+            &self.preferred,
+            move |connection| async move {
+                get_scripts_unspent_batch(scripts, Some("include_tokens"), &connection).await
+            },
+            |a: &Vec<String>, b: &Vec<String>| same_utxo_sets(a, b),
+        ))
+    }
+
+    fn history<'a>(&'a self, script: &'a Script) -> BackendFuture<'a, String> {
+        Box::pin(self.pool.with_failover(
+            &self.connections,
+            &self.preferred,
+            move |connection| async move { get_script_history(script, &connection).await },
+        ))
+    }
+
+    fn transaction<'a>(&'a self, txid: &'a str) -> BackendFuture<'a, String> {
+        Box::pin(self.pool.with_failover(
+            &self.connections,
+            &self.preferred,
+            move |connection| async move { get_transaction(txid, &connection).await },
+        ))
+    }
+
+    fn broadcast<'a>(&'a self, raw_tx: &'a str) -> BackendFuture<'a, String> {
+        Box::pin(async move {
+            let txid = self
+                .pool
+                .cross_checked(
+                    &self.connections,
+                    &self.preferred,
+                    |connection| async move { send_raw_transaction(raw_tx, &connection).await },
+                    |a: &String, b: &String| a == b,
+                )
+                .await?;
+            Ok(txid.trim_matches('"').to_string())
+        })
+    }
+
+    fn fee_estimate(&self) -> BackendFuture<'_, f64> {
+        Box::pin(self.pool.with_failover(
+            &self.connections,
+            &self.preferred,
+            |connection| async move { estimate_fee(FEE_TARGET_BLOCKS, &connection).await },
+        ))
+    }
+
+    fn tip(&self) -> BackendFuture<'_, (u32, Vec<u8>)> {
+        Box::pin(self.pool.with_failover(
+            &self.connections,
+            &self.preferred,
+            |connection| async move { get_tip_header(&connection).await },
+        ))
+    }
+}
+
+/// Backend the send and sync commands go through, shared with the tauri state. Electrum
+/// through the server pool unless another one was selected.
+#[derive(Clone)]
+pub struct BackendSelection {
+    pool: ServerPool,
+    connections: ConnectionManager,
+    selected: Arc<Mutex<Option<Arc<dyn ChainBackend>>>>,
+}
+
+impl BackendSelection {
+    /// Selection of the Electrum servers of `pool`, opened through `connections`.
+    pub fn new(pool: ServerPool, connections: ConnectionManager) -> Self {
+        BackendSelection {
+            pool,
+            connections,
+            selected: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Sends and syncs through `backend` from now on, through the server pool again when
+    /// `None`.
+    pub fn select(&self, backend: Option<Arc<dyn ChainBackend>>) {
+        *self.selected.lock().unwrap() = backend;
+    }
+
+    /// The selected backend, or a [PoolBackend] asking `preferred` first.
+    pub fn backend(&self, preferred: &str) -> Arc<dyn ChainBackend> {
+        match self.selected.lock().unwrap().as_ref() {
+            Some(backend) => backend.clone(),
+            None => {
+                let connections = self.connections.clone();
+                Arc::new(PoolBackend::new(self.pool.clone(), connections, preferred))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::address_to_script;
+    use crate::network::mock::MockElectrum;
+
+    #[tokio::test]
+    async fn electrum_backend() {
+        let mock = MockElectrum::start();
+        let address = "bchtest:qptnz3u8atavszhaqk037v0fjrtahxmsl5mm45u3pf";
+        mock.add_utxo(address, &"bb".repeat(32), 0, 5_000, 10);
+        let connection = Arc::new(ElectrumConnection::new(&mock.url(), Default::default()));
+        let backend: Arc<dyn ChainBackend> = Arc::new(ElectrumBackend::new(connection));
+
+        let script = address_to_script(address).unwrap();
+        assert!(backend.unspent(&script).await.unwrap().contains("5000"));
+        assert_eq!(backend.broadcast("00aa").await.unwrap().len(), 64);
+
+        let mut tips = subscribe_tip(backend, Duration::from_millis(10));
+        assert_eq!(tips.recv().await.unwrap().0, 0);
+    }
+
+    #[tokio::test]
+    async fn pool_backend_by_default() {
+        let mock = MockElectrum::start();
+        let address = "bchtest:qptnz3u8atavszhaqk037v0fjrtahxmsl5mm45u3pf";
+        mock.add_utxo(address, &"bb".repeat(32), 0, 5_000, 10);
+        let backends = BackendSelection::new(ServerPool::default(), ConnectionManager::default());
+        let backend = backends.backend(&mock.url());
+        assert_eq!(backend.name(), mock.url());
+
+        let scripts = vec![address_to_script(address).unwrap()];
+        let utxos = backend.unspent_batch(&scripts).await.unwrap();
+        assert!(utxos[0].contains("5000"));
+        assert_eq!(backend.broadcast("00aa").await.unwrap().len(), 64);
+    }
+}
@@ -0,0 +1,343 @@
+//! BCHN `bitcoind` JSON-RPC backend for users running their own node. There is no ZMQ
+//! subscription, it would need the native libzmq: new tips are found by polling `tip`, see
+//! `backend::subscribe_tip`. Without an address index the node can only answer utxo lookups
+//! (`scantxoutset`, confirmed outputs only) and `history` always fails with `Unsupported`, so
+//! the node can't replace an Electrum server for wallet sync. `getrawtransaction` needs
+//! `-txindex` for confirmed transactions. Requests go through the configured proxy.
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::Engine;
+use bitcoinsuite_core::script::Script;
+use serde_json::{json, Value};
+
+use super::backend::{BackendFuture, ChainBackend};
+use super::error::NetworkError;
+use super::proxy::{connect_stream, ProxyConfig};
+
+#[derive(Clone, Debug)]
+struct RpcEndpoint {
+    url: String,
+    host: String,
+    port: u16,
+    path: String,
+    /// base64 of `user:password`
+    auth: String,
+    timeout: Duration,
+    proxy: Option<ProxyConfig>,
+}
+
+pub struct BchnBackend {
+    endpoint: Arc<RpcEndpoint>,
+    next_id: AtomicUsize,
+}
+
+/// Status code and body of a raw HTTP/1.1 response.
+fn parse_http_response(raw: &[u8]) -> Result<(u16, Vec<u8>), String> {
+    let split = match raw.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(split) => split,
+        None => return Err("incomplete http response".to_string()),
+    };
+    let head = String::from_utf8_lossy(&raw[..split]).to_string();
+    let body = &raw[split + 4..];
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok());
+    let status = match status {
+        Some(status) => status,
+        None => return Err(format!("bad status line {}", head.lines().next().unwrap_or(""))),
+    };
+    let chunked = head
+        .lines()
+        .any(|line| line.to_ascii_lowercase() == "transfer-encoding: chunked");
+    match chunked {
+        true => Ok((status, dechunk(body)?)),
+        false => Ok((status, body.to_vec())),
+    }
+}
+
+fn dechunk(mut body: &[u8]) -> Result<Vec<u8>, String> {
+    let mut out = vec![];
+    loop {
+        let line_end = match body.windows(2).position(|w| w == b"\r\n") {
+            Some(end) => end,
+            None => return Err("bad chunk".to_string()),
+        };
+        let size = String::from_utf8_lossy(&body[..line_end]).to_string();
+        let size = match usize::from_str_radix(size.split(';').next().unwrap_or("").trim(), 16) {
+            Ok(size) => size,
+            Err(e) => return Err(e.to_string()),
+        };
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Ok(out);
+        }
+        if body.len() < size {
+            return Err("truncated chunk".to_string());
+        }
+        out.extend_from_slice(&body[..size]);
+        body = body.get(size + 2..).unwrap_or_default();
+    }
+}
+
+/// `scantxoutset` result to a `listunspent` array. BCHN `tokenData` already has the Fulcrum
+/// `token_data` layout.
+fn scan_to_listunspent(scan: &Value) -> Option<Value> {
+    let unspents = scan["unspents"].as_array()?;
+    let mut utxos = vec![];
+    for unspent in unspents {
+        let mut utxo = json!({
+            "tx_hash": unspent["txid"].as_str()?,
+            "tx_pos": unspent["vout"].as_u64()?,
+            "value": (unspent["amount"].as_f64()? * 1e8).round() as u64,
+            "height": unspent["height"].as_u64()?,
+        });
+        if !unspent["tokenData"].is_null() {
+            utxo["token_data"] = unspent["tokenData"].clone();
+        }
+        utxos.push(utxo);
+    }
+    Some(Value::Array(utxos))
+}
+
+impl RpcEndpoint {
+    fn call(&self, id: usize, method: &str, params: Vec<Value>) -> Result<Value, NetworkError> {
+        let request_err = |reason: String| NetworkError::Request {
+            method: method.to_string(),
+            reason,
+        };
+        let body = json!({"jsonrpc":"1.0","id":id,"method":method,"params":params}).to_string();
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}:{}\r\nAuthorization: Basic {}\r\n\
+             Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.host,
+            self.port,
+            self.auth,
+            body.len(),
+            body
+        );
+        let mut stream = connect_stream(&self.host, self.port, self.timeout, self.proxy.as_ref())?;
+        if let Err(e) = stream.write_all(request.as_bytes()) {
+            return Err(request_err(e.to_string()));
+        }
+        let mut raw = vec![];
+        if let Err(e) = stream.read_to_end(&mut raw) {
+            return Err(request_err(e.to_string()));
+        }
+        let (status, body) = parse_http_response(&raw).map_err(request_err)?;
+        if status == 401 || status == 403 {
+            return Err(NetworkError::Connect {
+                url: self.url.clone(),
+                reason: "rpc credentials refused".to_string(),
+            });
+        }
+        // rpc errors come back as 500 with a json body
+        let response: Value = match serde_json::from_slice(&body) {
+            Ok(response) => response,
+            Err(_) => return Err(request_err(format!("http status {}", status))),
+        };
+        if !response["error"].is_null() {
+            return Err(request_err(response["error"]["message"].to_string()));
+        }
+        Ok(response["result"].clone())
+    }
+}
+
+impl BchnBackend {
+    /// `url` is the node rpc url, e.g. `http://127.0.0.1:18443` for regtest.
+    pub fn new(
+        url: &str,
+        user: &str,
+        password: &str,
+        timeout: Duration,
+        proxy: Option<ProxyConfig>,
+    ) -> Result<Self, NetworkError> {
+        let invalid = |reason: &str| NetworkError::InvalidUrl {
+            url: url.to_string(),
+            reason: reason.to_string(),
+        };
+        let parsed = match url::Url::parse(url) {
+            Ok(parsed) => parsed,
+            Err(e) => return Err(invalid(&e.to_string())),
+        };
+        if parsed.scheme() != "http" {
+            return Err(invalid("only http rpc is supported"));
+        }
+        let host = match parsed.host_str() {
+            Some(host) => host.to_string(),
+            None => return Err(invalid("missing host")),
+        };
+        let auth = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password));
+        Ok(BchnBackend {
+            endpoint: Arc::new(RpcEndpoint {
+                url: url.to_string(),
+                host,
+                port: parsed.port().unwrap_or(8332),
+                path: parsed.path().to_string(),
+                auth,
+                timeout,
+                proxy,
+            }),
+            next_id: AtomicUsize::new(0),
+        })
+    }
+
+    /// Runs the blocking rpc call on the blocking pool.
+    pub async fn call(&self, method: &str, params: Vec<Value>) -> Result<Value, NetworkError> {
+        let endpoint = self.endpoint.clone();
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let request_method = method.to_string();
+        let call = tokio::task::spawn_blocking(move || endpoint.call(id, &request_method, params));
+        match call.await {
+            Ok(res) => res,
+            Err(e) => Err(NetworkError::Request {
+                method: method.to_string(),
+                reason: e.to_string(),
+            }),
+        }
+    }
+
+    fn unexpected(method: &str, res: &Value) -> NetworkError {
+        NetworkError::UnexpectedResponse {
+            method: method.to_string(),
+            response: res.to_string(),
+        }
+    }
+
+    async fn string_call(&self, method: &str, params: Vec<Value>) -> Result<String, NetworkError> {
+        let res = self.call(method, params).await?;
+        match res.as_str() {
+            Some(res) => Ok(res.to_string()),
+            None => Err(Self::unexpected(method, &res)),
+        }
+    }
+}
+
+impl ChainBackend for BchnBackend {
+    fn name(&self) -> String {
+        self.endpoint.url.clone()
+    }
+
+    fn unspent<'a>(&'a self, script: &'a Script) -> BackendFuture<'a, String> {
+        Box::pin(async move {
+            let method = "scantxoutset";
+            let descriptor = format!("raw({})", hex::encode(script.bytecode()));
+            let params = vec![json!("start"), json!([{ "desc": descriptor }])];
+            let res = self.call(method, params).await?;
+            match scan_to_listunspent(&res) {
+                Some(utxos) => Ok(utxos.to_string()),
+                None => Err(Self::unexpected(method, &res)),
+            }
+        })
+    }
+
+    /// Always `Unsupported`, bitcoind keeps no index of the transactions of a script.
+    fn history<'a>(&'a self, _script: &'a Script) -> BackendFuture<'a, String> {
+        Box::pin(async move {
+            Err(NetworkError::Unsupported {
+                url: self.endpoint.url.clone(),
+                reason: "bitcoind has no address index for history".to_string(),
+            })
+        })
+    }
+
+    fn transaction<'a>(&'a self, txid: &'a str) -> BackendFuture<'a, String> {
+        Box::pin(self.string_call("getrawtransaction", vec![json!(txid), json!(false)]))
+    }
+
+    fn broadcast<'a>(&'a self, raw_tx: &'a str) -> BackendFuture<'a, String> {
+        Box::pin(self.string_call("sendrawtransaction", vec![json!(raw_tx)]))
+    }
+
+    fn fee_estimate(&self) -> BackendFuture<'_, f64> {
+        Box::pin(async move {
+            let method = "estimatefee";
+            let res = self.call(method, vec![]).await?;
+            match res.as_f64() {
+                Some(fee) => Ok(fee),
+                None => Err(Self::unexpected(method, &res)),
+            }
+        })
+    }
+
+    fn tip(&self) -> BackendFuture<'_, (u32, Vec<u8>)> {
+        Box::pin(async move {
+            let hash = self.string_call("getbestblockhash", vec![]).await?;
+            let method = "getblockheader";
+            let verbose = self.call(method, vec![json!(hash), json!(true)]).await?;
+            let height = match verbose["height"].as_u64() {
+                Some(height) => height as u32,
+                None => return Err(Self::unexpected(method, &verbose)),
+            };
+            let raw = self.string_call(method, vec![json!(hash), json!(false)]).await?;
+            match hex::decode(&raw) {
+                Ok(header) => Ok((height, header)),
+                Err(_) => Err(Self::unexpected(method, &Value::String(raw))),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http_responses() {
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n{\"result\":1}";
+        assert_eq!(
+            parse_http_response(raw).unwrap(),
+            (200, b"{\"result\":1}".to_vec())
+        );
+        let chunked = b"HTTP/1.1 500 Internal Server Error\r\nTransfer-Encoding: chunked\r\n\r\n4\r\n{\"a\"\r\n3\r\n:1}\r\n0\r\n\r\n";
+        assert_eq!(
+            parse_http_response(chunked).unwrap(),
+            (500, b"{\"a\":1}".to_vec())
+        );
+        assert!(parse_http_response(b"HTTP/1.1 200").is_err());
+    }
+
+    #[test]
+    fn scanned_utxos() {
+        let scan = json!({
+            "success": true,
+            "unspents": [{
+                "txid": "bb".repeat(32),
+                "vout": 1,
+                "scriptPubKey": "76a914",
+                "amount": 0.00001,
+                "height": 120,
+                "tokenData": {"category": "cc".repeat(32), "amount": "100"},
+            }],
+        });
+        let utxos = scan_to_listunspent(&scan).unwrap();
+        assert_eq!(utxos[0]["value"], 1_000);
+        assert_eq!(utxos[0]["tx_pos"], 1);
+        assert_eq!(utxos[0]["token_data"]["amount"], "100");
+    }
+
+    /// Needs a BCHN regtest node, e.g.
+    /// `BCHN_RPC_URL=http://127.0.0.1:18443 BCHN_RPC_USER=u BCHN_RPC_PASSWORD=p cargo test -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn regtest_node() {
+        let env = |key: &str| std::env::var(key).unwrap();
+        let backend = BchnBackend::new(
+            &env("BCHN_RPC_URL"),
+            &env("BCHN_RPC_USER"),
+            &env("BCHN_RPC_PASSWORD"),
+            Duration::from_secs(30),
+            None,
+        )
+        .unwrap();
+        let (height, header) = backend.tip().await.unwrap();
+        assert_eq!(header.len(), 80);
+        let hash = backend.call("getblockhash", vec![json!(height)]).await.unwrap();
+        assert_eq!(hash.as_str().unwrap().len(), 64);
+        assert!(backend.transaction(&"00".repeat(32)).await.is_err());
+    }
+}
@@ -0,0 +1,192 @@
+//! `server.version` / `server.features` handshake run on every new connection. The wallet
+//! needs CashTokens aware responses, servers without them are refused, and so are servers on
+//! another chain than the wallet's. Double-spend proofs are optional.
+use std::fmt;
+
+use electrum_client::Param;
+use serde_json::{json, Value};
+
+use super::error::NetworkError;
+use super::p2p::message::{hash_hex, Network};
+use super::transport::ElectrumTransport;
+
+const CLIENT_NAME: &str = concat!("cashcaster ", env!("CARGO_PKG_VERSION"));
+/// First protocol with CashTokens, the version asked for in the handshake
+const PROTOCOL_CASHTOKENS: ProtocolVersion = ProtocolVersion(1, 5, 0);
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct ProtocolVersion(pub u32, pub u32, pub u32);
+
+impl ProtocolVersion {
+    /// Parses `1.4` or `1.4.3`.
+    pub fn parse(version: &str) -> Option<Self> {
+        let mut parts = version.trim().split('.').map(|p| p.parse::<u32>());
+        let major = parts.next()?.ok()?;
+        let minor = parts.next()?.ok()?;
+        let patch = match parts.next() {
+            Some(patch) => patch.ok()?,
+            None => 0,
+        };
+        match parts.next() {
+            Some(_) => None,
+            None => Some(ProtocolVersion(major, minor, patch)),
+        }
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.2 {
+            0 => write!(f, "{}.{}", self.0, self.1),
+            patch => write!(f, "{}.{}.{}", self.0, self.1, patch),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerCapabilities {
+    pub server_software: String,
+    /// Version agreed in `server.version`
+    pub protocol: ProtocolVersion,
+    pub genesis_hash: Option<String>,
+    pub cashtokens: bool,
+    pub dsproof: bool,
+}
+
+fn unexpected(method: &str, res: &Value) -> NetworkError {
+    NetworkError::UnexpectedResponse {
+        method: method.to_string(),
+        response: res.to_string(),
+    }
+}
+
+impl ServerCapabilities {
+    /// Negotiates the protocol version and reads the server features. Must be the first
+    /// request on a connection. Params can't carry a `[min, max]` range, so the CashTokens
+    /// version is asked for and servers that don't speak it fail here.
+    pub fn negotiate(client: &dyn ElectrumTransport) -> Result<Self, NetworkError> {
+        let method = "server.version";
+        let params = vec![
+            Param::String(CLIENT_NAME.to_string()),
+            Param::String(PROTOCOL_CASHTOKENS.to_string()),
+        ];
+        let version = client.raw_call(method, params)?;
+        let (server_software, protocol) = match version.as_array().map(|v| v.as_slice()) {
+            Some([software, protocol]) => {
+                match (software.as_str(), protocol.as_str().and_then(ProtocolVersion::parse)) {
+                    (Some(software), Some(protocol)) => (software.to_string(), protocol),
+                    _ => return Err(unexpected(method, &version)),
+                }
+            }
+            _ => return Err(unexpected(method, &version)),
+        };
+        let features = client.raw_call("server.features", vec![])?;
+        Ok(Self::from_features(server_software, protocol, &features))
+    }
+
+    fn from_features(
+        server_software: String,
+        protocol: ProtocolVersion,
+        features: &Value,
+    ) -> Self {
+        ServerCapabilities {
+            server_software,
+            protocol,
+            genesis_hash: features["genesis_hash"].as_str().map(String::from),
+            cashtokens: features["cashtokens"].as_bool() == Some(true)
+                || protocol >= PROTOCOL_CASHTOKENS,
+            dsproof: features["dsproof"].as_bool() == Some(true),
+        }
+    }
+
+    /// Why the wallet on `network` can't use this server, `None` when it can. Without a
+    /// network any chain is accepted.
+    pub fn missing_required(&self, network: Option<Network>) -> Option<String> {
+        if !self.cashtokens {
+            return Some(format!("{} does not support CashTokens", self.server_software));
+        }
+        let network = network?;
+        let genesis = hash_hex(&network.genesis_hash());
+        match self.genesis_hash.as_deref() {
+            Some(hash) if hash.eq_ignore_ascii_case(&genesis) => None,
+            Some(hash) => Some(format!("server genesis {} is not {}", hash, network.name())),
+            None => Some(format!(
+                "server does not report its genesis, {} expected",
+                network.name()
+            )),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "serverSoftware": self.server_software,
+            "protocol": self.protocol.to_string(),
+            "genesisHash": self.genesis_hash,
+            "cashtokens": self.cashtokens,
+            "dsproof": self.dsproof,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::connection::{ConnectionConfig, ElectrumConnection};
+    use crate::network::mock::MockElectrum;
+
+    #[test]
+    fn versions() {
+        assert_eq!(ProtocolVersion::parse("1.4"), Some(ProtocolVersion(1, 4, 0)));
+        assert_eq!(ProtocolVersion::parse("1.4.3"), Some(ProtocolVersion(1, 4, 3)));
+        assert_eq!(ProtocolVersion::parse("1.4.3.1"), None);
+        assert_eq!(ProtocolVersion::parse("one"), None);
+        assert!(ProtocolVersion(1, 4, 3) < ProtocolVersion(1, 5, 0));
+        assert_eq!(ProtocolVersion(1, 4, 3).to_string(), "1.4.3");
+
+        let old = ServerCapabilities::from_features(
+            "ElectronCash 3.3".to_string(),
+            ProtocolVersion(1, 4, 0),
+            &json!({}),
+        );
+        assert!(old.missing_required(None).is_some());
+        let tokens = ServerCapabilities::from_features(
+            "Fulcrum 1.9.8".to_string(),
+            ProtocolVersion(1, 4, 3),
+            &json!({"cashtokens": true}),
+        );
+        assert_eq!(tokens.missing_required(None), None);
+        // no genesis reported, the chain can't be checked
+        assert!(tokens.missing_required(Some(Network::Mainnet)).is_some());
+
+        let mainnet = ServerCapabilities::from_features(
+            "Fulcrum 1.9.8".to_string(),
+            ProtocolVersion(1, 5, 0),
+            &json!({"genesis_hash": hash_hex(&Network::Mainnet.genesis_hash())}),
+        );
+        assert_eq!(mainnet.missing_required(Some(Network::Mainnet)), None);
+        assert!(mainnet.missing_required(Some(Network::Chipnet)).is_some());
+    }
+
+    #[tokio::test]
+    async fn handshake() {
+        let mock = MockElectrum::start();
+        let connection = ElectrumConnection::new(&mock.url(), Default::default());
+        let capabilities = connection.capabilities().await.unwrap();
+        assert_eq!(capabilities.protocol, ProtocolVersion(1, 5, 0));
+        assert!(capabilities.cashtokens);
+        assert!(capabilities.dsproof);
+        assert_eq!(mock.requests()[..2], ["server.version", "server.features"]);
+
+        // the mock serves mainnet
+        let config = ConnectionConfig {
+            network: Some(Network::Chipnet),
+            max_connect_attempts: 1,
+            ..Default::default()
+        };
+        let chipnet = ElectrumConnection::new(&mock.url(), config);
+        assert!(matches!(
+            chipnet.capabilities().await,
+            Err(NetworkError::Unsupported { .. })
+        ));
+    }
+}
@@ -0,0 +1,416 @@
+//! Long lived Electrum connections shared by every command through tauri state.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use electrum_client::raw_client::RawClient;
+use electrum_client::{Client, ConfigBuilder, Param};
+use serde_json::Value;
+
+use super::capabilities::ServerCapabilities;
+use super::error::NetworkError;
+use super::p2p::message::Network;
+use super::proxy::{is_onion, ProxyConfig};
+use super::tls::{connect_pinned, server_id, TlsMode};
+use super::transport::ElectrumTransport;
+use super::ws::WsClient;
+use crate::store::storage::{wallet_db, SETTINGS_TREE};
+
+/// Requests per JSON-RPC batch, Fulcrum refuses batches above its `max_batch` (345 by default)
+const MAX_BATCH_SIZE: usize = 100;
+const NETWORK_KEY: &str = "network";
+
+#[derive(Clone, Debug)]
+pub struct ConnectionConfig {
+    /// Socket timeout, also the deadline of a single request
+    pub timeout_secs: u8,
+    /// Connection attempts before giving up, each one waits twice as long as the last
+    pub max_connect_attempts: u32,
+    pub backoff_base: Duration,
+    pub backoff_max: Duration,
+    /// How `ssl://` and `wss://` certificates are trusted
+    pub tls: TlsMode,
+    /// SOCKS5 proxy for every connection, required for onion servers
+    pub proxy: Option<ProxyConfig>,
+    /// Why the saved proxy could not be read, nothing connects until a proxy is set again
+    pub proxy_error: Option<NetworkError>,
+    /// Chain of the wallet, servers with another genesis are refused
+    pub network: Option<Network>,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        ConnectionConfig {
+            timeout_secs: 15,
+            max_connect_attempts: 4,
+            backoff_base: Duration::from_millis(250),
+            backoff_max: Duration::from_secs(10),
+            tls: TlsMode::default(),
+            proxy: None,
+            proxy_error: None,
+            network: None,
+        }
+    }
+}
+
+impl ConnectionConfig {
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self.backoff_base.saturating_mul(2u32.saturating_pow(attempt));
+        delay.min(self.backoff_max)
+    }
+}
+
+/// One server over tcp, ssl, ws or wss. The client is created lazily and dropped after a
+/// transport error so the next call reconnects. Every new client runs the version handshake
+/// first and servers missing required features are refused.
+pub struct ElectrumConnection {
+    url: String,
+    config: Mutex<ConnectionConfig>,
+    client: Mutex<Option<Arc<dyn ElectrumTransport>>>,
+    capabilities: Mutex<Option<ServerCapabilities>>,
+    /// Clients opened so far
+    generation: AtomicU64,
+}
+
+impl ElectrumConnection {
+    pub fn new(url: &str, config: ConnectionConfig) -> Self {
+        ElectrumConnection {
+            url: url.to_string(),
+            config: Mutex::new(config),
+            client: Mutex::new(None),
+            capabilities: Mutex::new(None),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn config(&self) -> ConnectionConfig {
+        self.config.lock().unwrap().clone()
+    }
+
+    /// Replaces the config and drops the current client so the next call uses it.
+    pub fn set_config(&self, config: ConnectionConfig) {
+        *self.config.lock().unwrap() = config;
+        self.disconnect();
+    }
+
+    /// Drops the current client, the next call opens a new connection.
+    pub fn disconnect(&self) {
+        *self.client.lock().unwrap() = None;
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.client.lock().unwrap().is_some()
+    }
+
+    /// Changes whenever a new client is opened. Server side subscriptions only live as long
+    /// as their client, a new generation has to subscribe again.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// What the server supports, connecting first when needed.
+    pub async fn capabilities(&self) -> Result<ServerCapabilities, NetworkError> {
+        self.client().await?;
+        match self.capabilities.lock().unwrap().clone() {
+            Some(capabilities) => Ok(capabilities),
+            None => Err(NetworkError::Connect {
+                url: self.url.clone(),
+                reason: "disconnected".to_string(),
+            }),
+        }
+    }
+
+    fn open(
+        url: &str,
+        config: &ConnectionConfig,
+    ) -> Result<Arc<dyn ElectrumTransport>, NetworkError> {
+        let connect_err = |reason: String| NetworkError::Connect {
+            url: url.to_string(),
+            reason,
+        };
+        if let Some(e) = &config.proxy_error {
+            return Err(connect_err(format!("proxy setting unavailable, {}", e)));
+        }
+        if config.proxy.is_none() && server_id(url).map_or(false, |(host, _)| is_onion(&host)) {
+            return Err(connect_err("onion servers need a proxy".to_string()));
+        }
+        let timeout = Duration::from_secs(config.timeout_secs as u64);
+        if url.starts_with("ws://") || url.starts_with("wss://") {
+            let client = WsClient::connect(url, timeout, config.tls, config.proxy.as_ref())?;
+            return Ok(Arc::new(client));
+        }
+        // electrum-client can't take our verifier, pinned servers get our own tls stream
+        if url.starts_with("ssl://") && config.tls == TlsMode::TrustOnFirstUse {
+            let stream = connect_pinned(url, timeout, config.proxy.as_ref())?;
+            return Ok(Arc::new(RawClient::from(stream)));
+        }
+        let electrum_config = ConfigBuilder::new()
+            .timeout(Some(config.timeout_secs))
+            .validate_domain(config.tls == TlsMode::SystemRoots)
+            .socks5(config.proxy.as_ref().map(|proxy| proxy.electrum_config()))
+            .build();
+        match Client::from_config(url, electrum_config) {
+            Ok(client) => Ok(Arc::new(client)),
+            Err(e) => Err(connect_err(e.to_string())),
+        }
+    }
+
+    async fn client(&self) -> Result<Arc<dyn ElectrumTransport>, NetworkError> {
+        if let Some(client) = self.client.lock().unwrap().clone() {
+            return Ok(client);
+        }
+        let config = self.config();
+        let mut attempt = 0;
+        loop {
+            let url = self.url.clone();
+            let open_config = config.clone();
+            let connected = tokio::task::spawn_blocking(move || {
+                let client = Self::open(&url, &open_config)?;
+                let capabilities = ServerCapabilities::negotiate(client.as_ref());
+                Ok::<_, NetworkError>((client, capabilities))
+            })
+            .await;
+            let reason = match connected {
+                Ok(Ok((client, Ok(capabilities)))) => {
+                    if let Some(reason) = capabilities.missing_required(config.network) {
+                        return Err(NetworkError::Unsupported {
+                            url: self.url.clone(),
+                            reason,
+                        });
+                    }
+                    *self.capabilities.lock().unwrap() = Some(capabilities);
+                    *self.client.lock().unwrap() = Some(client.clone());
+                    self.generation.fetch_add(1, Ordering::SeqCst);
+                    return Ok(client);
+                }
+                Ok(Ok((_, Err(e)))) => format!("handshake failed, {}", e),
+                // a changed certificate is never retried
+                Ok(Err(e @ NetworkError::CertificateChanged { .. })) => return Err(e),
+                Ok(Err(e)) => e.to_string(),
+                Err(e) => e.to_string(),
+            };
+            attempt += 1;
+            if attempt >= config.max_connect_attempts {
+                return Err(NetworkError::Connect {
+                    url: self.url.clone(),
+                    reason,
+                });
+            }
+            tokio::time::sleep(config.backoff(attempt)).await;
+        }
+    }
+
+    /// Runs a blocking electrum call off the async runtime with a deadline. A call failing on
+    /// the transport drops the client and is retried once on a fresh connection, an error
+    /// answered by the server is returned as is.
+    pub async fn call(&self, method: &str, params: Vec<Param>) -> Result<Value, NetworkError> {
+        match self.call_once(method, params.clone()).await {
+            Err(NetworkError::Request { .. }) | Err(NetworkError::Timeout { .. }) => {
+                self.disconnect();
+                self.call_once(method, params).await
+            }
+            res => res,
+        }
+    }
+
+    /// Runs `f` against the transport on the blocking pool, for the subscription api.
+    pub async fn with_client<T, F>(&self, f: F) -> Result<T, NetworkError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn ElectrumTransport) -> Result<T, NetworkError> + Send + 'static,
+    {
+        let client = self.client().await?;
+        match tokio::task::spawn_blocking(move || f(client.as_ref())).await {
+            Ok(res) => res,
+            Err(e) => Err(NetworkError::Request {
+                method: "client".to_string(),
+                reason: e.to_string(),
+            }),
+        }
+    }
+
+    async fn call_once(&self, method: &str, params: Vec<Param>) -> Result<Value, NetworkError> {
+        self.request(method, move |client, method| client.raw_call(method, params)).await
+    }
+
+    /// Sends one `method` request per entry of `params`, `MAX_BATCH_SIZE` per round trip.
+    /// Results are in the order of `params`. A batch failing on the transport is retried once
+    /// on a fresh connection.
+    pub async fn batch_call(
+        &self,
+        method: &str,
+        params: Vec<Vec<Param>>,
+    ) -> Result<Vec<Value>, NetworkError> {
+        let mut results = Vec::with_capacity(params.len());
+        for chunk in params.chunks(MAX_BATCH_SIZE) {
+            let res = match self.batch_once(method, chunk.to_vec()).await {
+                Err(NetworkError::Request { .. }) | Err(NetworkError::Timeout { .. }) => {
+                    self.disconnect();
+                    self.batch_once(method, chunk.to_vec()).await
+                }
+                res => res,
+            }?;
+            if res.len() != chunk.len() {
+                return Err(NetworkError::UnexpectedResponse {
+                    method: method.to_string(),
+                    response: format!("{} results for {} requests", res.len(), chunk.len()),
+                });
+            }
+            results.extend(res);
+        }
+        Ok(results)
+    }
+
+    async fn batch_once(
+        &self,
+        method: &str,
+        params: Vec<Vec<Param>>,
+    ) -> Result<Vec<Value>, NetworkError> {
+        self.request(method, move |client, method| client.batch_call(method, params)).await
+    }
+
+    /// Runs `f` on the blocking pool with the request deadline.
+    async fn request<T, F>(&self, method: &str, f: F) -> Result<T, NetworkError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn ElectrumTransport, &str) -> Result<T, NetworkError> + Send + 'static,
+    {
+        let client = self.client().await?;
+        let request_method = method.to_string();
+        let request = tokio::task::spawn_blocking(move || f(client.as_ref(), &request_method));
+        let deadline = Duration::from_secs(self.config().timeout_secs as u64);
+        match tokio::time::timeout(deadline, request).await {
+            Ok(Ok(res)) => res,
+            Ok(Err(e)) => Err(NetworkError::Request {
+                method: method.to_string(),
+                reason: e.to_string(),
+            }),
+            Err(_) => Err(NetworkError::Timeout {
+                method: method.to_string(),
+                seconds: deadline.as_secs(),
+            }),
+        }
+    }
+}
+
+fn settings_tree() -> Result<sled::Tree, sled::Error> {
+    wallet_db()?.open_tree(SETTINGS_TREE)
+}
+
+/// Connections keyed by server url, held in tauri state. Clones share the connections and
+/// settings.
+#[derive(Clone, Default)]
+pub struct ConnectionManager {
+    config: Arc<Mutex<ConnectionConfig>>,
+    connections: Arc<Mutex<HashMap<String, Arc<ElectrumConnection>>>>,
+    tls_modes: Arc<Mutex<HashMap<String, TlsMode>>>,
+}
+
+impl ConnectionManager {
+    pub fn new(config: ConnectionConfig) -> Self {
+        ConnectionManager {
+            config: Arc::new(Mutex::new(config)),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            tls_modes: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Manager using the saved proxy and network settings. When the proxy setting can't be
+    /// read every connection fails until a proxy is set again.
+    pub fn load() -> Self {
+        let network = settings_tree()
+            .ok()
+            .and_then(|tree| tree.get(NETWORK_KEY).ok().flatten())
+            .and_then(|name| Network::from_name(&String::from_utf8_lossy(&name)).ok());
+        let (proxy, proxy_error) = match ProxyConfig::load() {
+            Ok(proxy) => (proxy, None),
+            Err(e) => (None, Some(e)),
+        };
+        ConnectionManager::new(ConnectionConfig {
+            proxy,
+            proxy_error,
+            network,
+            ..Default::default()
+        })
+    }
+
+    pub fn network(&self) -> Option<Network> {
+        self.config.lock().unwrap().network
+    }
+
+    /// Only accepts servers on `network` from now on, open connections are reconnected so
+    /// they are checked again.
+    pub fn set_network(&self, network: Network) -> Result<(), NetworkError> {
+        let saved = settings_tree().and_then(|tree| tree.insert(NETWORK_KEY, network.name()));
+        if let Err(e) = saved {
+            return Err(NetworkError::Request {
+                method: "set_network".to_string(),
+                reason: e.to_string(),
+            });
+        }
+        self.config.lock().unwrap().network = Some(network);
+        for connection in self.connections.lock().unwrap().values() {
+            let mut config = connection.config();
+            config.network = Some(network);
+            connection.set_config(config);
+        }
+        Ok(())
+    }
+
+    /// Proxy for connections made outside the manager, an error when the saved setting
+    /// could not be read.
+    pub fn proxy(&self) -> Result<Option<ProxyConfig>, NetworkError> {
+        let config = self.config.lock().unwrap();
+        match &config.proxy_error {
+            Some(e) => Err(e.clone()),
+            None => Ok(config.proxy.clone()),
+        }
+    }
+
+    /// Routes every connection through `proxy`, or direct when `None`. Open connections are
+    /// reconnected so nothing keeps bypassing the new setting.
+    pub fn set_proxy(&self, proxy: Option<ProxyConfig>) -> Result<(), NetworkError> {
+        ProxyConfig::save(proxy.as_ref())?;
+        {
+            let mut config = self.config.lock().unwrap();
+            config.proxy = proxy.clone();
+            config.proxy_error = None;
+        }
+        for connection in self.connections.lock().unwrap().values() {
+            let mut config = connection.config();
+            config.proxy = proxy.clone();
+            config.proxy_error = None;
+            connection.set_config(config);
+        }
+        Ok(())
+    }
+
+    /// Shared connection for `url`, created on first use.
+    pub fn get(&self, url: &str) -> Arc<ElectrumConnection> {
+        let mut config = self.config.lock().unwrap().clone();
+        if let Some(mode) = self.tls_modes.lock().unwrap().get(url) {
+            config.tls = *mode;
+        }
+        self.connections
+            .lock()
+            .unwrap()
+            .entry(url.to_string())
+            .or_insert_with(|| Arc::new(ElectrumConnection::new(url, config)))
+            .clone()
+    }
+
+    /// Changes how the certificate of `url` is trusted, the open connection is dropped.
+    pub fn set_tls_mode(&self, url: &str, mode: TlsMode) {
+        self.tls_modes.lock().unwrap().insert(url.to_string(), mode);
+        if let Some(connection) = self.connections.lock().unwrap().get(url) {
+            let mut config = connection.config();
+            config.tls = mode;
+            connection.set_config(config);
+        }
+    }
+}
@@ -1,240 +1,542 @@
+//! Electrum Cash requests. Wallet queries go through `blockchain.scripthash.*` with the
+//! scripthash of a locking script, so P2SH and contract outputs are tracked like P2PKH. The
+//! address functions resolve the locking script of a cash address first.
 use bitcoinsuite_core::{
     hash::{Hashed, Sha256},
     script::Script,
 };
-use electrum_client::{Client, ElectrumApi, Param};
+use electrum_client::Param;
+use serde_json::Value;
 
-// /* type SerError = */ Box<dyn std::error::Error>
+use super::connection::ElectrumConnection;
+use super::error::NetworkError;
+use crate::address::address_to_script;
+
+fn unexpected(method: &str, res: &Value) -> NetworkError {
+    NetworkError::UnexpectedResponse {
+        method: method.to_string(),
+        response: res.to_string(),
+    }
+}
+
+/// Electrum scripthash: sha256 of the locking script, hex in reversed byte order
+pub fn scripthash_hex(script: &Script) -> String {
+    Sha256::digest(script.bytecode()).hex_be()
+}
+
+fn address_script(address: &str) -> Result<Script, NetworkError> {
+    match address_to_script(address) {
+        Ok(script) => Ok(script),
+        Err(e) => Err(NetworkError::InvalidAddress {
+            address: address.to_string(),
+            reason: e.to_string(),
+        }),
+    }
+}
+
+/// `[scripthash]`, followed by the Fulcrum token filter when one is given.
+fn script_params(script: &Script, token_filter: Option<&str>) -> Vec<Param> {
+    let mut params = vec![Param::String(scripthash_hex(script))];
+    if let Some(filter) = token_filter {
+        params.push(Param::String(filter.to_string()));
+    }
+    params
+}
+
+/// Unspent outputs locked by `script`. `token_filter` is `include_tokens`,
+/// `exclude_tokens` or `tokens_only`, the server includes tokens without one.
+pub async fn get_script_unspent(
+    script: &Script,
+    token_filter: Option<&str>,
+    connection: &ElectrumConnection,
+) -> Result<String, NetworkError> {
+    let method = "blockchain.scripthash.listunspent";
+    let res = connection.call(method, script_params(script, token_filter)).await?;
+    match res.as_array() {
+        Some(utxos) => Ok(Value::from(utxos.clone()).to_string()),
+        None => Err(unexpected(method, &res)),
+    }
+}
+
+pub async fn get_script_balance(
+    script: &Script,
+    token_filter: Option<&str>,
+    connection: &ElectrumConnection,
+) -> Result<String, NetworkError> {
+    let method = "blockchain.scripthash.get_balance";
+    let res = connection.call(method, script_params(script, token_filter)).await?;
+    match res.is_object() {
+        true => Ok(res.to_string()),
+        false => Err(unexpected(method, &res)),
+    }
+}
+
+pub async fn get_script_history(
+    script: &Script,
+    connection: &ElectrumConnection,
+) -> Result<String, NetworkError> {
+    let method = "blockchain.scripthash.get_history";
+    let res = connection.call(method, script_params(script, None)).await?;
+    match res.as_array() {
+        Some(history) => Ok(Value::from(history.clone()).to_string()),
+        None => Err(unexpected(method, &res)),
+    }
+}
+
+pub async fn get_script_mempool(
+    script: &Script,
+    connection: &ElectrumConnection,
+) -> Result<String, NetworkError> {
+    let method = "blockchain.scripthash.get_mempool";
+    let res = connection.call(method, script_params(script, None)).await?;
+    Ok(res.to_string())
+}
 
-// #[tauri::command]
 pub async fn get_unspent_utxos(
     address: &str,
-    network_url: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
+    connection: &ElectrumConnection,
+) -> Result<String, NetworkError> {
     println!("ELECTRUM REQUEST UNSPENT UTXOS: \n{}\n:", address);
-    let client = Client::new(network_url)?;
-    let method = "blockchain.address.listunspent";
-    let params = vec![
-        Param::String(address.to_string()),
-        Param::String("include_tokens".to_string()),
-    ];
-    let res = ElectrumApi::raw_call(&client, &method, params);
-    let utxo_data = serde_json::to_string(&res?.as_array());
-
-    Ok(utxo_data?)
+    get_script_unspent(&address_script(address)?, Some("include_tokens"), connection).await
 }
 
-// #[tauri::command]
 pub async fn get_utxos_balance_no_tokens(
     address: &str,
-    network_url: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
+    connection: &ElectrumConnection,
+) -> Result<String, NetworkError> {
     println!("ELECTRUM REQUEST ADDRESS BALANCE: \n{}\n", address);
-    let client = Client::new(network_url)?;
-    let method = "blockchain.address.get_balance";
-    let params = vec![Param::String(address.to_string())];
-    let res = ElectrumApi::raw_call(&client, &method, params);
-    let balance = serde_json::to_string(&res?.as_object());
-    Ok(balance?)
+    get_script_balance(&address_script(address)?, None, connection).await
 }
 
-// #[tauri::command]
 pub async fn get_utxos_balance_include_tokens(
     address: &str,
-    network_url: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
+    connection: &ElectrumConnection,
+) -> Result<String, NetworkError> {
     println!("ELECTRUM REQUEST ADDRESS BALANCE Include Tokens: \n{}\n:", address);
-    let client = Client::new(network_url)?;
-    let method = "blockchain.address.get_balance";
-    let params = vec![
-        Param::String(address.to_string()),
-        Param::String("include_tokens".to_string()),
-    ];
-    let res = ElectrumApi::raw_call(&client, &method, params);
-    let balance = serde_json::to_string(&res?.as_object());
-    Ok(balance?)
-}
-
-// #[tauri::command]
+    get_script_balance(&address_script(address)?, Some("include_tokens"), connection).await
+}
+
 pub async fn get_unspent_non_token_utxos(
     address: &str,
-    network_url: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let client = Client::new(network_url)?;
-    let method = "blockchain.address.listunspent";
-    let params = vec![Param::String(address.to_string())];
-    let res = ElectrumApi::raw_call(&client, &method, params);
-    let utxo_data = serde_json::to_string(&res?.as_array());
-
-    Ok(utxo_data?)
+    connection: &ElectrumConnection,
+) -> Result<String, NetworkError> {
+    let script = address_script(address)?;
+    get_script_unspent(&script, Some("exclude_tokens"), connection).await
 }
 
-// #[tauri::command]
 pub async fn get_address_history(
     address: &str,
-    network_url: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let client = Client::new(network_url)?;
-    let method = "blockchain.address.get_history";
+    connection: &ElectrumConnection,
+) -> Result<String, NetworkError> {
+    get_script_history(&address_script(address)?, connection).await
+}
 
-    let params = vec![Param::String(address.to_string())];
-    let res = ElectrumApi::raw_call(&client, &method, params);
-    let addr_history = serde_json::to_string(&res?.as_array());
+fn batch_params(
+    addresses: &[String],
+    token_filter: Option<&str>,
+) -> Result<Vec<Vec<Param>>, NetworkError> {
+    addresses
+        .iter()
+        .map(|address| Ok(script_params(&address_script(address)?, token_filter)))
+        .collect()
+}
 
-    Ok(addr_history?)
+/// `listunspent` of every address in one batched request, in the order of `addresses`.
+pub async fn get_unspent_utxos_batch(
+    addresses: &[String],
+    connection: &ElectrumConnection,
+) -> Result<Vec<String>, NetworkError> {
+    let scripts = addresses
+        .iter()
+        .map(|address| address_script(address))
+        .collect::<Result<Vec<Script>, NetworkError>>()?;
+    get_scripts_unspent_batch(&scripts, Some("include_tokens"), connection).await
 }
 
-fn to_electrum_scripthash(script: &Script) -> [u8; 32] {
-    Sha256::digest(script).to_le_bytes()
+/// [get_script_unspent] of every script in one batched request, in the order of `scripts`.
+pub async fn get_scripts_unspent_batch(
+    scripts: &[Script],
+    token_filter: Option<&str>,
+    connection: &ElectrumConnection,
+) -> Result<Vec<String>, NetworkError> {
+    let method = "blockchain.scripthash.listunspent";
+    let params = scripts
+        .iter()
+        .map(|script| script_params(script, token_filter))
+        .collect();
+    let mut utxos = Vec::with_capacity(scripts.len());
+    for res in connection.batch_call(method, params).await? {
+        match res.as_array() {
+            Some(entries) => utxos.push(Value::from(entries.clone()).to_string()),
+            None => return Err(unexpected(method, &res)),
+        }
+    }
+    Ok(utxos)
 }
 
-// #[tauri::command]
-pub async fn subscribe(
-    address: &str,
-    network_url: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let client = Client::new(network_url)?;
-    let method = "blockchain.address.subscribe";
-    let params = vec![Param::String(address.to_string())];
-    let res = ElectrumApi::raw_call(&client, &method, params);
+/// Balances including tokens of every address in one batched request.
+pub async fn get_balance_batch(
+    addresses: &[String],
+    connection: &ElectrumConnection,
+) -> Result<Vec<String>, NetworkError> {
+    let method = "blockchain.scripthash.get_balance";
+    let params = batch_params(addresses, Some("include_tokens"))?;
+    let mut balances = Vec::with_capacity(addresses.len());
+    for res in connection.batch_call(method, params).await? {
+        match res.is_object() {
+            true => balances.push(res.to_string()),
+            false => return Err(unexpected(method, &res)),
+        }
+    }
+    Ok(balances)
+}
 
-    match res {
-        Ok(res) => {
-            println!("subscribe hash{:?}", res);
-            Ok(res.as_str().unwrap().to_string())
+/// History of every address in one batched request.
+pub async fn get_address_history_batch(
+    addresses: &[String],
+    connection: &ElectrumConnection,
+) -> Result<Vec<String>, NetworkError> {
+    let method = "blockchain.scripthash.get_history";
+    let params = batch_params(addresses, None)?;
+    let mut histories = Vec::with_capacity(addresses.len());
+    for res in connection.batch_call(method, params).await? {
+        match res.as_array() {
+            Some(history) => histories.push(Value::from(history.clone()).to_string()),
+            None => return Err(unexpected(method, &res)),
         }
-        Err(e) => Err(e.into()),
+    }
+    Ok(histories)
+}
+
+pub async fn get_transaction(
+    txid: &str,
+    connection: &ElectrumConnection,
+) -> Result<String, NetworkError> {
+    let method = "blockchain.transaction.get";
+    let params = vec![Param::String(txid.to_string())];
+    let res = connection.call(method, params).await?;
+    match res.as_str() {
+        Some(raw_tx) => Ok(raw_tx.to_string()),
+        None => Err(unexpected(method, &res)),
+    }
+}
+
+/// Raw transactions in one batched request, in the order of `txids`.
+pub async fn get_transactions_batch(
+    txids: &[String],
+    connection: &ElectrumConnection,
+) -> Result<Vec<String>, NetworkError> {
+    let method = "blockchain.transaction.get";
+    let params = txids
+        .iter()
+        .map(|txid| vec![Param::String(txid.clone())])
+        .collect();
+    let mut raw_txs = Vec::with_capacity(txids.len());
+    for res in connection.batch_call(method, params).await? {
+        match res.as_str() {
+            Some(raw_tx) => raw_txs.push(raw_tx.to_string()),
+            None => return Err(unexpected(method, &res)),
+        }
+    }
+    Ok(raw_txs)
+}
+
+/// Current chain tip as `(height, raw header)`.
+pub async fn get_tip_header(
+    connection: &ElectrumConnection,
+) -> Result<(u32, Vec<u8>), NetworkError> {
+    let method = "blockchain.headers.subscribe";
+    let res = connection.call(method, vec![]).await?;
+    let height = res["height"].as_u64();
+    let header = res["hex"].as_str().and_then(|h| hex::decode(h).ok());
+    match (height, header) {
+        (Some(height), Some(header)) => Ok((height as u32, header)),
+        _ => Err(unexpected(method, &res)),
+    }
+}
+
+/// Up to `count` raw 80 byte headers starting at `start_height`, concatenated.
+pub async fn get_block_headers(
+    start_height: u32,
+    count: u32,
+    connection: &ElectrumConnection,
+) -> Result<Vec<u8>, NetworkError> {
+    let method = "blockchain.block.headers";
+    let params = vec![Param::U32(start_height), Param::U32(count)];
+    let res = connection.call(method, params).await?;
+    match res["hex"].as_str().and_then(|h| hex::decode(h).ok()) {
+        Some(headers) => Ok(headers),
+        None => Err(unexpected(method, &res)),
+    }
+}
+
+/// Merkle branch of `txid` in the block at `height`, as `(branch, position)`.
+pub async fn get_merkle(
+    txid: &str,
+    height: u32,
+    connection: &ElectrumConnection,
+) -> Result<(Vec<String>, u32), NetworkError> {
+    let method = "blockchain.transaction.get_merkle";
+    let params = vec![Param::String(txid.to_string()), Param::U32(height)];
+    let res = connection.call(method, params).await?;
+    let branch: Option<Vec<String>> = res["merkle"].as_array().map(|branch| {
+        branch
+            .iter()
+            .filter_map(|h| h.as_str().map(String::from))
+            .collect()
+    });
+    match (branch, res["pos"].as_u64()) {
+        (Some(branch), Some(pos)) => Ok((branch, pos as u32)),
+        _ => Err(unexpected(method, &res)),
+    }
+}
+
+/// Fee in BCH per kB for confirmation within `blocks`, negative when the server can't tell.
+pub async fn estimate_fee(
+    blocks: u32,
+    connection: &ElectrumConnection,
+) -> Result<f64, NetworkError> {
+    let method = "blockchain.estimatefee";
+    let res = connection.call(method, vec![Param::U32(blocks)]).await?;
+    match res.as_f64() {
+        Some(fee) => Ok(fee),
+        None => Err(unexpected(method, &res)),
+    }
+}
+
+/// Minimum fee in BCH per kB the server relays.
+pub async fn relay_fee(connection: &ElectrumConnection) -> Result<f64, NetworkError> {
+    let method = "blockchain.relayfee";
+    let res = connection.call(method, vec![]).await?;
+    match res.as_f64() {
+        Some(fee) => Ok(fee),
+        None => Err(unexpected(method, &res)),
     }
 }
 
-// #[tauri::command]
 pub async fn get_mempool(
     address: &str,
-    network_url: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let client = Client::new(network_url)?;
-    let method = "blockchain.address.get_mempool";
-    let params = vec![Param::String(address.to_string())];
-    let res = ElectrumApi::raw_call(&client, &method, params);
+    connection: &ElectrumConnection,
+) -> Result<String, NetworkError> {
+    get_script_mempool(&address_script(address)?, connection).await
+}
 
+/// Subscribes to the status of `script`, empty when it has no history yet.
+pub async fn script_subscribe(
+    script: &Script,
+    connection: &ElectrumConnection,
+) -> Result<String, NetworkError> {
+    let method = "blockchain.scripthash.subscribe";
+    let res = connection.call(method, script_params(script, None)).await?;
     match res {
-        Ok(res) => {
-            println!("get_mempool {:?}", res);
-            Ok(res.to_string())
-        }
-        Err(e) => Err(e.into()),
+        Value::String(status) => Ok(status),
+        // no history yet
+        Value::Null => Ok(String::new()),
+        res => Err(unexpected(method, &res)),
     }
 }
 
-#[tauri::command]
+pub async fn script_unsubscribe(
+    script: &Script,
+    connection: &ElectrumConnection,
+) -> Result<bool, NetworkError> {
+    let method = "blockchain.scripthash.unsubscribe";
+    let res = connection.call(method, script_params(script, None)).await?;
+    match res.as_bool() {
+        Some(unsubscribed) => Ok(unsubscribed),
+        None => Err(unexpected(method, &res)),
+    }
+}
+
+pub async fn subscribe(
+    address: &str,
+    connection: &ElectrumConnection,
+) -> Result<String, NetworkError> {
+    script_subscribe(&address_script(address)?, connection).await
+}
+
 pub async fn unsubscribe(
     address: &str,
-    network_url: &str,
-) -> Result<bool, Box<dyn std::error::Error>> {
-    let client = Client::new(network_url)?;
-    let method = "blockchain.address.unsubscribe";
-    let params = vec![Param::String(address.to_string())];
-    let res = ElectrumApi::raw_call(&client, &method, params);
+    connection: &ElectrumConnection,
+) -> Result<bool, NetworkError> {
+    script_unsubscribe(&address_script(address)?, connection).await
+}
+
+/// Double-spend proof for the unconfirmed `txid` or its ancestors, `None` when there is none.
+pub async fn get_dsproof(
+    txid: &str,
+    connection: &ElectrumConnection,
+) -> Result<Option<Value>, NetworkError> {
+    let method = "blockchain.transaction.dsproof.get";
+    let res = connection.call(method, vec![Param::String(txid.to_string())]).await?;
     match res {
-        Ok(res) => {
-            println!("unsubscribed: {:?}", res);
-            Ok(res.as_bool().unwrap())
-        }
-        Err(e) => Err(e.into()),
+        Value::Null => Ok(None),
+        Value::Object(_) => Ok(Some(res)),
+        res => Err(unexpected(method, &res)),
     }
 }
 
-#[tauri::command]
-pub async fn ping(network: &str) -> Result<String, Box<dyn std::error::Error>> {
-    let client = Client::new(network)?;
+/// Double-spend proofs for several transactions in one batched request.
+pub async fn get_dsproofs_batch(
+    txids: &[String],
+    connection: &ElectrumConnection,
+) -> Result<Vec<Option<Value>>, NetworkError> {
+    let method = "blockchain.transaction.dsproof.get";
+    let params = txids
+        .iter()
+        .map(|txid| vec![Param::String(txid.clone())])
+        .collect();
+    let res = connection.batch_call(method, params).await?;
+    res.into_iter()
+        .map(|proof| match proof {
+            Value::Null => Ok(None),
+            Value::Object(_) => Ok(Some(proof)),
+            proof => Err(unexpected(method, &proof)),
+        })
+        .collect()
+}
+
+pub async fn ping(connection: &ElectrumConnection) -> Result<String, NetworkError> {
     let method = "server.ping";
-    let params = vec![];
-    let res = ElectrumApi::raw_call(&client, &method, params);
-    println!("PING {:?}", res);
-    let res = serde_json::to_string(&res?.as_array());
-    Ok(res?)
+    let res = connection.call(method, vec![]).await?;
+    Ok(res.to_string())
 }
-#[tauri::command]
+
 pub async fn send_raw_transaction(
     transaction: &str,
-    network_url: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
-    println!("Broadcasting Transaction");
-    let client = Client::new(network_url)?;
+    connection: &ElectrumConnection,
+) -> Result<String, NetworkError> {
     let method = "blockchain.transaction.broadcast";
     let params = vec![Param::String(transaction.to_string())];
-    let res = ElectrumApi::raw_call(&client, &method, params);
-    let res = serde_json::to_string(&res?.as_str());
-    println!("TXID {:?}", res);
-    Ok(res?)
+    let res = connection.call(method, params).await?;
+    match res.as_str() {
+        Some(txid) => Ok(Value::from(txid).to_string()),
+        None => Err(unexpected(method, &res)),
+    }
 }
 
-#[allow(dead_code)]
-mod test {
-    use bitcoinsuite_core::hash::{Hashed, ShaRmd160};
-    use electrum_client::{
-        bitcoin::Script,
-        raw_client::{self, RawClient},
-        ElectrumApi, Param,
-    };
-    use std::str::FromStr;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::mock::MockElectrum;
+
+    const ADDRESS: &str = "bchtest:qptnz3u8atavszhaqk037v0fjrtahxmsl5mm45u3pf";
 
-    fn to_electrum_scripthash(script: &Script) -> [u8; 32] {
-        Sha256::digest(script).to_le_bytes()
-    }
+    #[tokio::test]
+    async fn test_api() {
+        let mock = MockElectrum::start();
+        let txid = "bb".repeat(32);
+        mock.add_utxo(ADDRESS, &txid, 0, 5_000, 10);
+        mock.add_token_utxo(
+            ADDRESS,
+            &txid,
+            1,
+            1_000,
+            serde_json::json!({"category":"cc".repeat(32),"amount":"100"}),
+        );
+        let connection = ElectrumConnection::new(&mock.url(), Default::default());
 
-    fn get_test_server() -> String {
-        // "tcp://chipnet.imaginary.cash:50001".to_string()
-        "0.0.0.0:50001".to_string()
-        // std::env::var("TEST_ELECTRUM_SERVER").unwrap_or("electrum.blockstream.info:50001".into())
+        let utxos: Value =
+            serde_json::from_str(&get_unspent_utxos(ADDRESS, &connection).await.unwrap()).unwrap();
+        assert_eq!(utxos.as_array().unwrap().len(), 2);
+        let non_token: Value = serde_json::from_str(
+            &get_unspent_non_token_utxos(ADDRESS, &connection)
+                .await
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(non_token.as_array().unwrap().len(), 1);
+        let history: Value =
+            serde_json::from_str(&get_address_history(ADDRESS, &connection).await.unwrap())
+                .unwrap();
+        assert_eq!(history[0]["tx_hash"], txid);
     }
-    use crate::address::address_to_pubkey_hash;
 
-    use super::*;
+    #[tokio::test]
+    async fn test_p2sh() {
+        let mock = MockElectrum::start();
+        let p2sh = "bchtest:pqaf54jyp7xlx5dah84gqnry6n786evj55zwnnalcq";
+        mock.add_utxo(p2sh, &"bb".repeat(32), 0, 7_000, 10);
+        let connection = ElectrumConnection::new(&mock.url(), Default::default());
+
+        let script = address_to_script(p2sh).unwrap();
+        let utxos: Value =
+            serde_json::from_str(&get_script_unspent(&script, None, &connection).await.unwrap())
+                .unwrap();
+        assert_eq!(utxos[0]["value"], 7_000);
+        let balance: Value =
+            serde_json::from_str(&get_utxos_balance_no_tokens(p2sh, &connection).await.unwrap())
+                .unwrap();
+        assert_eq!(balance["confirmed"], 7_000);
+        assert!(!script_subscribe(&script, &connection).await.unwrap().is_empty());
+        // the p2pkh address is untouched
+        assert_eq!(get_unspent_utxos(ADDRESS, &connection).await.unwrap(), "[]");
+        assert!(mock
+            .requests()
+            .iter()
+            .all(|method| !method.starts_with("blockchain.address.")));
+    }
 
-    #[allow(dead_code)]
     #[tokio::test]
-    async fn test_api() {
-        let res = get_unspent_non_token_utxos(
-            "bchtest:qptnz3u8atavszhaqk037v0fjrtahxmsl5mm45u3pf",
-            // "tcp://localhost:50001",
-            "ws://localhost:50003",
-            // "tcp://chipnet.imaginary.cash:50001",
+    async fn test_batch() {
+        let mock = MockElectrum::start();
+        let other = "bchtest:qzxu4ynqdgyjr2hvt5xcx7x35ncdz8zffsf2hgn9mp".to_string();
+        let txid = "bb".repeat(32);
+        mock.add_utxo(ADDRESS, &txid, 0, 5_000, 10);
+        let tx = mock.add_transaction("00aa");
+        let connection = ElectrumConnection::new(&mock.url(), Default::default());
+
+        let addresses = vec![ADDRESS.to_string(), other];
+        let utxos = get_unspent_utxos_batch(&addresses, &connection).await.unwrap();
+        assert_eq!(utxos.len(), 2);
+        assert_eq!(
+            serde_json::from_str::<Value>(&utxos[0]).unwrap()[0]["tx_hash"],
+            txid
+        );
+        assert_eq!(utxos[1], "[]");
+        let balances = get_balance_batch(&addresses, &connection).await.unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&balances[0]).unwrap()["confirmed"],
+            5_000
         );
-        println!("{:?}", res.await);
-    }
-    #[test]
-    fn test_script_subscribe() {
-        use bitcoincash_addr::Address;
-        use bitcoinsuite_core::script::Script as bScript;
-        use std::str::FromStr;
-
-        // let client = Client::new(get_test_server().as_str()).unwrap();
-        // let client = Client::new(get_test_server().as_str()).unwrap();
-        let client = RawClient::new(get_test_server(), None).unwrap();
-        let script_hash =
-            // address_to_pubkey_hash("bchtest:qptnz3u8atavszhaqk037v0fjrtahxmsl5mm45u3pf").unwrap();
-            address_to_pubkey_hash("bchtest:zzxu4ynqdgyjr2hvt5xcx7x35ncdz8zffswqykaryj").unwrap();
-        // let script_hash = to_electrum_scripthash(Script::from_bytes(&script_hash));
-
-        let script_hash = hex::encode::<&[u8]>(script_hash.as_ref());
-        let script_hash = ShaRmd160::from_le_hex(&script_hash);
-        let p2pkh = bScript::p2pkh(&script_hash.unwrap());
-
-        let x = client
-            .script_get_balance(&Script::from_bytes(p2pkh.bytecode()))
-            // .script_subscribe(&script_hash)
+        let histories = get_address_history_batch(&addresses, &connection)
+            .await
             .unwrap();
-        let y = client.script_unsubscribe(&Script::from_bytes(p2pkh.bytecode()));
-        // .script_subscribe(&script_hash)
-        // .unwrap();
+        assert_eq!(histories[1], "[]");
+        let raw_txs = get_transactions_batch(&[tx], &connection).await.unwrap();
+        assert_eq!(raw_txs, vec!["00aa".to_string()]);
+        assert!(get_unspent_utxos_batch(&[], &connection).await.unwrap().is_empty());
+    }
 
-        println!("{:?}", x);
-        println!("{:?}", y);
+    #[tokio::test]
+    async fn test_script_subscribe() {
+        let mock = MockElectrum::start();
+        let connection = ElectrumConnection::new(&mock.url(), Default::default());
+        // no history yet
+        assert_eq!(subscribe(ADDRESS, &connection).await.unwrap(), "");
+        mock.add_utxo(ADDRESS, &"bb".repeat(32), 0, 5_000, 0);
+        assert!(!subscribe(ADDRESS, &connection).await.unwrap().is_empty());
+        assert!(unsubscribe(ADDRESS, &connection).await.unwrap());
     }
-    #[test]
-    fn test_ping() {
-        let client = Client::new(get_test_server().as_str()).unwrap().ping();
-        assert_eq!(client.is_ok(), true);
+
+    #[tokio::test]
+    async fn test_dsproof() {
+        let mock = MockElectrum::start();
+        let connection = ElectrumConnection::new(&mock.url(), Default::default());
+        let txid = "cc".repeat(32);
+        assert_eq!(get_dsproof(&txid, &connection).await.unwrap(), None);
+        mock.set_dsproof(&txid, serde_json::json!({"dspid": "dd".repeat(32), "txid": txid}));
+        let proofs = get_dsproofs_batch(&[txid.clone(), "ee".repeat(32)], &connection)
+            .await
+            .unwrap();
+        assert_eq!(proofs[0].as_ref().unwrap()["txid"], txid);
+        assert_eq!(proofs[1], None);
+    }
+
+    #[tokio::test]
+    async fn test_ping() {
+        let mock = MockElectrum::start();
+        let connection = ElectrumConnection::new(&mock.url(), Default::default());
+        assert!(ping(&connection).await.is_ok());
     }
 }
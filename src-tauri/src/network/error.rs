@@ -0,0 +1,60 @@
+//! Defines errors for the network module.
+use thiserror::Error;
+
+use crate::error::WalletError;
+
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum NetworkError {
+    #[error("invalid server url {url}: {reason}")]
+    InvalidUrl { url: String, reason: String },
+    #[error("invalid address {address}: {reason}")]
+    InvalidAddress { address: String, reason: String },
+    #[error("could not connect to {url}: {reason}")]
+    Connect { url: String, reason: String },
+    #[error("{method} timed out after {seconds}s")]
+    Timeout { method: String, seconds: u64 },
+    #[error("{method} failed: {reason}")]
+    Request { method: String, reason: String },
+    /// A JSON-RPC error object answered by the server, asking again gets the same answer
+    #[error("{method} refused by the server: {reason}")]
+    Server { method: String, reason: String },
+    #[error("tls handshake with {server} failed: {reason}")]
+    Tls { server: String, reason: String },
+    #[error("certificate of {server} changed, pinned {pinned} but got {presented}")]
+    CertificateChanged {
+        server: String,
+        pinned: String,
+        presented: String,
+    },
+    #[error("unexpected response to {method}: {response}")]
+    UnexpectedResponse { method: String, response: String },
+    #[error("{url} can't be used: {reason}")]
+    Unsupported { url: String, reason: String },
+    #[error("no electrum servers configured")]
+    NoServers,
+    #[error("servers {first} and {second} returned different responses")]
+    ServersDisagree { first: String, second: String },
+    /// Cross-checking is on and no second server answered
+    #[error("only {server} answered, the response could not be cross-checked")]
+    NotCrossChecked { server: String },
+}
+
+impl NetworkError {
+    /// The request got no answer, another server may still give one.
+    pub fn is_transport(&self) -> bool {
+        matches!(
+            self,
+            NetworkError::Connect { .. }
+                | NetworkError::Timeout { .. }
+                | NetworkError::Request { .. }
+        )
+    }
+}
+
+impl From<NetworkError> for WalletError {
+    fn from(value: NetworkError) -> Self {
+        WalletError::NetworkError {
+            reason: value.to_string(),
+        }
+    }
+}
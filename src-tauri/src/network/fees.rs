@@ -0,0 +1,178 @@
+//! Fee rates from the server's `blockchain.estimatefee` and `blockchain.relayfee`, cached per
+//! server. Every rate is at least the 1 sat/byte floor, which is also used when the server
+//! can't be reached.
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use super::connection::ElectrumConnection;
+use super::electrum::{estimate_fee, relay_fee};
+use super::error::NetworkError;
+use crate::coins::selection::FeeRate;
+
+/// Estimates older than this are fetched again
+const STALE_AFTER: Duration = Duration::from_secs(10 * 60);
+const NORMAL_TARGET_BLOCKS: u32 = 6;
+const FAST_TARGET_BLOCKS: u32 = 1;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FeePreset {
+    /// The server's minimum relay fee
+    Minimum,
+    Normal,
+    Fast,
+}
+
+impl FeePreset {
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "minimum" => Ok(FeePreset::Minimum),
+            "normal" => Ok(FeePreset::Normal),
+            "fast" => Ok(FeePreset::Fast),
+            _ => Err(format!("unknown fee preset {}", name)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FeeEstimates {
+    pub minimum: FeeRate,
+    pub normal: FeeRate,
+    pub fast: FeeRate,
+    pub fetched_at: Instant,
+    /// The server could not be asked and these are the 1 sat/byte floor
+    pub fallback: bool,
+}
+
+impl FeeEstimates {
+    fn floor() -> Self {
+        FeeEstimates {
+            minimum: FeeRate::default_min_relay_fee(),
+            normal: FeeRate::default_min_relay_fee(),
+            fast: FeeRate::default_min_relay_fee(),
+            fetched_at: Instant::now(),
+            fallback: true,
+        }
+    }
+
+    pub fn is_stale(&self) -> bool {
+        self.fetched_at.elapsed() > STALE_AFTER
+    }
+
+    pub fn rate(&self, preset: FeePreset) -> FeeRate {
+        match preset {
+            FeePreset::Minimum => self.minimum,
+            FeePreset::Normal => self.normal,
+            FeePreset::Fast => self.fast,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "minimum": self.minimum.as_sat_per_vb(),
+            "normal": self.normal.as_sat_per_vb(),
+            "fast": self.fast.as_sat_per_vb(),
+            "ageSecs": self.fetched_at.elapsed().as_secs(),
+            "stale": self.is_stale(),
+            "fallback": self.fallback,
+        })
+    }
+}
+
+/// BCH/kB from the server to a rate no lower than `floor`. Unknown (negative) estimates
+/// become the floor.
+pub fn to_fee_rate(bch_per_kb: f64, floor: FeeRate) -> FeeRate {
+    if !bch_per_kb.is_finite() || bch_per_kb <= 0.0 {
+        return floor;
+    }
+    // 1 BCH/kB is 100000 sat/byte, converted in f64 so round values stay round
+    let rate = FeeRate::from_sat_per_vb((bch_per_kb * 1e5) as f32);
+    match rate.as_sat_per_vb() < floor.as_sat_per_vb() {
+        true => floor,
+        false => rate,
+    }
+}
+
+async fn fetch_estimates(connection: &ElectrumConnection) -> Result<FeeEstimates, NetworkError> {
+    let minimum = to_fee_rate(relay_fee(connection).await?, FeeRate::default_min_relay_fee());
+    let normal = to_fee_rate(estimate_fee(NORMAL_TARGET_BLOCKS, connection).await?, minimum);
+    let fast = to_fee_rate(estimate_fee(FAST_TARGET_BLOCKS, connection).await?, normal);
+    Ok(FeeEstimates {
+        minimum,
+        normal,
+        fast,
+        fetched_at: Instant::now(),
+        fallback: false,
+    })
+}
+
+/// Held in tauri state, estimates keyed by server url.
+#[derive(Default)]
+pub struct FeeCache {
+    estimates: Mutex<HashMap<String, FeeEstimates>>,
+}
+
+impl FeeCache {
+    /// Cached estimates while fresh, otherwise asks the server. When that fails the stale
+    /// estimates are kept, or the floor is returned if there are none.
+    pub async fn estimates(&self, connection: &ElectrumConnection) -> FeeEstimates {
+        let cached = self.estimates.lock().unwrap().get(connection.url()).cloned();
+        if let Some(cached) = cached.as_ref() {
+            if !cached.is_stale() && !cached.fallback {
+                return cached.clone();
+            }
+        }
+        match fetch_estimates(connection).await {
+            Ok(estimates) => {
+                self.estimates
+                    .lock()
+                    .unwrap()
+                    .insert(connection.url().to_string(), estimates.clone());
+                estimates
+            }
+            Err(e) => {
+                log::warn!("fee estimate failed {}", e);
+                cached.unwrap_or_else(FeeEstimates::floor)
+            }
+        }
+    }
+
+    pub async fn rate(&self, preset: FeePreset, connection: &ElectrumConnection) -> FeeRate {
+        self.estimates(connection).await.rate(preset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::mock::MockElectrum;
+
+    #[test]
+    fn conversion() {
+        let floor = FeeRate::default_min_relay_fee();
+        assert_eq!(to_fee_rate(0.00002, floor).as_sat_per_vb(), 2.0);
+        assert_eq!(to_fee_rate(0.000005, floor).as_sat_per_vb(), 1.0);
+        assert_eq!(to_fee_rate(-1.0, floor).as_sat_per_vb(), 1.0);
+    }
+
+    #[tokio::test]
+    async fn presets() {
+        let mock = MockElectrum::start();
+        mock.set_fees(0.00003, 0.00001);
+        let connection = ElectrumConnection::new(&mock.url(), Default::default());
+        let cache = FeeCache::default();
+        let estimates = cache.estimates(&connection).await;
+        assert!(!estimates.fallback);
+        assert_eq!(estimates.minimum.as_sat_per_vb(), 1.0);
+        assert_eq!(estimates.fast.as_sat_per_vb(), 3.0);
+
+        // served from the cache
+        mock.set_fees(0.0001, 0.00001);
+        assert_eq!(cache.rate(FeePreset::Fast, &connection).await.as_sat_per_vb(), 3.0);
+
+        let offline = ElectrumConnection::new("tcp://127.0.0.1:1", Default::default());
+        assert!(cache.estimates(&offline).await.fallback);
+    }
+}
@@ -0,0 +1,159 @@
+//! Plain HTTP(S) GET over `connect_stream`, for the web requests that have to go through the
+//! SOCKS5 proxy like everything else. Requests are HTTP/1.0 so the body ends with the
+//! connection and is never chunked.
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::{ClientConfig, ClientConnection, OwnedTrustAnchor, RootCertStore, ServerName};
+use url::Url;
+
+use super::error::NetworkError;
+use super::proxy::{connect_stream, ProxyConfig};
+
+const MAX_REDIRECTS: usize = 5;
+/// Largest body read, BCMR registries are well below it
+const MAX_BODY_SIZE: u64 = 16 * 1024 * 1024;
+
+fn web_roots_config() -> Arc<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    Arc::new(
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    )
+}
+
+/// Status, `Location` header and body of a raw HTTP response.
+fn parse_response(response: &[u8]) -> Option<(u16, Option<String>, Vec<u8>)> {
+    let end = response.windows(4).position(|w| w == b"\r\n\r\n")?;
+    let head = String::from_utf8_lossy(&response[..end]);
+    let mut lines = head.split("\r\n");
+    let status = lines.next()?.split_whitespace().nth(1)?.parse().ok()?;
+    let location = lines.find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        match name.trim().eq_ignore_ascii_case("location") {
+            true => Some(value.trim().to_string()),
+            false => None,
+        }
+    });
+    Some((status, location, response[end + 4..].to_vec()))
+}
+
+fn get_once(
+    url: &Url,
+    timeout: Duration,
+    proxy: Option<&ProxyConfig>,
+) -> Result<Vec<u8>, NetworkError> {
+    let request_err = |reason: String| NetworkError::Request {
+        method: format!("GET {}", url),
+        reason,
+    };
+    let host = match url.host_str() {
+        Some(host) => host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string(),
+        None => return Err(request_err("missing host".to_string())),
+    };
+    let port = match url.port_or_known_default() {
+        Some(port) => port,
+        None => return Err(request_err("missing port".to_string())),
+    };
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: application/json\r\nUser-Agent: cashcaster\r\n\r\n",
+        &url[url::Position::BeforePath..url::Position::AfterQuery],
+        host
+    );
+    let mut socket = connect_stream(&host, port, timeout, proxy)?;
+    let mut response = vec![];
+    let res = match url.scheme() {
+        "https" => {
+            let server_name = match ServerName::try_from(host.as_str()) {
+                Ok(name) => name,
+                Err(e) => return Err(request_err(e.to_string())),
+            };
+            let connection = match ClientConnection::new(web_roots_config(), server_name) {
+                Ok(connection) => connection,
+                Err(e) => return Err(request_err(e.to_string())),
+            };
+            let mut stream = rustls::StreamOwned::new(connection, socket);
+            stream
+                .write_all(request.as_bytes())
+                .and_then(|_| (&mut stream).take(MAX_BODY_SIZE).read_to_end(&mut response))
+        }
+        "http" => socket
+            .write_all(request.as_bytes())
+            .and_then(|_| (&mut socket).take(MAX_BODY_SIZE).read_to_end(&mut response)),
+        scheme => return Err(request_err(format!("unsupported scheme {}", scheme))),
+    };
+    // servers often close tls without a close_notify once the body is sent
+    if let Err(e) = res {
+        if response.is_empty() {
+            return Err(request_err(e.to_string()));
+        }
+    }
+    Ok(response)
+}
+
+/// Body of `url`, following redirects.
+pub fn http_get(
+    url: &str,
+    timeout: Duration,
+    proxy: Option<&ProxyConfig>,
+) -> Result<String, NetworkError> {
+    let invalid = |reason: String| NetworkError::InvalidUrl {
+        url: url.to_string(),
+        reason,
+    };
+    let mut current = match Url::parse(url) {
+        Ok(parsed) => parsed,
+        Err(e) => return Err(invalid(e.to_string())),
+    };
+    for _ in 0..=MAX_REDIRECTS {
+        let response = get_once(&current, timeout, proxy)?;
+        let method = format!("GET {}", current);
+        let request_err = |reason: String| NetworkError::Request {
+            method: method.clone(),
+            reason,
+        };
+        let (status, location, body) = match parse_response(&response) {
+            Some(parsed) => parsed,
+            None => return Err(request_err("malformed response".to_string())),
+        };
+        match (status, location) {
+            (200..=299, _) => return Ok(String::from_utf8_lossy(&body).to_string()),
+            (300..=399, Some(location)) => match current.join(&location) {
+                Ok(next) => current = next,
+                Err(e) => return Err(request_err(e.to_string())),
+            },
+            (status, _) => return Err(request_err(format!("status {}", status))),
+        }
+    }
+    Err(invalid("too many redirects".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn responses() {
+        let ok = b"HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n{\"a\":1}";
+        assert_eq!(parse_response(ok), Some((200, None, b"{\"a\":1}".to_vec())));
+        let moved = b"HTTP/1.1 301 Moved Permanently\r\nlocation: /registry.json\r\n\r\n";
+        assert_eq!(
+            parse_response(moved),
+            Some((301, Some("/registry.json".to_string()), vec![]))
+        );
+        assert_eq!(parse_response(b"HTTP/1.0 200 OK\r\n"), None);
+    }
+}
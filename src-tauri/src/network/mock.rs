@@ -0,0 +1,384 @@
+//! In-process fake Fulcrum server speaking newline delimited JSON-RPC over tcp, for offline
+//! tests. Utxos, histories, transactions and broadcast results are scripted per test.
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use bitcoin_hashes::{sha256, sha256d, Hash};
+use serde_json::{json, Value};
+
+use crate::address::address_to_script;
+
+/// Mainnet genesis, served as the only header
+pub const GENESIS_HEADER: &str = "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c";
+
+#[derive(Default)]
+struct MockState {
+    /// `listunspent` entries keyed by scripthash
+    utxos: HashMap<String, Vec<Value>>,
+    /// `get_history` entries keyed by scripthash
+    histories: HashMap<String, Vec<Value>>,
+    transactions: HashMap<String, String>,
+    /// Double-spend proofs keyed by the txid they are served for
+    dsproofs: HashMap<String, Value>,
+    broadcasts: Vec<String>,
+    /// Error message returned for every broadcast when set
+    reject_broadcasts: Option<String>,
+    /// BCH per kB answered to `blockchain.estimatefee`, -1 when unknown
+    fee_estimate: f64,
+    relay_fee: f64,
+    requests: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct MockElectrum {
+    port: u16,
+    state: Arc<Mutex<MockState>>,
+}
+
+/// Displayed txid of a raw transaction.
+pub fn txid_of(raw_tx: &str) -> String {
+    let mut hash = sha256d::Hash::hash(&hex::decode(raw_tx).unwrap_or_default()).into_inner();
+    hash.reverse();
+    hex::encode(hash)
+}
+
+/// Electrum scripthash of `address`, computed here rather than with the client code so a
+/// wrong scripthash on the client side finds nothing.
+fn address_scripthash(address: &str) -> Option<String> {
+    let script = address_to_script(address).ok()?;
+    let mut hash = sha256::Hash::hash(script.bytecode()).into_inner();
+    hash.reverse();
+    Some(hex::encode(hash))
+}
+
+impl MockElectrum {
+    /// Binds a random local port and serves every connection on its own thread.
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let state = Arc::new(Mutex::new(MockState {
+            fee_estimate: 0.00001,
+            relay_fee: 0.00001,
+            ..Default::default()
+        }));
+        let server = MockElectrum { port, state };
+        let accepting = server.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let connection = accepting.clone();
+                thread::spawn(move || connection.serve(stream));
+            }
+        });
+        server
+    }
+
+    pub fn url(&self) -> String {
+        format!("tcp://127.0.0.1:{}", self.port)
+    }
+
+    pub fn add_utxo(&self, address: &str, txid: &str, vout: u32, value: u64, height: u32) {
+        self.add_utxo_json(
+            address,
+            json!({"tx_hash":txid,"tx_pos":vout,"value":value,"height":height}),
+        );
+    }
+
+    /// Adds an unconfirmed token utxo, `token` is the Fulcrum `token_data` object.
+    pub fn add_token_utxo(&self, address: &str, txid: &str, vout: u32, value: u64, token: Value) {
+        self.add_utxo_json(
+            address,
+            json!({"tx_hash":txid,"tx_pos":vout,"value":value,"height":0,"token_data":token}),
+        );
+    }
+
+    fn add_utxo_json(&self, address: &str, utxo: Value) {
+        let scripthash = address_scripthash(address).unwrap();
+        let mut state = self.state.lock().unwrap();
+        let history_entry = json!({"tx_hash":utxo["tx_hash"],"height":utxo["height"]});
+        state
+            .histories
+            .entry(scripthash.clone())
+            .or_default()
+            .push(history_entry);
+        state.utxos.entry(scripthash).or_default().push(utxo);
+    }
+
+    pub fn add_transaction(&self, raw_tx: &str) -> String {
+        let txid = txid_of(raw_tx);
+        self.state
+            .lock()
+            .unwrap()
+            .transactions
+            .insert(txid.clone(), raw_tx.to_string());
+        txid
+    }
+
+    pub fn set_history(&self, address: &str, history: Vec<(String, i64)>) {
+        let history = history
+            .into_iter()
+            .map(|(txid, height)| json!({"tx_hash":txid,"height":height}))
+            .collect();
+        let scripthash = address_scripthash(address).unwrap();
+        self.state.lock().unwrap().histories.insert(scripthash, history);
+    }
+
+    pub fn set_dsproof(&self, txid: &str, proof: Value) {
+        self.state
+            .lock()
+            .unwrap()
+            .dsproofs
+            .insert(txid.to_string(), proof);
+    }
+
+    pub fn reject_broadcasts(&self, reason: Option<&str>) {
+        self.state.lock().unwrap().reject_broadcasts = reason.map(String::from);
+    }
+
+    pub fn set_fees(&self, fee_estimate: f64, relay_fee: f64) {
+        let mut state = self.state.lock().unwrap();
+        state.fee_estimate = fee_estimate;
+        state.relay_fee = relay_fee;
+    }
+
+    /// Raw transactions accepted so far.
+    pub fn broadcasts(&self) -> Vec<String> {
+        self.state.lock().unwrap().broadcasts.clone()
+    }
+
+    /// Methods received so far, in order.
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    fn serve(&self, stream: TcpStream) {
+        let mut writer = match stream.try_clone() {
+            Ok(writer) => writer,
+            Err(_) => return,
+        };
+        for line in BufReader::new(stream).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => return,
+            };
+            let response = match serde_json::from_str::<Value>(&line) {
+                Ok(Value::Array(batch)) => {
+                    Value::Array(batch.iter().map(|r| self.respond(r)).collect())
+                }
+                Ok(request) => self.respond(&request),
+                Err(e) => {
+                    let error = json!({"code":-32700,"message":e.to_string()});
+                    json!({"jsonrpc":"2.0","id":null,"error":error})
+                }
+            };
+            if writeln!(writer, "{}", response).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn respond(&self, request: &Value) -> Value {
+        let id = request["id"].clone();
+        let method = request["method"].as_str().unwrap_or_default();
+        let params = request["params"].as_array().cloned().unwrap_or_default();
+        self.state.lock().unwrap().requests.push(method.to_string());
+        match self.dispatch(method, &params) {
+            Ok(result) => json!({"jsonrpc":"2.0","id":id,"result":result}),
+            Err(message) => json!({"jsonrpc":"2.0","id":id,"error":{"code":1,"message":message}}),
+        }
+    }
+
+    fn dispatch(&self, method: &str, params: &[Value]) -> Result<Value, String> {
+        let param = |i: usize| params.get(i).and_then(|p| p.as_str()).unwrap_or_default();
+        // address methods are answered from the same scripthash keyed state
+        let scripthash = match method.starts_with("blockchain.address.") {
+            true => address_scripthash(param(0)).ok_or("invalid address")?,
+            false => param(0).to_string(),
+        };
+        let mut state = self.state.lock().unwrap();
+        match method {
+            "server.version" => Ok(json!(["Fulcrum 1.9.8", "1.5"])),
+            "server.ping" => Ok(Value::Null),
+            "server.features" => Ok(json!({
+                "genesis_hash": "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+                "protocol_min": "1.4",
+                "protocol_max": "1.5",
+                "server_version": "Fulcrum 1.9.8",
+                "hash_function": "sha256",
+                "cashtokens": true,
+                "dsproof": true,
+            })),
+            "server.peers.subscribe" => Ok(json!([])),
+            "blockchain.headers.subscribe" => Ok(json!({"height":0,"hex":GENESIS_HEADER})),
+            "blockchain.block.headers" => {
+                let start = params.first().and_then(|p| p.as_u64()).unwrap_or(0);
+                let hex = if start == 0 { GENESIS_HEADER } else { "" };
+                let count = if start == 0 { 1 } else { 0 };
+                Ok(json!({"hex":hex,"count":count,"max":2016}))
+            }
+            "blockchain.estimatefee" => Ok(json!(state.fee_estimate)),
+            "blockchain.relayfee" => Ok(json!(state.relay_fee)),
+            "blockchain.address.listunspent" | "blockchain.scripthash.listunspent" => {
+                // Fulcrum token filter, tokens are included by default
+                let filter = param(1);
+                let utxos = state.utxos.get(&scripthash).cloned().unwrap_or_default();
+                Ok(Value::Array(
+                    utxos
+                        .into_iter()
+                        .filter(|u| match filter {
+                            "exclude_tokens" => u["token_data"].is_null(),
+                            "tokens_only" => !u["token_data"].is_null(),
+                            _ => true,
+                        })
+                        .collect(),
+                ))
+            }
+            "blockchain.address.get_balance" | "blockchain.scripthash.get_balance" => {
+                let utxos = state.utxos.get(&scripthash).cloned().unwrap_or_default();
+                let sum = |confirmed: bool| -> u64 {
+                    utxos
+                        .iter()
+                        .filter(|u| (u["height"].as_u64().unwrap_or(0) > 0) == confirmed)
+                        .filter_map(|u| u["value"].as_u64())
+                        .sum()
+                };
+                Ok(json!({"confirmed":sum(true),"unconfirmed":sum(false)}))
+            }
+            "blockchain.address.get_history" | "blockchain.scripthash.get_history" => Ok(
+                Value::Array(state.histories.get(&scripthash).cloned().unwrap_or_default()),
+            ),
+            "blockchain.address.get_mempool" | "blockchain.scripthash.get_mempool" => {
+                let history = state.histories.get(&scripthash).cloned().unwrap_or_default();
+                Ok(Value::Array(
+                    history
+                        .into_iter()
+                        .filter(|h| h["height"].as_i64().unwrap_or(0) <= 0)
+                        .collect(),
+                ))
+            }
+            "blockchain.address.subscribe" | "blockchain.scripthash.subscribe" => {
+                match state.histories.get(&scripthash) {
+                    Some(history) if !history.is_empty() => {
+                        Ok(json!(txid_of(&hex::encode(history.len().to_le_bytes()))))
+                    }
+                    _ => Ok(Value::Null),
+                }
+            }
+            "blockchain.address.unsubscribe" | "blockchain.scripthash.unsubscribe" => {
+                Ok(json!(true))
+            }
+            "blockchain.transaction.get" => match state.transactions.get(param(0)) {
+                Some(raw_tx) => Ok(json!(raw_tx)),
+                None => Err(format!("transaction {} not found", param(0))),
+            },
+            "blockchain.transaction.dsproof.get" => {
+                Ok(state.dsproofs.get(param(0)).cloned().unwrap_or(Value::Null))
+            }
+            "blockchain.transaction.broadcast" => {
+                if let Some(reason) = state.reject_broadcasts.clone() {
+                    return Err(reason);
+                }
+                let raw_tx = param(0).to_string();
+                if hex::decode(&raw_tx).is_err() {
+                    return Err("TX decode failed".to_string());
+                }
+                let txid = txid_of(&raw_tx);
+                state.transactions.insert(txid.clone(), raw_tx.clone());
+                state.broadcasts.push(raw_tx);
+                Ok(json!(txid))
+            }
+            _ => Err(format!("unknown method {}", method)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoincash_addr::Network;
+
+    use super::*;
+    use crate::address::address_to_p2pkh;
+    use crate::coins::selection::FeeRate;
+    use crate::coins::utxo::get_utxos_for_address;
+    use crate::keys::address::get_address;
+    use crate::keys::bip44::{default_testnet_derivation, derive_hd_path_public_key};
+    use crate::network::backend::BackendSelection;
+    use crate::network::connection::{ConnectionManager, ElectrumConnection};
+    use crate::network::electrum::{get_unspent_utxos, send_raw_transaction};
+    use crate::network::pool::ServerPool;
+    use crate::store::storage::sync_address_utxos;
+    use crate::transaction::build::create_tx_for_destination_output;
+    use electrum_client::bitcoin::bip32::ExtendedPrivKey;
+
+    /// First testnet receive address of the fixture seed `[7; 64]` and its scripthash
+    const ADDRESS: &str = "bchtest:qq223jxjvl6t9u9apd25crvyrxly8c29lyqmeftcp0";
+    const SCRIPTHASH: &str = "a5dc6e8ce152976523f2d2a5a927b64819bd9a723caffc230d757f3ef0654f14";
+
+    #[test]
+    fn fixture_scripthash() {
+        assert_eq!(address_scripthash(ADDRESS).unwrap(), SCRIPTHASH);
+    }
+
+    #[tokio::test]
+    async fn sync_select_build_broadcast() {
+        let xpriv =
+            ExtendedPrivKey::new_master(electrum_client::bitcoin::Network::Testnet, &[7u8; 64])
+                .unwrap();
+        let pubkey =
+            derive_hd_path_public_key(default_testnet_derivation().unwrap(), xpriv).unwrap();
+        let address = get_address(&pubkey, Network::Test)
+            .unwrap()
+            .encode()
+            .unwrap();
+        assert_eq!(address, ADDRESS);
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mock = MockElectrum::start();
+        let funding = "aa".repeat(32);
+        mock.add_utxo(&address, &funding, 0, 100_000, 0);
+        mock.add_utxo(&address, &funding, 1, 20_000, 0);
+        let connection = ElectrumConnection::new(&mock.url(), Default::default());
+
+        let utxos: Value =
+            serde_json::from_str(&get_unspent_utxos(&address, &connection).await.unwrap()).unwrap();
+        assert_eq!(utxos.as_array().unwrap().len(), 2);
+        let backends = BackendSelection::new(ServerPool::default(), ConnectionManager::default());
+        let backend = backends.backend(&mock.url());
+        assert!(
+            sync_address_utxos(&db, &address, backend.as_ref(), &connection)
+                .await
+                .unwrap()
+        );
+        // nothing changed on the server
+        assert!(
+            !sync_address_utxos(&db, &address, backend.as_ref(), &connection)
+                .await
+                .unwrap()
+        );
+
+        let utxos = get_utxos_for_address(&db, &address).unwrap();
+        assert_eq!(utxos.non_token.len(), 2);
+        let script = address_to_p2pkh(&address).unwrap();
+        let tx = create_tx_for_destination_output(
+            &xpriv,
+            &default_testnet_derivation().unwrap().to_string(),
+            None,
+            &script,
+            &script,
+            50_000,
+            utxos,
+            None,
+            FeeRate::default(),
+        )
+        .unwrap();
+
+        let txid = send_raw_transaction(&tx.raw_tx, &connection).await.unwrap();
+        assert_eq!(txid, json!(txid_of(&tx.raw_tx)).to_string());
+        assert_eq!(mock.broadcasts(), vec![tx.raw_tx.clone()]);
+
+        mock.reject_broadcasts(Some("bad-txns-inputs-missingorspent"));
+        assert!(send_raw_transaction(&tx.raw_tx, &connection).await.is_err());
+        assert_eq!(mock.broadcasts().len(), 1);
+    }
+}
@@ -1 +1,18 @@
+pub mod backend;
+pub mod bchn;
+pub mod capabilities;
+pub mod connection;
 pub mod electrum;
+pub mod error;
+pub mod fees;
+pub mod http;
+#[cfg(test)]
+pub mod mock;
+pub mod p2p;
+pub mod pool;
+pub mod proxy;
+pub mod subscription;
+pub mod task;
+pub mod tls;
+pub mod transport;
+pub mod ws;
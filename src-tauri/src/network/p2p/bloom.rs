@@ -0,0 +1,155 @@
+//! BIP37 bloom filter sent in `filterload`.
+use bitcoinsuite_core::script::Script;
+
+use super::message::{filterload, RawMessage};
+
+const LN2_SQUARED: f64 = 0.480_453_013_918_201_4;
+const LN2: f64 = std::f64::consts::LN_2;
+const MAX_FILTER_BYTES: usize = 36_000;
+const MAX_HASH_FUNCS: u32 = 50;
+/// Peer adds the outpoints of matched outputs, so spends of our coins match too
+pub const BLOOM_UPDATE_ALL: u8 = 1;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BloomFilter {
+    data: Vec<u8>,
+    hash_funcs: u32,
+    tweak: u32,
+    flags: u8,
+}
+
+fn murmur3(seed: u32, data: &[u8]) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+    let mut h = seed;
+    let blocks = data.chunks_exact(4);
+    let tail = blocks.remainder();
+    for block in blocks {
+        let k = u32::from_le_bytes(block.try_into().unwrap());
+        h ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+    if !tail.is_empty() {
+        let mut k = 0u32;
+        for (i, byte) in tail.iter().enumerate() {
+            k ^= (*byte as u32) << (8 * i);
+        }
+        h ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    }
+    h ^= data.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^ (h >> 16)
+}
+
+/// Data pushes of a locking script, the elements a peer matches against the filter.
+pub fn script_elements(script: &Script) -> Vec<Vec<u8>> {
+    let code = script.bytecode();
+    let mut elements = vec![];
+    let mut pos = 0;
+    while pos < code.len() {
+        let opcode = code[pos];
+        pos += 1;
+        let (len_bytes, len) = match opcode {
+            0x01..=0x4b => (0, opcode as usize),
+            0x4c..=0x4e => {
+                let len_bytes = 1 << (opcode - 0x4c);
+                let len = match code.get(pos..pos + len_bytes) {
+                    Some(len) => len
+                        .iter()
+                        .rev()
+                        .fold(0usize, |acc, byte| (acc << 8) | *byte as usize),
+                    None => return elements,
+                };
+                (len_bytes, len)
+            }
+            _ => continue,
+        };
+        pos += len_bytes;
+        match code.get(pos..pos + len) {
+            Some(push) => elements.push(push.to_vec()),
+            None => return elements,
+        }
+        pos += len;
+    }
+    elements
+}
+
+impl BloomFilter {
+    /// Filter sized for `elements` entries at `fp_rate` false positives, capped at the BIP37
+    /// limits.
+    pub fn new(elements: usize, fp_rate: f64, tweak: u32, flags: u8) -> Self {
+        let elements = elements.max(1);
+        let bits = (-1.0 / LN2_SQUARED * elements as f64 * fp_rate.ln()) as usize;
+        let size = (bits.min(MAX_FILTER_BYTES * 8) / 8).max(1);
+        let hash_funcs = ((size * 8) as f64 / elements as f64 * LN2) as u32;
+        BloomFilter {
+            data: vec![0; size],
+            hash_funcs: hash_funcs.clamp(1, MAX_HASH_FUNCS),
+            tweak,
+            flags,
+        }
+    }
+
+    fn bit_index(&self, n: u32, element: &[u8]) -> usize {
+        let seed = n.wrapping_mul(0xfba4_c795).wrapping_add(self.tweak);
+        murmur3(seed, element) as usize % (self.data.len() * 8)
+    }
+
+    pub fn insert(&mut self, element: &[u8]) {
+        for n in 0..self.hash_funcs {
+            let index = self.bit_index(n, element);
+            self.data[index >> 3] |= 1 << (7 & index);
+        }
+    }
+
+    pub fn contains(&self, element: &[u8]) -> bool {
+        (0..self.hash_funcs).all(|n| {
+            let index = self.bit_index(n, element);
+            self.data[index >> 3] & (1 << (7 & index)) != 0
+        })
+    }
+
+    pub fn to_message(&self) -> RawMessage {
+        filterload(&self.data, self.hash_funcs, self.tweak, self.flags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::address_to_script;
+
+    #[test]
+    fn murmur3_vectors() {
+        assert_eq!(murmur3(0, b""), 0);
+        assert_eq!(murmur3(0xfba4_c795, b""), 0x6a39_6f08);
+        assert_eq!(murmur3(0, &[0]), 0x514e_28b7);
+    }
+
+    /// Filter from the Bitcoin Core bloom tests
+    #[test]
+    fn serialized_filter() {
+        let mut filter = BloomFilter::new(3, 0.01, 0, BLOOM_UPDATE_ALL);
+        let element = hex::decode("99108ad8ed9bb6274d3980bab5a85c048f0950c8").unwrap();
+        filter.insert(&element);
+        assert!(filter.contains(&element));
+        filter.insert(&hex::decode("b5a2c786d9ef4658287ced5914b37a1b4aa32eee").unwrap());
+        filter.insert(&hex::decode("b9300670b4c5366e95b2699e8b18bc75e5f729c5").unwrap());
+        assert_eq!(
+            hex::encode(filter.to_message().payload),
+            "03614e9b050000000000000001"
+        );
+    }
+
+    #[test]
+    fn p2pkh_elements() {
+        let script =
+            address_to_script("bchtest:qptnz3u8atavszhaqk037v0fjrtahxmsl5mm45u3pf").unwrap();
+        let elements = script_elements(&script);
+        assert_eq!(elements.len(), 1);
+        assert_eq!(elements[0], script.bytecode()[3..23].to_vec());
+    }
+}
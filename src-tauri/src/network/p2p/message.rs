@@ -0,0 +1,427 @@
+//! Bitcoin Cash wire messages, only the ones a light client needs.
+use std::io::Read;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bitcoin_hashes::{sha256d::Hash as Sha256d, Hash};
+
+use super::super::error::NetworkError;
+use crate::encoding::{write_varbytes, write_varint, Reader};
+
+pub const PROTOCOL_VERSION: u32 = 70016;
+/// Largest payload accepted, a full 32MB block is never requested
+const MAX_PAYLOAD: usize = 4 * 1024 * 1024;
+const HEADER_LEN: usize = 24;
+
+pub const MSG_TX: u32 = 1;
+pub const MSG_BLOCK: u32 = 2;
+pub const MSG_FILTERED_BLOCK: u32 = 3;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Network {
+    Mainnet,
+    Testnet3,
+    Testnet4,
+    /// Shares magic and genesis with testnet4
+    Chipnet,
+    Regtest,
+}
+
+impl Network {
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "mainnet" => Ok(Network::Mainnet),
+            "testnet3" => Ok(Network::Testnet3),
+            "testnet4" => Ok(Network::Testnet4),
+            "chipnet" => Ok(Network::Chipnet),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(format!("unknown network {}", name)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Network::Mainnet => "mainnet",
+            Network::Testnet3 => "testnet3",
+            Network::Testnet4 => "testnet4",
+            Network::Chipnet => "chipnet",
+            Network::Regtest => "regtest",
+        }
+    }
+
+    pub fn magic(&self) -> [u8; 4] {
+        match self {
+            Network::Mainnet => [0xe3, 0xe1, 0xf3, 0xe8],
+            Network::Testnet3 => [0xf4, 0xe5, 0xf3, 0xf4],
+            Network::Testnet4 | Network::Chipnet => [0xe2, 0xb7, 0xda, 0xaf],
+            Network::Regtest => [0xda, 0xb5, 0xbf, 0xfa],
+        }
+    }
+
+    /// Genesis block hash, internal byte order
+    pub fn genesis_hash(&self) -> [u8; 32] {
+        let hex_hash = match self {
+            Network::Mainnet => "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+            Network::Testnet3 => "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943",
+            Network::Testnet4 | Network::Chipnet => {
+                "000000001dd410c49a788668ce26751718cc797474d3152a5fc073dd44fd9f7b"
+            }
+            Network::Regtest => "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206",
+        };
+        let mut hash: [u8; 32] = hex::decode(hex_hash).unwrap().try_into().unwrap();
+        hash.reverse();
+        hash
+    }
+
+    pub fn default_port(&self) -> u16 {
+        match self {
+            Network::Mainnet => 8333,
+            Network::Testnet3 => 18333,
+            Network::Testnet4 => 28333,
+            Network::Chipnet => 48333,
+            Network::Regtest => 18444,
+        }
+    }
+}
+
+pub fn sha256d(data: &[u8]) -> [u8; 32] {
+    Sha256d::hash(data).into_inner()
+}
+
+/// Hash as displayed by explorers, from internal byte order.
+pub fn hash_hex(hash: &[u8; 32]) -> String {
+    let mut hash = *hash;
+    hash.reverse();
+    hex::encode(hash)
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RawMessage {
+    pub command: String,
+    pub payload: Vec<u8>,
+}
+
+fn protocol_err(reason: String) -> NetworkError {
+    NetworkError::Request {
+        method: "p2p".to_string(),
+        reason,
+    }
+}
+
+impl RawMessage {
+    pub fn new(command: &str, payload: Vec<u8>) -> Self {
+        RawMessage {
+            command: command.to_string(),
+            payload,
+        }
+    }
+
+    pub fn encode(&self, magic: [u8; 4]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        out.extend_from_slice(&magic);
+        let mut command = [0u8; 12];
+        command[..self.command.len()].copy_from_slice(self.command.as_bytes());
+        out.extend_from_slice(&command);
+        out.extend_from_slice(&(self.payload.len() as u32).to_le_bytes());
+        out.extend_from_slice(&sha256d(&self.payload)[..4]);
+        out.extend_from_slice(&self.payload);
+        out
+    }
+
+    pub fn read<R: Read>(reader: &mut R, magic: [u8; 4]) -> Result<Self, NetworkError> {
+        let io_err = |e: std::io::Error| protocol_err(e.to_string());
+        let mut header = [0u8; HEADER_LEN];
+        reader.read_exact(&mut header).map_err(io_err)?;
+        if header[..4] != magic {
+            return Err(protocol_err("wrong network magic".to_string()));
+        }
+        let command = header[4..16]
+            .iter()
+            .take_while(|b| **b != 0)
+            .map(|b| *b as char)
+            .collect::<String>();
+        let len = u32::from_le_bytes(header[16..20].try_into().unwrap()) as usize;
+        if len > MAX_PAYLOAD {
+            return Err(protocol_err(format!(
+                "{} payload of {} bytes",
+                command, len
+            )));
+        }
+        let mut payload = vec![0u8; len];
+        reader.read_exact(&mut payload).map_err(io_err)?;
+        if sha256d(&payload)[..4] != header[20..24] {
+            return Err(protocol_err(format!("bad checksum on {}", command)));
+        }
+        Ok(RawMessage { command, payload })
+    }
+}
+
+fn net_addr(out: &mut Vec<u8>, ip: IpAddr, port: u16) {
+    out.extend_from_slice(&0u64.to_le_bytes());
+    let ip = match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    };
+    out.extend_from_slice(&ip.octets());
+    out.extend_from_slice(&port.to_be_bytes());
+}
+
+/// Our `version`, advertising no services and asking for no relay until a filter is loaded.
+pub fn version(peer_ip: IpAddr, peer_port: u16, nonce: u64, start_height: u32) -> RawMessage {
+    let mut payload = vec![];
+    payload.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+    payload.extend_from_slice(&0u64.to_le_bytes());
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    payload.extend_from_slice(&now.to_le_bytes());
+    net_addr(&mut payload, peer_ip, peer_port);
+    net_addr(&mut payload, IpAddr::from([0, 0, 0, 0]), 0);
+    payload.extend_from_slice(&nonce.to_le_bytes());
+    let user_agent = format!("/cashcaster:{}/", env!("CARGO_PKG_VERSION"));
+    write_varbytes(&mut payload, user_agent.as_bytes());
+    payload.extend_from_slice(&start_height.to_le_bytes());
+    payload.push(0);
+    RawMessage::new("version", payload)
+}
+
+/// Fields of the peer's `version` we use.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerVersion {
+    pub version: u32,
+    pub services: u64,
+    pub user_agent: String,
+    pub start_height: u32,
+}
+
+pub fn parse_version(payload: &[u8]) -> Option<PeerVersion> {
+    let mut reader = Reader::new(payload);
+    let version = reader.u32()?;
+    let services = reader.u64()?;
+    // timestamp, both addresses and the nonce
+    reader.bytes(8 + 26 + 26 + 8)?;
+    let user_agent = String::from_utf8_lossy(reader.varbytes()?).to_string();
+    let start_height = reader.u32()?;
+    Some(PeerVersion {
+        version,
+        services,
+        user_agent,
+        start_height,
+    })
+}
+
+pub fn nonce_message(command: &str, nonce: u64) -> RawMessage {
+    RawMessage::new(command, nonce.to_le_bytes().to_vec())
+}
+
+/// `getheaders` for everything after the first locator hash the peer knows. With an empty
+/// locator the peer answers with the `stop` header alone.
+pub fn getheaders(locator: &[[u8; 32]], stop: [u8; 32]) -> RawMessage {
+    let mut payload = vec![];
+    payload.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+    write_varint(&mut payload, locator.len() as u64);
+    for hash in locator {
+        payload.extend_from_slice(hash);
+    }
+    payload.extend_from_slice(&stop);
+    RawMessage::new("getheaders", payload)
+}
+
+/// Raw 80 byte headers of a `headers` message.
+pub fn parse_headers(payload: &[u8]) -> Option<Vec<[u8; 80]>> {
+    let mut reader = Reader::new(payload);
+    let count = reader.varint()?;
+    let mut headers = vec![];
+    for _ in 0..count {
+        headers.push(reader.bytes(80)?.try_into().ok()?);
+        // transaction count, always 0
+        reader.varint()?;
+    }
+    Some(headers)
+}
+
+/// `inv`, `getdata` and `notfound` share this layout.
+pub fn inventory(command: &str, items: &[(u32, [u8; 32])]) -> RawMessage {
+    let mut payload = vec![];
+    write_varint(&mut payload, items.len() as u64);
+    for (kind, hash) in items {
+        payload.extend_from_slice(&kind.to_le_bytes());
+        payload.extend_from_slice(hash);
+    }
+    RawMessage::new(command, payload)
+}
+
+pub fn parse_inventory(payload: &[u8]) -> Option<Vec<(u32, [u8; 32])>> {
+    let mut reader = Reader::new(payload);
+    let count = reader.varint()?;
+    let mut items = vec![];
+    for _ in 0..count {
+        items.push((reader.u32()?, reader.hash()?));
+    }
+    Some(items)
+}
+
+/// `feefilter` rate in satoshis per kB.
+pub fn parse_feefilter(payload: &[u8]) -> Option<u64> {
+    Reader::new(payload).u64()
+}
+
+/// `reject` as `(rejected message, reason)`.
+pub fn parse_reject(payload: &[u8]) -> Option<(String, String)> {
+    let mut reader = Reader::new(payload);
+    let message = String::from_utf8_lossy(reader.varbytes()?).to_string();
+    let _code = reader.u8()?;
+    let reason = String::from_utf8_lossy(reader.varbytes()?).to_string();
+    Some((message, reason))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MerkleBlock {
+    pub header: [u8; 80],
+    pub total_txs: u32,
+    pub hashes: Vec<[u8; 32]>,
+    pub flags: Vec<u8>,
+}
+
+pub fn parse_merkleblock(payload: &[u8]) -> Option<MerkleBlock> {
+    let mut reader = Reader::new(payload);
+    let header = reader.bytes(80)?.try_into().ok()?;
+    let total_txs = reader.u32()?;
+    let count = reader.varint()?;
+    let mut hashes = vec![];
+    for _ in 0..count {
+        hashes.push(reader.hash()?);
+    }
+    let flags = reader.varbytes()?.to_vec();
+    Some(MerkleBlock {
+        header,
+        total_txs,
+        hashes,
+        flags,
+    })
+}
+
+impl MerkleBlock {
+    /// Walks the partial merkle tree, returns the root and the matched txids in block order.
+    /// `None` when the tree is malformed or not fully consumed.
+    pub fn extract(&self) -> Option<([u8; 32], Vec<[u8; 32]>)> {
+        if self.total_txs == 0 || self.hashes.len() > self.total_txs as usize {
+            return None;
+        }
+        let mut height = 0;
+        while tree_width(self.total_txs, height) > 1 {
+            height += 1;
+        }
+        let mut walk = TreeWalk {
+            block: self,
+            bit: 0,
+            hash: 0,
+            matched: vec![],
+        };
+        let root = walk.node(height, 0)?;
+        let flag_bytes_used = (walk.bit + 7) / 8;
+        if walk.hash != self.hashes.len() || flag_bytes_used != self.flags.len() {
+            return None;
+        }
+        Some((root, walk.matched))
+    }
+}
+
+fn tree_width(total_txs: u32, height: u32) -> u32 {
+    (total_txs + (1 << height) - 1) >> height
+}
+
+struct TreeWalk<'a> {
+    block: &'a MerkleBlock,
+    bit: usize,
+    hash: usize,
+    matched: Vec<[u8; 32]>,
+}
+
+impl<'a> TreeWalk<'a> {
+    fn node(&mut self, height: u32, pos: u32) -> Option<[u8; 32]> {
+        let flag = (self.block.flags.get(self.bit / 8)? >> (self.bit % 8)) & 1 == 1;
+        self.bit += 1;
+        if height == 0 || !flag {
+            let hash = *self.block.hashes.get(self.hash)?;
+            self.hash += 1;
+            if height == 0 && flag {
+                self.matched.push(hash);
+            }
+            return Some(hash);
+        }
+        let left = self.node(height - 1, pos * 2)?;
+        let right = match pos * 2 + 1 < tree_width(self.block.total_txs, height - 1) {
+            true => {
+                let right = self.node(height - 1, pos * 2 + 1)?;
+                // identical children would allow CVE-2012-2459 style duplicates
+                if right == left {
+                    return None;
+                }
+                right
+            }
+            false => left,
+        };
+        Some(sha256d(&[left, right].concat()))
+    }
+}
+
+/// `filterload` for a serialized bloom filter.
+pub fn filterload(filter: &[u8], hash_funcs: u32, tweak: u32, flags: u8) -> RawMessage {
+    let mut payload = vec![];
+    write_varbytes(&mut payload, filter);
+    payload.extend_from_slice(&hash_funcs.to_le_bytes());
+    payload.extend_from_slice(&tweak.to_le_bytes());
+    payload.push(flags);
+    RawMessage::new("filterload", payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn framing() {
+        let ping = nonce_message("ping", 7);
+        let encoded = ping.encode(Network::Regtest.magic());
+        assert_eq!(encoded.len(), HEADER_LEN + 8);
+        assert_eq!(&encoded[4..8], b"ping");
+        let decoded = RawMessage::read(&mut &encoded[..], Network::Regtest.magic()).unwrap();
+        assert_eq!(decoded, ping);
+        assert!(RawMessage::read(&mut &encoded[..], Network::Mainnet.magic()).is_err());
+
+        let mut corrupted = encoded;
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(RawMessage::read(&mut &corrupted[..], Network::Regtest.magic()).is_err());
+    }
+
+    #[test]
+    fn partial_merkle_tree() {
+        let a = [1u8; 32];
+        let b = [2u8; 32];
+        let root = sha256d(&[a, b].concat());
+        // root (parent of a match), a (matched), b (not matched)
+        let block = MerkleBlock {
+            header: [0u8; 80],
+            total_txs: 2,
+            hashes: vec![a, b],
+            flags: vec![0b011],
+        };
+        assert_eq!(block.extract(), Some((root, vec![a])));
+
+        let single = MerkleBlock {
+            header: [0u8; 80],
+            total_txs: 1,
+            hashes: vec![a],
+            flags: vec![1],
+        };
+        assert_eq!(single.extract(), Some((a, vec![a])));
+
+        let unused_hash = MerkleBlock {
+            hashes: vec![a, b, b],
+            ..block
+        };
+        assert_eq!(unused_hash.extract(), None);
+    }
+}
@@ -0,0 +1,631 @@
+//! Bitcoin Cash peer-to-peer light client, a `ChainBackend` that needs no Electrum server.
+//! Headers are synced headers-first into a header store of their own per network, the most
+//! work wins, and the chain must reach the network minimum work. Wallet transactions are found
+//! with a BIP37 bloom filter and `merkleblock`s, each one proven against a stored header.
+//! Unconfirmed transactions come from the peer's `mempool` and are not proven. Blocks before
+//! `scan_from` are never scanned, it should be the wallet birthday.
+pub mod bloom;
+pub mod message;
+pub mod peer;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bitcoinsuite_core::script::Script;
+use bitcoinsuite_core::tx::Transaction;
+use serde_json::{json, Value};
+
+use self::bloom::{script_elements, BloomFilter, BLOOM_UPDATE_ALL};
+use self::message::{
+    getheaders, hash_hex, inventory, parse_headers, parse_inventory, parse_merkleblock,
+    parse_reject, sha256d, Network, PeerVersion, RawMessage, MSG_FILTERED_BLOCK, MSG_TX,
+};
+use self::peer::Peer;
+use super::backend::{BackendFuture, ChainBackend};
+use super::error::NetworkError;
+use super::proxy::ProxyConfig;
+use crate::coins::utxo::token_json;
+use crate::error::WalletError;
+use crate::spv::headers::{BlockHeader, HeaderStore};
+use crate::store::transactions::{cache_transaction, decode_transaction, get_cached_transaction};
+
+/// Most headers a peer sends per `headers` message
+const MAX_HEADERS: usize = 2000;
+/// Filtered blocks asked for per `getdata`
+const BLOCKS_PER_REQUEST: u32 = 500;
+/// Blocks below the last scanned height scanned again, to follow reorgs
+const RESCAN_DEPTH: u32 = 10;
+const FILTER_FP_RATE: f64 = 0.0001;
+
+/// Wallet transaction found by a scan, `height` is 0 for mempool transactions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScannedTx {
+    pub txid: String,
+    pub raw: Vec<u8>,
+    pub height: u32,
+}
+
+#[derive(Default)]
+struct ScriptScan {
+    /// Next height to scan
+    next_height: u32,
+    txs: Vec<ScannedTx>,
+}
+
+struct PeerSession {
+    host: String,
+    port: u16,
+    network: Network,
+    scan_from: u32,
+    timeout: Duration,
+    proxy: Option<ProxyConfig>,
+    /// Wallet database, the headers and scanned transactions are kept in it
+    db: sled::Db,
+    peer: Mutex<Option<Peer>>,
+    scans: Mutex<HashMap<Vec<u8>, ScriptScan>>,
+}
+
+pub struct PeerBackend {
+    session: Arc<PeerSession>,
+}
+
+fn p2p_err(method: &str, reason: String) -> NetworkError {
+    NetworkError::Request {
+        method: method.to_string(),
+        reason,
+    }
+}
+
+fn store_err(e: WalletError) -> NetworkError {
+    p2p_err("p2p", e.to_string())
+}
+
+/// Reads until a `command` message arrives.
+fn wait_for(peer: &mut Peer, command: &str) -> Result<RawMessage, NetworkError> {
+    loop {
+        let message = peer.receive()?;
+        if message.command == command {
+            return Ok(message);
+        }
+    }
+}
+
+/// Downloads headers into `store` until the peer has no more and returns our tip height.
+fn sync_headers(
+    peer: &mut Peer,
+    network: Network,
+    store: &HeaderStore,
+) -> Result<u32, NetworkError> {
+    loop {
+        let locator = store.block_locator().map_err(store_err)?;
+        let request = match locator.is_empty() {
+            true => getheaders(&[], network.genesis_hash()),
+            false => {
+                let hashes: Vec<[u8; 32]> = locator.iter().map(|(_, hash)| *hash).collect();
+                getheaders(&hashes, [0u8; 32])
+            }
+        };
+        peer.send(&request)?;
+        let message = wait_for(peer, "headers")?;
+        let headers = match parse_headers(&message.payload) {
+            Some(headers) => headers
+                .iter()
+                .map(|raw| BlockHeader::from_slice(raw))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(store_err)?,
+            None => return Err(p2p_err("getheaders", "bad headers message".to_string())),
+        };
+        let first = match headers.first() {
+            Some(first) => first,
+            None => {
+                let tip = store.tip_height().map_err(store_err)?.unwrap_or(0);
+                store.check_chainwork(tip).map_err(store_err)?;
+                return Ok(tip);
+            }
+        };
+        let start = match locator.is_empty() {
+            true if first.hash() == network.genesis_hash() => 0,
+            true => {
+                return Err(p2p_err(
+                    "getheaders",
+                    "peer sent another genesis".to_string(),
+                ))
+            }
+            false => match locator.iter().find(|(_, hash)| *hash == first.prev_hash()) {
+                Some((height, _)) => height + 1,
+                None => {
+                    return Err(p2p_err(
+                        "getheaders",
+                        "headers do not connect to our chain".to_string(),
+                    ))
+                }
+            },
+        };
+        let tip = store.connect_headers(start, &headers).map_err(store_err)?;
+        // a branch with less work than ours is not taken, asking again gets the same one
+        let taken = tip == start + headers.len() as u32 - 1;
+        if !taken || (start > 0 && headers.len() < MAX_HEADERS) {
+            store.check_chainwork(tip).map_err(store_err)?;
+            return Ok(tip);
+        }
+    }
+}
+
+fn decode_scanned(txs: &[ScannedTx]) -> Result<Vec<(&ScannedTx, Transaction)>, WalletError> {
+    txs.iter()
+        .map(|tx| Ok((tx, decode_transaction(&tx.txid, &hex::encode(&tx.raw))?)))
+        .collect()
+}
+
+/// `listunspent` array of the outputs to `script` not spent by any of `txs`.
+pub fn unspent_json(script: &Script, txs: &[ScannedTx]) -> Result<Value, WalletError> {
+    let decoded = decode_scanned(txs)?;
+    let spent: HashSet<(String, u32)> = decoded
+        .iter()
+        .flat_map(|(_, tx)| tx.inputs.iter())
+        .map(|input| {
+            (
+                input.prev_out.txid.to_string(),
+                input.prev_out.outpoint_index,
+            )
+        })
+        .collect();
+    let mut utxos = vec![];
+    for (scanned, tx) in decoded.iter() {
+        for (vout, output) in tx.outputs.iter().enumerate() {
+            let outpoint = (scanned.txid.clone(), vout as u32);
+            if output.script != *script || spent.contains(&outpoint) {
+                continue;
+            }
+            let mut utxo = json!({
+                "tx_hash": scanned.txid,
+                "tx_pos": vout,
+                "value": output.value,
+                "height": scanned.height,
+            });
+            if let Some(token) = output.token.as_ref() {
+                utxo["token_data"] = token_json(token);
+            }
+            utxos.push(utxo);
+        }
+    }
+    Ok(Value::Array(utxos))
+}
+
+/// Outpoints, as matched by a bloom filter, of the outputs to `script` in `txs`.
+fn script_outpoints(script: &Script, txs: &[ScannedTx]) -> Result<Vec<Vec<u8>>, WalletError> {
+    let mut outpoints = vec![];
+    for (scanned, tx) in decode_scanned(txs)? {
+        let txid = sha256d(&scanned.raw);
+        for (vout, output) in tx.outputs.iter().enumerate() {
+            if output.script == *script {
+                outpoints.push([&txid[..], &(vout as u32).to_le_bytes()].concat());
+            }
+        }
+    }
+    Ok(outpoints)
+}
+
+impl PeerSession {
+    /// Headers of this network, kept apart from the wallet's own store.
+    fn headers(&self) -> Result<HeaderStore, NetworkError> {
+        let name = format!("p2p_{}", self.network.name());
+        HeaderStore::open(&self.db, Some(&name)).map_err(store_err)
+    }
+
+    /// Runs `f` with the connected peer, connecting first. The peer is dropped on any error
+    /// so the next call reconnects.
+    fn with_peer<T, F>(&self, f: F) -> Result<T, NetworkError>
+    where
+        F: FnOnce(&mut Peer) -> Result<T, NetworkError>,
+    {
+        let mut guard = self.peer.lock().unwrap();
+        if guard.is_none() {
+            let start_height = self
+                .headers()?
+                .tip_height()
+                .map_err(store_err)?
+                .unwrap_or(0);
+            *guard = Some(Peer::connect(
+                &self.host,
+                self.port,
+                self.network,
+                start_height,
+                self.timeout,
+                self.proxy.as_ref(),
+            )?);
+        }
+        let res = f(guard.as_mut().unwrap());
+        if res.is_err() {
+            *guard = None;
+        }
+        res
+    }
+
+    /// Transactions touching `script`, scanning the blocks added since the last scan.
+    fn scan(&self, script: &Script) -> Result<Vec<ScannedTx>, NetworkError> {
+        let elements = script_elements(script);
+        if elements.is_empty() {
+            return Err(p2p_err(
+                "filterload",
+                "script has no data pushes to filter on".to_string(),
+            ));
+        }
+        let store = self.headers()?;
+        let mut scans = self.scans.lock().unwrap();
+        let scan = scans.entry(script.bytecode().to_vec()).or_default();
+        self.with_peer(|peer| {
+            let tip = sync_headers(peer, self.network, &store)?;
+            let from = match scan.next_height > self.scan_from {
+                true => scan
+                    .next_height
+                    .saturating_sub(RESCAN_DEPTH)
+                    .max(self.scan_from),
+                false => self.scan_from,
+            };
+            let mut txs: Vec<ScannedTx> = scan
+                .txs
+                .iter()
+                .filter(|tx| tx.height > 0 && tx.height < from)
+                .cloned()
+                .collect();
+
+            let outpoints = script_outpoints(script, &txs).map_err(store_err)?;
+            let mut filter = BloomFilter::new(
+                elements.len() + outpoints.len(),
+                FILTER_FP_RATE,
+                rand::random(),
+                BLOOM_UPDATE_ALL,
+            );
+            for element in elements.iter().chain(outpoints.iter()) {
+                filter.insert(element);
+            }
+            peer.send(&filter.to_message())?;
+
+            let mut height = from;
+            while height <= tip {
+                let end = tip.min(height + BLOCKS_PER_REQUEST - 1);
+                txs.extend(filtered_blocks(peer, &store, height, end)?);
+                height = end + 1;
+            }
+            txs.extend(mempool_transactions(peer)?);
+            for tx in txs.iter() {
+                cache_transaction(&self.db, &tx.txid, &hex::encode(&tx.raw)).map_err(store_err)?;
+            }
+            scan.next_height = tip + 1;
+            scan.txs = txs.clone();
+            Ok(txs)
+        })
+    }
+}
+
+/// Matched transactions of the blocks `start..=end`, in chain order.
+fn filtered_blocks(
+    peer: &mut Peer,
+    store: &HeaderStore,
+    start: u32,
+    end: u32,
+) -> Result<Vec<ScannedTx>, NetworkError> {
+    let method = "merkleblock";
+    let mut heights: HashMap<[u8; 32], (u32, BlockHeader)> = HashMap::new();
+    let mut items = vec![];
+    for height in start..=end {
+        let header = match store.get_header(height).map_err(store_err)? {
+            Some(header) => header,
+            None => return Err(p2p_err(method, format!("no header at {}", height))),
+        };
+        items.push((MSG_FILTERED_BLOCK, header.hash()));
+        heights.insert(header.hash(), (height, header));
+    }
+    peer.send(&inventory("getdata", &items))?;
+
+    let mut matched: Vec<(u32, Vec<[u8; 32]>)> = vec![];
+    let mut raw_txs: HashMap<[u8; 32], Vec<u8>> = HashMap::new();
+    for message in peer.drain()? {
+        match message.command.as_str() {
+            "merkleblock" => {
+                let block = match parse_merkleblock(&message.payload) {
+                    Some(block) => block,
+                    None => return Err(p2p_err(method, "bad merkleblock".to_string())),
+                };
+                let (height, header) = match heights.get(&sha256d(&block.header)) {
+                    Some(entry) => entry,
+                    None => continue,
+                };
+                match block.extract() {
+                    Some((root, txids)) if root == header.merkle_root() => {
+                        matched.push((*height, txids))
+                    }
+                    _ => {
+                        return Err(p2p_err(
+                            method,
+                            format!("bad merkle proof in block {}", height),
+                        ))
+                    }
+                }
+            }
+            "tx" => {
+                raw_txs.insert(sha256d(&message.payload), message.payload);
+            }
+            "notfound" => return Err(p2p_err(method, "peer does not have the blocks".to_string())),
+            _ => {}
+        }
+    }
+    if matched.len() != items.len() {
+        return Err(p2p_err(
+            method,
+            format!("peer sent {} of {} blocks", matched.len(), items.len()),
+        ));
+    }
+    matched.sort_by_key(|(height, _)| *height);
+    let mut txs = vec![];
+    for (height, txids) in matched {
+        for txid in txids {
+            match raw_txs.get(&txid) {
+                Some(raw) => txs.push(ScannedTx {
+                    txid: hash_hex(&txid),
+                    raw: raw.clone(),
+                    height,
+                }),
+                None => {
+                    return Err(p2p_err(
+                        method,
+                        format!("peer did not send {}", hash_hex(&txid)),
+                    ))
+                }
+            }
+        }
+    }
+    Ok(txs)
+}
+
+/// Mempool transactions matching the loaded filter.
+fn mempool_transactions(peer: &mut Peer) -> Result<Vec<ScannedTx>, NetworkError> {
+    peer.send(&RawMessage::new("mempool", vec![]))?;
+    let mut items = vec![];
+    for message in peer.drain()? {
+        if message.command == "inv" {
+            if let Some(inv) = parse_inventory(&message.payload) {
+                items.extend(inv.into_iter().filter(|(kind, _)| *kind == MSG_TX));
+            }
+        }
+    }
+    if items.is_empty() {
+        return Ok(vec![]);
+    }
+    peer.send(&inventory("getdata", &items))?;
+    let wanted: HashSet<[u8; 32]> = items.iter().map(|(_, hash)| *hash).collect();
+    let mut txs = vec![];
+    for message in peer.drain()? {
+        let txid = sha256d(&message.payload);
+        if message.command == "tx" && wanted.contains(&txid) {
+            txs.push(ScannedTx {
+                txid: hash_hex(&txid),
+                raw: message.payload,
+                height: 0,
+            });
+        }
+    }
+    Ok(txs)
+}
+
+impl PeerBackend {
+    /// `host` is a node address, `port` defaults to the network port. Nothing connects until
+    /// the first call.
+    pub fn new(
+        host: &str,
+        port: Option<u16>,
+        network: Network,
+        scan_from: u32,
+        timeout: Duration,
+        proxy: Option<ProxyConfig>,
+        db: sled::Db,
+    ) -> Self {
+        PeerBackend {
+            session: Arc::new(PeerSession {
+                host: host.to_string(),
+                port: port.unwrap_or_else(|| network.default_port()),
+                network,
+                scan_from,
+                timeout,
+                proxy,
+                db,
+                peer: Mutex::new(None),
+                scans: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Connects without syncing anything and returns what the peer said about itself.
+    pub async fn handshake(&self) -> Result<PeerVersion, NetworkError> {
+        self.run("version", |session| {
+            session.with_peer(|peer| Ok(peer.version.clone()))
+        })
+        .await
+    }
+
+    /// Runs the blocking peer exchange on the blocking pool.
+    async fn run<T, F>(&self, method: &str, f: F) -> Result<T, NetworkError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&PeerSession) -> Result<T, NetworkError> + Send + 'static,
+    {
+        let session = self.session.clone();
+        match tokio::task::spawn_blocking(move || f(&session)).await {
+            Ok(res) => res,
+            Err(e) => Err(p2p_err(method, e.to_string())),
+        }
+    }
+}
+
+impl ChainBackend for PeerBackend {
+    fn name(&self) -> String {
+        format!("p2p://{}:{}", self.session.host, self.session.port)
+    }
+
+    fn unspent<'a>(&'a self, script: &'a Script) -> BackendFuture<'a, String> {
+        let script = script.clone();
+        Box::pin(self.run("unspent", move |session| {
+            let txs = session.scan(&script)?;
+            match unspent_json(&script, &txs) {
+                Ok(utxos) => Ok(utxos.to_string()),
+                Err(e) => Err(store_err(e)),
+            }
+        }))
+    }
+
+    fn history<'a>(&'a self, script: &'a Script) -> BackendFuture<'a, String> {
+        let script = script.clone();
+        Box::pin(self.run("history", move |session| {
+            let history: Vec<Value> = session
+                .scan(&script)?
+                .iter()
+                .map(|tx| json!({"tx_hash": tx.txid, "height": tx.height}))
+                .collect();
+            Ok(Value::Array(history).to_string())
+        }))
+    }
+
+    fn transaction<'a>(&'a self, txid: &'a str) -> BackendFuture<'a, String> {
+        let txid = txid.to_string();
+        Box::pin(self.run("transaction", move |session| {
+            if let Some(raw_tx) = get_cached_transaction(&session.db, &txid).map_err(store_err)? {
+                return Ok(raw_tx);
+            }
+            // only mempool transactions can be asked for by txid
+            let mut hash: [u8; 32] = match hex::decode(&txid).map(|h| h.try_into()) {
+                Ok(Ok(hash)) => hash,
+                _ => return Err(p2p_err("getdata", format!("bad txid {}", txid))),
+            };
+            hash.reverse();
+            let raw = session.with_peer(|peer| {
+                peer.send(&inventory("getdata", &[(MSG_TX, hash)]))?;
+                let raw = peer
+                    .drain()?
+                    .into_iter()
+                    .find(|m| m.command == "tx" && sha256d(&m.payload) == hash);
+                Ok(raw.map(|m| m.payload))
+            })?;
+            match raw {
+                Some(raw) => Ok(hex::encode(raw)),
+                None => Err(p2p_err("getdata", format!("peer does not have {}", txid))),
+            }
+        }))
+    }
+
+    /// Relays the transaction. Peers only answer with `reject`, acceptance shows in the next
+    /// scan.
+    fn broadcast<'a>(&'a self, raw_tx: &'a str) -> BackendFuture<'a, String> {
+        let raw_tx = raw_tx.to_string();
+        Box::pin(self.run("broadcast", move |session| {
+            let raw = match hex::decode(&raw_tx) {
+                Ok(raw) => raw,
+                Err(e) => return Err(p2p_err("tx", e.to_string())),
+            };
+            let txid = hash_hex(&sha256d(&raw));
+            session.with_peer(|peer| {
+                peer.send(&RawMessage::new("tx", raw))?;
+                for message in peer.drain()? {
+                    if message.command != "reject" {
+                        continue;
+                    }
+                    if let Some((rejected, reason)) = parse_reject(&message.payload) {
+                        if rejected == "tx" {
+                            return Err(p2p_err("tx", reason));
+                        }
+                    }
+                }
+                Ok(txid)
+            })
+        }))
+    }
+
+    /// The peer's `feefilter` relay fee.
+    fn fee_estimate(&self) -> BackendFuture<'_, f64> {
+        Box::pin(self.run("feefilter", |session| {
+            let fee_filter = session.with_peer(|peer| {
+                peer.drain()?;
+                Ok(peer.fee_filter)
+            })?;
+            match fee_filter {
+                Some(sats_per_kb) => Ok(sats_per_kb as f64 / 1e8),
+                None => Ok(-1.0),
+            }
+        }))
+    }
+
+    fn tip(&self) -> BackendFuture<'_, (u32, Vec<u8>)> {
+        Box::pin(self.run("getheaders", |session| {
+            let store = session.headers()?;
+            let tip = session.with_peer(|peer| sync_headers(peer, session.network, &store))?;
+            match store.get_header(tip).map_err(store_err)? {
+                Some(header) => Ok((tip, header.0.to_vec())),
+                None => Err(p2p_err("getheaders", format!("no header at {}", tip))),
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // coinbase of block 9 and the transaction in block 170 spending it, both pay to the same
+    // pay-to-pubkey script
+    const COINBASE_9: &str = "01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff0704ffff001d0134ffffffff0100f2052a0100000043410411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3ac00000000";
+    const TX_170: &str = "0100000001c997a5e56e104102fa209c6a852dd90660a20b2d9c352423edce25857fcd3704000000004847304402204e45e16932b8af514961a1d3a1a25fdf3f4f7732e9d624c6c61548ab5fb8cd410220181522ec8eca07de4860a4acdd12909d831cc56cbbac4622082221a8768d1d0901ffffffff0200ca9a3b00000000434104ae1a62fe09c5f51b13905f07f06b99a2f7159b2225f374cd378d71302fa28414e7aab37397f554a7df5f142c21c1b7303b8a0626f1baded5c72a704f7e6cd84cac00286bee0000000043410411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3ac00000000";
+
+    fn scanned(raw_hex: &str, height: u32) -> ScannedTx {
+        let raw = hex::decode(raw_hex).unwrap();
+        ScannedTx {
+            txid: hash_hex(&sha256d(&raw)),
+            raw,
+            height,
+        }
+    }
+
+    #[test]
+    fn spent_outputs_removed() {
+        let txs = vec![scanned(COINBASE_9, 9), scanned(TX_170, 170)];
+        let coinbase = decode_transaction(&txs[0].txid, COINBASE_9).unwrap();
+        let script = coinbase.outputs[0].script.clone();
+
+        let utxos = unspent_json(&script, &txs).unwrap();
+        assert_eq!(utxos.as_array().unwrap().len(), 1);
+        assert_eq!(utxos[0]["tx_hash"], txs[1].txid);
+        assert_eq!(utxos[0]["tx_pos"], 1);
+        assert_eq!(utxos[0]["value"], 4_000_000_000u64);
+        assert_eq!(utxos[0]["height"], 170);
+
+        let outpoints = script_outpoints(&script, &txs).unwrap();
+        assert_eq!(outpoints.len(), 2);
+        assert_eq!(outpoints[0].len(), 36);
+    }
+
+    /// Needs a BCHN regtest node with a few blocks, e.g.
+    /// `BCHN_P2P_HOST=127.0.0.1 cargo test -- --ignored`.
+    #[tokio::test]
+    #[ignore]
+    async fn regtest_peer() {
+        let host = std::env::var("BCHN_P2P_HOST").unwrap();
+        let backend = PeerBackend::new(
+            &host,
+            None,
+            Network::Regtest,
+            0,
+            Duration::from_secs(30),
+            None,
+            sled::Config::new().temporary(true).open().unwrap(),
+        );
+        let (height, header) = backend.tip().await.unwrap();
+        assert!(height > 0);
+        assert_eq!(header.len(), 80);
+        assert!(backend.fee_estimate().await.is_ok());
+
+        let address = "bchtest:qptnz3u8atavszhaqk037v0fjrtahxmsl5mm45u3pf";
+        let script = crate::address::address_to_script(address).unwrap();
+        let utxos: Value = serde_json::from_str(&backend.unspent(&script).await.unwrap()).unwrap();
+        assert!(utxos.is_array());
+    }
+}
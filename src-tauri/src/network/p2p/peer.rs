@@ -0,0 +1,136 @@
+//! Blocking connection to one peer, with the version handshake done on connect.
+use std::io::Write;
+use std::net::{IpAddr, TcpStream};
+use std::time::Duration;
+
+use super::super::error::NetworkError;
+use super::super::proxy::{connect_stream, ProxyConfig};
+use super::message::{nonce_message, parse_feefilter, parse_version, version};
+use super::message::{Network, PeerVersion, RawMessage};
+
+/// Service bit of peers answering `filterload`
+const NODE_BLOOM: u64 = 1 << 2;
+/// Messages read while waiting for the handshake to finish
+const HANDSHAKE_MESSAGES: usize = 20;
+
+pub struct Peer {
+    stream: TcpStream,
+    network: Network,
+    address: String,
+    pub version: PeerVersion,
+    /// Relay fee from the peer's `feefilter`, satoshis per kB
+    pub fee_filter: Option<u64>,
+}
+
+fn handshake_err(address: &str, reason: &str) -> NetworkError {
+    NetworkError::Connect {
+        url: address.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+impl Peer {
+    /// Connects and completes `version`/`verack`. Peers without the bloom service are refused.
+    pub fn connect(
+        host: &str,
+        port: u16,
+        network: Network,
+        start_height: u32,
+        timeout: Duration,
+        proxy: Option<&ProxyConfig>,
+    ) -> Result<Self, NetworkError> {
+        let address = format!("{}:{}", host, port);
+        let stream = connect_stream(host, port, timeout, proxy)?;
+        let peer_ip = match stream.peer_addr() {
+            Ok(addr) => addr.ip(),
+            Err(_) => IpAddr::from([0, 0, 0, 0]),
+        };
+        let mut peer = Peer {
+            stream,
+            network,
+            address,
+            version: PeerVersion {
+                version: 0,
+                services: 0,
+                user_agent: String::new(),
+                start_height: 0,
+            },
+            fee_filter: None,
+        };
+        peer.send(&version(peer_ip, port, rand::random(), start_height))?;
+        let mut got_version = false;
+        let mut got_verack = false;
+        for _ in 0..HANDSHAKE_MESSAGES {
+            let message = peer.receive()?;
+            match message.command.as_str() {
+                "version" => match parse_version(&message.payload) {
+                    Some(version) => {
+                        peer.version = version;
+                        got_version = true;
+                        peer.send(&RawMessage::new("verack", vec![]))?;
+                    }
+                    None => return Err(handshake_err(&peer.address, "bad version message")),
+                },
+                "verack" => got_verack = true,
+                _ => {}
+            }
+            if got_version && got_verack {
+                break;
+            }
+        }
+        if !(got_version && got_verack) {
+            return Err(handshake_err(&peer.address, "no version handshake"));
+        }
+        if peer.version.services & NODE_BLOOM == 0 {
+            return Err(NetworkError::Unsupported {
+                url: peer.address.clone(),
+                reason: "peer does not serve bloom filters".to_string(),
+            });
+        }
+        Ok(peer)
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    pub fn send(&mut self, message: &RawMessage) -> Result<(), NetworkError> {
+        match self.stream.write_all(&message.encode(self.network.magic())) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(NetworkError::Request {
+                method: message.command.clone(),
+                reason: e.to_string(),
+            }),
+        }
+    }
+
+    /// Next message, answering pings and recording fee filters on the way.
+    pub fn receive(&mut self) -> Result<RawMessage, NetworkError> {
+        loop {
+            let message = RawMessage::read(&mut self.stream, self.network.magic())?;
+            match message.command.as_str() {
+                "ping" => {
+                    let pong = RawMessage::new("pong", message.payload);
+                    self.send(&pong)?;
+                }
+                "feefilter" => self.fee_filter = parse_feefilter(&message.payload),
+                _ => return Ok(message),
+            }
+        }
+    }
+
+    /// Sends a ping and returns the messages received before its pong. Peers answer in
+    /// order, so everything requested before the ping has arrived once this returns.
+    pub fn drain(&mut self) -> Result<Vec<RawMessage>, NetworkError> {
+        let nonce: u64 = rand::random();
+        self.send(&nonce_message("ping", nonce))?;
+        let mut messages = vec![];
+        loop {
+            let message = self.receive()?;
+            if message.command == "pong" && message.payload == nonce.to_le_bytes() {
+                return Ok(messages);
+            }
+            messages.push(message);
+        }
+    }
+}
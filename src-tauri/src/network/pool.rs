@@ -0,0 +1,449 @@
+//! Configured server list with latency and error scoring, failover and optional cross-checking
+//! of critical responses between two servers.
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use super::connection::{ConnectionManager, ElectrumConnection};
+use super::error::NetworkError;
+use super::proxy::is_onion;
+use crate::store::schema::Record;
+use crate::store::storage::{wallet_db, SETTINGS_TREE};
+
+/// Settings key of the server list, a `Vec<String>` record
+pub const SERVERS_KEY: &str = "servers";
+/// Settings key of the cross-check switch, a `bool` record
+pub const CROSS_CHECK_KEY: &str = "cross_check";
+
+/// Weight of the newest ping in the latency average
+const LATENCY_SMOOTHING: f64 = 0.3;
+/// Score added per consecutive failure, and the latency assumed for unmeasured servers
+const ERROR_PENALTY_MS: f64 = 1000.0;
+const MAX_SERVERS: usize = 32;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ServerStats {
+    /// Moving average of `server.ping` round trips
+    pub latency_ms: Option<f64>,
+    pub successes: u64,
+    pub errors: u64,
+    pub consecutive_errors: u32,
+    pub last_error: Option<String>,
+}
+
+impl ServerStats {
+    fn record_latency(&mut self, elapsed: Duration) {
+        let ms = elapsed.as_secs_f64() * 1000.0;
+        self.latency_ms = Some(match self.latency_ms {
+            Some(avg) => avg + LATENCY_SMOOTHING * (ms - avg),
+            None => ms,
+        });
+    }
+
+    /// Lower is better.
+    pub fn score(&self) -> f64 {
+        self.latency_ms.unwrap_or(ERROR_PENALTY_MS)
+            + ERROR_PENALTY_MS * self.consecutive_errors as f64
+    }
+}
+
+/// Held in tauri state next to the `ConnectionManager`, which owns the actual connections.
+/// Clones share the servers and their stats, background services keep one.
+#[derive(Clone, Default)]
+pub struct ServerPool {
+    servers: Arc<Mutex<Vec<String>>>,
+    stats: Arc<Mutex<HashMap<String, ServerStats>>>,
+    cross_check: Arc<AtomicBool>,
+}
+
+fn settings_tree() -> Result<sled::Tree, sled::Error> {
+    wallet_db()?.open_tree(SETTINGS_TREE)
+}
+
+/// Urls advertised in a `server.peers.subscribe` response, ssl preferred over tcp. Onion
+/// peers are only kept when they can be reached through a proxy.
+pub fn peer_urls(peers: &Value, include_onion: bool) -> Vec<String> {
+    let mut urls = vec![];
+    for peer in peers.as_array().cloned().unwrap_or_default() {
+        let host = match peer[1].as_str() {
+            Some(host) if include_onion || !is_onion(host) => host.to_string(),
+            _ => continue,
+        };
+        let features: Vec<&str> = match peer[2].as_array() {
+            Some(features) => features.iter().filter_map(|f| f.as_str()).collect(),
+            None => continue,
+        };
+        let port = |prefix: char| {
+            features
+                .iter()
+                .find(|f| f.starts_with(prefix) && f.len() > 1)
+                .map(|f| f[1..].to_string())
+        };
+        if let Some(port) = port('s') {
+            urls.push(format!("ssl://{}:{}", host, port));
+        } else if let Some(port) = port('t') {
+            urls.push(format!("tcp://{}:{}", host, port));
+        }
+    }
+    urls
+}
+
+/// Compares two `listunspent` responses ignoring the order of the entries. Only outpoint,
+/// value and token are compared, a server that saw the block a moment later reports another
+/// height for the same coins.
+pub fn same_utxo_set(a: &str, b: &str) -> bool {
+    let sorted = |utxos: &str| -> Option<Vec<String>> {
+        let utxos: Value = serde_json::from_str(utxos).ok()?;
+        let mut entries: Vec<String> = utxos
+            .as_array()?
+            .iter()
+            .map(|u| json!([u["tx_hash"], u["tx_pos"], u["value"], u["token_data"]]).to_string())
+            .collect();
+        entries.sort();
+        Some(entries)
+    };
+    match (sorted(a), sorted(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// [same_utxo_set] for the `listunspent` responses of a batch, address by address.
+pub fn same_utxo_sets(a: &[String], b: &[String]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same_utxo_set(a, b))
+}
+
+impl ServerPool {
+    /// Pool with the server list saved by `set_servers` and the saved cross-check setting.
+    pub fn load() -> Self {
+        let pool = ServerPool::default();
+        let tree = settings_tree().ok();
+        let saved = tree
+            .as_ref()
+            .and_then(|tree| tree.get(SERVERS_KEY).ok().flatten())
+            .and_then(|bytes| Vec::<String>::decode(&bytes));
+        if let Some(servers) = saved {
+            *pool.servers.lock().unwrap() = servers;
+        }
+        let cross_check = tree
+            .and_then(|tree| tree.get(CROSS_CHECK_KEY).ok().flatten())
+            .and_then(|bytes| bool::decode(&bytes));
+        if let Some(enabled) = cross_check {
+            pool.cross_check.store(enabled, Ordering::SeqCst);
+        }
+        pool
+    }
+
+    pub fn servers(&self) -> Vec<String> {
+        self.servers.lock().unwrap().clone()
+    }
+
+    /// Replaces and saves the server list.
+    pub fn set_servers(&self, servers: Vec<String>) -> Result<(), NetworkError> {
+        let mut unique: Vec<String> = vec![];
+        for url in servers {
+            if !unique.contains(&url) && unique.len() < MAX_SERVERS {
+                unique.push(url);
+            }
+        }
+        let saved = unique.encode();
+        match settings_tree().and_then(|tree| tree.insert(SERVERS_KEY, saved)) {
+            Ok(_) => {
+                *self.servers.lock().unwrap() = unique;
+                Ok(())
+            }
+            Err(e) => Err(NetworkError::Request {
+                method: "set_servers".to_string(),
+                reason: e.to_string(),
+            }),
+        }
+    }
+
+    /// Turns cross-checking on or off and saves the setting.
+    pub fn set_cross_check(&self, enabled: bool) -> Result<(), NetworkError> {
+        let saved = enabled.encode();
+        match settings_tree().and_then(|tree| tree.insert(CROSS_CHECK_KEY, saved)) {
+            Ok(_) => {
+                self.cross_check.store(enabled, Ordering::SeqCst);
+                Ok(())
+            }
+            Err(e) => Err(NetworkError::Request {
+                method: "set_cross_check".to_string(),
+                reason: e.to_string(),
+            }),
+        }
+    }
+
+    pub fn cross_check(&self) -> bool {
+        self.cross_check.load(Ordering::SeqCst)
+    }
+
+    fn record_success(&self, url: &str) {
+        let mut stats = self.stats.lock().unwrap();
+        let stats = stats.entry(url.to_string()).or_default();
+        stats.successes += 1;
+        stats.consecutive_errors = 0;
+    }
+
+    fn record_failure(&self, url: &str, e: &NetworkError) {
+        let mut stats = self.stats.lock().unwrap();
+        let stats = stats.entry(url.to_string()).or_default();
+        stats.errors += 1;
+        stats.consecutive_errors += 1;
+        stats.last_error = Some(e.to_string());
+    }
+
+    /// Servers best first. `preferred`, the url selected in the UI, is tried first when set.
+    pub fn ranked(&self, preferred: &str) -> Vec<String> {
+        let stats = self.stats.lock().unwrap();
+        let mut servers = self.servers();
+        servers.retain(|url| url != preferred);
+        servers.sort_by(|a, b| {
+            let score = |url: &String| {
+                stats
+                    .get(url)
+                    .map(|s| s.score())
+                    .unwrap_or(ERROR_PENALTY_MS)
+            };
+            score(a)
+                .partial_cmp(&score(b))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        if !preferred.is_empty() {
+            servers.insert(0, preferred.to_string());
+        }
+        servers
+    }
+
+    /// Pings every server and updates its latency.
+    pub async fn check_health(&self, connections: &ConnectionManager) {
+        for url in self.servers() {
+            let connection = connections.get(&url);
+            let start = Instant::now();
+            match connection.call("server.ping", vec![]).await {
+                Ok(_) => {
+                    self.record_success(&url);
+                    if let Some(stats) = self.stats.lock().unwrap().get_mut(&url) {
+                        stats.record_latency(start.elapsed());
+                    }
+                }
+                Err(e) => self.record_failure(&url, &e),
+            }
+        }
+    }
+
+    /// Runs `f` on the best server, moving down the ranking while it fails on the transport.
+    /// An error answered by a server is returned as is.
+    pub async fn with_failover<T, F, Fut>(
+        &self,
+        connections: &ConnectionManager,
+        preferred: &str,
+        f: F,
+    ) -> Result<T, NetworkError>
+    where
+        F: Fn(Arc<ElectrumConnection>) -> Fut,
+        Fut: Future<Output = Result<T, NetworkError>>,
+    {
+        let mut last_error = NetworkError::NoServers;
+        for url in self.ranked(preferred) {
+            match f(connections.get(&url)).await {
+                Ok(res) => {
+                    self.record_success(&url);
+                    return Ok(res);
+                }
+                // an answer from the server, asking another one is not a retry
+                Err(e) if !e.is_transport() => return Err(e),
+                Err(e) => {
+                    log::warn!("server {} failed: {}", url, e);
+                    self.record_failure(&url, &e);
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Like `with_failover`, but when cross-checking is on the answer must also come from a
+    /// second server and `same` must accept both. An answer no second server could confirm
+    /// is [NetworkError::NotCrossChecked].
+    pub async fn cross_checked<T, F, Fut, S>(
+        &self,
+        connections: &ConnectionManager,
+        preferred: &str,
+        f: F,
+        same: S,
+    ) -> Result<T, NetworkError>
+    where
+        F: Fn(Arc<ElectrumConnection>) -> Fut,
+        Fut: Future<Output = Result<T, NetworkError>>,
+        S: Fn(&T, &T) -> bool,
+    {
+        if !self.cross_check() {
+            return self.with_failover(connections, preferred, f).await;
+        }
+        let mut answered: Option<(String, T)> = None;
+        let mut last_error = NetworkError::NoServers;
+        for url in self.ranked(preferred) {
+            let res = match f(connections.get(&url)).await {
+                Ok(res) => res,
+                Err(e) if !e.is_transport() => return Err(e),
+                Err(e) => {
+                    self.record_failure(&url, &e);
+                    last_error = e;
+                    continue;
+                }
+            };
+            self.record_success(&url);
+            match answered {
+                None => answered = Some((url, res)),
+                Some((first, first_res)) => {
+                    return match same(&first_res, &res) {
+                        true => Ok(first_res),
+                        false => Err(NetworkError::ServersDisagree { first, second: url }),
+                    }
+                }
+            }
+        }
+        match answered {
+            Some((server, _)) => Err(NetworkError::NotCrossChecked { server }),
+            None => Err(last_error),
+        }
+    }
+
+    /// Asks the best server for its peers and adds the new ones to the list.
+    pub async fn discover(
+        &self,
+        connections: &ConnectionManager,
+        preferred: &str,
+    ) -> Result<Vec<String>, NetworkError> {
+        let peers = self
+            .with_failover(connections, preferred, |connection| async move {
+                connection.call("server.peers.subscribe", vec![]).await
+            })
+            .await?;
+        let mut servers = self.servers();
+        let mut added = vec![];
+        for url in peer_urls(&peers, connections.proxy()?.is_some()) {
+            if servers.len() >= MAX_SERVERS {
+                break;
+            }
+            if !servers.contains(&url) {
+                servers.push(url.clone());
+                added.push(url);
+            }
+        }
+        if !added.is_empty() {
+            self.set_servers(servers)?;
+        }
+        Ok(added)
+    }
+
+    pub fn to_json(&self) -> Value {
+        let ranked = self.ranked("");
+        let stats = self.stats.lock().unwrap();
+        let servers: Vec<Value> = ranked
+            .iter()
+            .map(|url| {
+                let s = stats.get(url).cloned().unwrap_or_default();
+                json!({
+                    "url": url,
+                    "latencyMs": s.latency_ms,
+                    "successes": s.successes,
+                    "errors": s.errors,
+                    "lastError": s.last_error,
+                    "score": s.score(),
+                })
+            })
+            .collect();
+        json!({"servers": servers, "crossCheck": self.cross_check()})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::mock::MockElectrum;
+
+    #[test]
+    fn peers() {
+        let peers = json!([
+            ["1.2.3.4", "a.example", ["v1.4.2", "s50002", "t50001"]],
+            ["5.6.7.8", "b.example", ["v1.4.2", "t50001"]],
+            ["onion", "abc.onion", ["v1.4.2", "t50001"]],
+            ["9.9.9.9", "c.example", ["v1.4.2"]],
+        ]);
+        assert_eq!(
+            peer_urls(&peers, false),
+            vec!["ssl://a.example:50002", "tcp://b.example:50001"]
+        );
+        assert_eq!(peer_urls(&peers, true)[2], "tcp://abc.onion:50001");
+    }
+
+    #[test]
+    fn ranking() {
+        let pool = ServerPool::default();
+        *pool.servers.lock().unwrap() =
+            vec!["tcp://slow:1".to_string(), "tcp://fast:1".to_string()];
+        pool.stats
+            .lock()
+            .unwrap()
+            .entry("tcp://fast:1".to_string())
+            .or_default()
+            .record_latency(Duration::from_millis(50));
+        pool.stats
+            .lock()
+            .unwrap()
+            .entry("tcp://slow:1".to_string())
+            .or_default()
+            .record_latency(Duration::from_millis(400));
+        assert_eq!(pool.ranked(""), vec!["tcp://fast:1", "tcp://slow:1"]);
+        pool.record_failure("tcp://fast:1", &NetworkError::NoServers);
+        assert_eq!(pool.ranked(""), vec!["tcp://slow:1", "tcp://fast:1"]);
+        assert_eq!(pool.ranked("tcp://other:1")[0], "tcp://other:1");
+    }
+
+    #[test]
+    fn utxo_sets() {
+        let first = json!([
+            {"tx_hash": "aa", "tx_pos": 0, "value": 1000, "height": 100},
+            {"tx_hash": "bb", "tx_pos": 1, "value": 800, "height": 0},
+        ]);
+        // same coins in another order, the mempool one confirmed meanwhile
+        let later = json!([
+            {"tx_hash": "bb", "tx_pos": 1, "value": 800, "height": 101},
+            {"tx_hash": "aa", "tx_pos": 0, "value": 1000, "height": 100},
+        ]);
+        let other_value = json!([
+            {"tx_hash": "aa", "tx_pos": 0, "value": 1000, "height": 100},
+            {"tx_hash": "bb", "tx_pos": 1, "value": 900, "height": 0},
+        ]);
+        assert!(same_utxo_set(&first.to_string(), &later.to_string()));
+        assert!(!same_utxo_set(&first.to_string(), &other_value.to_string()));
+        assert!(!same_utxo_set(&first.to_string(), "not json"));
+    }
+
+    #[tokio::test]
+    async fn one_answer_is_not_cross_checked() {
+        let mock = MockElectrum::start();
+        let pool = ServerPool::default();
+        *pool.servers.lock().unwrap() = vec![mock.url(), "tcp://127.0.0.1:1".to_string()];
+        pool.cross_check.store(true, Ordering::SeqCst);
+        let connections = ConnectionManager::default();
+        let res = pool
+            .cross_checked(
+                &connections,
+                "",
+                |connection| async move { connection.call("server.ping", vec![]).await },
+                |a: &Value, b: &Value| a == b,
+            )
+            .await;
+        assert_eq!(
+            res,
+            Err(NetworkError::NotCrossChecked { server: mock.url() })
+        );
+    }
+}
@@ -0,0 +1,164 @@
+//! SOCKS5 proxy, usually a local Tor daemon, used by every transport. With isolation each
+//! wallet sends its own credentials so Tor puts its streams on separate circuits.
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use electrum_client::Socks5Config;
+use socks::Socks5Stream;
+
+use super::error::NetworkError;
+use crate::encoding::{write_optional_str, write_str, Reader};
+use crate::store::schema::Record;
+use crate::store::storage::{wallet_db, SETTINGS_TREE};
+
+/// Settings key of the proxy, a `ProxyConfig` record
+pub const PROXY_KEY: &str = "proxy";
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ProxyConfig {
+    /// `host:port` of the SOCKS5 server, `127.0.0.1:9050` for Tor
+    pub address: String,
+    /// Stream isolation key, usually the wallet name
+    pub isolation: Option<String>,
+}
+
+impl ProxyConfig {
+    /// `user:password` pair sent to the proxy, Tor isolates streams on it.
+    fn credentials(&self) -> Option<(&str, &str)> {
+        self.isolation
+            .as_deref()
+            .map(|isolation| (isolation, isolation))
+    }
+
+    pub fn electrum_config(&self) -> Socks5Config {
+        match self.credentials() {
+            Some((user, password)) => Socks5Config::with_credentials(
+                self.address.as_str(),
+                user.into(),
+                password.into(),
+            ),
+            None => Socks5Config::new(self.address.as_str()),
+        }
+    }
+
+    /// Saved proxy, `None` when traffic goes direct. A setting that can't be read is an
+    /// error, never direct traffic.
+    pub fn load() -> Result<Option<ProxyConfig>, NetworkError> {
+        let load_err = |reason: String| NetworkError::Request {
+            method: "load proxy".to_string(),
+            reason,
+        };
+        let saved = wallet_db()
+            .and_then(|db| db.open_tree(SETTINGS_TREE))
+            .and_then(|tree| tree.get(PROXY_KEY));
+        let saved = match saved {
+            Ok(Some(saved)) => saved,
+            Ok(None) => return Ok(None),
+            Err(e) => return Err(load_err(e.to_string())),
+        };
+        match ProxyConfig::decode(&saved) {
+            Some(proxy) => Ok(Some(proxy)),
+            None => Err(load_err("malformed proxy setting".to_string())),
+        }
+    }
+
+    /// Saves `proxy`, or removes the setting when `None`.
+    pub fn save(proxy: Option<&ProxyConfig>) -> Result<(), NetworkError> {
+        let save_err = |e: sled::Error| NetworkError::Request {
+            method: "save proxy".to_string(),
+            reason: e.to_string(),
+        };
+        let db = wallet_db().map_err(save_err)?;
+        let tree = db.open_tree(SETTINGS_TREE).map_err(save_err)?;
+        let res = match proxy {
+            Some(proxy) => tree.insert(PROXY_KEY, proxy.encode()),
+            None => tree.remove(PROXY_KEY),
+        };
+        res.map(|_| ()).map_err(save_err)
+    }
+}
+
+impl Record for ProxyConfig {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        write_str(&mut out, &self.address);
+        write_optional_str(&mut out, self.isolation.as_deref());
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader::new(bytes);
+        Some(ProxyConfig {
+            address: reader.string()?,
+            isolation: reader.optional_string()?,
+        })
+    }
+}
+
+pub fn is_onion(host: &str) -> bool {
+    host.ends_with(".onion")
+}
+
+/// Tcp stream to `host:port`, through the proxy when one is set. Onion hosts are resolved by
+/// the proxy and refused without one.
+pub fn connect_stream(
+    host: &str,
+    port: u16,
+    timeout: Duration,
+    proxy: Option<&ProxyConfig>,
+) -> Result<TcpStream, NetworkError> {
+    let connect_err = |reason: String| NetworkError::Connect {
+        url: format!("{}:{}", host, port),
+        reason,
+    };
+    let stream = match proxy {
+        Some(proxy) => {
+            // the socks crate has no connect timeout, the handshake runs on its own thread
+            let (sender, receiver) = mpsc::channel();
+            let (target, socks) = ((host.to_string(), port), proxy.clone());
+            thread::spawn(move || {
+                let target = (target.0.as_str(), target.1);
+                let stream = match socks.credentials() {
+                    Some((user, password)) => Socks5Stream::connect_with_password(
+                        socks.address.as_str(),
+                        target,
+                        user,
+                        password,
+                    ),
+                    None => Socks5Stream::connect(socks.address.as_str(), target),
+                };
+                _ = sender.send(stream);
+            });
+            match receiver.recv_timeout(timeout) {
+                Ok(Ok(stream)) => stream.into_inner(),
+                Ok(Err(e)) => return Err(connect_err(format!("proxy {}: {}", proxy.address, e))),
+                Err(_) => {
+                    return Err(connect_err(format!(
+                        "proxy {} timed out after {}s",
+                        proxy.address,
+                        timeout.as_secs()
+                    )))
+                }
+            }
+        }
+        None if is_onion(host) => {
+            return Err(connect_err("onion servers need a proxy".to_string()));
+        }
+        None => {
+            let addr = match (host, port).to_socket_addrs().map(|mut addrs| addrs.next()) {
+                Ok(Some(addr)) => addr,
+                Ok(None) => return Err(connect_err("no address".to_string())),
+                Err(e) => return Err(connect_err(e.to_string())),
+            };
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => stream,
+                Err(e) => return Err(connect_err(e.to_string())),
+            }
+        }
+    };
+    _ = stream.set_read_timeout(Some(timeout));
+    _ = stream.set_write_timeout(Some(timeout));
+    Ok(stream)
+}
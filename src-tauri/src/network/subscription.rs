@@ -0,0 +1,414 @@
+//! Background service keeping address and header subscriptions alive and pushing changes to the UI.
+//! Unconfirmed wallet transactions are watched for double-spend proofs when the server has
+//! them. electrum-client drops `dsproof` notifications, so proofs are polled with
+//! `blockchain.transaction.dsproof.get` on every pass instead of subscribed to. Only verified
+//! proofs put a payment at risk, unverifiable ones are reported as warnings and invalid ones
+//! are ignored.
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use super::backend::BackendSelection;
+use super::connection::ElectrumConnection;
+use super::electrum::{get_address_history, get_dsproofs_batch};
+use super::task::ServiceTask;
+use crate::address::address_to_script;
+use crate::coins::utxo::{get_utxos_for_address, Utxo};
+use crate::error::WalletError;
+use crate::store::history::{set_tip, sync_history};
+use crate::store::storage::sync_address_utxos;
+use crate::transaction::dsproof::{check_dsproof, DoubleSpendAlert, DsProofStatus};
+
+pub static EVENT_BALANCE_CHANGED: &str = "balance-changed";
+pub static EVENT_NEW_TRANSACTION: &str = "new-transaction";
+pub static EVENT_NEW_BLOCK: &str = "new-block";
+pub static EVENT_DOUBLE_SPEND: &str = "double-spend";
+pub static EVENT_HISTORY_CHANGED: &str = "history-changed";
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, Default)]
+struct WatchedAddress {
+    status: Option<String>,
+    txids: BTreeSet<String>,
+    /// Mempool txids, the ones watched for double spends
+    unconfirmed: BTreeSet<String>,
+}
+
+/// Held in tauri state. `start` spawns the polling task, `stop` ends it after the current pass.
+#[derive(Clone, Default)]
+pub struct SubscriptionService {
+    watched: Arc<Mutex<BTreeMap<String, WatchedAddress>>>,
+    /// `(address, alert)` keyed by the wallet txid the alert puts at risk
+    alerts: Arc<Mutex<BTreeMap<String, (String, DoubleSpendAlert)>>>,
+    /// Ids of the proofs that failed verification, not checked again
+    invalid_proofs: Arc<Mutex<BTreeSet<String>>>,
+    task: ServiceTask,
+}
+
+/// Txids in the history of `address`, and the unconfirmed ones among them.
+async fn history_txids(
+    address: &str,
+    connection: &ElectrumConnection,
+) -> Result<(BTreeSet<String>, BTreeSet<String>), WalletError> {
+    let history: Value = serde_json::from_str(&get_address_history(address, connection).await?)?;
+    let mut txids = BTreeSet::new();
+    let mut unconfirmed = BTreeSet::new();
+    for tx in history.as_array().cloned().unwrap_or_default() {
+        if let Some(txid) = tx["tx_hash"].as_str() {
+            if tx["height"].as_i64().unwrap_or(0) <= 0 {
+                unconfirmed.insert(txid.to_string());
+            }
+            txids.insert(txid.to_string());
+        }
+    }
+    Ok((txids, unconfirmed))
+}
+
+fn alert_json(address: &str, txid: &str, alert: &DoubleSpendAlert) -> Value {
+    let mut json = alert.to_json();
+    json["address"] = json!(address);
+    json["paymentTxid"] = json!(txid);
+    json
+}
+
+impl SubscriptionService {
+    pub fn watch(&self, addresses: Vec<String>) {
+        let mut watched = self.watched.lock().unwrap();
+        for address in addresses {
+            watched.entry(address).or_default();
+        }
+    }
+
+    pub fn unwatch(&self, address: &str) {
+        self.watched.lock().unwrap().remove(address);
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.task.is_running()
+    }
+
+    pub fn stop(&self) {
+        self.task.stop();
+    }
+
+    /// Spawns the service on the wallet database `db` unless it is already running, a running
+    /// service moves over to `connection` on its next pass. `emit` receives an event name and
+    /// payload. Changed utxos are fetched from the backend selected in `backends`.
+    pub fn start<E>(
+        &self,
+        connection: Arc<ElectrumConnection>,
+        backends: BackendSelection,
+        db: sled::Db,
+        emit: E,
+    ) where
+        E: Fn(&str, Value) + Send + Sync + 'static,
+    {
+        let id = match self.task.start(connection) {
+            Some(id) => id,
+            None => return,
+        };
+        let service = self.clone();
+        tauri::async_runtime::spawn(async move {
+            service.run(id, &backends, &db, emit).await;
+        });
+    }
+
+    fn addresses(&self) -> Vec<String> {
+        self.watched.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Double-spend alerts on unconfirmed wallet transactions.
+    pub fn double_spend_alerts(&self) -> Vec<Value> {
+        self.alerts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(txid, (address, alert))| alert_json(address, txid, alert))
+            .collect()
+    }
+
+    async fn dsproof_capable(&self, connection: &ElectrumConnection) -> Result<bool, WalletError> {
+        Ok(connection.capabilities().await?.dsproof)
+    }
+
+    fn emit_balance<E>(&self, db: &sled::Db, address: &str, emit: &E) -> Result<(), WalletError>
+    where
+        E: Fn(&str, Value),
+    {
+        let utxos = get_utxos_for_address(db, address)?;
+        let balance: u64 = utxos.non_token.iter().map(|u| u.0.output.value).sum();
+        let token_balance: u64 = utxos.with_token.iter().map(|u| u.0.output.value).sum();
+        let at_risk: u64 = {
+            let alerts = self.alerts.lock().unwrap();
+            let at_risk = |utxo: &Utxo| match alerts.get(&utxo.outpoint.txid.to_string()) {
+                Some((_, alert)) => alert.status == DsProofStatus::Verified,
+                None => false,
+            };
+            utxos
+                .non_token
+                .iter()
+                .map(|u| &u.0)
+                .chain(utxos.with_token.iter().map(|u| &u.0))
+                .filter(|utxo| at_risk(utxo))
+                .map(|utxo| utxo.output.value)
+                .sum()
+        };
+        emit(
+            EVENT_BALANCE_CHANGED,
+            json!({"address":address,"balance":balance,"tokenUtxoBalance":token_balance,"atRisk":at_risk}),
+        );
+        Ok(())
+    }
+
+    /// Starts and stops double-spend watching as transactions enter and leave the mempool.
+    fn update_unconfirmed<E>(&self, address: &str, unconfirmed: BTreeSet<String>, emit: &E)
+    where
+        E: Fn(&str, Value),
+    {
+        let known = self
+            .watched
+            .lock()
+            .unwrap()
+            .get(address)
+            .map(|w| w.unconfirmed.clone())
+            .unwrap_or_default();
+        for txid in known.difference(&unconfirmed) {
+            if let Some((_, alert)) = self.alerts.lock().unwrap().remove(txid) {
+                emit(
+                    EVENT_DOUBLE_SPEND,
+                    json!({"address":address,"paymentTxid":txid,"dspid":alert.dspid,"resolved":true}),
+                );
+            }
+        }
+        if let Some(watched) = self.watched.lock().unwrap().get_mut(address) {
+            watched.unconfirmed = unconfirmed;
+        }
+    }
+
+    /// Asks for proofs on every unconfirmed transaction without an alert yet.
+    async fn check_double_spends<E>(
+        &self,
+        db: &sled::Db,
+        connection: &ElectrumConnection,
+        emit: &E,
+    ) -> Result<(), WalletError>
+    where
+        E: Fn(&str, Value),
+    {
+        let pending: Vec<(String, String)> = {
+            let watched = self.watched.lock().unwrap();
+            let alerts = self.alerts.lock().unwrap();
+            watched
+                .iter()
+                .flat_map(|(address, w)| w.unconfirmed.iter().map(move |txid| (address, txid)))
+                .filter(|(_, txid)| !alerts.contains_key(*txid))
+                .map(|(address, txid)| (address.clone(), txid.clone()))
+                .collect()
+        };
+        if pending.is_empty() || !self.dsproof_capable(connection).await? {
+            return Ok(());
+        }
+        let txids: Vec<String> = pending.iter().map(|(_, txid)| txid.clone()).collect();
+        let proofs = get_dsproofs_batch(&txids, connection).await?;
+        let mut alerted = BTreeSet::new();
+        for ((address, txid), proof) in pending.into_iter().zip(proofs) {
+            let proof = match proof {
+                Some(proof) => proof,
+                None => continue,
+            };
+            let dspid = proof["dspid"].as_str().unwrap_or_default().to_string();
+            if self.invalid_proofs.lock().unwrap().contains(&dspid) {
+                continue;
+            }
+            let alert = match check_dsproof(db, &proof, connection).await {
+                Ok(alert) => alert,
+                Err(e) => {
+                    log::warn!("dsproof for {} not checked {}", txid, e);
+                    continue;
+                }
+            };
+            log::info!("double spend of {} {:?}", alert.outpoint, alert.status);
+            if let DsProofStatus::Invalid(_) = alert.status {
+                self.invalid_proofs.lock().unwrap().insert(alert.dspid);
+                continue;
+            }
+            emit(EVENT_DOUBLE_SPEND, alert_json(&address, &txid, &alert));
+            let verified = alert.status == DsProofStatus::Verified;
+            self.alerts
+                .lock()
+                .unwrap()
+                .insert(txid, (address.clone(), alert));
+            if verified {
+                alerted.insert(address);
+            }
+        }
+        for address in alerted {
+            self.emit_balance(db, &address, emit)?;
+        }
+        Ok(())
+    }
+
+    /// Subscribes every watched address, returns the connection generation subscribed on.
+    async fn subscribe_all(&self, connection: &ElectrumConnection) -> Result<u64, WalletError> {
+        connection
+            .with_client(|client| client.headers_subscribe())
+            .await?;
+        let generation = connection.generation();
+        for address in self.addresses() {
+            let script = address_to_script(&address)?;
+            let status = connection
+                .with_client(move |client| client.script_subscribe(&script))
+                .await?;
+            let (txids, unconfirmed) = history_txids(&address, connection).await?;
+            if let Some(watched) = self.watched.lock().unwrap().get_mut(&address) {
+                if status.is_some() {
+                    watched.status = status;
+                }
+                watched.txids = txids;
+            }
+            let no_events = |_: &str, _: Value| {};
+            self.update_unconfirmed(&address, unconfirmed, &no_events);
+        }
+        Ok(generation)
+    }
+
+    async fn on_status_change<E>(
+        &self,
+        backends: &BackendSelection,
+        db: &sled::Db,
+        address: &str,
+        connection: &ElectrumConnection,
+        emit: &E,
+    ) -> Result<(), WalletError>
+    where
+        E: Fn(&str, Value),
+    {
+        let (txids, unconfirmed) = history_txids(address, connection).await?;
+        let known = self
+            .watched
+            .lock()
+            .unwrap()
+            .get(address)
+            .map(|w| w.txids.clone())
+            .unwrap_or_default();
+        let new_txids: Vec<&String> = txids.difference(&known).collect();
+        if !new_txids.is_empty() {
+            emit(
+                EVENT_NEW_TRANSACTION,
+                json!({"address":address,"txids":new_txids}),
+            );
+        }
+        if let Some(watched) = self.watched.lock().unwrap().get_mut(address) {
+            watched.txids = txids.clone();
+        }
+        self.update_unconfirmed(address, unconfirmed, emit);
+        // before the utxos, pending spends settle on the history of the address
+        match sync_history(db, &[address.to_string()], &self.addresses(), connection).await {
+            Ok(changed) if !changed.is_empty() => {
+                emit(EVENT_HISTORY_CHANGED, json!({"txids":changed}));
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!("history not synced {}", e),
+        }
+        let backend = backends.backend(connection.url());
+        if sync_address_utxos(db, address, backend.as_ref(), connection).await? {
+            self.emit_balance(db, address, emit)?;
+        }
+        Ok(())
+    }
+
+    async fn poll<E>(
+        &self,
+        backends: &BackendSelection,
+        db: &sled::Db,
+        connection: &ElectrumConnection,
+        emit: &E,
+    ) -> Result<(), WalletError>
+    where
+        E: Fn(&str, Value),
+    {
+        // reading a response is what drains pending notifications from the socket
+        connection.call("server.ping", vec![]).await?;
+        while let Some(header) = connection
+            .with_client(|client| client.headers_pop())
+            .await?
+        {
+            // confirmations are counted from here
+            if let Err(e) = set_tip(db, header.height as u32) {
+                log::warn!("history tip not saved {}", e);
+            }
+            emit(
+                EVENT_NEW_BLOCK,
+                json!({"height":header.height,"header":hex::encode(&header.header)}),
+            );
+        }
+        for address in self.addresses() {
+            let script = address_to_script(&address)?;
+            let status = match connection
+                .with_client(move |client| client.script_pop(&script))
+                .await?
+            {
+                Some(status) => status,
+                None => continue,
+            };
+            let changed = match self.watched.lock().unwrap().get_mut(&address) {
+                Some(watched) if watched.status.as_ref() != Some(&status) => {
+                    watched.status = Some(status);
+                    true
+                }
+                _ => false,
+            };
+            if changed {
+                self.on_status_change(backends, db, &address, connection, emit)
+                    .await?;
+            }
+        }
+        self.check_double_spends(db, connection, emit).await
+    }
+
+    async fn run<E>(&self, id: u64, backends: &BackendSelection, db: &sled::Db, emit: E)
+    where
+        E: Fn(&str, Value),
+    {
+        // connection, generation and address count the subscriptions were made for
+        let mut subscribed: Option<(Arc<ElectrumConnection>, u64, usize)> = None;
+        while self.task.is_current(id) {
+            let connection = match self.task.connection() {
+                Some(connection) => connection,
+                None => break,
+            };
+            // new addresses, another server or a reconnect need a fresh set of subscriptions
+            let addresses = self.addresses().len();
+            let current = match &subscribed {
+                Some((subscribed_on, generation, watching)) => {
+                    Arc::ptr_eq(subscribed_on, &connection)
+                        && *generation == connection.generation()
+                        && *watching == addresses
+                }
+                None => false,
+            };
+            if !current {
+                match self.subscribe_all(&connection).await {
+                    Ok(generation) => {
+                        subscribed = Some((connection.clone(), generation, addresses));
+                    }
+                    Err(e) => {
+                        log::warn!("subscription error {}", e);
+                        subscribed = None;
+                        tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+                        continue;
+                    }
+                }
+            }
+            if let Err(e) = self.poll(backends, db, &connection, &emit).await {
+                log::warn!("subscription poll error {}", e);
+                subscribed = None;
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+}
@@ -0,0 +1,82 @@
+//! Start and stop bookkeeping for the background services talking to one server. Every
+//! `start` that spawns gets a new task id and a task keeps going only while its id is the
+//! current one, so a `stop` followed by a quick `start` can't leave two tasks running. The
+//! connection can be swapped while a task runs, it is read again on every pass.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::connection::ElectrumConnection;
+
+static NEXT_TASK_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Clone, Default)]
+pub struct ServiceTask {
+    /// Id of the running task, 0 when stopped
+    current: Arc<AtomicU64>,
+    connection: Arc<Mutex<Option<Arc<ElectrumConnection>>>>,
+}
+
+impl ServiceTask {
+    pub fn is_running(&self) -> bool {
+        self.current.load(Ordering::SeqCst) != 0
+    }
+
+    /// Uses `connection` from the next pass on. Returns the id of the task to spawn, `None`
+    /// when one is already running.
+    pub fn start(&self, connection: Arc<ElectrumConnection>) -> Option<u64> {
+        *self.connection.lock().unwrap() = Some(connection);
+        let id = NEXT_TASK_ID.fetch_add(1, Ordering::SeqCst);
+        match self
+            .current
+            .compare_exchange(0, id, Ordering::SeqCst, Ordering::SeqCst)
+        {
+            Ok(_) => Some(id),
+            Err(_) => None,
+        }
+    }
+
+    /// The running task ends after its current pass.
+    pub fn stop(&self) {
+        self.current.store(0, Ordering::SeqCst);
+    }
+
+    /// Whether the task `id` should keep going.
+    pub fn is_current(&self, id: u64) -> bool {
+        self.current.load(Ordering::SeqCst) == id
+    }
+
+    pub fn connection(&self) -> Option<Arc<ElectrumConnection>> {
+        self.connection.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_task_at_a_time() {
+        let task = ServiceTask::default();
+        let first = Arc::new(ElectrumConnection::new(
+            "tcp://127.0.0.1:1",
+            Default::default(),
+        ));
+        let second = Arc::new(ElectrumConnection::new(
+            "tcp://127.0.0.1:2",
+            Default::default(),
+        ));
+
+        let id = task.start(first).unwrap();
+        assert!(task.is_current(id));
+        // already running, only the connection changes
+        assert_eq!(task.start(second.clone()), None);
+        assert!(Arc::ptr_eq(&task.connection().unwrap(), &second));
+
+        task.stop();
+        assert!(!task.is_running());
+        let restarted = task.start(second).unwrap();
+        // the old task stops even though the service runs again
+        assert!(!task.is_current(id));
+        assert!(task.is_current(restarted));
+    }
+}
@@ -0,0 +1,260 @@
+//! Certificate pinning for `ssl://` and `wss://` servers. Fulcrum servers often use self-signed
+//! certificates, so besides the system roots we support trust-on-first-use: the sha256
+//! fingerprint of the first certificate seen is stored per server and every later handshake
+//! fails unless the server presents it.
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use bitcoin_hashes::{sha256, Hash};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, ClientConnection, ServerName, StreamOwned};
+use url::Url;
+
+use super::error::NetworkError;
+use super::proxy::{connect_stream, ProxyConfig};
+use crate::store::storage::wallet_db;
+
+/// sled tree of pinned fingerprints keyed by `host:port`
+pub static CERTIFICATE_TREE: &str = "certificates";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TlsMode {
+    /// Certificate must chain to a system root and match the host name
+    SystemRoots,
+    /// Any certificate is accepted the first time, then it is pinned
+    TrustOnFirstUse,
+}
+
+impl Default for TlsMode {
+    fn default() -> Self {
+        TlsMode::SystemRoots
+    }
+}
+
+impl TlsMode {
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "system" => Ok(TlsMode::SystemRoots),
+            "pinned" => Ok(TlsMode::TrustOnFirstUse),
+            _ => Err(format!("unknown tls mode {}", name)),
+        }
+    }
+}
+
+/// `host:port` of a tls server url
+pub fn server_id(url: &str) -> Result<(String, u16), NetworkError> {
+    let invalid = |reason: &str| NetworkError::InvalidUrl {
+        url: url.to_string(),
+        reason: reason.to_string(),
+    };
+    let parsed = match Url::parse(url) {
+        Ok(parsed) => parsed,
+        Err(e) => return Err(invalid(&e.to_string())),
+    };
+    let host = match parsed.host_str() {
+        Some(host) => host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string(),
+        None => return Err(invalid("missing host")),
+    };
+    let port = match (parsed.port(), parsed.scheme()) {
+        (Some(port), _) => port,
+        (None, "ws") => 80,
+        (None, "wss") => 443,
+        (None, "ssl") => 50002,
+        _ => return Err(invalid("missing port")),
+    };
+    Ok((host, port))
+}
+
+fn certificate_tree() -> Result<sled::Tree, NetworkError> {
+    let db_err = |e: sled::Error| NetworkError::Tls {
+        server: String::new(),
+        reason: e.to_string(),
+    };
+    let db = wallet_db().map_err(db_err)?;
+    db.open_tree(CERTIFICATE_TREE).map_err(db_err)
+}
+
+/// Checks the certificate a server presents against its pin during the handshake. The first
+/// certificate seen is pinned, a different one later fails the handshake and is kept as
+/// [NetworkError::CertificateChanged] for the caller.
+pub(crate) struct PinnedCertificateVerifier {
+    /// `host:port`, the key of the pin
+    server: String,
+    tree: sled::Tree,
+    changed: Mutex<Option<NetworkError>>,
+}
+
+impl PinnedCertificateVerifier {
+    pub(crate) fn new(server: String, tree: sled::Tree) -> Self {
+        PinnedCertificateVerifier {
+            server,
+            tree,
+            changed: Mutex::new(None),
+        }
+    }
+
+    /// The pin mismatch that failed the handshake, if that is what failed it.
+    pub(crate) fn certificate_changed(&self) -> Option<NetworkError> {
+        self.changed.lock().unwrap().take()
+    }
+}
+
+impl ServerCertVerifier for PinnedCertificateVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let presented = sha256::Hash::hash(&end_entity.0).to_string();
+        let pinned = match self.tree.get(self.server.as_bytes()) {
+            Ok(pinned) => pinned,
+            Err(e) => return Err(rustls::Error::General(e.to_string())),
+        };
+        match pinned {
+            Some(pinned) if pinned.as_ref() == presented.as_bytes() => {
+                Ok(ServerCertVerified::assertion())
+            }
+            Some(pinned) => {
+                *self.changed.lock().unwrap() = Some(NetworkError::CertificateChanged {
+                    server: self.server.clone(),
+                    pinned: String::from_utf8_lossy(&pinned).to_string(),
+                    presented,
+                });
+                Err(rustls::Error::General(format!(
+                    "certificate of {} changed",
+                    self.server
+                )))
+            }
+            None => {
+                let pinned = self
+                    .tree
+                    .insert(self.server.as_bytes(), presented.as_bytes())
+                    .and_then(|_| self.tree.flush());
+                match pinned {
+                    Ok(_) => Ok(ServerCertVerified::assertion()),
+                    Err(e) => Err(rustls::Error::General(e.to_string())),
+                }
+            }
+        }
+    }
+}
+
+/// Client config for `url` pinning the server certificate, with the verifier to ask why a
+/// handshake failed.
+pub(crate) fn pinned_certificate_config(
+    url: &str,
+) -> Result<(Arc<ClientConfig>, Arc<PinnedCertificateVerifier>), NetworkError> {
+    let (host, port) = server_id(url)?;
+    let verifier = Arc::new(PinnedCertificateVerifier::new(
+        format!("{}:{}", host, port),
+        certificate_tree()?,
+    ));
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(verifier.clone())
+        .with_no_client_auth();
+    Ok((Arc::new(config), verifier))
+}
+
+/// Tls stream to the server of `url` with its certificate pinned, fails with
+/// [NetworkError::CertificateChanged] when it no longer matches the pin.
+pub fn connect_pinned(
+    url: &str,
+    timeout: Duration,
+    proxy: Option<&ProxyConfig>,
+) -> Result<StreamOwned<ClientConnection, TcpStream>, NetworkError> {
+    let (host, port) = server_id(url)?;
+    let tls_err = |reason: String| NetworkError::Tls {
+        server: format!("{}:{}", host, port),
+        reason,
+    };
+    let server_name = match ServerName::try_from(host.as_str()) {
+        Ok(name) => name,
+        Err(e) => return Err(tls_err(e.to_string())),
+    };
+    let (config, verifier) = pinned_certificate_config(url)?;
+    let mut connection = match ClientConnection::new(config, server_name) {
+        Ok(connection) => connection,
+        Err(e) => return Err(tls_err(e.to_string())),
+    };
+    let mut socket = connect_stream(&host, port, timeout, proxy)?;
+    while connection.is_handshaking() {
+        if let Err(e) = connection.complete_io(&mut socket) {
+            return Err(verifier
+                .certificate_changed()
+                .unwrap_or_else(|| tls_err(e.to_string())));
+        }
+    }
+    Ok(StreamOwned::new(connection, socket))
+}
+
+/// Removes the pin so the next connection trusts whatever certificate the server presents.
+pub fn forget_certificate(url: &str) -> Result<(), NetworkError> {
+    let (host, port) = server_id(url)?;
+    _ = certificate_tree()?.remove(format!("{}:{}", host, port).as_bytes());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn verify(verifier: &PinnedCertificateVerifier, cert: &[u8]) -> bool {
+        verifier
+            .verify_server_cert(
+                &Certificate(cert.to_vec()),
+                &[],
+                &ServerName::try_from("example.com").unwrap(),
+                &mut std::iter::empty(),
+                &[],
+                SystemTime::now(),
+            )
+            .is_ok()
+    }
+
+    fn pins() -> sled::Tree {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        db.open_tree(CERTIFICATE_TREE).unwrap()
+    }
+
+    #[test]
+    fn pinned_on_first_use() {
+        let tree = pins();
+        let verifier =
+            PinnedCertificateVerifier::new("example.com:50002".to_string(), tree.clone());
+        assert!(verify(&verifier, b"first"));
+        let fingerprint = sha256::Hash::hash(b"first").to_string();
+        assert_eq!(
+            tree.get("example.com:50002").unwrap().unwrap().as_ref(),
+            fingerprint.as_bytes()
+        );
+        assert!(verify(&verifier, b"first"));
+        assert_eq!(verifier.certificate_changed(), None);
+    }
+
+    #[test]
+    fn changed_certificate_fails_the_handshake() {
+        let tree = pins();
+        let first = PinnedCertificateVerifier::new("example.com:50002".to_string(), tree.clone());
+        assert!(verify(&first, b"first"));
+
+        let later = PinnedCertificateVerifier::new("example.com:50002".to_string(), tree);
+        assert!(!verify(&later, b"second"));
+        assert_eq!(
+            later.certificate_changed(),
+            Some(NetworkError::CertificateChanged {
+                server: "example.com:50002".to_string(),
+                pinned: sha256::Hash::hash(b"first").to_string(),
+                presented: sha256::Hash::hash(b"second").to_string(),
+            })
+        );
+    }
+}
@@ -0,0 +1,137 @@
+//! Request and notification api shared by the tcp/ssl and websocket Electrum transports.
+use bitcoinsuite_core::script::Script;
+use electrum_client::bitcoin::ScriptBuf;
+use electrum_client::{Batch, ElectrumApi, Param};
+use serde_json::Value;
+
+use super::error::NetworkError;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeaderNotification {
+    pub height: usize,
+    pub header: Vec<u8>,
+}
+
+/// Blocking client for one server. Notifications are queued while reading responses, so
+/// callers poll with a request (usually `server.ping`) before popping them.
+pub trait ElectrumTransport: Send + Sync {
+    fn raw_call(&self, method: &str, params: Vec<Param>) -> Result<Value, NetworkError>;
+    /// Sends one `method` request per entry of `params` as a single JSON-RPC batch. Results
+    /// are in the order of `params`, an error in any of them fails the batch.
+    fn batch_call(
+        &self,
+        method: &str,
+        params: Vec<Vec<Param>>,
+    ) -> Result<Vec<Value>, NetworkError>;
+    /// Subscribes to the status of `script`, `None` when it has no history.
+    fn script_subscribe(&self, script: &Script) -> Result<Option<String>, NetworkError>;
+    /// Next queued status notification for `script`.
+    fn script_pop(&self, script: &Script) -> Result<Option<String>, NetworkError>;
+    fn headers_subscribe(&self) -> Result<HeaderNotification, NetworkError>;
+    /// Next queued header notification.
+    fn headers_pop(&self) -> Result<Option<HeaderNotification>, NetworkError>;
+}
+
+/// `message` of a JSON-RPC error object, or the whole object.
+pub fn server_reason(error: &Value) -> String {
+    match error["message"].as_str() {
+        Some(message) => message.to_string(),
+        None => error.to_string(),
+    }
+}
+
+fn request_err(method: &str, e: electrum_client::Error) -> NetworkError {
+    let server_err = |error: &Value| NetworkError::Server {
+        method: method.to_string(),
+        reason: server_reason(error),
+    };
+    match e {
+        electrum_client::Error::Protocol(error) => server_err(&error),
+        // the client retries on its own, a server answer among the attempts is final
+        electrum_client::Error::AllAttemptsErrored(errors) => {
+            let answered = errors.iter().find_map(|e| match e {
+                electrum_client::Error::Protocol(error) => Some(server_err(error)),
+                _ => None,
+            });
+            match answered {
+                Some(e) => e,
+                None => NetworkError::Request {
+                    method: method.to_string(),
+                    reason: electrum_client::Error::AllAttemptsErrored(errors).to_string(),
+                },
+            }
+        }
+        e => NetworkError::Request {
+            method: method.to_string(),
+            reason: e.to_string(),
+        },
+    }
+}
+
+fn electrum_script(script: &Script) -> ScriptBuf {
+    ScriptBuf::from(script.bytecode().to_vec())
+}
+
+/// electrum-client's `Client`, and its `RawClient` over our own pinned tls stream.
+impl<C> ElectrumTransport for C
+where
+    C: ElectrumApi + Send + Sync,
+{
+    fn raw_call(&self, method: &str, params: Vec<Param>) -> Result<Value, NetworkError> {
+        match ElectrumApi::raw_call(self, method, params) {
+            Ok(res) => Ok(res),
+            Err(e) => Err(request_err(method, e)),
+        }
+    }
+
+    fn batch_call(
+        &self,
+        method: &str,
+        params: Vec<Vec<Param>>,
+    ) -> Result<Vec<Value>, NetworkError> {
+        let mut batch = Batch::default();
+        for params in params {
+            batch.raw(method.to_string(), params);
+        }
+        match ElectrumApi::batch_call(self, &batch) {
+            Ok(res) => Ok(res),
+            Err(e) => Err(request_err(method, e)),
+        }
+    }
+
+    fn script_subscribe(&self, script: &Script) -> Result<Option<String>, NetworkError> {
+        match ElectrumApi::script_subscribe(self, &electrum_script(script)) {
+            Ok(status) => Ok(status.map(|s| s.to_string())),
+            // the client keeps its own subscriptions, resubscribing is not an error for us
+            Err(electrum_client::Error::AlreadySubscribed(_)) => Ok(None),
+            Err(e) => Err(request_err("blockchain.scripthash.subscribe", e)),
+        }
+    }
+
+    fn script_pop(&self, script: &Script) -> Result<Option<String>, NetworkError> {
+        match ElectrumApi::script_pop(self, &electrum_script(script)) {
+            Ok(status) => Ok(status.map(|s| s.to_string())),
+            Err(e) => Err(request_err("blockchain.scripthash.subscribe", e)),
+        }
+    }
+
+    fn headers_subscribe(&self) -> Result<HeaderNotification, NetworkError> {
+        match ElectrumApi::block_headers_subscribe_raw(self) {
+            Ok(header) => Ok(HeaderNotification {
+                height: header.height,
+                header: header.header,
+            }),
+            Err(e) => Err(request_err("blockchain.headers.subscribe", e)),
+        }
+    }
+
+    fn headers_pop(&self) -> Result<Option<HeaderNotification>, NetworkError> {
+        match ElectrumApi::block_headers_pop_raw(self) {
+            Ok(header) => Ok(header.map(|header| HeaderNotification {
+                height: header.height,
+                header: header.header,
+            })),
+            Err(e) => Err(request_err("blockchain.headers.subscribe", e)),
+        }
+    }
+}
@@ -1,90 +1,326 @@
-use rustls::*;
-use serde_json::from_str;
-use serde_json::json;
-use serde_json::Value;
-use tungstenite::{connect, Message};
-use url::Url;
-
-// use electrum_client::Call
-#[tauri::command]
-pub fn ws_subscribe(address: &str, network_url: &str) -> Result<Value, ()> {
-    let mut subscription: Value = "".into();
-    //use ws://
-    println!("HELLO {:?} {:?}", network_url, address);
-    if let Ok(url) = Url::parse(network_url) {
-        println!("HELLO2");
-        if let Ok((mut socket, response)) = connect(url.clone()) {
-            println!("Connected to the server");
-            println!("Response HTTP code: {}", response.status());
-            println!("Response contains the following headers:");
-            for (ref header, _value) in response.headers() {
-                println!("* {}", header);
+//! Electrum Cash protocol over `ws://` and `wss://`, as served by Fulcrum.
+use std::collections::{HashMap, VecDeque};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use bitcoinsuite_core::script::Script;
+use electrum_client::Param;
+use serde_json::{json, Value};
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{Connector, Message, WebSocket};
+
+use super::electrum::scripthash_hex;
+use super::error::NetworkError;
+use super::proxy::{connect_stream, ProxyConfig};
+use super::tls::{pinned_certificate_config, server_id, TlsMode};
+use super::transport::{server_reason, ElectrumTransport, HeaderNotification};
+
+pub struct WsClient {
+    url: String,
+    timeout: Duration,
+    socket: Mutex<WebSocket<MaybeTlsStream<TcpStream>>>,
+    next_id: AtomicUsize,
+    headers: Mutex<VecDeque<HeaderNotification>>,
+    /// Queued statuses keyed by scripthash, only for subscribed scripts
+    statuses: Mutex<HashMap<String, VecDeque<Option<String>>>>,
+}
+
+/// A message pushed by the server without a request id.
+#[derive(Debug, PartialEq)]
+enum Notification {
+    Header(HeaderNotification),
+    Status {
+        scripthash: String,
+        status: Option<String>,
+    },
+}
+
+fn parse_header(value: &Value) -> Option<HeaderNotification> {
+    Some(HeaderNotification {
+        height: value["height"].as_u64()? as usize,
+        header: hex::decode(value["hex"].as_str()?).ok()?,
+    })
+}
+
+fn server_err(method: &str, error: &Value) -> NetworkError {
+    NetworkError::Server {
+        method: method.to_string(),
+        reason: server_reason(error),
+    }
+}
+
+fn parse_notification(message: &Value) -> Option<Notification> {
+    let params = message["params"].as_array()?;
+    match message["method"].as_str()? {
+        "blockchain.headers.subscribe" => {
+            Some(Notification::Header(parse_header(params.first()?)?))
+        }
+        "blockchain.scripthash.subscribe" => Some(Notification::Status {
+            scripthash: params.first()?.as_str()?.to_string(),
+            status: params.get(1).and_then(|s| s.as_str()).map(String::from),
+        }),
+        _ => None,
+    }
+}
+
+impl WsClient {
+    /// Opens the websocket. With `TlsMode::TrustOnFirstUse` the handshake checks the pinned
+    /// certificate and fails with [NetworkError::CertificateChanged] when it differs.
+    pub fn connect(
+        url: &str,
+        timeout: Duration,
+        tls: TlsMode,
+        proxy: Option<&ProxyConfig>,
+    ) -> Result<Self, NetworkError> {
+        let connect_err = |reason: String| NetworkError::Connect {
+            url: url.to_string(),
+            reason,
+        };
+        let (host, port) = server_id(url)?;
+        let stream = connect_stream(&host, port, timeout, proxy)?;
+        let (connector, verifier) = match tls {
+            TlsMode::TrustOnFirstUse if url.starts_with("wss://") => {
+                let (config, verifier) = pinned_certificate_config(url)?;
+                (Some(Connector::Rustls(config)), Some(verifier))
+            }
+            _ => (None, None),
+        };
+        let socket = match tungstenite::client_tls_with_config(url, stream, None, connector) {
+            Ok((socket, _response)) => socket,
+            Err(e) => {
+                return Err(verifier
+                    .and_then(|verifier| verifier.certificate_changed())
+                    .unwrap_or_else(|| connect_err(e.to_string())))
             }
-            let value = json!({
-                "method": "blockchain.address.subscribe",
-                "params":   [
-                    address
-                ],
-                "id":1
-            });
-
-            socket.send(Message::Text(value.to_string()));
-
-            loop {
-                let msg = socket.read();
-                match msg.as_ref() {
-                    Ok(res) => match res {
-                        Message::Text(res) => {
-                            match from_str::<Value>(res) {
-                                Ok(scripthash) => match scripthash {
-                                    Value::Object(data) => {
-                                        // println!("DATA {:?}", data);
-                                        subscription = data["result"].clone();
-                                        println!("SUSCRIBED {:?}", subscription);
-                                        // subscription
-                                    }
-                                    _ => {}
-                                },
-                                Err(_) => {}
-                            }
-                        }
-                        Message::Ping(msg) => {
-                            println!("Connection is alive {:?}", msg)
-                        }
-                        _ => {}
-                    },
-                    Err(e) => {}
+        };
+        Ok(WsClient {
+            url: url.to_string(),
+            timeout,
+            socket: Mutex::new(socket),
+            next_id: AtomicUsize::new(0),
+            headers: Mutex::new(VecDeque::new()),
+            statuses: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn queue(&self, notification: Notification) {
+        match notification {
+            Notification::Header(header) => self.headers.lock().unwrap().push_back(header),
+            Notification::Status { scripthash, status } => {
+                if let Some(queue) = self.statuses.lock().unwrap().get_mut(&scripthash) {
+                    queue.push_back(status);
                 }
-                println!("Received: {:?}", msg);
             }
-            // socket.close(None);
+        }
+    }
+
+    /// Reads until every id in `ids` is answered, queueing notifications on the way. Batch
+    /// responses arrive as one array.
+    fn read_responses(
+        &self,
+        socket: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+        method: &str,
+        ids: &[usize],
+    ) -> Result<Vec<Value>, NetworkError> {
+        let request_err = |reason: String| NetworkError::Request {
+            method: method.to_string(),
+            reason,
         };
-        println!("{:?}", connect(url));
+        let mut responses: HashMap<u64, Value> = HashMap::new();
+        while responses.len() < ids.len() {
+            let text = match socket.read() {
+                Ok(Message::Text(text)) => text,
+                Ok(Message::Binary(bytes)) => match String::from_utf8(bytes) {
+                    Ok(text) => text,
+                    Err(e) => return Err(request_err(e.to_string())),
+                },
+                Ok(Message::Close(_)) => return Err(request_err("connection closed".to_string())),
+                // pings are answered by tungstenite on the next write
+                Ok(_) => continue,
+                Err(e) => return Err(self.socket_err(method, e)),
+            };
+            let messages = match serde_json::from_str(&text) {
+                Ok(Value::Array(messages)) => messages,
+                Ok(message) => vec![message],
+                Err(e) => return Err(request_err(e.to_string())),
+            };
+            for message in messages {
+                if message.get("method").is_some() {
+                    if let Some(notification) = parse_notification(&message) {
+                        self.queue(notification);
+                    }
+                    continue;
+                }
+                match message["id"].as_u64() {
+                    Some(id) if ids.contains(&(id as usize)) => {
+                        responses.insert(id, message);
+                    }
+                    // an error without one of our ids, like a refused batch, answers nothing
+                    // else and the requests would wait for the timeout
+                    _ if !message["error"].is_null() => {
+                        return Err(server_err(method, &message["error"]))
+                    }
+                    _ => continue,
+                }
+            }
+        }
+        let mut results = Vec::with_capacity(ids.len());
+        for id in ids {
+            let response = responses.remove(&(*id as u64)).unwrap();
+            if !response["error"].is_null() {
+                return Err(server_err(method, &response["error"]));
+            }
+            results.push(response["result"].clone());
+        }
+        Ok(results)
+    }
+
+    fn socket_err(&self, method: &str, e: tungstenite::Error) -> NetworkError {
+        match e {
+            tungstenite::Error::Io(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                NetworkError::Timeout {
+                    method: method.to_string(),
+                    seconds: self.timeout.as_secs(),
+                }
+            }
+            e => NetworkError::Request {
+                method: method.to_string(),
+                reason: e.to_string(),
+            },
+        }
     }
-    Ok(subscription.clone())
 }
 
-mod test {
-    use super::*;
-    #[test]
-    fn maain() {
-        // env_logger::init();
+impl ElectrumTransport for WsClient {
+    fn raw_call(&self, method: &str, params: Vec<Param>) -> Result<Value, NetworkError> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        });
+        let mut socket = self.socket.lock().unwrap();
+        if let Err(e) = socket.send(Message::Text(request.to_string())) {
+            return Err(self.socket_err(method, e));
+        }
+        let mut res = self.read_responses(&mut socket, method, &[id])?;
+        Ok(res.remove(0))
+    }
+
+    fn batch_call(
+        &self,
+        method: &str,
+        params: Vec<Vec<Param>>,
+    ) -> Result<Vec<Value>, NetworkError> {
+        if params.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut ids = Vec::with_capacity(params.len());
+        let requests: Vec<Value> = params
+            .into_iter()
+            .map(|params| {
+                let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+                ids.push(id);
+                json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "method": method,
+                    "params": params,
+                })
+            })
+            .collect();
+        let mut socket = self.socket.lock().unwrap();
+        if let Err(e) = socket.send(Message::Text(Value::from(requests).to_string())) {
+            return Err(self.socket_err(method, e));
+        }
+        self.read_responses(&mut socket, method, &ids)
+    }
 
-        let (mut socket, response) =
-            connect(Url::parse("ws://localhost:50003").unwrap()).expect("Can't connect");
+    fn script_subscribe(&self, script: &Script) -> Result<Option<String>, NetworkError> {
+        let scripthash = scripthash_hex(script);
+        self.statuses
+            .lock()
+            .unwrap()
+            .entry(scripthash.clone())
+            .or_default();
+        let status = self.raw_call(
+            "blockchain.scripthash.subscribe",
+            vec![Param::String(scripthash)],
+        )?;
+        Ok(status.as_str().map(String::from))
+    }
 
-        println!("Connected to the server");
-        println!("Response HTTP code: {}", response.status());
-        println!("Response contains the following headers:");
-        for (ref header, _value) in response.headers() {
-            println!("* {}", header);
+    fn script_pop(&self, script: &Script) -> Result<Option<String>, NetworkError> {
+        let mut statuses = self.statuses.lock().unwrap();
+        match statuses.get_mut(&scripthash_hex(script)) {
+            Some(queue) => Ok(queue.pop_front().flatten()),
+            None => Err(NetworkError::Request {
+                method: "blockchain.scripthash.subscribe".to_string(),
+                reason: "script is not subscribed".to_string(),
+            }),
         }
-        let request = r#"{"jsonrpc": "2.0", "method": "blockchain.address.subscribe", "params": [bchtest:zzxu4ynqdgyjr2hvt5xcx7x35ncdz8zffswqykaryj], "id": 1}"#;
-        socket.send(Message::Text(request.into())).unwrap();
-        loop {
-            let msg = socket.read().expect("Error reading message");
-            println!("Received: {}", msg);
+    }
+
+    fn headers_subscribe(&self) -> Result<HeaderNotification, NetworkError> {
+        let method = "blockchain.headers.subscribe";
+        let header = self.raw_call(method, vec![])?;
+        match parse_header(&header) {
+            Some(header) => Ok(header),
+            None => Err(NetworkError::UnexpectedResponse {
+                method: method.to_string(),
+                response: header.to_string(),
+            }),
         }
-        // socket.close(None);
+    }
+
+    fn headers_pop(&self) -> Result<Option<HeaderNotification>, NetworkError> {
+        Ok(self.headers.lock().unwrap().pop_front())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notifications() {
+        let header = json!({
+            "jsonrpc": "2.0",
+            "method": "blockchain.headers.subscribe",
+            "params": [{"height": 5, "hex": "00ff"}],
+        });
+        assert_eq!(
+            parse_notification(&header),
+            Some(Notification::Header(HeaderNotification {
+                height: 5,
+                header: vec![0x00, 0xff],
+            }))
+        );
+        let status = json!({
+            "jsonrpc": "2.0",
+            "method": "blockchain.scripthash.subscribe",
+            "params": ["aa", null],
+        });
+        assert_eq!(
+            parse_notification(&status),
+            Some(Notification::Status {
+                scripthash: "aa".to_string(),
+                status: None,
+            })
+        );
+        let response = json!({"jsonrpc": "2.0", "id": 1, "result": null});
+        assert_eq!(parse_notification(&response), None);
     }
 }
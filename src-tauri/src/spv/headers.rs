@@ -0,0 +1,997 @@
+//! Header chain store. Headers are checked for linkage, proof-of-work, the `bits` the
+//! difficulty rules of their network require and the network checkpoints. `bits` are
+//! recomputed from the cw-144 DAA activation on, ASERT after its anchor block, the headers
+//! below are fixed by a checkpoint at the activation. Headers come from an Electrum server
+//! (`sync_headers`) or from a peer (`connect_headers`, see `network::p2p`).
+//!
+//! A store starts at a checkpoint. Electrum syncs start at the last checkpoint above the ASERT
+//! anchor, which only needs the parent to check the next header, and fill in older headers
+//! downwards when a transaction needs them (`sync_headers_down`).
+use bitcoin_hashes::{sha256d, Hash};
+use num_bigint::BigUint;
+
+use crate::error::WalletError;
+use crate::network::connection::ElectrumConnection;
+use crate::network::electrum::{get_block_headers, get_tip_header};
+
+/// sled tree of raw headers keyed by big endian height
+pub static HEADER_TREE: &str = "headers";
+pub const HEADER_SIZE: usize = 80;
+/// Headers requested per `blockchain.block.headers` call
+const CHUNK_SIZE: u32 = 2016;
+/// Deepest reorg followed before the chain is considered invalid
+const MAX_REORG_DEPTH: u32 = 100;
+/// Easiest target allowed on mainnet and the test networks
+const POW_LIMIT_BITS: u32 = 0x1d00ffff;
+const REGTEST_POW_LIMIT_BITS: u32 = 0x207fffff;
+/// sled tree of the total work up to each stored header, keyed like `HEADER_TREE`
+pub static CHAINWORK_TREE: &str = "header_work";
+/// Seconds between blocks the difficulty adjustments aim for
+const TARGET_SPACING: i64 = 600;
+/// Blocks averaged by the cw-144 DAA
+const DAA_WINDOW: u32 = 144;
+/// Time for the ASERT target to double, two days
+const ASERT_HALF_LIFE: i64 = 2 * 24 * 3600;
+
+/// (height, block hash) pairs, the first one is the genesis block.
+const MAINNET_CHECKPOINTS: &[(u32, &str)] = &[
+    (
+        0,
+        "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+    ),
+    // first block after the split from BTC
+    (
+        478559,
+        "000000000000000000651ef99cb9fcbe0dadde1d424bd9f15ff20136191a5eec",
+    ),
+    // cw-144 DAA activation, the headers below are not checked against the old rules
+    (
+        504031,
+        "0000000000000000011ebf65b60d0a3de80b8175be709d653b4c1a1beeb6ab9c",
+    ),
+    (
+        530359,
+        "0000000000000000011ada8bd08f46074f44a8f155396f43e38acf9501c49103",
+    ),
+    (
+        556767,
+        "0000000000000000004626ff6e3b936941d341c5932ece4357eeccac44e6d56c",
+    ),
+    (
+        582680,
+        "000000000000000001b4b8e36aec7d4f9671a47872cb9a74dc16ca398c7dcc18",
+    ),
+    (
+        609136,
+        "000000000000000000b48bb207faac5ac655c313e41ac909322eaa694f5bc5b1",
+    ),
+    (
+        635259,
+        "00000000000000000033dfef1fc2d6a5d5520b078c55193a9bf498c5b27530f7",
+    ),
+    // first ASERT block
+    (
+        661648,
+        "0000000000000000029e471c41818d24b8b74c911071c4ef0b4a0509f9b5a8ce",
+    ),
+];
+const TESTNET3_CHECKPOINTS: &[(u32, &str)] = &[(
+    0,
+    "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943",
+)];
+/// testnet4 and chipnet share a genesis block
+const TESTNET4_CHECKPOINTS: &[(u32, &str)] = &[(
+    0,
+    "000000001dd410c49a788668ce26751718cc797474d3152a5fc073dd44fd9f7b",
+)];
+const REGTEST_CHECKPOINTS: &[(u32, &str)] = &[(
+    0,
+    "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206",
+)];
+
+/// Block the ASERT difficulty adjustment is anchored to
+struct AsertAnchor {
+    height: u32,
+    bits: u32,
+    /// Timestamp of the parent of the anchor block
+    prev_time: u32,
+}
+
+static NETWORKS: [&ChainParams; 4] = [&MAINNET, &TESTNET3, &TESTNET4, &REGTEST];
+
+/// Difficulty rules and checkpoints of a network, found by its genesis block.
+pub struct ChainParams {
+    /// (height, block hash) pairs, the first one is the genesis block
+    pub checkpoints: &'static [(u32, &'static str)],
+    /// Easiest `bits` allowed
+    pow_limit: u32,
+    /// Test networks accept a block at the easiest target 20 minutes after its parent
+    min_difficulty_blocks: bool,
+    /// Regtest keeps the `bits` of the parent
+    no_retargeting: bool,
+    /// Blocks above this height follow the cw-144 DAA, `bits` below it are not recomputed
+    daa_height: u32,
+    /// Blocks above the anchor follow ASERT
+    asert_anchor: Option<AsertAnchor>,
+    /// Least total work of a synced chain, big endian hex
+    min_chainwork: Option<&'static str>,
+}
+
+pub static MAINNET: ChainParams = ChainParams {
+    checkpoints: MAINNET_CHECKPOINTS,
+    pow_limit: POW_LIMIT_BITS,
+    min_difficulty_blocks: false,
+    no_retargeting: false,
+    daa_height: 504031,
+    asert_anchor: Some(AsertAnchor {
+        height: 661647,
+        bits: 0x1804dafe,
+        prev_time: 1605447844,
+    }),
+    // what Bitcoin Core 0.14 required at height 447235, history we share
+    min_chainwork: Some("2cb971dd56d1c583c20f90"),
+};
+/// testnet3 and testnet4 have no checkpoint at the DAA activation, their first blocks keep
+/// the `bits` they claim.
+pub static TESTNET3: ChainParams = ChainParams {
+    checkpoints: TESTNET3_CHECKPOINTS,
+    pow_limit: POW_LIMIT_BITS,
+    min_difficulty_blocks: true,
+    no_retargeting: false,
+    daa_height: 1188697,
+    asert_anchor: Some(AsertAnchor {
+        height: 1421481,
+        bits: 0x1d00ffff,
+        prev_time: 1605445400,
+    }),
+    // what Bitcoin Core 0.14 required at height 1079274, history we share
+    min_chainwork: Some("1f057509eba81aed91"),
+};
+pub static TESTNET4: ChainParams = ChainParams {
+    checkpoints: TESTNET4_CHECKPOINTS,
+    pow_limit: POW_LIMIT_BITS,
+    min_difficulty_blocks: true,
+    no_retargeting: false,
+    daa_height: 3000,
+    asert_anchor: Some(AsertAnchor {
+        height: 16844,
+        bits: 0x1d00ffff,
+        prev_time: 1605451779,
+    }),
+    min_chainwork: None,
+};
+pub static REGTEST: ChainParams = ChainParams {
+    checkpoints: REGTEST_CHECKPOINTS,
+    pow_limit: REGTEST_POW_LIMIT_BITS,
+    min_difficulty_blocks: true,
+    no_retargeting: true,
+    daa_height: 0,
+    asert_anchor: None,
+    min_chainwork: None,
+};
+
+fn spv_err(reason: String) -> WalletError {
+    WalletError::SpvError { reason }
+}
+
+/// Parameters of the network starting at `genesis`, `None` for unknown networks.
+pub fn params_for(genesis: &BlockHeader) -> Option<&'static ChainParams> {
+    let hash = genesis.hash_hex();
+    NETWORKS
+        .into_iter()
+        .find(|params| params.checkpoints[0].1 == hash)
+}
+
+impl ChainParams {
+    /// Height an empty store is synced from: the last checkpoint above the ASERT anchor, or
+    /// genesis on networks without one.
+    fn seed_height(&self) -> u32 {
+        let anchor = match &self.asert_anchor {
+            Some(anchor) => anchor,
+            None => return 0,
+        };
+        self.checkpoints
+            .iter()
+            .rev()
+            .map(|(height, _)| *height)
+            .find(|height| *height > anchor.height)
+            .unwrap_or(0)
+    }
+
+    fn is_checkpoint(&self, height: u32, header: &BlockHeader) -> bool {
+        self.checkpoints
+            .iter()
+            .any(|(h, hash)| *h == height && header.hash_hex() == *hash)
+    }
+
+    /// `bits` required of `header` at `height`, `None` below the DAA activation.
+    fn expected_bits(
+        &self,
+        height: u32,
+        header: &BlockHeader,
+        chain: &ChainWindow,
+    ) -> Result<Option<u32>, WalletError> {
+        let prev_height = height - 1;
+        let prev = chain.at(prev_height)?;
+        if self.no_retargeting {
+            return Ok(Some(prev.bits()));
+        }
+        let asert = self
+            .asert_anchor
+            .as_ref()
+            .filter(|anchor| prev_height >= anchor.height);
+        if asert.is_none() && prev_height < self.daa_height {
+            return Ok(None);
+        }
+        if self.min_difficulty_blocks
+            && header.time() as i64 > prev.time() as i64 + 2 * TARGET_SPACING
+        {
+            return Ok(Some(self.pow_limit));
+        }
+        let limit = target_from_bits(self.pow_limit).unwrap();
+        let target = match asert {
+            Some(anchor) => asert_target(anchor, prev_height, prev.time(), &limit),
+            None => {
+                let last = suitable_block(prev_height, chain)?;
+                let first = suitable_block(prev_height - DAA_WINDOW, chain)?;
+                let target = daa_target(
+                    chain.work_between(first, last)?,
+                    chain.at(first)?.time(),
+                    chain.at(last)?.time(),
+                );
+                target.min(limit)
+            }
+        };
+        Ok(Some(bits_from_target(&target)))
+    }
+}
+
+/// Target encoded in the compact `bits` field, `None` when negative or overflowing.
+pub fn target_from_bits(bits: u32) -> Option<BigUint> {
+    let exponent = bits >> 24;
+    let mantissa = bits & 0x007f_ffff;
+    if bits & 0x0080_0000 != 0 || exponent > 34 {
+        return None;
+    }
+    let mantissa = BigUint::from(mantissa);
+    Some(match exponent <= 3 {
+        true => mantissa >> (8 * (3 - exponent) as usize),
+        false => mantissa << (8 * (exponent - 3) as usize),
+    })
+}
+
+/// Compact `bits` encoding of `target`, the inverse of [target_from_bits].
+pub fn bits_from_target(target: &BigUint) -> u32 {
+    let low = |n: BigUint| n.to_u64_digits().first().copied().unwrap_or(0) as u32;
+    let mut size = ((target.bits() + 7) / 8) as u32;
+    let mut compact = match size <= 3 {
+        true => low(target.clone()) << (8 * (3 - size)),
+        false => low(target >> (8 * (size - 3) as usize)),
+    };
+    if compact & 0x0080_0000 != 0 {
+        compact >>= 8;
+        size += 1;
+    }
+    compact | size << 24
+}
+
+/// Expected number of hashes to find a block at the target of `bits`, 0 for invalid `bits`.
+pub fn block_work(bits: u32) -> BigUint {
+    match target_from_bits(bits) {
+        Some(target) if target != BigUint::default() => {
+            (BigUint::from(1u8) << 256) / (target + 1u8)
+        }
+        _ => BigUint::default(),
+    }
+}
+
+/// aserti3-2d: the anchor target, doubled for every half life the parent is behind the
+/// ideal schedule and halved for every one it is ahead. Integer only, to match the nodes.
+fn asert_target(
+    anchor: &AsertAnchor,
+    prev_height: u32,
+    prev_time: u32,
+    limit: &BigUint,
+) -> BigUint {
+    let time_diff = prev_time as i64 - anchor.prev_time as i64;
+    let height_diff = prev_height as i64 - anchor.height as i64;
+    let exponent = ((time_diff - TARGET_SPACING * (height_diff + 1)) * 65536) / ASERT_HALF_LIFE;
+    let shifts = (exponent >> 16) - 16;
+    let frac = exponent as u16 as u64;
+    // cubic approximation of 2^frac, fixed point with 16 fractional bits
+    let factor = 65536
+        + ((195766423245049 * frac
+            + 971821376 * frac * frac
+            + 5127 * frac * frac * frac
+            + (1 << 47))
+            >> 48);
+    let target = target_from_bits(anchor.bits).unwrap() * factor;
+    let target = match shifts <= 0 {
+        true => target >> (-shifts) as usize,
+        false => target << shifts as usize,
+    };
+    match target == BigUint::default() {
+        true => BigUint::from(1u8),
+        false => target.min(limit.clone()),
+    }
+}
+
+/// cw-144: the target at which `work` done between two blocks `first_time` and `last_time`
+/// would have taken ten minutes a block, the timespan clamped to between half and twice a day.
+fn daa_target(work: BigUint, first_time: u32, last_time: u32) -> BigUint {
+    let timespan =
+        (last_time as i64 - first_time as i64).clamp(72 * TARGET_SPACING, 288 * TARGET_SPACING);
+    let work = work * TARGET_SPACING as u64 / timespan as u64;
+    ((BigUint::from(1u8) << 256) - &work) / &work
+}
+
+/// Height of the median by time of the block at `height` and its two parents, as the DAA
+/// picks them. Equal times keep their order, like the sorting network of the nodes.
+fn suitable_block(height: u32, chain: &ChainWindow) -> Result<u32, WalletError> {
+    let mut blocks = [height - 2, height - 1, height];
+    let time = |height: u32| chain.at(height).map(|header| header.time());
+    if time(blocks[0])? > time(blocks[2])? {
+        blocks.swap(0, 2);
+    }
+    if time(blocks[0])? > time(blocks[1])? {
+        blocks.swap(0, 1);
+    }
+    if time(blocks[1])? > time(blocks[2])? {
+        blocks.swap(1, 2);
+    }
+    Ok(blocks[1])
+}
+
+/// Consecutive headers with the work done up to each of them, counted from the first one.
+struct ChainWindow<'a> {
+    first: u32,
+    headers: Vec<&'a BlockHeader>,
+    work: Vec<BigUint>,
+}
+
+impl<'a> ChainWindow<'a> {
+    fn new(first: u32, headers: Vec<&'a BlockHeader>) -> Self {
+        let mut total = BigUint::default();
+        let work = headers
+            .iter()
+            .map(|header| {
+                total += block_work(header.bits());
+                total.clone()
+            })
+            .collect();
+        ChainWindow {
+            first,
+            headers,
+            work,
+        }
+    }
+
+    fn index(&self, height: u32) -> Result<usize, WalletError> {
+        match height.checked_sub(self.first) {
+            Some(index) if (index as usize) < self.headers.len() => Ok(index as usize),
+            _ => Err(spv_err(format!("no header at {}", height))),
+        }
+    }
+
+    fn at(&self, height: u32) -> Result<&BlockHeader, WalletError> {
+        Ok(self.headers[self.index(height)?])
+    }
+
+    /// Work of the blocks above `from` up to `to`.
+    fn work_between(&self, from: u32, to: u32) -> Result<BigUint, WalletError> {
+        Ok(&self.work[self.index(to)?] - &self.work[self.index(from)?])
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BlockHeader(pub [u8; HEADER_SIZE]);
+
+impl BlockHeader {
+    pub fn from_slice(raw: &[u8]) -> Result<Self, WalletError> {
+        match <[u8; HEADER_SIZE]>::try_from(raw) {
+            Ok(raw) => Ok(BlockHeader(raw)),
+            Err(_) => Err(spv_err(format!("header is {} bytes", raw.len()))),
+        }
+    }
+
+    /// Hash of the previous block, internal byte order
+    pub fn prev_hash(&self) -> [u8; 32] {
+        self.0[4..36].try_into().unwrap()
+    }
+
+    /// Merkle root, internal byte order
+    pub fn merkle_root(&self) -> [u8; 32] {
+        self.0[36..68].try_into().unwrap()
+    }
+
+    /// Block timestamp, unix seconds
+    pub fn time(&self) -> u32 {
+        u32::from_le_bytes(self.0[68..72].try_into().unwrap())
+    }
+
+    pub fn bits(&self) -> u32 {
+        u32::from_le_bytes(self.0[72..76].try_into().unwrap())
+    }
+
+    /// Block hash, internal byte order
+    pub fn hash(&self) -> [u8; 32] {
+        sha256d::Hash::hash(&self.0).into_inner()
+    }
+
+    /// Block hash as displayed by explorers
+    pub fn hash_hex(&self) -> String {
+        let mut hash = self.hash();
+        hash.reverse();
+        hex::encode(hash)
+    }
+
+    /// The hash is below the target claimed in `bits`, and that target is allowed on mainnet.
+    /// Whether `bits` is the target the chain requires is checked by `validate_chunk`.
+    pub fn check_pow(&self) -> bool {
+        self.check_pow_limit(POW_LIMIT_BITS)
+    }
+
+    fn check_pow_limit(&self, limit_bits: u32) -> bool {
+        let target = match target_from_bits(self.bits()) {
+            Some(target) => target,
+            None => return false,
+        };
+        let limit = target_from_bits(limit_bits).unwrap();
+        target <= limit && BigUint::from_bytes_le(&self.hash()) <= target
+    }
+}
+
+/// Header and chain work trees of one chain. The wallet's own store follows the Electrum
+/// server, a peer-to-peer backend keeps a store per network so a peer can't rewrite it.
+#[derive(Clone)]
+pub struct HeaderStore {
+    headers: sled::Tree,
+    work: sled::Tree,
+}
+
+/// Checks `headers`, starting at `start`, against `ancestors`, the headers right before
+/// them, the difficulty rules and the checkpoints of the network. Without ancestors the first
+/// header must be a checkpoint.
+fn validate_chunk(
+    start: u32,
+    ancestors: &[BlockHeader],
+    headers: &[BlockHeader],
+    params: &ChainParams,
+) -> Result<(), WalletError> {
+    let chain = ChainWindow::new(
+        start - ancestors.len() as u32,
+        ancestors.iter().chain(headers).collect(),
+    );
+    let mut prev_hash = ancestors.last().map(|p| p.hash());
+    for (i, header) in headers.iter().enumerate() {
+        let height = start + i as u32;
+        match prev_hash {
+            Some(prev_hash) if header.prev_hash() != prev_hash => {
+                return Err(spv_err(format!("header {} does not connect", height)));
+            }
+            None if !params.is_checkpoint(height, header) => {
+                return Err(spv_err(format!(
+                    "header {} is not a checkpoint to start from",
+                    height
+                )));
+            }
+            _ => {}
+        }
+        if !header.check_pow_limit(params.pow_limit) {
+            return Err(spv_err(format!(
+                "header {} has invalid proof of work",
+                height
+            )));
+        }
+        if prev_hash.is_some() {
+            if let Some(expected) = params.expected_bits(height, header, &chain)? {
+                if header.bits() != expected {
+                    return Err(spv_err(format!(
+                        "header {} has bits {:08x}, expected {:08x}",
+                        height,
+                        header.bits(),
+                        expected
+                    )));
+                }
+            }
+        }
+        if let Some((_, hash)) = params.checkpoints.iter().find(|(h, _)| *h == height) {
+            if header.hash_hex() != *hash {
+                return Err(spv_err(format!(
+                    "header {} does not match checkpoint",
+                    height
+                )));
+            }
+        }
+        prev_hash = Some(header.hash());
+    }
+    Ok(())
+}
+
+fn total_work(headers: &[BlockHeader]) -> BigUint {
+    headers.iter().map(|header| block_work(header.bits())).sum()
+}
+
+impl HeaderStore {
+    /// Trees `headers` and `header_work` of `db`, the names suffixed with `name` when given.
+    pub fn open(db: &sled::Db, name: Option<&str>) -> Result<Self, WalletError> {
+        let tree_name = |tree: &str| match name {
+            Some(name) => format!("{}_{}", tree, name),
+            None => tree.to_string(),
+        };
+        Ok(HeaderStore {
+            headers: db.open_tree(tree_name(HEADER_TREE))?,
+            work: db.open_tree(tree_name(CHAINWORK_TREE))?,
+        })
+    }
+
+    /// Store of the wallet in `db`, synced from the Electrum server.
+    pub fn wallet(db: &sled::Db) -> Result<Self, WalletError> {
+        Self::open(db, None)
+    }
+
+    /// Highest stored header height.
+    pub fn tip_height(&self) -> Result<Option<u32>, WalletError> {
+        match self.headers.last()? {
+            Some((key, _)) => Ok(Some(height_key(&key)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Lowest stored header height, genesis or the checkpoint the store was seeded from.
+    pub fn first_height(&self) -> Result<Option<u32>, WalletError> {
+        match self.headers.first()? {
+            Some((key, _)) => Ok(Some(height_key(&key)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_header(&self, height: u32) -> Result<Option<BlockHeader>, WalletError> {
+        match self.headers.get(height.to_be_bytes())? {
+            Some(raw) => Ok(Some(BlockHeader::from_slice(&raw)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Total work of the stored chain up to `height`. Stores written before the work was
+    /// kept are counted once from genesis.
+    pub fn chainwork(&self, height: u32) -> Result<BigUint, WalletError> {
+        if let Some(work) = self.work.get(height.to_be_bytes())? {
+            return Ok(BigUint::from_bytes_be(&work));
+        }
+        let mut total = BigUint::default();
+        let mut batch = sled::Batch::default();
+        for h in 0..=height {
+            match self.get_header(h)? {
+                Some(header) => total += block_work(header.bits()),
+                None => return Err(spv_err(format!("no header at {}", h))),
+            }
+            batch.insert(&h.to_be_bytes()[..], total.to_bytes_be());
+        }
+        self.work.apply_batch(batch)?;
+        Ok(total)
+    }
+
+    fn truncate_above(&self, height: u32) -> Result<(), WalletError> {
+        for tree in [&self.headers, &self.work] {
+            for key in tree.range((height + 1).to_be_bytes()..).keys() {
+                tree.remove(key?)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Stored headers before `start`, as many as the DAA looks back. The last one is the
+    /// parent.
+    fn stored_ancestors(&self, start: u32) -> Result<Vec<BlockHeader>, WalletError> {
+        let first = self.first_height()?.unwrap_or(0);
+        let mut ancestors = vec![];
+        for height in start.saturating_sub(DAA_WINDOW + 3).max(first)..start {
+            match self.get_header(height)? {
+                Some(header) => ancestors.push(header),
+                None => return Err(spv_err(format!("no header at {}", height))),
+            }
+        }
+        Ok(ancestors)
+    }
+
+    /// Fails when the stored chain has less work than the network minimum, which means the
+    /// source is far behind or serves a chain of its own. A store seeded from a later
+    /// checkpoint is pinned to the network by it and only counts work from there.
+    pub fn check_chainwork(&self, tip: u32) -> Result<(), WalletError> {
+        if self.get_header(0)?.is_none() {
+            return Ok(());
+        }
+        let minimum = match self.network_params(1, &[])?.min_chainwork {
+            Some(minimum) => BigUint::parse_bytes(minimum.as_bytes(), 16).unwrap(),
+            None => return Ok(()),
+        };
+        match self.chainwork(tip)? >= minimum {
+            true => Ok(()),
+            false => Err(spv_err(format!(
+                "chain up to {} has less work than the network minimum",
+                tip
+            ))),
+        }
+    }
+
+    /// Validates `headers`, the first one at `start`, and stores them. Stored headers from
+    /// `start` up are replaced when the new branch has more work and forks less than
+    /// `MAX_REORG_DEPTH` blocks deep. Returns the tip height, unchanged when the headers
+    /// were not taken.
+    pub fn connect_headers(&self, start: u32, headers: &[BlockHeader]) -> Result<u32, WalletError> {
+        let tip = self.tip_height()?;
+        if headers.is_empty() {
+            return Ok(tip.unwrap_or(0));
+        }
+        let new_tip = start + headers.len() as u32 - 1;
+        if let Some(tip) = tip {
+            if start > tip + 1 {
+                return Err(spv_err(format!(
+                    "headers from {} leave a gap after {}",
+                    start, tip
+                )));
+            }
+            if start <= self.first_height()?.unwrap_or(0) {
+                return Err(spv_err(format!(
+                    "headers from {} replace the first stored one",
+                    start
+                )));
+            }
+            if start <= tip {
+                if tip - start + 1 > MAX_REORG_DEPTH {
+                    return Err(spv_err("reorg deeper than allowed".to_string()));
+                }
+                let branch_work = self.chainwork(start - 1)? + total_work(headers);
+                if branch_work <= self.chainwork(tip)? {
+                    return Ok(tip);
+                }
+            }
+        }
+        let params = self.network_params(start, headers)?;
+        validate_chunk(start, &self.stored_ancestors(start)?, headers, params)?;
+        if let Some(tip) = tip {
+            if start <= tip {
+                log::info!("header reorg at {}", start);
+                self.truncate_above(start - 1)?;
+            }
+        }
+        self.store_chunk(start, headers)?;
+        Ok(new_tip)
+    }
+
+    /// `(height, hash)` of stored headers from the tip back to the first one, dense near the
+    /// tip and doubling the step after ten, as expected by `getheaders`.
+    pub fn block_locator(&self) -> Result<Vec<(u32, [u8; 32])>, WalletError> {
+        let (first, tip) = match (self.first_height()?, self.tip_height()?) {
+            (Some(first), Some(tip)) => (first, tip),
+            _ => return Ok(vec![]),
+        };
+        let mut locator = vec![];
+        let mut height = tip;
+        let mut step = 1;
+        loop {
+            match self.get_header(height)? {
+                Some(header) => locator.push((height, header.hash())),
+                None => return Err(spv_err(format!("no header at {}", height))),
+            }
+            if height == first {
+                return Ok(locator);
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step).max(first);
+        }
+    }
+
+    /// Parameters of the network with a checkpoint in the stored chain, or in `headers` from
+    /// `start` on.
+    fn network_params(
+        &self,
+        start: u32,
+        headers: &[BlockHeader],
+    ) -> Result<&'static ChainParams, WalletError> {
+        for params in NETWORKS {
+            for (height, _) in params.checkpoints {
+                let header = match self.get_header(*height)? {
+                    Some(header) => Some(header),
+                    None => height
+                        .checked_sub(start)
+                        .and_then(|i| headers.get(i as usize))
+                        .cloned(),
+                };
+                if header.map_or(false, |header| params.is_checkpoint(*height, &header)) {
+                    return Ok(params);
+                }
+            }
+        }
+        Err(spv_err("unknown network".to_string()))
+    }
+
+    fn store_chunk(&self, start: u32, headers: &[BlockHeader]) -> Result<(), WalletError> {
+        let mut total = match self.first_height()? {
+            Some(first) if start > first => self.chainwork(start - 1)?,
+            _ => BigUint::default(),
+        };
+        let mut batch = sled::Batch::default();
+        let mut work = sled::Batch::default();
+        for (i, header) in headers.iter().enumerate() {
+            let key = (start + i as u32).to_be_bytes();
+            total += block_work(header.bits());
+            batch.insert(&key[..], &header.0[..]);
+            work.insert(&key[..], total.to_bytes_be());
+        }
+        self.work.apply_batch(work)?;
+        Ok(self.headers.apply_batch(batch)?)
+    }
+}
+
+fn height_key(key: &[u8]) -> Result<u32, WalletError> {
+    match <[u8; 4]>::try_from(key) {
+        Ok(key) => Ok(u32::from_be_bytes(key)),
+        Err(_) => Err(spv_err("bad header key".to_string())),
+    }
+}
+
+fn parse_headers(raw: &[u8]) -> Result<Vec<BlockHeader>, WalletError> {
+    if raw.is_empty() || raw.len() % HEADER_SIZE != 0 {
+        return Err(spv_err(format!("server sent {} header bytes", raw.len())));
+    }
+    raw.chunks(HEADER_SIZE)
+        .map(BlockHeader::from_slice)
+        .collect()
+}
+
+/// Height an empty store is synced from on the network of the server.
+async fn seed_height(connection: &ElectrumConnection) -> Result<u32, WalletError> {
+    let genesis = parse_headers(&get_block_headers(0, 1, connection).await?)?;
+    match params_for(&genesis[0]) {
+        Some(params) => Ok(params.seed_height()),
+        None => Err(spv_err("unknown network genesis".to_string())),
+    }
+}
+
+/// Downloads and validates headers into `store` up to the server tip, following reorgs up to
+/// `MAX_REORG_DEPTH` blocks deep. An empty store starts at the seed checkpoint of the network.
+/// Returns the new tip height.
+pub async fn sync_headers(
+    store: &HeaderStore,
+    connection: &ElectrumConnection,
+) -> Result<u32, WalletError> {
+    let (server_tip, _) = get_tip_header(connection).await?;
+    let mut rewound = 0;
+    loop {
+        let start = match store.tip_height()? {
+            Some(tip) => tip + 1,
+            None => seed_height(connection).await?,
+        };
+        if start > server_tip {
+            store.check_chainwork(server_tip)?;
+            return Ok(server_tip);
+        }
+        let count = CHUNK_SIZE.min(server_tip - start + 1);
+        let headers = parse_headers(&get_block_headers(start, count, connection).await?)?;
+        let params = store.network_params(start, &headers)?;
+        let ancestors = store.stored_ancestors(start)?;
+        if let Some(prev) = ancestors.last() {
+            // the server follows a different branch, drop our tip and try one block lower
+            if headers[0].prev_hash() != prev.hash() {
+                rewound += 1;
+                if rewound > MAX_REORG_DEPTH || start - 1 <= store.first_height()?.unwrap_or(0) {
+                    return Err(spv_err("reorg deeper than allowed".to_string()));
+                }
+                log::info!("header reorg at {}", start - 1);
+                store.truncate_above(start - 2)?;
+                continue;
+            }
+        }
+        validate_chunk(start, &ancestors, &headers, params)?;
+        store.store_chunk(start, &headers)?;
+    }
+}
+
+/// Downloads the headers from `height` up to the first stored one into `store`. Each must be
+/// the parent of the header above it, which ties them to the checkpoint the store starts from.
+pub async fn sync_headers_down(
+    store: &HeaderStore,
+    height: u32,
+    connection: &ElectrumConnection,
+) -> Result<(), WalletError> {
+    loop {
+        let first = match store.first_height()? {
+            Some(first) if first > height => first,
+            _ => return Ok(()),
+        };
+        let start = height.max(first.saturating_sub(CHUNK_SIZE));
+        let headers = parse_headers(&get_block_headers(start, first - start, connection).await?)?;
+        if headers.len() != (first - start) as usize {
+            return Err(spv_err(format!(
+                "server sent {} headers below {}",
+                headers.len(),
+                first
+            )));
+        }
+        let mut prev_hash = match store.get_header(first)? {
+            Some(header) => header.prev_hash(),
+            None => return Err(spv_err(format!("no header at {}", first))),
+        };
+        let mut batch = sled::Batch::default();
+        for (i, header) in headers.iter().enumerate().rev() {
+            let height = start + i as u32;
+            if header.hash() != prev_hash {
+                return Err(spv_err(format!("header {} does not connect", height)));
+            }
+            batch.insert(&height.to_be_bytes()[..], &header.0[..]);
+            prev_hash = header.prev_hash();
+        }
+        store.headers.apply_batch(batch)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REGTEST_GENESIS_HEADER: &str = "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4adae5494dffff7f2002000000";
+    const GENESIS_HEADER: &str = "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c";
+
+    #[test]
+    fn genesis() {
+        let header = BlockHeader::from_slice(&hex::decode(GENESIS_HEADER).unwrap()).unwrap();
+        assert_eq!(header.hash_hex(), MAINNET_CHECKPOINTS[0].1);
+        assert!(header.check_pow());
+        assert_eq!(
+            params_for(&header).map(|params| params.checkpoints),
+            Some(MAINNET_CHECKPOINTS)
+        );
+        assert!(validate_chunk(0, &[], &[header.clone()], &MAINNET).is_ok());
+
+        let mut tampered = header;
+        tampered.0[79] ^= 1;
+        assert!(!tampered.check_pow());
+    }
+
+    /// Regtest child of `parent` at `bits`, mined at the regtest limit. `salt` goes into the
+    /// merkle root so siblings differ.
+    fn mine(parent: &BlockHeader, bits: u32, salt: u8) -> BlockHeader {
+        let mut header = parent.clone();
+        header.0[4..36].copy_from_slice(&parent.hash());
+        header.0[36] = salt;
+        header.0[68..72].copy_from_slice(&(parent.time() + 600).to_le_bytes());
+        header.0[72..76].copy_from_slice(&bits.to_le_bytes());
+        for nonce in 0u32.. {
+            header.0[76..80].copy_from_slice(&nonce.to_le_bytes());
+            if header.check_pow_limit(REGTEST_POW_LIMIT_BITS) {
+                break;
+            }
+        }
+        header
+    }
+
+    #[test]
+    fn regtest_genesis() {
+        let header =
+            BlockHeader::from_slice(&hex::decode(REGTEST_GENESIS_HEADER).unwrap()).unwrap();
+        assert_eq!(
+            params_for(&header).map(|params| params.checkpoints),
+            Some(REGTEST_CHECKPOINTS)
+        );
+        assert!(!header.check_pow());
+        assert!(validate_chunk(0, &[], &[header.clone()], &REGTEST).is_ok());
+
+        // regtest never retargets, a harder target than the parent's is still wrong
+        let child = mine(&header, header.bits(), 0);
+        assert!(validate_chunk(1, &[header.clone()], &[child], &REGTEST).is_ok());
+        let harder = mine(&header, 0x207ffffe, 0);
+        assert!(validate_chunk(1, &[header], &[harder], &REGTEST).is_err());
+    }
+
+    /// `count` regtest headers after `parent`
+    fn branch(parent: &BlockHeader, count: usize, salt: u8) -> Vec<BlockHeader> {
+        let mut headers: Vec<BlockHeader> = vec![];
+        for _ in 0..count {
+            let parent = headers.last().unwrap_or(parent);
+            headers.push(mine(parent, parent.bits(), salt));
+        }
+        headers
+    }
+
+    #[test]
+    fn reorg_by_work() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = HeaderStore::open(&db, Some("regtest")).unwrap();
+        let genesis =
+            BlockHeader::from_slice(&hex::decode(REGTEST_GENESIS_HEADER).unwrap()).unwrap();
+        let ours = branch(&genesis, 3, 1);
+        assert_eq!(store.connect_headers(0, &[genesis.clone()]).unwrap(), 0);
+        assert_eq!(store.connect_headers(1, &ours).unwrap(), 3);
+
+        // as much work as ours is not enough to switch
+        assert_eq!(store.connect_headers(1, &branch(&genesis, 3, 2)).unwrap(), 3);
+        assert_eq!(store.get_header(3).unwrap(), Some(ours[2].clone()));
+
+        let theirs = branch(&genesis, 4, 2);
+        assert_eq!(store.connect_headers(1, &theirs).unwrap(), 4);
+        assert_eq!(store.get_header(3).unwrap(), Some(theirs[2].clone()));
+        assert_eq!(store.chainwork(4).unwrap(), BigUint::from(10u8));
+        // the wallet store is untouched
+        assert!(!db.tree_names().iter().any(|name| name.as_ref() == b"headers"));
+    }
+
+    #[test]
+    fn starts_at_a_checkpoint() {
+        assert_eq!(MAINNET.seed_height(), 661648);
+        assert_eq!(TESTNET4.seed_height(), 0);
+        assert_eq!(REGTEST.seed_height(), 0);
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = HeaderStore::open(&db, Some("regtest")).unwrap();
+        let genesis =
+            BlockHeader::from_slice(&hex::decode(REGTEST_GENESIS_HEADER).unwrap()).unwrap();
+        let headers = branch(&genesis, 3, 1);
+        assert!(store.connect_headers(1, &headers).is_err());
+        assert_eq!(store.tip_height().unwrap(), None);
+    }
+
+    #[test]
+    fn compact_targets() {
+        for bits in [0x1d00ffff, 0x1804dafe, 0x207fffff, 0x03123456] {
+            assert_eq!(bits_from_target(&target_from_bits(bits).unwrap()), bits);
+        }
+        // the mantissa sign bit moves into the exponent
+        assert_eq!(bits_from_target(&BigUint::from(0x80u32)), 0x02008000);
+        assert_eq!(block_work(0x207fffff), BigUint::from(2u8));
+    }
+
+    #[test]
+    fn asert() {
+        let anchor = MAINNET.asert_anchor.as_ref().unwrap();
+        let limit = target_from_bits(POW_LIMIT_BITS).unwrap();
+        // on schedule keeps the anchor target
+        let on_time = anchor.prev_time + 600 * 11;
+        let target = asert_target(anchor, anchor.height + 10, on_time, &limit);
+        assert_eq!(bits_from_target(&target), anchor.bits);
+        // a half life behind doubles it, one ahead halves it
+        let late = on_time + ASERT_HALF_LIFE as u32;
+        let target = asert_target(anchor, anchor.height + 10, late, &limit);
+        assert_eq!(bits_from_target(&target), 0x1809b5fc);
+        let early = on_time - ASERT_HALF_LIFE as u32;
+        let target = asert_target(anchor, anchor.height + 10, early, &limit);
+        assert_eq!(bits_from_target(&target), 0x18026d7f);
+    }
+
+    /// `count` headers at `bits` ten minutes apart, only times and bits are set.
+    fn steady_headers(count: usize, bits: u32) -> Vec<BlockHeader> {
+        (0..count)
+            .map(|i| {
+                let mut header = BlockHeader([0; HEADER_SIZE]);
+                header.0[68..72].copy_from_slice(&(1_500_000_000 + 600 * i as u32).to_le_bytes());
+                header.0[72..76].copy_from_slice(&bits.to_le_bytes());
+                header
+            })
+            .collect()
+    }
+
+    #[test]
+    fn cash_daa() {
+        let first = 600_000;
+        let headers = steady_headers(DAA_WINDOW as usize + 4, 0x1804dafe);
+        let (next, window) = headers.split_last().unwrap();
+        let chain = ChainWindow::new(first, window.iter().collect());
+        let height = first + window.len() as u32;
+        // blocks ten minutes apart keep their target
+        assert_eq!(
+            MAINNET.expected_bits(height, next, &chain).unwrap(),
+            Some(0x1804dafe)
+        );
+        // before the DAA the bits are left to the checkpoints
+        let chain = ChainWindow::new(400_000, window.iter().collect());
+        let height = 400_000 + window.len() as u32;
+        assert_eq!(MAINNET.expected_bits(height, next, &chain).unwrap(), None);
+        // the window must reach back far enough
+        let chain = ChainWindow::new(first + 10, window[10..].iter().collect());
+        let height = first + window.len() as u32;
+        assert!(MAINNET.expected_bits(height, next, &chain).is_err());
+    }
+}
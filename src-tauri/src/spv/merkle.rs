@@ -0,0 +1,78 @@
+//! Merkle branch checks for `blockchain.transaction.get_merkle` responses.
+use bitcoin_hashes::{sha256d, Hash};
+
+use super::headers::BlockHeader;
+use crate::error::WalletError;
+
+/// Displayed (reversed) hex hash to internal byte order.
+fn internal_bytes(hash_hex: &str) -> Result<[u8; 32], WalletError> {
+    let bytes = match hex::decode(hash_hex) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            return Err(WalletError::SpvError {
+                reason: e.to_string(),
+            })
+        }
+    };
+    let mut hash: [u8; 32] = match bytes.try_into() {
+        Ok(hash) => hash,
+        Err(_) => {
+            return Err(WalletError::SpvError {
+                reason: format!("{} is not a 32 byte hash", hash_hex),
+            })
+        }
+    };
+    hash.reverse();
+    Ok(hash)
+}
+
+/// Root obtained by hashing `txid` up the `branch`, `pos` is the index of the tx in the block.
+pub fn merkle_root(txid: &str, branch: &[String], pos: u32) -> Result<[u8; 32], WalletError> {
+    let mut hash = internal_bytes(txid)?;
+    let mut index = pos;
+    for sibling in branch {
+        let sibling = internal_bytes(sibling)?;
+        let mut concat = [0u8; 64];
+        match index & 1 {
+            0 => {
+                concat[..32].copy_from_slice(&hash);
+                concat[32..].copy_from_slice(&sibling);
+            }
+            _ => {
+                concat[..32].copy_from_slice(&sibling);
+                concat[32..].copy_from_slice(&hash);
+            }
+        }
+        hash = sha256d::Hash::hash(&concat).into_inner();
+        index >>= 1;
+    }
+    Ok(hash)
+}
+
+pub fn verify_merkle(
+    txid: &str,
+    branch: &[String],
+    pos: u32,
+    header: &BlockHeader,
+) -> Result<bool, WalletError> {
+    Ok(merkle_root(txid, branch, pos)? == header.merkle_root())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_170() {
+        // coinbase and the first bitcoin payment
+        let coinbase = "b1fea52486ce0c62bb442b530a3f0132b826c74e473d1f2c220bfa78111c5082";
+        let payment = "f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16";
+        let root =
+            internal_bytes("7dac2c5666815c17a3b36427de37bb9d2e2c5ccec3f8633eb91a4205cb4c10ff")
+                .unwrap();
+        assert_eq!(merkle_root(payment, &[coinbase.to_string()], 1).unwrap(), root);
+        assert_eq!(merkle_root(coinbase, &[payment.to_string()], 0).unwrap(), root);
+        // wrong position hashes the pair in the wrong order
+        assert_ne!(merkle_root(payment, &[coinbase.to_string()], 0).unwrap(), root);
+    }
+}
@@ -0,0 +1,98 @@
+//! Simplified payment verification: a validated header chain and merkle proofs for the wallet
+//! transactions. A utxo only counts as confirmed once its transaction is proven in our chain.
+pub mod headers;
+pub mod merkle;
+
+use std::collections::HashMap;
+
+use serde_json::{json, Value};
+
+use crate::error::WalletError;
+use crate::network::connection::ElectrumConnection;
+use crate::network::electrum::get_merkle;
+use headers::{sync_headers, sync_headers_down, HeaderStore};
+use merkle::verify_merkle;
+
+/// sled tree of proven txids, the value is the hash of the block they were proven in
+pub static SPV_TREE: &str = "spv";
+
+/// Proves `txid` is in our header chain at `height`. Headers below the first stored one are
+/// downloaded first. Proofs are cached per block hash, so a reorged block is proven again.
+pub async fn verify_transaction(
+    db: &sled::Db,
+    txid: &str,
+    height: u32,
+    connection: &ElectrumConnection,
+) -> Result<bool, WalletError> {
+    let headers = HeaderStore::wallet(db)?;
+    if headers.first_height()?.map_or(false, |first| height < first) {
+        sync_headers_down(&headers, height, connection).await?;
+    }
+    let header = match headers.get_header(height)? {
+        Some(header) => header,
+        None => return Ok(false),
+    };
+    let block_hash = header.hash();
+    let spv_tree = db.open_tree(SPV_TREE)?;
+    if let Some(proven) = spv_tree.get(txid.as_bytes())? {
+        if proven.as_ref() == block_hash {
+            return Ok(true);
+        }
+    }
+    let (branch, pos) = match get_merkle(txid, height, connection).await {
+        Ok(proof) => proof,
+        Err(e) => {
+            log::warn!("no merkle proof for {}: {}", txid, e);
+            return Ok(false);
+        }
+    };
+    if !verify_merkle(txid, &branch, pos, &header)? {
+        log::warn!("invalid merkle proof for {} at {}", txid, height);
+        return Ok(false);
+    }
+    spv_tree.insert(txid.as_bytes(), &block_hash[..])?;
+    Ok(true)
+}
+
+/// Takes a `listunspent` response and sets the height of every utxo whose transaction could
+/// not be proven to 0, so it is treated as unconfirmed.
+pub async fn verify_utxos(
+    db: &sled::Db,
+    utxos: String,
+    connection: &ElectrumConnection,
+) -> Result<String, WalletError> {
+    let mut utxos: Value = serde_json::from_str(&utxos)?;
+    let entries = match utxos.as_array_mut() {
+        Some(entries) => entries,
+        None => return Ok(utxos.to_string()),
+    };
+    let highest = entries
+        .iter()
+        .filter_map(|utxo| utxo["height"].as_u64())
+        .max()
+        .unwrap_or(0) as u32;
+    let headers = HeaderStore::wallet(db)?;
+    if highest > 0 && headers.tip_height()?.map_or(true, |tip| tip < highest) {
+        sync_headers(&headers, connection).await?;
+    }
+    let mut proven: HashMap<String, bool> = HashMap::new();
+    for utxo in entries.iter_mut() {
+        let height = utxo["height"].as_u64().unwrap_or(0) as u32;
+        let txid = utxo["tx_hash"].as_str().unwrap_or_default().to_string();
+        if height == 0 {
+            continue;
+        }
+        let ok = match proven.get(&txid) {
+            Some(ok) => *ok,
+            None => {
+                let ok = verify_transaction(db, &txid, height, connection).await?;
+                proven.insert(txid, ok);
+                ok
+            }
+        };
+        if !ok {
+            utxo["height"] = json!(0);
+        }
+    }
+    Ok(utxos.to_string())
+}
@@ -0,0 +1,624 @@
+//! Wallet transaction history. Every transaction touching a wallet address is stored once with
+//! what it did to the wallet as a whole: net satoshis, net tokens per category and the fee.
+//! Confirmations are counted from the last block seen, so a new block only moves the tip.
+//! The txids each address's history listed at its last sync are kept too, so syncing some
+//! addresses only drops the transactions that left their histories.
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bitcoinsuite_core::tx::{Output, Transaction, TxId};
+use serde_json::{json, Value};
+
+use crate::address::address_to_script;
+use crate::encoding::Reader;
+use crate::error::WalletError;
+use crate::network::connection::ElectrumConnection;
+use crate::network::electrum::get_address_history_batch;
+use crate::spv::headers::{sync_headers, sync_headers_down, HeaderStore};
+use crate::store::schema::Record;
+use crate::store::transactions::{decode_transaction, fetch_transactions, spent_outputs};
+
+/// sled tree of history entries keyed by txid hex, plus the tip height
+pub static HISTORY_TREE: &str = "history";
+/// Key of the last block height seen, txid keys are 64 characters
+const TIP_KEY: &str = "tip";
+/// sled tree of the txids listed in the history of an address, keyed by address
+pub static ADDRESS_HISTORY_TREE: &str = "address_history";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TokenDelta {
+    pub category: TxId,
+    pub fungible: i128,
+    pub nfts: i32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryEntry {
+    pub txid: String,
+    /// 0 while unconfirmed
+    pub height: u32,
+    /// Block time, or when the transaction was first seen while unconfirmed
+    pub timestamp: u32,
+    pub net_sats: i64,
+    pub tokens: Vec<TokenDelta>,
+    /// Inputs minus outputs when the wallet funded every input, 0 otherwise
+    pub fee: u64,
+}
+
+impl HistoryEntry {
+    pub fn confirmations(&self, tip: u32) -> u32 {
+        match self.height > 0 && self.height <= tip {
+            true => tip - self.height + 1,
+            false => 0,
+        }
+    }
+
+    /// Satoshis or tokens came into the wallet.
+    pub fn receives(&self) -> bool {
+        self.net_sats > 0
+            || self
+                .tokens
+                .iter()
+                .any(|token| token.fungible > 0 || token.nfts > 0)
+    }
+
+    /// Satoshis or tokens left the wallet.
+    pub fn sends(&self) -> bool {
+        self.net_sats < 0
+            || self
+                .tokens
+                .iter()
+                .any(|token| token.fungible < 0 || token.nfts < 0)
+    }
+
+    pub fn to_json(&self, tip: u32) -> Value {
+        let tokens: Vec<Value> = self
+            .tokens
+            .iter()
+            .map(|token| {
+                json!({
+                    "category": token.category.to_string(),
+                    "amount": token.fungible.to_string(),
+                    "nfts": token.nfts,
+                })
+            })
+            .collect();
+        json!({
+            "txid": self.txid,
+            "height": self.height,
+            "timestamp": self.timestamp,
+            "confirmations": self.confirmations(tip),
+            "netSats": self.net_sats,
+            "fee": self.fee,
+            "tokens": tokens,
+        })
+    }
+}
+
+impl Record for HistoryEntry {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = hex::decode(&self.txid).unwrap_or_default();
+        out.extend_from_slice(&self.height.to_le_bytes());
+        out.extend_from_slice(&self.timestamp.to_le_bytes());
+        out.extend_from_slice(&self.net_sats.to_le_bytes());
+        out.extend_from_slice(&self.fee.to_le_bytes());
+        out.extend_from_slice(&(self.tokens.len() as u32).to_le_bytes());
+        for token in self.tokens.iter() {
+            out.extend_from_slice(&hex::decode(token.category.to_string()).unwrap_or_default());
+            out.extend_from_slice(&token.fungible.to_le_bytes());
+            out.extend_from_slice(&token.nfts.to_le_bytes());
+        }
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader::new(bytes);
+        let txid = hex::encode(reader.bytes(32)?);
+        let height = reader.u32()?;
+        let timestamp = reader.u32()?;
+        let net_sats = reader.u64()? as i64;
+        let fee = reader.u64()?;
+        let mut tokens = vec![];
+        for _ in 0..reader.u32()? {
+            tokens.push(TokenDelta {
+                category: TxId::from_str(&hex::encode(reader.bytes(32)?)).ok()?,
+                fungible: i128::from_le_bytes(reader.bytes(16)?.try_into().ok()?),
+                nfts: reader.u32()? as i32,
+            });
+        }
+        Some(HistoryEntry {
+            txid,
+            height,
+            timestamp,
+            net_sats,
+            tokens,
+            fee,
+        })
+    }
+}
+
+/// Selects entries for `history_page`, every field set has to match.
+#[derive(Clone, Debug, Default)]
+pub struct HistoryFilter {
+    /// Only transactions moving tokens of this category
+    pub category: Option<TxId>,
+    /// Only transactions adding satoshis or tokens to the wallet when true, taking them out of
+    /// it when false. A transaction doing both, like buying tokens, matches either way.
+    pub incoming: Option<bool>,
+    pub min_height: Option<u32>,
+    pub max_height: Option<u32>,
+}
+
+impl HistoryFilter {
+    pub fn matches(&self, entry: &HistoryEntry) -> bool {
+        if let Some(category) = self.category.as_ref() {
+            if !entry.tokens.iter().any(|token| token.category == *category) {
+                return false;
+            }
+        }
+        if let Some(incoming) = self.incoming {
+            let matched = match incoming {
+                true => entry.receives(),
+                false => entry.sends(),
+            };
+            if !matched {
+                return false;
+            }
+        }
+        let confirmed = entry.height > 0;
+        if let Some(min_height) = self.min_height {
+            if !confirmed || entry.height < min_height {
+                return false;
+            }
+        }
+        if let Some(max_height) = self.max_height {
+            if !confirmed || entry.height > max_height {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+fn now_secs() -> u32 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(now) => now.as_secs() as u32,
+        Err(_) => 0,
+    }
+}
+
+fn is_coinbase(tx: &Transaction) -> bool {
+    tx.inputs.len() == 1 && tx.inputs[0].prev_out.outpoint_index == u32::MAX
+}
+
+/// `txid` in the lowercase hex history keys use, an error when it isn't a txid.
+fn history_txid(txid: &str) -> Result<String, WalletError> {
+    match TxId::from_str(txid) {
+        Ok(txid) => Ok(txid.to_string()),
+        Err(_) => Err(WalletError::Generic {
+            reason: format!("bad txid {}", txid),
+        }),
+    }
+}
+
+/// What `tx` did to the wallet locking its coins with `scripts`. `spent` are the outputs its
+/// inputs spend, in input order, empty for a coinbase.
+pub fn wallet_entry(
+    txid: &str,
+    tx: &Transaction,
+    spent: &[Output],
+    scripts: &HashSet<Vec<u8>>,
+    height: u32,
+    timestamp: u32,
+) -> Result<HistoryEntry, WalletError> {
+    let txid = history_txid(txid)?;
+    let mut net_sats: i64 = 0;
+    let mut tokens: BTreeMap<TxId, (i128, i32)> = BTreeMap::new();
+    let mut apply = |output: &Output, sign: i64| {
+        if !scripts.contains(&output.script.bytecode()[..]) {
+            return;
+        }
+        net_sats += sign * output.value as i64;
+        if let Some(token) = output.token.as_ref() {
+            let delta = tokens.entry(token.category).or_default();
+            delta.0 += sign as i128 * token.amount.0 as i128;
+            if token.nft.is_some() {
+                delta.1 += sign as i32;
+            }
+        }
+    };
+    for output in spent {
+        apply(output, -1);
+    }
+    for output in tx.outputs.iter() {
+        apply(output, 1);
+    }
+    // the fee of a payment to the wallet was paid by the sender
+    let funded = !spent.is_empty()
+        && spent
+            .iter()
+            .all(|output| scripts.contains(&output.script.bytecode()[..]));
+    let fee = match funded {
+        true => {
+            let spent_value: u64 = spent.iter().map(|output| output.value).sum();
+            let output_value: u64 = tx.outputs.iter().map(|output| output.value).sum();
+            spent_value.saturating_sub(output_value)
+        }
+        false => 0,
+    };
+    Ok(HistoryEntry {
+        txid,
+        height,
+        timestamp,
+        net_sats,
+        tokens: tokens
+            .into_iter()
+            .filter(|(_, (fungible, nfts))| *fungible != 0 || *nfts != 0)
+            .map(|(category, (fungible, nfts))| TokenDelta {
+                category,
+                fungible,
+                nfts,
+            })
+            .collect(),
+        fee,
+    })
+}
+
+/// Txids stored for one address, 32 bytes each.
+fn decode_txids(bytes: &[u8]) -> Result<BTreeSet<String>, WalletError> {
+    if bytes.len() % 32 != 0 {
+        return Err(WalletError::DataBaseError {
+            reason: format!("malformed record in {}", ADDRESS_HISTORY_TREE),
+        });
+    }
+    Ok(bytes.chunks(32).map(hex::encode).collect())
+}
+
+/// Txids the history of `address` listed when it was last synced.
+pub fn address_txids(db: &sled::Db, address: &str) -> Result<BTreeSet<String>, WalletError> {
+    match db
+        .open_tree(ADDRESS_HISTORY_TREE)?
+        .get(address.as_bytes())?
+    {
+        Some(bytes) => decode_txids(&bytes),
+        None => Ok(BTreeSet::new()),
+    }
+}
+
+fn encode_txids(txids: &BTreeSet<String>) -> Vec<u8> {
+    txids
+        .iter()
+        .flat_map(|txid| hex::decode(txid).unwrap_or_default())
+        .collect()
+}
+
+fn read_tip(tree: &sled::Tree) -> Result<u32, WalletError> {
+    match tree.get(TIP_KEY)? {
+        Some(bytes) => Ok(Reader::new(&bytes).u32().unwrap_or(0)),
+        None => Ok(0),
+    }
+}
+
+fn read_entries(tree: &sled::Tree) -> Result<Vec<HistoryEntry>, WalletError> {
+    let mut entries = vec![];
+    for entry in tree.iter() {
+        let (key, bytes) = entry?;
+        if key.as_ref() == TIP_KEY.as_bytes() {
+            continue;
+        }
+        match HistoryEntry::decode(&bytes) {
+            Some(entry) => entries.push(entry),
+            None => {
+                return Err(WalletError::DataBaseError {
+                    reason: format!("malformed record in {}", HISTORY_TREE),
+                })
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Records the height of a new block. Entries above it were reorged out and are unconfirmed
+/// again until the next sync.
+pub fn set_tip(db: &sled::Db, height: u32) -> Result<(), WalletError> {
+    let tree = db.open_tree(HISTORY_TREE)?;
+    let mut batch = sled::Batch::default();
+    for mut entry in read_entries(&tree)? {
+        if entry.height > height {
+            entry.height = 0;
+            batch.insert(entry.txid.as_bytes(), entry.encode());
+        }
+    }
+    batch.insert(TIP_KEY, &height.to_le_bytes()[..]);
+    Ok(tree.apply_batch(batch)?)
+}
+
+/// Newest first: unconfirmed entries, then by height, then by timestamp.
+fn sort_newest_first(entries: &mut [HistoryEntry]) {
+    entries.sort_by(|a, b| {
+        let height = |entry: &HistoryEntry| match entry.height {
+            0 => u32::MAX,
+            height => height,
+        };
+        height(b)
+            .cmp(&height(a))
+            .then(b.timestamp.cmp(&a.timestamp))
+            .then(a.txid.cmp(&b.txid))
+    });
+}
+
+/// `limit` entries matching `filter` starting at `offset`, newest first. Also returns the
+/// number of matching entries and the tip the confirmations are counted from.
+pub fn history_page(
+    db: &sled::Db,
+    filter: &HistoryFilter,
+    offset: usize,
+    limit: usize,
+) -> Result<(Vec<HistoryEntry>, usize, u32), WalletError> {
+    let tree = db.open_tree(HISTORY_TREE)?;
+    let mut entries: Vec<HistoryEntry> = read_entries(&tree)?
+        .into_iter()
+        .filter(|entry| filter.matches(entry))
+        .collect();
+    sort_newest_first(&mut entries);
+    let total = entries.len();
+    let page = entries.into_iter().skip(offset).take(limit).collect();
+    Ok((page, total, read_tip(&tree)?))
+}
+
+/// Brings the history of `addresses` in line with the server. New transactions are fetched
+/// and netted over `wallet`, every address of the wallet, confirmed ones get their block
+/// time. Transactions that left the histories of `addresses` are dropped unless another
+/// address still lists them. Returns the changed txids.
+pub async fn sync_history(
+    db: &sled::Db,
+    addresses: &[String],
+    wallet: &[String],
+    connection: &ElectrumConnection,
+) -> Result<Vec<String>, WalletError> {
+    let mut heights: BTreeMap<String, u32> = BTreeMap::new();
+    let mut listed: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    let histories = get_address_history_batch(addresses, connection).await?;
+    for (address, history) in addresses.iter().zip(histories) {
+        let history: Value = serde_json::from_str(&history)?;
+        let txids = listed.entry(address.clone()).or_default();
+        for tx in history.as_array().cloned().unwrap_or_default() {
+            let txid = match tx["tx_hash"].as_str() {
+                Some(txid) => history_txid(txid)?,
+                None => continue,
+            };
+            let height = tx["height"].as_i64().unwrap_or(0).max(0) as u32;
+            txids.insert(txid.clone());
+            let known = heights.entry(txid).or_insert(height);
+            *known = (*known).max(height);
+        }
+    }
+    let highest = heights.values().copied().max().unwrap_or(0);
+    let headers = HeaderStore::wallet(db)?;
+    if highest > 0 && headers.tip_height()?.map_or(true, |tip| tip < highest) {
+        sync_headers(&headers, connection).await?;
+    }
+    if let Some(lowest) = heights.values().copied().filter(|height| *height > 0).min() {
+        sync_headers_down(&headers, lowest, connection).await?;
+    }
+
+    let tree = db.open_tree(HISTORY_TREE)?;
+    let known: HashMap<String, HistoryEntry> = read_entries(&tree)?
+        .into_iter()
+        .map(|entry| (entry.txid.clone(), entry))
+        .collect();
+    let mut scripts = HashSet::new();
+    for address in wallet.iter().chain(addresses) {
+        scripts.insert(address_to_script(address)?.bytecode().to_vec());
+    }
+    let block_time = |height: u32| -> Result<Option<u32>, WalletError> {
+        match height {
+            0 => Ok(None),
+            height => Ok(headers.get_header(height)?.map(|header| header.time())),
+        }
+    };
+
+    // dropped from the mempool or replaced, unless an address not synced here lists it
+    let address_tree = db.open_tree(ADDRESS_HISTORY_TREE)?;
+    let mut dropped = BTreeSet::new();
+    let mut address_batch = sled::Batch::default();
+    for (address, txids) in listed.iter() {
+        if let Some(bytes) = address_tree.get(address.as_bytes())? {
+            dropped.extend(decode_txids(&bytes)?.difference(txids).cloned());
+        }
+        address_batch.insert(address.as_bytes(), encode_txids(txids));
+    }
+    for item in address_tree.iter() {
+        let (address, bytes) = item?;
+        if !listed.contains_key(String::from_utf8_lossy(&address).as_ref()) {
+            for txid in decode_txids(&bytes)? {
+                dropped.remove(&txid);
+            }
+        }
+    }
+
+    let mut changed = vec![];
+    let mut batch = sled::Batch::default();
+    dropped.retain(|txid| !heights.contains_key(txid) && known.contains_key(txid));
+    for txid in dropped {
+        batch.remove(txid.as_bytes());
+        changed.push(txid);
+    }
+    let new_txids: Vec<String> = heights
+        .keys()
+        .filter(|txid| !known.contains_key(*txid))
+        .cloned()
+        .collect();
+    let raw_txs: HashMap<String, String> = new_txids
+        .iter()
+        .cloned()
+        .zip(fetch_transactions(db, &new_txids, connection).await?)
+        .collect();
+    for (txid, height) in heights {
+        let entry = match known.get(&txid) {
+            Some(entry) if entry.height == height => continue,
+            Some(entry) => {
+                let mut entry = entry.clone();
+                entry.height = height;
+                if let Some(time) = block_time(height)? {
+                    entry.timestamp = time;
+                }
+                entry
+            }
+            None => {
+                let tx = decode_transaction(&txid, &raw_txs[&txid])?;
+                let spent = match is_coinbase(&tx) {
+                    true => vec![],
+                    false => spent_outputs(db, &tx, connection).await?,
+                };
+                let timestamp = block_time(height)?.unwrap_or_else(now_secs);
+                wallet_entry(&txid, &tx, &spent, &scripts, height, timestamp)?
+            }
+        };
+        batch.insert(txid.as_bytes(), entry.encode());
+        changed.push(txid);
+    }
+    if read_tip(&tree)? < highest {
+        batch.insert(TIP_KEY, &highest.to_le_bytes()[..]);
+    }
+    tree.apply_batch(batch)?;
+    address_tree.apply_batch(address_batch)?;
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::mock::MockElectrum;
+
+    // coinbase of block 9 and the transaction in block 170 spending it
+    const COINBASE_9: &str = "01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff0704ffff001d0134ffffffff0100f2052a0100000043410411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3ac00000000";
+    const TX_170: &str = "0100000001c997a5e56e104102fa209c6a852dd90660a20b2d9c352423edce25857fcd3704000000004847304402204e45e16932b8af514961a1d3a1a25fdf3f4f7732e9d624c6c61548ab5fb8cd410220181522ec8eca07de4860a4acdd12909d831cc56cbbac4622082221a8768d1d0901ffffffff0200ca9a3b00000000434104ae1a62fe09c5f51b13905f07f06b99a2f7159b2225f374cd378d71302fa28414e7aab37397f554a7df5f142c21c1b7303b8a0626f1baded5c72a704f7e6cd84cac00286bee0000000043410411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3ac00000000";
+    const TX_170_TXID: &str = "f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16";
+    const COINBASE_9_TXID: &str =
+        "0437cd7f8525ceed2324359c2d0ba26006d92d856a9c20fa0241106ee5a597c9";
+
+    #[test]
+    fn netted_over_the_wallet() {
+        let coinbase = decode_transaction("", COINBASE_9).unwrap();
+        let tx = decode_transaction(TX_170_TXID, TX_170).unwrap();
+        assert!(is_coinbase(&coinbase));
+        assert!(!is_coinbase(&tx));
+        // the wallet of block 9's miner, which sent 10 BCH and kept 40
+        let scripts: HashSet<Vec<u8>> = [coinbase.outputs[0].script.bytecode().to_vec()].into();
+        let entry = wallet_entry(
+            TX_170_TXID,
+            &tx,
+            &coinbase.outputs,
+            &scripts,
+            170,
+            1231731025,
+        )
+        .unwrap();
+        assert_eq!(entry.net_sats, -1_000_000_000);
+        assert_eq!(entry.fee, 0);
+        assert!(entry.tokens.is_empty());
+        assert_eq!(entry.confirmations(170), 1);
+        assert_eq!(entry.confirmations(169), 0);
+        assert_eq!(HistoryEntry::decode(&entry.encode()), Some(entry.clone()));
+
+        let received =
+            wallet_entry(COINBASE_9_TXID, &coinbase, &[], &scripts, 9, 1231473279).unwrap();
+        assert_eq!(received.net_sats, 5_000_000_000);
+        assert!(wallet_entry("aa", &coinbase, &[], &scripts, 9, 1231473279).is_err());
+
+        // the receiver of the 10 BCH didn't fund the transaction, no fee is theirs
+        let receiver: HashSet<Vec<u8>> = [tx.outputs[0].script.bytecode().to_vec()].into();
+        let payment = wallet_entry(TX_170_TXID, &tx, &coinbase.outputs, &receiver, 170, 0);
+        assert_eq!(payment.unwrap().fee, 0);
+        let incoming = HistoryFilter {
+            incoming: Some(true),
+            ..Default::default()
+        };
+        assert!(incoming.matches(&received));
+        assert!(!incoming.matches(&entry));
+        let recent = HistoryFilter {
+            min_height: Some(100),
+            ..Default::default()
+        };
+        assert!(recent.matches(&entry));
+        assert!(!recent.matches(&received));
+
+        // tokens coming in with no satoshis moving are incoming too
+        let tokens_received = HistoryEntry {
+            net_sats: 0,
+            tokens: vec![TokenDelta {
+                category: TxId::from_str(TX_170_TXID).unwrap(),
+                fungible: 100,
+                nfts: 0,
+            }],
+            ..entry.clone()
+        };
+        assert!(incoming.matches(&tokens_received));
+        let outgoing = HistoryFilter {
+            incoming: Some(false),
+            ..Default::default()
+        };
+        assert!(!outgoing.matches(&tokens_received));
+        assert!(outgoing.matches(&entry));
+    }
+
+    #[test]
+    fn newest_first() {
+        let entry = |txid: &str, height: u32, timestamp: u32| HistoryEntry {
+            txid: txid.repeat(32),
+            height,
+            timestamp,
+            net_sats: 0,
+            tokens: vec![],
+            fee: 0,
+        };
+        let mut entries = vec![
+            entry("aa", 10, 100),
+            entry("bb", 0, 50),
+            entry("cc", 12, 120),
+            entry("dd", 0, 60),
+        ];
+        sort_newest_first(&mut entries);
+        let order: Vec<&str> = entries.iter().map(|e| &e.txid[..2]).collect();
+        assert_eq!(order, vec!["dd", "bb", "cc", "aa"]);
+    }
+
+    #[tokio::test]
+    async fn drops_only_what_left_the_synced_address() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mock = MockElectrum::start();
+        let coinbase = mock.add_transaction(COINBASE_9);
+        let payment = mock.add_transaction(TX_170);
+        let wallet = vec![
+            "bchtest:qptnz3u8atavszhaqk037v0fjrtahxmsl5mm45u3pf".to_string(),
+            "bchtest:qzxu4ynqdgyjr2hvt5xcx7x35ncdz8zffsf2hgn9mp".to_string(),
+        ];
+        mock.set_history(&wallet[0], vec![(coinbase.clone(), 0)]);
+        mock.set_history(
+            &wallet[1],
+            vec![(coinbase.clone(), 0), (payment.clone(), 0)],
+        );
+        let connection = ElectrumConnection::new(&mock.url(), Default::default());
+        assert_eq!(
+            sync_history(&db, &wallet, &wallet, &connection)
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+
+        mock.set_history(&wallet[0], vec![]);
+        mock.set_history(&wallet[1], vec![(coinbase, 0)]);
+        // the second address still lists the coinbase and wasn't synced yet
+        let changed = sync_history(&db, &wallet[..1], &wallet, &connection)
+            .await
+            .unwrap();
+        assert!(changed.is_empty());
+        let changed = sync_history(&db, &wallet[1..], &wallet, &connection)
+            .await
+            .unwrap();
+        assert_eq!(changed, vec![payment]);
+    }
+}
@@ -1 +1,5 @@
+pub mod history;
+pub mod pending;
+pub mod schema;
 pub mod storage;
+pub mod transactions;
@@ -0,0 +1,408 @@
+//! Spends the wallet made that the stored utxos don't show yet. A sent transaction marks the
+//! utxos it spends as pending-spent and keeps its outputs to wallet addresses as unconfirmed
+//! utxos, so a second send right after the first can't pick the same coins. Each part of a
+//! pending spend is dropped once the server shows it: change when `listunspent` lists it, an
+//! input when it leaves the list after being listed or the spending transaction shows up in
+//! the address history. A rejected, conflicted or forgotten transaction drops all of it,
+//! there is no expiry.
+use std::collections::{BTreeSet, HashSet};
+use std::str::FromStr;
+
+use bitcoinsuite_core::tx::{OutPoint, Transaction, TxId};
+
+use crate::address::script_address_hash;
+use crate::encoding::{write_varbytes, Reader};
+use crate::error::WalletError;
+use crate::store::history::address_txids;
+use crate::store::schema::{
+    utxo_owner, AddressRecord, Record, UtxoRecord, ADDRESS_TREE, UTXO_TREE,
+};
+use crate::store::transactions::decode_transaction;
+
+/// sled tree of pending spends keyed by the txid hex of the spending transaction
+pub static PENDING_TREE: &str = "pending";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingInput {
+    pub address_hash: Vec<u8>,
+    pub outpoint: OutPoint,
+    /// The server listed the outpoint since the spend was made, or before for a stored utxo.
+    /// Until then it not being listed says nothing about the spend.
+    pub seen: bool,
+}
+
+/// Inputs and wallet outputs of a sent transaction, each with the hash of its address.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingSpend {
+    pub txid: String,
+    /// Spent utxos the server may still list
+    pub inputs: Vec<PendingInput>,
+    /// Outputs to wallet addresses the server may not list yet
+    pub change: Vec<(Vec<u8>, UtxoRecord)>,
+}
+
+impl PendingSpend {
+    /// Pending spend of `tx`. Inputs are looked up in the stored utxos and the unconfirmed
+    /// change of `pending`, outputs count as change when they pay an address of an input or
+    /// one of `wallet_hashes`.
+    pub fn new(
+        txid: &str,
+        tx: &Transaction,
+        utxos: &sled::Tree,
+        pending: &[PendingSpend],
+        wallet_hashes: &HashSet<Vec<u8>>,
+    ) -> Result<Self, WalletError> {
+        let mut inputs = vec![];
+        for input in tx.inputs.iter() {
+            let owner = match utxo_owner(utxos, &input.prev_out)? {
+                Some(owner) => Some((owner, true)),
+                // spends the change of a transaction the server hasn't reported yet
+                None => pending
+                    .iter()
+                    .flat_map(|spend| spend.change.iter())
+                    .find(|(_, utxo)| utxo.outpoint == input.prev_out)
+                    .map(|(hash, _)| (hash.clone(), false)),
+            };
+            if let Some((address_hash, seen)) = owner {
+                inputs.push(PendingInput {
+                    address_hash,
+                    outpoint: input.prev_out.clone(),
+                    seen,
+                });
+            }
+        }
+        let spending = match TxId::from_str(txid) {
+            Ok(spending) => spending,
+            Err(_) => {
+                return Err(WalletError::Generic {
+                    reason: format!("bad txid {}", txid),
+                })
+            }
+        };
+        let mut change = vec![];
+        for (index, output) in tx.outputs.iter().enumerate() {
+            let hash = match script_address_hash(&output.script) {
+                Some(hash) => hash,
+                None => continue,
+            };
+            let ours = wallet_hashes.contains(&hash)
+                || inputs.iter().any(|input| input.address_hash == hash);
+            if !ours {
+                continue;
+            }
+            let utxo = UtxoRecord {
+                outpoint: OutPoint {
+                    txid: spending,
+                    outpoint_index: index as u32,
+                },
+                height: 0,
+                value: output.value,
+                token: output.token.clone(),
+            };
+            change.push((hash, utxo));
+        }
+        Ok(PendingSpend {
+            txid: txid.to_string(),
+            inputs,
+            change,
+        })
+    }
+
+    pub fn is_settled(&self) -> bool {
+        self.inputs.is_empty() && self.change.is_empty()
+    }
+}
+
+impl Record for PendingSpend {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = hex::decode(&self.txid).unwrap_or_default();
+        out.extend_from_slice(&(self.inputs.len() as u32).to_le_bytes());
+        for input in self.inputs.iter() {
+            write_varbytes(&mut out, &input.address_hash);
+            let txid = input.outpoint.txid.to_string();
+            out.extend_from_slice(&hex::decode(txid).unwrap_or_default());
+            out.extend_from_slice(&input.outpoint.outpoint_index.to_le_bytes());
+            out.push(input.seen as u8);
+        }
+        out.extend_from_slice(&(self.change.len() as u32).to_le_bytes());
+        for (hash, utxo) in self.change.iter() {
+            write_varbytes(&mut out, hash);
+            write_varbytes(&mut out, &utxo.encode());
+        }
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader::new(bytes);
+        let txid = hex::encode(reader.bytes(32)?);
+        let mut inputs = vec![];
+        for _ in 0..reader.u32()? {
+            let address_hash = reader.varbytes()?.to_vec();
+            let outpoint = OutPoint {
+                txid: TxId::from_str(&hex::encode(reader.bytes(32)?)).ok()?,
+                outpoint_index: reader.u32()?,
+            };
+            let seen = match reader.u8()? {
+                0 => false,
+                1 => true,
+                _ => return None,
+            };
+            inputs.push(PendingInput {
+                address_hash,
+                outpoint,
+                seen,
+            });
+        }
+        let mut change = vec![];
+        for _ in 0..reader.u32()? {
+            let hash = reader.varbytes()?.to_vec();
+            change.push((hash, UtxoRecord::decode(reader.varbytes()?)?));
+        }
+        Some(PendingSpend {
+            txid,
+            inputs,
+            change,
+        })
+    }
+}
+
+fn read_pending(tree: &sled::Tree) -> Result<Vec<PendingSpend>, WalletError> {
+    let mut pending = vec![];
+    for entry in tree.iter() {
+        let (_, bytes) = entry?;
+        match PendingSpend::decode(&bytes) {
+            Some(spend) => pending.push(spend),
+            None => {
+                return Err(WalletError::DataBaseError {
+                    reason: format!("malformed record in {}", PENDING_TREE),
+                })
+            }
+        }
+    }
+    Ok(pending)
+}
+
+pub fn pending_spends(db: &sled::Db) -> Result<Vec<PendingSpend>, WalletError> {
+    read_pending(&db.open_tree(PENDING_TREE)?)
+}
+
+/// Marks the inputs of a sent transaction as pending-spent and its change as unconfirmed.
+/// A transaction already marked keeps what was reconciled of it.
+pub fn mark_pending(db: &sled::Db, txid: &str, raw_tx: &str) -> Result<(), WalletError> {
+    let tree = db.open_tree(PENDING_TREE)?;
+    if tree.contains_key(txid.as_bytes())? {
+        return Ok(());
+    }
+    let tx = decode_transaction(txid, raw_tx)?;
+    let mut wallet_hashes = HashSet::new();
+    for entry in db.open_tree(ADDRESS_TREE)?.iter() {
+        let (_, bytes) = entry?;
+        if let Some(record) = AddressRecord::decode(&bytes) {
+            wallet_hashes.insert(record.pubkey_hash);
+        }
+    }
+    let spend = PendingSpend::new(
+        txid,
+        &tx,
+        &db.open_tree(UTXO_TREE)?,
+        &read_pending(&tree)?,
+        &wallet_hashes,
+    )?;
+    tree.insert(txid.as_bytes(), spend.encode())?;
+    Ok(())
+}
+
+/// Forgets the pending spend of a transaction that won't confirm, its inputs are spendable
+/// again.
+pub fn drop_pending(db: &sled::Db, txid: &str) -> Result<bool, WalletError> {
+    Ok(db
+        .open_tree(PENDING_TREE)?
+        .remove(txid.as_bytes())?
+        .is_some())
+}
+
+/// `utxos` of an address without the pending-spent ones, plus the unconfirmed change paid
+/// to it.
+pub fn apply_pending(
+    pending: &[PendingSpend],
+    address_hash: &[u8],
+    utxos: Vec<UtxoRecord>,
+) -> Vec<UtxoRecord> {
+    let spent: Vec<&OutPoint> = pending
+        .iter()
+        .flat_map(|spend| spend.inputs.iter().map(|input| &input.outpoint))
+        .collect();
+    let mut utxos: Vec<UtxoRecord> = utxos
+        .into_iter()
+        .filter(|utxo| !spent.contains(&&utxo.outpoint))
+        .collect();
+    for (hash, utxo) in pending.iter().flat_map(|spend| spend.change.iter()) {
+        let known = utxos.iter().any(|u| u.outpoint == utxo.outpoint);
+        if hash == address_hash && !known && !spent.contains(&&utxo.outpoint) {
+            utxos.push(utxo.clone());
+        }
+    }
+    utxos
+}
+
+/// Updates `pending` with a `listunspent` answer for an address and the txids of its
+/// history. An input the server listed and no longer lists, or whose spending transaction
+/// is in `history`, is spent as far as the stored utxos go. Change the server lists is
+/// stored. Change spent by a settled input is dropped with it, it won't be listed again.
+/// Returns true when anything was dropped.
+pub fn reconcile(
+    pending: &mut [PendingSpend],
+    address_hash: &[u8],
+    listed: &[UtxoRecord],
+    history: &BTreeSet<String>,
+) -> bool {
+    let is_listed = |outpoint: &OutPoint| listed.iter().any(|utxo| utxo.outpoint == *outpoint);
+    let mut settled = vec![];
+    let mut changed = false;
+    for spend in pending.iter_mut() {
+        let before = spend.inputs.len() + spend.change.len();
+        let in_history = history.contains(&spend.txid.to_lowercase());
+        for input in spend.inputs.iter_mut() {
+            if input.address_hash != address_hash {
+                continue;
+            }
+            let listed = is_listed(&input.outpoint);
+            if in_history || (input.seen && !listed) {
+                settled.push(input.outpoint.clone());
+            }
+            input.seen = input.seen || listed;
+        }
+        spend
+            .inputs
+            .retain(|input| !settled.contains(&input.outpoint));
+        spend
+            .change
+            .retain(|(hash, utxo)| !(hash == address_hash && is_listed(&utxo.outpoint)));
+        changed = changed || spend.inputs.len() + spend.change.len() != before;
+    }
+    for spend in pending.iter_mut() {
+        let before = spend.change.len();
+        spend
+            .change
+            .retain(|(_, utxo)| !settled.contains(&utxo.outpoint));
+        changed = changed || spend.change.len() != before;
+    }
+    changed
+}
+
+/// Reconciles the pending spends with the utxos the server listed for `address` and its
+/// last synced history, the settled ones are removed. Returns true when the utxos shown for
+/// the wallet changed.
+pub fn reconcile_pending(
+    db: &sled::Db,
+    address: &str,
+    address_hash: &[u8],
+    listed: &[UtxoRecord],
+) -> Result<bool, WalletError> {
+    let tree = db.open_tree(PENDING_TREE)?;
+    let mut pending = read_pending(&tree)?;
+    if pending.is_empty() {
+        return Ok(false);
+    }
+    let before = pending.clone();
+    let changed = reconcile(
+        &mut pending,
+        address_hash,
+        listed,
+        &address_txids(db, address)?,
+    );
+    for (spend, old) in pending.iter().zip(before.iter()) {
+        if spend.is_settled() {
+            tree.remove(spend.txid.as_bytes())?;
+        } else if spend != old {
+            tree.insert(spend.txid.as_bytes(), spend.encode())?;
+        }
+    }
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utxo(txid: &str, index: u32, value: u64) -> UtxoRecord {
+        UtxoRecord {
+            outpoint: OutPoint {
+                txid: TxId::from_str(txid).unwrap(),
+                outpoint_index: index,
+            },
+            height: 0,
+            value,
+            token: None,
+        }
+    }
+
+    #[test]
+    fn pending_until_listed() {
+        let (ours, other) = (vec![1u8; 20], vec![2u8; 20]);
+        let (funding, send, next) = ("aa".repeat(32), "bb".repeat(32), "cc".repeat(32));
+        let coin = utxo(&funding, 0, 10_000);
+        let change = utxo(&send, 1, 4_000);
+        let input = |outpoint: &OutPoint, seen: bool| PendingInput {
+            address_hash: ours.clone(),
+            outpoint: outpoint.clone(),
+            seen,
+        };
+        let mut pending = vec![
+            PendingSpend {
+                txid: send.clone(),
+                inputs: vec![input(&coin.outpoint, true)],
+                change: vec![(ours.clone(), change.clone())],
+            },
+            // spends the change before any server listed it
+            PendingSpend {
+                txid: next.clone(),
+                inputs: vec![input(&change.outpoint, false)],
+                change: vec![],
+            },
+        ];
+        for spend in pending.iter() {
+            assert_eq!(PendingSpend::decode(&spend.encode()).as_ref(), Some(spend));
+        }
+        let unspent = utxo(&funding, 1, 500);
+        let stored = vec![coin.clone(), unspent.clone()];
+        assert_eq!(
+            apply_pending(&pending[..1], &ours, stored.clone()),
+            vec![unspent.clone(), change.clone()]
+        );
+        assert_eq!(
+            apply_pending(&pending, &ours, stored.clone()),
+            vec![unspent.clone()]
+        );
+        assert_eq!(apply_pending(&pending, &other, vec![]), vec![]);
+
+        let no_history = BTreeSet::new();
+        assert!(!reconcile(&mut pending, &other, &[], &no_history));
+        // the server hasn't seen either send, the unlisted change says nothing yet
+        assert!(!reconcile(&mut pending, &ours, &stored, &no_history));
+        assert_eq!(pending[1].inputs, vec![input(&change.outpoint, false)]);
+        // it saw the first send, the coin is spent and the change listed
+        let listed = vec![unspent.clone(), change.clone()];
+        assert!(reconcile(&mut pending, &ours, &listed, &no_history));
+        assert!(pending[0].is_settled());
+        assert_eq!(pending[1].inputs, vec![input(&change.outpoint, true)]);
+        assert_eq!(
+            apply_pending(&pending, &ours, listed.clone()),
+            vec![unspent.clone()]
+        );
+        // then the second, the change it spends is gone
+        let listed = vec![unspent];
+        assert!(reconcile(&mut pending, &ours, &listed, &no_history));
+        assert!(pending[1].is_settled());
+
+        // an input never listed settles once its spend is in the address history
+        let mut unseen = vec![PendingSpend {
+            txid: next.clone(),
+            inputs: vec![input(&change.outpoint, false)],
+            change: vec![],
+        }];
+        assert!(!reconcile(&mut unseen, &ours, &listed, &no_history));
+        assert!(reconcile(&mut unseen, &ours, &listed, &[next].into()));
+        assert!(unseen[0].is_settled());
+    }
+}
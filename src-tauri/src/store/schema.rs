@@ -0,0 +1,700 @@
+//! Layout of the wallet database. Wallet data lives in named trees holding typed, binary
+//! encoded records, the default tree only keeps the schema version and the wallet
+//! passphrase. `migrate` upgrades a database written by an older release before anything
+//! else reads it.
+use std::str::FromStr;
+
+use bitcoinsuite_core::ser::CompactUint;
+use bitcoinsuite_core::tx::{
+    Capability, CashToken, Commitment, NonFungibleTokenCapability, OutPoint, TxId, NFT,
+};
+use serde_json::{json, Value};
+
+use crate::coins::utxo::token_json;
+use crate::encoding::{write_str, write_varbytes, Reader};
+use crate::error::WalletError;
+use crate::network::pool::SERVERS_KEY;
+use crate::network::proxy::{ProxyConfig, PROXY_KEY};
+use crate::network::tls::CERTIFICATE_TREE;
+use crate::spv::headers::{CHAINWORK_TREE, HEADER_TREE};
+use crate::spv::SPV_TREE;
+use crate::store::history::{ADDRESS_HISTORY_TREE, HISTORY_TREE};
+use crate::store::pending::PENDING_TREE;
+use crate::store::storage::SETTINGS_TREE;
+use crate::store::transactions::TX_TREE;
+use crate::tokens::bcmr::{TokenMetadata, TOKEN_TREE};
+use crate::tokens::supply::SUPPLY_TREE;
+use crate::transaction::broadcast::{OutgoingTx, OUTGOING_TREE};
+
+/// Version written by this release
+pub const SCHEMA_VERSION: u32 = 2;
+/// Default tree key of the schema version, a big endian u32. Databases without one are
+/// version 0.
+pub static SCHEMA_VERSION_KEY: &str = "schema_version";
+
+/// Utxos keyed by the hash of the address that can spend them, then the outpoint
+pub static UTXO_TREE: &str = "utxos";
+/// Derived key hashes keyed by chain and index
+pub static ADDRESS_TREE: &str = "addresses";
+/// User labels keyed by txid or address
+pub static LABEL_TREE: &str = "labels";
+
+type Migration = fn(&sled::Db) -> Result<(), WalletError>;
+
+/// `MIGRATIONS[n]` upgrades a version `n` database to `n + 1`.
+const MIGRATIONS: [Migration; SCHEMA_VERSION as usize] = [split_default_tree, encode_json_records];
+
+/// Binary encoding of the values of a typed tree.
+pub trait Record: Sized {
+    fn encode(&self) -> Vec<u8>;
+    fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+fn record_err(tree: &str) -> WalletError {
+    WalletError::DataBaseError {
+        reason: format!("malformed record in {}", tree),
+    }
+}
+
+fn txid_bytes(txid: &TxId) -> Vec<u8> {
+    hex::decode(txid.to_string()).unwrap_or_default()
+}
+
+fn read_txid(reader: &mut Reader) -> Option<TxId> {
+    TxId::from_str(&hex::encode(reader.bytes(32)?)).ok()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct UtxoRecord {
+    pub outpoint: OutPoint,
+    /// 0 while unconfirmed or not proven
+    pub height: u32,
+    pub value: u64,
+    pub token: Option<CashToken>,
+}
+
+impl UtxoRecord {
+    /// Record of a `listunspent` entry.
+    pub fn from_json(utxo: &Value) -> Option<Self> {
+        let outpoint = OutPoint {
+            txid: TxId::from_str(utxo["tx_hash"].as_str()?).ok()?,
+            outpoint_index: utxo["tx_pos"].as_u64()? as u32,
+        };
+        let token = match utxo["token_data"].as_object() {
+            Some(token_data) => {
+                let nft = match token_data.get("nft") {
+                    Some(nft) => {
+                        let capability = match nft["capability"].as_str()? {
+                            "none" => Capability::None,
+                            "mutable" => Capability::Mutable,
+                            "minting" => Capability::Minting,
+                            _ => return None,
+                        };
+                        let commitment = hex::decode(nft["commitment"].as_str().unwrap_or(""));
+                        Some(NFT {
+                            capability: NonFungibleTokenCapability(capability),
+                            commitment: Commitment(commitment.ok()?.into()),
+                        })
+                    }
+                    None => None,
+                };
+                Some(CashToken {
+                    amount: CompactUint(token_data["amount"].as_str()?.parse().ok()?),
+                    category: TxId::from_str(token_data["category"].as_str()?).ok()?,
+                    nft,
+                })
+            }
+            None => None,
+        };
+        Some(UtxoRecord {
+            outpoint,
+            height: utxo["height"].as_u64()? as u32,
+            value: utxo["value"].as_u64()?,
+            token,
+        })
+    }
+
+    /// Records of a whole `listunspent` response, failing on the first entry that can't be
+    /// read rather than losing it.
+    pub fn from_json_list(utxos: &[Value]) -> Result<Vec<Self>, WalletError> {
+        utxos
+            .iter()
+            .map(|utxo| {
+                UtxoRecord::from_json(utxo).ok_or_else(|| WalletError::DataBaseError {
+                    reason: format!("unreadable utxo {}", utxo),
+                })
+            })
+            .collect()
+    }
+
+    /// `listunspent` entry of the record.
+    pub fn to_json(&self) -> Value {
+        let mut utxo = json!({
+            "tx_hash": self.outpoint.txid.to_string(),
+            "tx_pos": self.outpoint.outpoint_index,
+            "height": self.height,
+            "value": self.value,
+        });
+        if let Some(token) = self.token.as_ref() {
+            utxo["token_data"] = token_json(token);
+        }
+        utxo
+    }
+}
+
+impl Record for UtxoRecord {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = txid_bytes(&self.outpoint.txid);
+        out.extend_from_slice(&self.outpoint.outpoint_index.to_le_bytes());
+        out.extend_from_slice(&self.height.to_le_bytes());
+        out.extend_from_slice(&self.value.to_le_bytes());
+        match self.token.as_ref() {
+            Some(token) => {
+                out.push(1);
+                out.extend_from_slice(&txid_bytes(&token.category));
+                out.extend_from_slice(&token.amount.0.to_le_bytes());
+                match token.nft.as_ref() {
+                    Some(nft) => {
+                        out.push(match nft.capability.0 {
+                            Capability::None => 1,
+                            Capability::Mutable => 2,
+                            Capability::Minting => 3,
+                        });
+                        write_varbytes(&mut out, &nft.commitment.0);
+                    }
+                    None => out.push(0),
+                }
+            }
+            None => out.push(0),
+        }
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader::new(bytes);
+        let outpoint = OutPoint {
+            txid: read_txid(&mut reader)?,
+            outpoint_index: reader.u32()?,
+        };
+        let height = reader.u32()?;
+        let value = reader.u64()?;
+        let token = match reader.u8()? {
+            0 => None,
+            _ => {
+                let category = read_txid(&mut reader)?;
+                let amount = CompactUint(reader.u64()?);
+                let capability = match reader.u8()? {
+                    0 => None,
+                    1 => Some(Capability::None),
+                    2 => Some(Capability::Mutable),
+                    3 => Some(Capability::Minting),
+                    _ => return None,
+                };
+                let nft = match capability {
+                    Some(capability) => Some(NFT {
+                        capability: NonFungibleTokenCapability(capability),
+                        commitment: Commitment(reader.varbytes()?.to_vec().into()),
+                    }),
+                    None => None,
+                };
+                Some(CashToken {
+                    amount,
+                    category,
+                    nft,
+                })
+            }
+        };
+        Some(UtxoRecord {
+            outpoint,
+            height,
+            value,
+            token,
+        })
+    }
+}
+
+/// Hash of a derived public key. `change` is the internal chain.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AddressRecord {
+    pub change: bool,
+    pub index: u32,
+    pub pubkey_hash: Vec<u8>,
+}
+
+impl AddressRecord {
+    pub fn key(&self) -> Vec<u8> {
+        address_key(self.change, self.index)
+    }
+}
+
+impl Record for AddressRecord {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = vec![self.change as u8];
+        out.extend_from_slice(&self.index.to_le_bytes());
+        write_varbytes(&mut out, &self.pubkey_hash);
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader::new(bytes);
+        Some(AddressRecord {
+            change: reader.u8()? == 1,
+            index: reader.u32()?,
+            pubkey_hash: reader.varbytes()?.to_vec(),
+        })
+    }
+}
+
+impl Record for String {
+    fn encode(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        String::from_utf8(bytes.to_vec()).ok()
+    }
+}
+
+impl Record for bool {
+    fn encode(&self) -> Vec<u8> {
+        vec![*self as u8]
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [0] => Some(false),
+            [1] => Some(true),
+            _ => None,
+        }
+    }
+}
+
+/// Count, then each string length prefixed.
+impl Record for Vec<String> {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = (self.len() as u32).to_le_bytes().to_vec();
+        for text in self.iter() {
+            write_str(&mut out, text);
+        }
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader::new(bytes);
+        (0..reader.u32()?).map(|_| reader.string()).collect()
+    }
+}
+
+/// Big endian chain and index, so addresses iterate in derivation order.
+pub fn address_key(change: bool, index: u32) -> Vec<u8> {
+    let mut key = vec![change as u8];
+    key.extend_from_slice(&index.to_be_bytes());
+    key
+}
+
+/// Chain and index of the `change-extern-N` and `change-intern-N` keys of version 0.
+pub fn legacy_address_key(key: &str) -> Option<(bool, u32)> {
+    if let Some(index) = key.strip_prefix("change-extern-") {
+        return Some((false, index.parse().ok()?));
+    }
+    match key.strip_prefix("change-intern-") {
+        Some(index) => Some((true, index.parse().ok()?)),
+        None => None,
+    }
+}
+
+/// Length prefixed address hash, p2sh32 hashes don't share a prefix with 20 byte ones.
+fn utxo_prefix(address_hash: &[u8]) -> Vec<u8> {
+    let mut prefix = vec![address_hash.len() as u8];
+    prefix.extend_from_slice(address_hash);
+    prefix
+}
+
+fn utxo_key(address_hash: &[u8], utxo: &UtxoRecord) -> Vec<u8> {
+    let mut key = utxo_prefix(address_hash);
+    key.extend_from_slice(&txid_bytes(&utxo.outpoint.txid));
+    key.extend_from_slice(&utxo.outpoint.outpoint_index.to_be_bytes());
+    key
+}
+
+pub fn read_utxos(tree: &sled::Tree, address_hash: &[u8]) -> Result<Vec<UtxoRecord>, WalletError> {
+    let mut utxos = vec![];
+    for entry in tree.scan_prefix(utxo_prefix(address_hash)) {
+        let (_, bytes) = entry?;
+        match UtxoRecord::decode(&bytes) {
+            Some(utxo) => utxos.push(utxo),
+            None => return Err(record_err(UTXO_TREE)),
+        }
+    }
+    Ok(utxos)
+}
+
+/// Replaces the utxos of an address in one batch.
+pub fn write_utxos(
+    tree: &sled::Tree,
+    address_hash: &[u8],
+    utxos: &[UtxoRecord],
+) -> Result<(), WalletError> {
+    let mut batch = sled::Batch::default();
+    for key in tree.scan_prefix(utxo_prefix(address_hash)).keys() {
+        batch.remove(key?);
+    }
+    for utxo in utxos {
+        batch.insert(utxo_key(address_hash, utxo), utxo.encode());
+    }
+    Ok(tree.apply_batch(batch)?)
+}
+
+/// Hash of the address a stored utxo belongs to.
+pub fn utxo_owner(tree: &sled::Tree, outpoint: &OutPoint) -> Result<Option<Vec<u8>>, WalletError> {
+    let mut suffix = txid_bytes(&outpoint.txid);
+    suffix.extend_from_slice(&outpoint.outpoint_index.to_be_bytes());
+    for key in tree.iter().keys() {
+        let key = key?;
+        let len = key[0] as usize;
+        if key.len() == 1 + len + suffix.len() && key.ends_with(&suffix) {
+            return Ok(Some(key[1..1 + len].to_vec()));
+        }
+    }
+    Ok(None)
+}
+
+pub fn address_utxos(db: &sled::Db, address_hash: &[u8]) -> Result<Vec<UtxoRecord>, WalletError> {
+    read_utxos(&db.open_tree(UTXO_TREE)?, address_hash)
+}
+
+pub fn store_address_utxos(
+    db: &sled::Db,
+    address_hash: &[u8],
+    utxos: &[UtxoRecord],
+) -> Result<(), WalletError> {
+    write_utxos(&db.open_tree(UTXO_TREE)?, address_hash, utxos)
+}
+
+pub fn put_address(db: &sled::Db, record: &AddressRecord) -> Result<(), WalletError> {
+    db.open_tree(ADDRESS_TREE)?
+        .insert(record.key(), record.encode())?;
+    Ok(())
+}
+
+pub fn get_address(
+    db: &sled::Db,
+    change: bool,
+    index: u32,
+) -> Result<Option<AddressRecord>, WalletError> {
+    match db
+        .open_tree(ADDRESS_TREE)?
+        .get(address_key(change, index))?
+    {
+        Some(bytes) => match AddressRecord::decode(&bytes) {
+            Some(record) => Ok(Some(record)),
+            None => Err(record_err(ADDRESS_TREE)),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Sets the label of a txid or address, an empty label removes it.
+pub fn set_label(db: &sled::Db, key: &str, label: &str) -> Result<(), WalletError> {
+    let tree = db.open_tree(LABEL_TREE)?;
+    match label.is_empty() {
+        true => tree.remove(key.as_bytes())?,
+        false => tree.insert(key.as_bytes(), label.to_string().encode())?,
+    };
+    Ok(())
+}
+
+pub fn labels(db: &sled::Db) -> Result<Vec<(String, String)>, WalletError> {
+    let mut labels = vec![];
+    for entry in db.open_tree(LABEL_TREE)?.iter() {
+        let (key, bytes) = entry?;
+        match (String::decode(&key), String::decode(&bytes)) {
+            (Some(key), Some(label)) => labels.push((key, label)),
+            _ => return Err(record_err(LABEL_TREE)),
+        }
+    }
+    Ok(labels)
+}
+
+pub fn schema_version(db: &sled::Db) -> Result<u32, WalletError> {
+    match db.get(SCHEMA_VERSION_KEY)? {
+        Some(bytes) => match <[u8; 4]>::try_from(bytes.as_ref()) {
+            Ok(version) => Ok(u32::from_be_bytes(version)),
+            Err(_) => Err(record_err("the default tree")),
+        },
+        None => Ok(0),
+    }
+}
+
+/// Runs the migrations from the stored schema version up to `SCHEMA_VERSION`, saving the
+/// version after each one so an interrupted upgrade resumes where it stopped. Databases of
+/// a newer release are refused.
+pub fn migrate(db: &sled::Db) -> Result<u32, WalletError> {
+    let mut version = schema_version(db)?;
+    if version > SCHEMA_VERSION {
+        return Err(WalletError::DataBaseError {
+            reason: format!(
+                "database schema {} is newer than supported {}",
+                version, SCHEMA_VERSION
+            ),
+        });
+    }
+    while version < SCHEMA_VERSION {
+        log::info!("migrating database schema {} to {}", version, version + 1);
+        MIGRATIONS[version as usize](db)?;
+        version += 1;
+        db.insert(SCHEMA_VERSION_KEY, &version.to_be_bytes()[..])?;
+    }
+    for tree in [
+        UTXO_TREE,
+        TX_TREE,
+        ADDRESS_TREE,
+        LABEL_TREE,
+        SETTINGS_TREE,
+        TOKEN_TREE,
+        SUPPLY_TREE,
+        HISTORY_TREE,
+        ADDRESS_HISTORY_TREE,
+        PENDING_TREE,
+        OUTGOING_TREE,
+        SPV_TREE,
+        HEADER_TREE,
+        CHAINWORK_TREE,
+        CERTIFICATE_TREE,
+    ] {
+        db.open_tree(tree)?;
+    }
+    db.flush()?;
+    Ok(version)
+}
+
+/// Version 0 to 1: utxo json and derived key hashes move out of the default tree into typed
+/// trees.
+fn split_default_tree(db: &sled::Db) -> Result<(), WalletError> {
+    let utxos = db.open_tree(UTXO_TREE)?;
+    let addresses = db.open_tree(ADDRESS_TREE)?;
+    let entries: Vec<(sled::IVec, sled::IVec)> = db.iter().collect::<Result<_, _>>()?;
+    for (key, value) in entries {
+        let legacy_key = std::str::from_utf8(&key).ok().and_then(legacy_address_key);
+        if let Some((change, index)) = legacy_key {
+            let record = AddressRecord {
+                change,
+                index,
+                pubkey_hash: value.to_vec(),
+            };
+            addresses.insert(record.key(), record.encode())?;
+            db.remove(key)?;
+            continue;
+        }
+        // `store_utxos` saved the listunspent json under the address hash
+        if key.len() != 20 && key.len() != 32 {
+            continue;
+        }
+        if let Ok(Value::Array(list)) = serde_json::from_slice::<Value>(&value) {
+            write_utxos(&utxos, &key, &UtxoRecord::from_json_list(&list)?)?;
+            db.remove(key)?;
+        }
+    }
+    Ok(())
+}
+
+/// Json of a version 1 setting, `None` when it is unset or an interrupted run already
+/// converted it.
+fn json_setting<R: Record>(settings: &sled::Tree, key: &str) -> Result<Option<Value>, WalletError> {
+    match settings.get(key)? {
+        Some(value) => match serde_json::from_slice(&value) {
+            Ok(json) => Ok(Some(json)),
+            Err(_) if R::decode(&value).is_some() => Ok(None),
+            Err(e) => Err(e.into()),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Version 1 to 2: the token metadata cache, the outgoing queue and the proxy and server
+/// settings are saved as records instead of json. The cross-check byte already is a `bool`
+/// record. Values already converted by an interrupted run are kept. Unreadable cache entries
+/// are dropped, anything else unreadable fails the migration.
+fn encode_json_records(db: &sled::Db) -> Result<(), WalletError> {
+    let tokens = db.open_tree(TOKEN_TREE)?;
+    let entries: Vec<(sled::IVec, sled::IVec)> = tokens.iter().collect::<Result<_, _>>()?;
+    for (key, value) in entries {
+        let token = match serde_json::from_slice::<Value>(&value) {
+            Ok(json) => TokenMetadata::from_json(&json).ok(),
+            Err(_) if TokenMetadata::decode(&value).is_some() => continue,
+            Err(_) => None,
+        };
+        match token {
+            Some(token) => tokens.insert(key, token.encode())?,
+            None => tokens.remove(key)?,
+        };
+    }
+    let outgoing = db.open_tree(OUTGOING_TREE)?;
+    let entries: Vec<(sled::IVec, sled::IVec)> = outgoing.iter().collect::<Result<_, _>>()?;
+    for (key, value) in entries {
+        let json: Value = match serde_json::from_slice(&value) {
+            Ok(json) => json,
+            Err(_) if OutgoingTx::decode(&value).is_some() => continue,
+            Err(e) => return Err(e.into()),
+        };
+        match OutgoingTx::from_json(&json) {
+            Some(tx) => outgoing.insert(key, tx.encode())?,
+            None => return Err(record_err(OUTGOING_TREE)),
+        };
+    }
+    let settings = db.open_tree(SETTINGS_TREE)?;
+    if let Some(json) = json_setting::<ProxyConfig>(&settings, PROXY_KEY)? {
+        let proxy = match json["address"].as_str() {
+            Some(address) => ProxyConfig {
+                address: address.to_string(),
+                isolation: json["isolation"].as_str().map(String::from),
+            },
+            None => return Err(record_err(SETTINGS_TREE)),
+        };
+        settings.insert(PROXY_KEY, proxy.encode())?;
+    }
+    if let Some(json) = json_setting::<Vec<String>>(&settings, SERVERS_KEY)? {
+        let servers: Vec<String> = serde_json::from_value(json)?;
+        settings.insert(SERVERS_KEY, servers.encode())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temporary_db() -> sled::Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    #[test]
+    fn utxo_records() {
+        let plain = json!({"tx_hash":"aa".repeat(32),"tx_pos":1,"height":800_000,"value":5_000});
+        let token = json!({"tx_hash":"bb".repeat(32),"tx_pos":0,"height":0,"value":1_000,
+            "token_data":{"amount":"100","category":"cc".repeat(32),
+                "nft":{"capability":"minting","commitment":"01ff"}}});
+        for json in [plain, token] {
+            let record = UtxoRecord::from_json(&json).unwrap();
+            assert_eq!(record.to_json(), json);
+            assert_eq!(UtxoRecord::decode(&record.encode()), Some(record.clone()));
+            let truncated = record.encode();
+            assert_eq!(UtxoRecord::decode(&truncated[..truncated.len() - 1]), None);
+        }
+    }
+
+    #[test]
+    fn migrates_version_0() {
+        let db = temporary_db();
+        let hash = [7u8; 20];
+        let utxos = json!([{"tx_hash":"aa".repeat(32),"tx_pos":0,"height":10,"value":600}]);
+        db.insert(hash, utxos.to_string().as_bytes()).unwrap();
+        db.insert("change-extern-3", vec![9u8; 20]).unwrap();
+        db.insert("passphrase", "kept").unwrap();
+
+        assert_eq!(schema_version(&db).unwrap(), 0);
+        assert_eq!(migrate(&db).unwrap(), SCHEMA_VERSION);
+        assert_eq!(schema_version(&db).unwrap(), SCHEMA_VERSION);
+
+        let stored = read_utxos(&db.open_tree(UTXO_TREE).unwrap(), &hash).unwrap();
+        assert_eq!(
+            stored.iter().map(|u| u.to_json()).collect::<Vec<_>>(),
+            utxos.as_array().unwrap().clone()
+        );
+        let addresses = db.open_tree(ADDRESS_TREE).unwrap();
+        let record = AddressRecord::decode(&addresses.get(address_key(false, 3)).unwrap().unwrap());
+        assert_eq!(record.unwrap().pubkey_hash, vec![9u8; 20]);
+        assert!(db.get(hash).unwrap().is_none());
+        assert!(db.get("change-extern-3").unwrap().is_none());
+        assert!(db.get("passphrase").unwrap().is_some());
+
+        // an utxo the migration can't read fails it instead of being dropped
+        let broken = temporary_db();
+        let utxos = json!([{"tx_hash":"aa".repeat(32),"tx_pos":0,"value":600}]);
+        broken.insert(hash, utxos.to_string().as_bytes()).unwrap();
+        assert!(migrate(&broken).is_err());
+        assert_eq!(schema_version(&broken).unwrap(), 0);
+        assert!(broken.get(hash).unwrap().is_some());
+
+        // already current, nothing to run
+        assert_eq!(migrate(&db).unwrap(), SCHEMA_VERSION);
+        db.insert(SCHEMA_VERSION_KEY, &(SCHEMA_VERSION + 1).to_be_bytes()[..])
+            .unwrap();
+        assert!(migrate(&db).is_err());
+    }
+
+    #[test]
+    fn migrates_version_1() {
+        let db = temporary_db();
+        db.insert(SCHEMA_VERSION_KEY, &1u32.to_be_bytes()[..])
+            .unwrap();
+        let category = "cc".repeat(32);
+        let token = json!({"category":category,"name":"Example","symbol":"XMPL","decimals":2,
+            "nftTypes":{"01":{"name":"Ticket"}}});
+        let tokens = db.open_tree(TOKEN_TREE).unwrap();
+        tokens
+            .insert(category.as_bytes(), token.to_string().as_bytes())
+            .unwrap();
+        tokens.insert("broken", "{}").unwrap();
+        let txid = "aa".repeat(32);
+        let outgoing = json!({"state":"mempool","txid":txid,"rawTx":"0100","attempts":1,
+            "lastError":null,"queuedAt":1_700_000_000});
+        db.open_tree(OUTGOING_TREE)
+            .unwrap()
+            .insert(txid.as_bytes(), outgoing.to_string().as_bytes())
+            .unwrap();
+        let settings = db.open_tree(SETTINGS_TREE).unwrap();
+        let proxy = json!({"address":"127.0.0.1:9050","isolation":"wallet"});
+        settings
+            .insert(PROXY_KEY, proxy.to_string().as_bytes())
+            .unwrap();
+        let servers = json!(["tcp://a:50001", "ssl://b:50002"]);
+        settings
+            .insert(SERVERS_KEY, servers.to_string().as_bytes())
+            .unwrap();
+
+        assert_eq!(migrate(&db).unwrap(), SCHEMA_VERSION);
+        let cached = TokenMetadata::decode(&tokens.get(category.as_bytes()).unwrap().unwrap());
+        assert_eq!(cached, TokenMetadata::from_json(&token).ok());
+        assert!(tokens.get("broken").unwrap().is_none());
+        let queued = db
+            .open_tree(OUTGOING_TREE)
+            .unwrap()
+            .get(txid.as_bytes())
+            .unwrap();
+        assert_eq!(
+            OutgoingTx::decode(&queued.unwrap()).unwrap().to_json(),
+            outgoing
+        );
+        let saved = ProxyConfig::decode(&settings.get(PROXY_KEY).unwrap().unwrap()).unwrap();
+        assert_eq!(saved.isolation.as_deref(), Some("wallet"));
+        let saved = Vec::<String>::decode(&settings.get(SERVERS_KEY).unwrap().unwrap());
+        assert_eq!(saved.unwrap(), vec!["tcp://a:50001", "ssl://b:50002"]);
+
+        // a run interrupted after converting some values resumes over them
+        db.insert(SCHEMA_VERSION_KEY, &1u32.to_be_bytes()[..])
+            .unwrap();
+        assert_eq!(migrate(&db).unwrap(), SCHEMA_VERSION);
+        assert!(tokens.get(category.as_bytes()).unwrap().is_some());
+    }
+
+    #[test]
+    fn utxos_replaced_per_address() {
+        let tree = temporary_db().open_tree(UTXO_TREE).unwrap();
+        let utxo = |txid: &str| {
+            UtxoRecord::from_json(&json!({"tx_hash":txid,"tx_pos":0,"height":1,"value":546}))
+                .unwrap()
+        };
+        let (a, b) = ([1u8; 20], [1u8; 32]);
+        write_utxos(&tree, &a, &[utxo(&"aa".repeat(32)), utxo(&"bb".repeat(32))]).unwrap();
+        write_utxos(&tree, &b, &[utxo(&"cc".repeat(32))]).unwrap();
+        write_utxos(&tree, &a, &[utxo(&"dd".repeat(32))]).unwrap();
+        assert_eq!(read_utxos(&tree, &a).unwrap(), vec![utxo(&"dd".repeat(32))]);
+        assert_eq!(read_utxos(&tree, &b).unwrap(), vec![utxo(&"cc".repeat(32))]);
+
+        let owner = |txid: &str| utxo_owner(&tree, &utxo(txid).outpoint).unwrap();
+        assert_eq!(owner(&"cc".repeat(32)), Some(b.to_vec()));
+        assert_eq!(owner(&"dd".repeat(32)), Some(a.to_vec()));
+        assert_eq!(owner(&"aa".repeat(32)), None);
+    }
+}
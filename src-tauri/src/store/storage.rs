@@ -1,29 +1,144 @@
+use std::path::PathBuf;
+use std::sync::Mutex;
+
 use bitcoincash_addr::{AddressCodec, CashAddrCodec, HashType, Network};
+use lazy_static::lazy_static;
 
 use crate::{
+    address::{address_to_pubkey_hash, address_to_script},
+    coins::utxo::{serde_json_to_utxo, UnspentUtxos},
     error::WalletError,
-    network::{self, electrum::get_unspent_utxos},
+    network::{backend::ChainBackend, connection::ElectrumConnection},
+    spv::verify_utxos,
+    store::{
+        pending::reconcile_pending,
+        schema::{address_utxos, store_address_utxos, UtxoRecord},
+    },
 };
 pub static KEY_PATH: &'static str = ".p2p-wallet/";
+/// sled tree of wallet settings
+pub static SETTINGS_TREE: &str = "settings";
+
+/// Directory of the wallet database and seed, `~/.p2p-wallet`.
+pub fn wallet_dir() -> PathBuf {
+    dirs::home_dir().unwrap().join(KEY_PATH)
+}
+
+lazy_static! {
+    static ref WALLET_DB: Mutex<Option<sled::Db>> = Mutex::new(None);
+}
+
+/// The wallet database in `wallet_dir`, opened by the first caller. sled locks the
+/// directory for as long as it is open, so the process keeps this one handle and every
+/// caller shares it.
+pub fn wallet_db() -> Result<sled::Db, sled::Error> {
+    let mut db = WALLET_DB.lock().unwrap();
+    if let Some(db) = db.as_ref() {
+        return Ok(db.clone());
+    }
+    let opened = sled::open(wallet_dir())?;
+    *db = Some(opened.clone());
+    Ok(opened)
+}
 
+/// Stores a `listunspent` response as the utxos of `address`, replacing the previous ones.
 #[tauri::command]
 pub fn store_utxos(address: String, data: String) -> Result<(), String> {
-    match dirs::home_dir().is_some() {
-        true => match sled::open(dirs::home_dir().unwrap().join(KEY_PATH)) {
-            Ok(db) => {
-                let address_content = CashAddrCodec::decode(address.as_str());
-                let script_hash = if address_content.is_ok() {
-                    address_content.unwrap().body
-                } else {
-                    return Err("Bad Address encoding".to_string());
-                };
-                let _ = db.insert(script_hash, data.as_bytes());
-                println!("utxo store updated");
-
-                Ok(())
-            }
+    match wallet_db() {
+        Ok(db) => match replace_address_utxos(&db, &address, &data) {
+            Ok(()) => Ok(()),
             Err(e) => Err(e.to_string()),
         },
-        false => Err("dirs::home_dir() is None".to_string()),
-    } 
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// `store_utxos` on `db`, settling the pending spends the new utxos show.
+pub fn replace_address_utxos(db: &sled::Db, address: &str, data: &str) -> Result<(), WalletError> {
+    let script_hash = match CashAddrCodec::decode(address) {
+        Ok(address) => address.body,
+        Err(e) => {
+            return Err(WalletError::AddresssDecodeError {
+                reason: e.to_string(),
+            })
+        }
+    };
+    let utxos: Vec<UtxoRecord> = match serde_json::from_str::<serde_json::Value>(data)? {
+        serde_json::Value::Array(utxos) => UtxoRecord::from_json_list(&utxos)?,
+        _ => {
+            return Err(WalletError::DataBaseError {
+                reason: "utxos are not a json array".to_string(),
+            })
+        }
+    };
+    reconcile_pending(db, address, &script_hash, &utxos)?;
+    store_address_utxos(db, &script_hash, &utxos)
+}
+
+/// Refreshes the stored utxos of `address` from `backend`. Returns true when they changed.
+/// Utxos without a merkle proof in our header chain, checked against the Electrum server of
+/// `connection`, are stored as unconfirmed.
+pub async fn sync_address_utxos(
+    db: &sled::Db,
+    address: &str,
+    backend: &dyn ChainBackend,
+    connection: &ElectrumConnection,
+) -> Result<bool, WalletError> {
+    let network_utxos = backend.unspent(&address_to_script(address)?).await?;
+    let network_utxos = verify_utxos(db, network_utxos, connection).await?;
+    store_network_utxos(db, address, network_utxos)
+}
+
+/// `sync_address_utxos` for many addresses with batched `listunspent` requests, for wallet
+/// restores. Returns the addresses whose utxos changed.
+pub async fn sync_addresses_utxos(
+    db: &sled::Db,
+    addresses: &[String],
+    backend: &dyn ChainBackend,
+    connection: &ElectrumConnection,
+) -> Result<Vec<String>, WalletError> {
+    let scripts = addresses
+        .iter()
+        .map(|address| address_to_script(address))
+        .collect::<Result<Vec<_>, WalletError>>()?;
+    let network_utxos = backend.unspent_batch(&scripts).await?;
+    let mut changed = Vec::new();
+    for (address, utxos) in addresses.iter().zip(network_utxos) {
+        let utxos = verify_utxos(db, utxos, connection).await?;
+        if store_network_utxos(db, address, utxos)? {
+            changed.push(address.clone());
+        }
+    }
+    Ok(changed)
+}
+
+/// Stores a `listunspent` response for `address` when it differs from the stored one, and
+/// settles the pending spends it shows. Returns true when the utxos shown for `address`
+/// changed.
+pub fn store_network_utxos(
+    db: &sled::Db,
+    address: &str,
+    network_utxos: String,
+) -> Result<bool, WalletError> {
+    let address_hash = address_to_pubkey_hash(address)?;
+    let db_utxos = address_utxos(db, &address_hash)?;
+    let db_utxos = serde_json::Value::Array(db_utxos.iter().map(UtxoRecord::to_json).collect());
+    let network_res = serde_json::from_str::<serde_json::Value>(&network_utxos)?;
+
+    // the store keeps utxos in outpoint order, the server in its own
+    let sorted = |utxos: serde_json::Value| -> Result<UnspentUtxos, WalletError> {
+        let mut utxos = serde_json_to_utxo(utxos, address)?;
+        utxos.with_token.sort();
+        utxos.non_token.sort();
+        Ok(utxos)
+    };
+    if sorted(network_res.clone())? == sorted(db_utxos)? {
+        let listed: Vec<UtxoRecord> = match network_res.as_array() {
+            Some(utxos) => UtxoRecord::from_json_list(utxos)?,
+            None => vec![],
+        };
+        return reconcile_pending(db, address, &address_hash, &listed);
+    }
+    replace_address_utxos(db, address, &network_utxos)?;
+    Ok(true)
 }
@@ -0,0 +1,162 @@
+//! Raw transaction cache. Transactions are immutable once their txid is known, so a
+//! transaction is fetched from the server once and served from sled afterwards. Every
+//! transaction is hashed before it is stored, a server can't answer with a different one.
+use std::collections::HashMap;
+
+use bitcoinsuite_core::{
+    hash::{Hashed, Sha256d},
+    ser::BitcoinSer,
+    tx::{Output, Transaction},
+};
+use bytes::Bytes;
+
+use crate::error::WalletError;
+use crate::network::connection::ElectrumConnection;
+use crate::network::electrum::{get_transaction, get_transactions_batch};
+
+/// sled tree of raw transactions keyed by lowercase txid hex
+pub static TX_TREE: &str = "txs";
+
+/// Txid of a raw transaction, as displayed by explorers.
+pub fn txid_of(raw_tx: &[u8]) -> String {
+    Sha256d::digest(raw_tx).hex_be()
+}
+
+fn decode_hex(txid: &str, raw_tx: &str) -> Result<Vec<u8>, WalletError> {
+    match hex::decode(raw_tx) {
+        Ok(raw) => Ok(raw),
+        Err(e) => Err(WalletError::NetworkError {
+            reason: format!("transaction {} is not hex: {}", txid, e),
+        }),
+    }
+}
+
+/// Txids are hex in either case, the cache is keyed by the lowercase form.
+fn tx_key(txid: &str) -> String {
+    txid.to_ascii_lowercase()
+}
+
+pub fn get_cached_transaction(db: &sled::Db, txid: &str) -> Result<Option<String>, WalletError> {
+    match db.open_tree(TX_TREE)?.get(tx_key(txid).as_bytes())? {
+        Some(raw) => Ok(Some(hex::encode(raw))),
+        None => Ok(None),
+    }
+}
+
+/// Raw bytes of `raw_tx` when it hashes to `txid`.
+fn checked_raw(txid: &str, raw_tx: &str) -> Result<Vec<u8>, WalletError> {
+    let raw = decode_hex(txid, raw_tx)?;
+    let hashed = txid_of(&raw);
+    match hashed == tx_key(txid) {
+        true => Ok(raw),
+        false => Err(WalletError::NetworkError {
+            reason: format!("server sent transaction {} for {}", hashed, txid),
+        }),
+    }
+}
+
+pub fn cache_transaction(db: &sled::Db, txid: &str, raw_tx: &str) -> Result<(), WalletError> {
+    db.open_tree(TX_TREE)?
+        .insert(tx_key(txid).as_bytes(), checked_raw(txid, raw_tx)?)?;
+    Ok(())
+}
+
+/// Raw transaction hex, from the cache or the server.
+pub async fn fetch_transaction(
+    db: &sled::Db,
+    txid: &str,
+    connection: &ElectrumConnection,
+) -> Result<String, WalletError> {
+    if let Some(raw_tx) = get_cached_transaction(db, txid)? {
+        return Ok(raw_tx);
+    }
+    let raw_tx = get_transaction(txid, connection).await?;
+    cache_transaction(db, txid, &raw_tx)?;
+    Ok(raw_tx)
+}
+
+/// Raw transactions in the order of `txids`, the ones not cached are fetched in one batch.
+pub async fn fetch_transactions(
+    db: &sled::Db,
+    txids: &[String],
+    connection: &ElectrumConnection,
+) -> Result<Vec<String>, WalletError> {
+    let mut found: HashMap<String, String> = HashMap::new();
+    let mut missing = vec![];
+    for txid in txids {
+        match get_cached_transaction(db, txid)? {
+            Some(raw_tx) => {
+                found.insert(txid.clone(), raw_tx);
+            }
+            None if !missing.contains(txid) => missing.push(txid.clone()),
+            None => {}
+        }
+    }
+    let fetched = get_transactions_batch(&missing, connection).await?;
+    for (txid, raw_tx) in missing.into_iter().zip(fetched) {
+        cache_transaction(db, &txid, &raw_tx)?;
+        found.insert(txid, raw_tx);
+    }
+    Ok(txids.iter().map(|txid| found[txid].clone()).collect())
+}
+
+pub fn decode_transaction(txid: &str, raw_tx: &str) -> Result<Transaction, WalletError> {
+    let raw = decode_hex(txid, raw_tx)?;
+    Ok(Transaction::deser(&mut Bytes::from(raw))?)
+}
+
+/// The outputs spent by the inputs of `tx`, in input order.
+pub async fn spent_outputs(
+    db: &sled::Db,
+    tx: &Transaction,
+    connection: &ElectrumConnection,
+) -> Result<Vec<Output>, WalletError> {
+    let txids: Vec<String> = tx
+        .inputs
+        .iter()
+        .map(|input| input.prev_out.txid.to_string())
+        .collect();
+    let raw_txs = fetch_transactions(db, &txids, connection).await?;
+    let mut outputs = Vec::with_capacity(tx.inputs.len());
+    for ((input, txid), raw_tx) in tx.inputs.iter().zip(txids.iter()).zip(raw_txs) {
+        let prev_tx = decode_transaction(txid, &raw_tx)?;
+        match prev_tx.outputs.get(input.prev_out.outpoint_index as usize) {
+            Some(output) => outputs.push(output.clone()),
+            None => {
+                return Err(WalletError::NetworkError {
+                    reason: format!("{}:{} does not exist", txid, input.prev_out.outpoint_index),
+                })
+            }
+        }
+    }
+    Ok(outputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // coinbase of block 9 and the transaction in block 170 spending it
+    const COINBASE_9: &str = "01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff0704ffff001d0134ffffffff0100f2052a0100000043410411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3ac00000000";
+    const TX_170: &str = "0100000001c997a5e56e104102fa209c6a852dd90660a20b2d9c352423edce25857fcd3704000000004847304402204e45e16932b8af514961a1d3a1a25fdf3f4f7732e9d624c6c61548ab5fb8cd410220181522ec8eca07de4860a4acdd12909d831cc56cbbac4622082221a8768d1d0901ffffffff0200ca9a3b00000000434104ae1a62fe09c5f51b13905f07f06b99a2f7159b2225f374cd378d71302fa28414e7aab37397f554a7df5f142c21c1b7303b8a0626f1baded5c72a704f7e6cd84cac00286bee0000000043410411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3ac00000000";
+    const COINBASE_9_TXID: &str =
+        "0437cd7f8525ceed2324359c2d0ba26006d92d856a9c20fa0241106ee5a597c9";
+    const TX_170_TXID: &str = "f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16";
+
+    #[test]
+    fn verified_by_hash() {
+        assert!(checked_raw(COINBASE_9_TXID, COINBASE_9).is_ok());
+        assert!(checked_raw(&COINBASE_9_TXID.to_uppercase(), COINBASE_9).is_ok());
+        assert!(checked_raw(COINBASE_9_TXID, TX_170).is_err());
+        assert!(checked_raw(COINBASE_9_TXID, "zz").is_err());
+
+        let tx = decode_transaction(TX_170_TXID, TX_170).unwrap();
+        let prev_out = &tx.inputs[0].prev_out;
+        assert_eq!(prev_out.txid.to_string(), COINBASE_9_TXID);
+        let coinbase = decode_transaction(COINBASE_9_TXID, COINBASE_9).unwrap();
+        assert_eq!(
+            coinbase.outputs[prev_out.outpoint_index as usize].value,
+            5_000_000_000
+        );
+    }
+}
@@ -0,0 +1,401 @@
+//! Bitcoin Cash Metadata Registries
+//! [spec](https://github.com/bitjson/chip-bcmr)
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde_json::{json, Map, Value};
+
+use crate::encoding::{write_optional_str, write_str, Reader};
+use crate::error::WalletError;
+use crate::store::schema::Record;
+use crate::store::storage::wallet_db;
+
+/// sled tree holding cached `TokenMetadata` records keyed by category hex
+pub static TOKEN_TREE: &str = "tokens";
+const MAX_DECIMALS: u64 = 18;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NftTypeMetadata {
+    pub name: String,
+    pub description: Option<String>,
+    pub icon: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TokenMetadata {
+    /// category hex, big endian like every txid shown to users
+    pub category: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub symbol: String,
+    pub decimals: u8,
+    pub icon: Option<String>,
+    /// keyed by commitment hex
+    pub nft_types: BTreeMap<String, NftTypeMetadata>,
+}
+
+fn bcmr_err(reason: &str) -> WalletError {
+    WalletError::Generic {
+        reason: format!("BCMR: {}", reason),
+    }
+}
+
+fn optional_str(value: &Value, key: &str) -> Result<Option<String>, WalletError> {
+    match &value[key] {
+        Value::Null => Ok(None),
+        Value::String(s) => Ok(Some(s.clone())),
+        _ => Err(bcmr_err(&format!("{} must be a string", key))),
+    }
+}
+
+fn icon_uri(snapshot: &Value) -> Result<Option<String>, WalletError> {
+    match &snapshot["uris"] {
+        Value::Null => Ok(None),
+        Value::Object(uris) => match uris.get("icon") {
+            None => Ok(None),
+            Some(Value::String(icon)) => Ok(Some(icon.clone())),
+            Some(_) => Err(bcmr_err("uris.icon must be a string")),
+        },
+        _ => Err(bcmr_err("uris must be an object")),
+    }
+}
+
+fn validate_version(registry: &Value) -> Result<(), WalletError> {
+    let version = match registry["version"].as_object() {
+        Some(version) => version,
+        None => return Err(bcmr_err("missing version")),
+    };
+    for part in ["major", "minor", "patch"] {
+        if !version.get(part).map(|v| v.is_u64()).unwrap_or(false) {
+            return Err(bcmr_err(&format!("version.{} must be a non-negative integer", part)));
+        }
+    }
+    if !registry["latestRevision"].is_string() {
+        return Err(bcmr_err("latestRevision must be a string"));
+    }
+    match &registry["registryIdentity"] {
+        Value::String(_) | Value::Object(_) => Ok(()),
+        _ => Err(bcmr_err("registryIdentity must be a string or an identity snapshot")),
+    }
+}
+
+fn parse_nft_types(token: &Value) -> Result<BTreeMap<String, NftTypeMetadata>, WalletError> {
+    let mut nft_types = BTreeMap::new();
+    let types = match token["nfts"]["parse"]["types"].as_object() {
+        Some(types) => types,
+        // parsable nfts (bytecode) are not supported, only sequential commitment types
+        None => return Ok(nft_types),
+    };
+    for (commitment, nft_type) in types {
+        if hex::decode(commitment).is_err() {
+            return Err(bcmr_err(&format!("nft type {} is not hex", commitment)));
+        }
+        let name = match nft_type["name"].as_str() {
+            Some(name) => name.to_string(),
+            None => return Err(bcmr_err("nft type name is required")),
+        };
+        nft_types.insert(
+            commitment.to_lowercase(),
+            NftTypeMetadata {
+                name,
+                description: optional_str(nft_type, "description")?,
+                icon: icon_uri(nft_type)?,
+            },
+        );
+    }
+    Ok(nft_types)
+}
+
+fn parse_snapshot(snapshot: &Value) -> Result<Option<TokenMetadata>, WalletError> {
+    let name = match snapshot["name"].as_str() {
+        Some(name) => name.to_string(),
+        None => return Err(bcmr_err("identity snapshot name is required")),
+    };
+    let token = &snapshot["token"];
+    if token.is_null() {
+        return Ok(None);
+    }
+    let category = match token["category"].as_str() {
+        Some(category) if category.len() == 64 && hex::decode(category).is_ok() => {
+            category.to_lowercase()
+        }
+        _ => return Err(bcmr_err("token.category must be 32 bytes hex")),
+    };
+    let symbol = match token["symbol"].as_str() {
+        Some(symbol) => symbol.to_string(),
+        None => return Err(bcmr_err("token.symbol is required")),
+    };
+    let decimals = match &token["decimals"] {
+        Value::Null => 0,
+        value => match value.as_u64() {
+            Some(decimals) if decimals <= MAX_DECIMALS => decimals as u8,
+            _ => return Err(bcmr_err("token.decimals must be an integer from 0 to 18")),
+        },
+    };
+    Ok(Some(TokenMetadata {
+        category,
+        name,
+        description: optional_str(snapshot, "description")?,
+        symbol,
+        decimals,
+        icon: icon_uri(snapshot)?,
+        nft_types: parse_nft_types(token)?,
+    }))
+}
+
+/// Validates a registry and resolves the latest snapshot of every identity that describes a token.
+pub fn parse_registry(registry: &str) -> Result<Vec<TokenMetadata>, WalletError> {
+    let registry: Value = match serde_json::from_str(registry) {
+        Ok(registry) => registry,
+        Err(e) => return Err(bcmr_err(&e.to_string())),
+    };
+    validate_version(&registry)?;
+    let identities = match registry["identities"].as_object() {
+        Some(identities) => identities,
+        None if registry["identities"].is_null() => return Ok(vec![]),
+        None => return Err(bcmr_err("identities must be an object")),
+    };
+    let mut tokens = vec![];
+    for (authbase, history) in identities {
+        let history: &Map<String, Value> = match history.as_object() {
+            Some(history) => history,
+            None => return Err(bcmr_err(&format!("identity {} history must be an object", authbase))),
+        };
+        // snapshots are keyed by ISO 8601 timestamps, which sort lexicographically
+        if let Some((_, snapshot)) = history.iter().max_by(|a, b| a.0.cmp(b.0)) {
+            if let Some(token) = parse_snapshot(snapshot)? {
+                tokens.push(token);
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+pub fn import_registry_file<P: AsRef<Path>>(path: P) -> Result<Vec<TokenMetadata>, WalletError> {
+    let registry = match std::fs::read_to_string(path) {
+        Ok(registry) => registry,
+        Err(e) => return Err(bcmr_err(&e.to_string())),
+    };
+    let tokens = parse_registry(&registry)?;
+    cache_metadata(&tokens)?;
+    Ok(tokens)
+}
+
+/// `fetch` returns the registry body for `url`, the app passes an http client, tests a stand-in.
+pub fn import_registry_url<F>(url: &str, fetch: F) -> Result<Vec<TokenMetadata>, WalletError>
+where
+    F: FnOnce(&str) -> Result<String, WalletError>,
+{
+    let tokens = parse_registry(&fetch(url)?)?;
+    cache_metadata(&tokens)?;
+    Ok(tokens)
+}
+
+fn token_tree() -> Result<sled::Tree, WalletError> {
+    Ok(wallet_db()?.open_tree(TOKEN_TREE)?)
+}
+
+pub fn cache_metadata(tokens: &[TokenMetadata]) -> Result<(), WalletError> {
+    let tree = token_tree()?;
+    for token in tokens {
+        tree.insert(token.category.as_bytes(), token.encode())?;
+    }
+    tree.flush()?;
+    Ok(())
+}
+
+/// Cached metadata for a category hex, if any registry described it.
+pub fn get_metadata(category: &str) -> Result<Option<TokenMetadata>, WalletError> {
+    match token_tree()?.get(category.to_lowercase().as_bytes())? {
+        Some(bytes) => match TokenMetadata::decode(&bytes) {
+            Some(token) => Ok(Some(token)),
+            None => Err(bcmr_err("malformed cached metadata")),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Shifts a base unit amount by `decimals`, trailing zeros of the fraction are dropped.
+pub fn format_token_amount(amount: u128, decimals: u8) -> String {
+    if decimals == 0 {
+        return amount.to_string();
+    }
+    let scale = 10u128.pow(decimals as u32);
+    let fraction = format!("{:0width$}", amount % scale, width = decimals as usize);
+    let fraction = fraction.trim_end_matches('0');
+    if fraction.is_empty() {
+        (amount / scale).to_string()
+    } else {
+        format!("{}.{}", amount / scale, fraction)
+    }
+}
+
+impl TokenMetadata {
+    pub fn nft_type(&self, commitment_hex: &str) -> Option<&NftTypeMetadata> {
+        self.nft_types.get(&commitment_hex.to_lowercase())
+    }
+
+    pub fn to_json(&self) -> Value {
+        let nft_types: Map<String, Value> = self
+            .nft_types
+            .iter()
+            .map(|(commitment, nft_type)| {
+                (
+                    commitment.clone(),
+                    json!({"name":nft_type.name,"description":nft_type.description,"icon":nft_type.icon}),
+                )
+            })
+            .collect();
+        json!({
+            "category": self.category,
+            "name": self.name,
+            "description": self.description,
+            "symbol": self.symbol,
+            "decimals": self.decimals,
+            "icon": self.icon,
+            "nftTypes": nft_types,
+        })
+    }
+
+    pub fn from_json(value: &Value) -> Result<Self, WalletError> {
+        let field = |key: &str| match value[key].as_str() {
+            Some(s) => Ok(s.to_string()),
+            None => Err(bcmr_err(&format!("cached metadata missing {}", key))),
+        };
+        let mut nft_types = BTreeMap::new();
+        if let Some(types) = value["nftTypes"].as_object() {
+            for (commitment, nft_type) in types {
+                nft_types.insert(
+                    commitment.clone(),
+                    NftTypeMetadata {
+                        name: nft_type["name"].as_str().unwrap_or_default().to_string(),
+                        description: nft_type["description"].as_str().map(String::from),
+                        icon: nft_type["icon"].as_str().map(String::from),
+                    },
+                );
+            }
+        }
+        Ok(TokenMetadata {
+            category: field("category")?,
+            name: field("name")?,
+            description: value["description"].as_str().map(String::from),
+            symbol: field("symbol")?,
+            decimals: value["decimals"].as_u64().unwrap_or(0) as u8,
+            icon: value["icon"].as_str().map(String::from),
+            nft_types,
+        })
+    }
+}
+
+impl Record for TokenMetadata {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        write_str(&mut out, &self.category);
+        write_str(&mut out, &self.name);
+        write_optional_str(&mut out, self.description.as_deref());
+        write_str(&mut out, &self.symbol);
+        out.push(self.decimals);
+        write_optional_str(&mut out, self.icon.as_deref());
+        out.extend_from_slice(&(self.nft_types.len() as u32).to_le_bytes());
+        for (commitment, nft_type) in self.nft_types.iter() {
+            write_str(&mut out, commitment);
+            write_str(&mut out, &nft_type.name);
+            write_optional_str(&mut out, nft_type.description.as_deref());
+            write_optional_str(&mut out, nft_type.icon.as_deref());
+        }
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader::new(bytes);
+        let category = reader.string()?;
+        let name = reader.string()?;
+        let description = reader.optional_string()?;
+        let symbol = reader.string()?;
+        let decimals = reader.u8()?;
+        let icon = reader.optional_string()?;
+        let mut nft_types = BTreeMap::new();
+        for _ in 0..reader.u32()? {
+            let commitment = reader.string()?;
+            let nft_type = NftTypeMetadata {
+                name: reader.string()?,
+                description: reader.optional_string()?,
+                icon: reader.optional_string()?,
+            };
+            nft_types.insert(commitment, nft_type);
+        }
+        Some(TokenMetadata {
+            category,
+            name,
+            description,
+            symbol,
+            decimals,
+            icon,
+            nft_types,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REGISTRY: &str = r#"{
+        "$schema": "https://cashtokens.org/bcmr-v2.schema.json",
+        "version": { "major": 1, "minor": 0, "patch": 0 },
+        "latestRevision": "2023-08-01T00:00:00.000Z",
+        "registryIdentity": { "name": "example registry" },
+        "identities": {
+            "89cad9e3e34280eb1e8bc420542c00a7fcc01002b663dbf7f38bceddf80e680c": {
+                "2023-01-01T00:00:00.000Z": {
+                    "name": "Old Name",
+                    "token": {
+                        "category": "89cad9e3e34280eb1e8bc420542c00a7fcc01002b663dbf7f38bceddf80e680c",
+                        "symbol": "OLD"
+                    }
+                },
+                "2023-06-01T00:00:00.000Z": {
+                    "name": "Example Token",
+                    "uris": { "icon": "ipfs://icon" },
+                    "token": {
+                        "category": "89cad9e3e34280eb1e8bc420542c00a7fcc01002b663dbf7f38bceddf80e680c",
+                        "symbol": "XMPL",
+                        "decimals": 2,
+                        "nfts": {
+                            "parse": {
+                                "types": { "01": { "name": "Ticket", "uris": { "icon": "ipfs://ticket" } } }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }"#;
+
+    #[test]
+    fn resolves_latest_snapshot() {
+        let tokens = parse_registry(REGISTRY).unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].symbol, "XMPL");
+        assert_eq!(tokens[0].decimals, 2);
+        assert_eq!(tokens[0].icon.as_deref(), Some("ipfs://icon"));
+        assert_eq!(tokens[0].nft_type("01").unwrap().name, "Ticket");
+        assert_eq!(TokenMetadata::from_json(&tokens[0].to_json()).unwrap(), tokens[0]);
+        assert_eq!(TokenMetadata::decode(&tokens[0].encode()), Some(tokens[0].clone()));
+    }
+
+    #[test]
+    fn rejects_invalid_registry() {
+        assert!(parse_registry(r#"{"identities":{}}"#).is_err());
+        let bad_decimals = REGISTRY.replace(r#""decimals": 2"#, r#""decimals": 19"#);
+        assert!(parse_registry(&bad_decimals).is_err());
+    }
+
+    #[test]
+    fn formats_decimals() {
+        assert_eq!(format_token_amount(12345, 2), "123.45");
+        assert_eq!(format_token_amount(12300, 2), "123");
+        assert_eq!(format_token_amount(5, 3), "0.005");
+        assert_eq!(format_token_amount(7, 0), "7");
+    }
+}
@@ -0,0 +1,3 @@
+pub mod bcmr;
+pub mod portfolio;
+pub mod supply;
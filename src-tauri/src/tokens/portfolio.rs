@@ -0,0 +1,179 @@
+//! Token holdings of the wallet grouped by category.
+use std::collections::BTreeMap;
+
+use bitcoinsuite_core::tx::{Capability, OutPoint, TxId, NFT};
+use serde_json::{json, Value};
+
+use crate::coins::utxo::{get_utxos_for_address, UnspentUtxos};
+use crate::error::WalletError;
+use crate::tokens::bcmr::{format_token_amount, get_metadata};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct NftHolding {
+    pub address: String,
+    pub outpoint: OutPoint,
+    pub nft: NFT,
+    pub confirmed: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CategoryHoldings {
+    /// Sums are u128, a category can hold up to 2^63 - 1 tokens per utxo
+    pub fungible_confirmed: u128,
+    pub fungible_unconfirmed: u128,
+    pub nfts: Vec<NftHolding>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TokenPortfolio {
+    pub categories: BTreeMap<TxId, CategoryHoldings>,
+}
+
+pub fn capability_str(nft: &NFT) -> &'static str {
+    match nft.capability.0 {
+        Capability::None => "none",
+        Capability::Mutable => "mutable",
+        Capability::Minting => "minting",
+    }
+}
+
+impl TokenPortfolio {
+    /// Groups the token utxos of every address by category. Utxos at height 0 are unconfirmed.
+    pub fn from_utxos(utxos: &[(String, UnspentUtxos)]) -> Self {
+        let mut portfolio = TokenPortfolio::default();
+        for (address, unspent) in utxos {
+            for utxo in unspent.with_token.iter() {
+                let token = match utxo.0.output.token.as_ref() {
+                    Some(token) => token,
+                    None => continue,
+                };
+                let confirmed = utxo.0.height > 0;
+                let holdings = portfolio.categories.entry(token.category).or_default();
+                if confirmed {
+                    holdings.fungible_confirmed += token.amount.0 as u128;
+                } else {
+                    holdings.fungible_unconfirmed += token.amount.0 as u128;
+                }
+                if let Some(nft) = token.nft.as_ref() {
+                    holdings.nfts.push(NftHolding {
+                        address: address.clone(),
+                        outpoint: utxo.0.outpoint,
+                        nft: nft.clone(),
+                        confirmed,
+                    });
+                }
+            }
+        }
+        portfolio
+    }
+
+    /// Reads the stored utxos of every address from `db`.
+    pub fn for_addresses(db: &sled::Db, addresses: &[String]) -> Result<Self, WalletError> {
+        let mut utxos = vec![];
+        for address in addresses {
+            utxos.push((address.clone(), get_utxos_for_address(db, address)?));
+        }
+        Ok(Self::from_utxos(&utxos))
+    }
+
+    /// Token amounts are strings, they do not fit in a javascript number.
+    pub fn to_json(&self) -> Value {
+        let categories: Vec<Value> = self
+            .categories
+            .iter()
+            .map(|(category, holdings)| {
+                let nfts: Vec<Value> = holdings
+                    .nfts
+                    .iter()
+                    .map(|holding| {
+                        let commitment = hex::encode(&holding.nft.commitment.0);
+                        let nft_type = get_metadata(&category.to_string())
+                            .unwrap_or(None)
+                            .and_then(|m| m.nft_type(&commitment).map(|t| t.name.clone()));
+                        json!({
+                            "address": holding.address,
+                            "txid": holding.outpoint.txid.to_string(),
+                            "vout": holding.outpoint.outpoint_index,
+                            "capability": capability_str(&holding.nft),
+                            "commitment": commitment,
+                            "nftType": nft_type,
+                            "confirmed": holding.confirmed,
+                        })
+                    })
+                    .collect();
+                let total = holdings.fungible_confirmed + holdings.fungible_unconfirmed;
+                let metadata = get_metadata(&category.to_string()).unwrap_or(None);
+                let display = metadata
+                    .as_ref()
+                    .map(|m| format_token_amount(total, m.decimals));
+                json!({
+                    "category": category.to_string(),
+                    "fungible": {
+                        "confirmed": holdings.fungible_confirmed.to_string(),
+                        "unconfirmed": holdings.fungible_unconfirmed.to_string(),
+                        "total": total.to_string(),
+                        "display": display,
+                    },
+                    "nfts": nfts,
+                    "metadata": metadata.map(|m| m.to_json()),
+                })
+            })
+            .collect();
+        json!({ "categories": categories })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coins::utxo::{UnspentOutputWithTokens, Utxo};
+    use bitcoinsuite_core::{
+        script::Script,
+        ser::CompactUint,
+        tx::{CashToken, Commitment, NonFungibleTokenCapability, Output},
+    };
+
+    fn token_utxo(height: u32, vout: u32, amount: u64, nft: Option<NFT>) -> UnspentOutputWithTokens {
+        UnspentOutputWithTokens(Utxo {
+            height,
+            outpoint: OutPoint {
+                txid: TxId::from([2; 32]),
+                outpoint_index: vout,
+            },
+            output: Output {
+                script: Script::default(),
+                token: Some(CashToken {
+                    amount: CompactUint(amount),
+                    category: TxId::from([1; 32]),
+                    nft,
+                }),
+                value: 1000,
+            },
+        })
+    }
+
+    #[test]
+    fn sums_past_u64_and_splits_confirmed() {
+        let max = 9223372036854775807;
+        let nft = NFT {
+            capability: NonFungibleTokenCapability(Capability::Minting),
+            commitment: Commitment(vec![0xab].into()),
+        };
+        let utxos = UnspentUtxos {
+            with_token: vec![
+                token_utxo(10, 0, max, None),
+                token_utxo(11, 1, max, None),
+                token_utxo(12, 2, max, None),
+                token_utxo(0, 3, 5, Some(nft)),
+            ],
+            non_token: vec![],
+        };
+        let portfolio = TokenPortfolio::from_utxos(&[("addr".to_string(), utxos)]);
+        let holdings = &portfolio.categories[&TxId::from([1; 32])];
+        assert_eq!(holdings.fungible_confirmed, max as u128 * 3);
+        assert_eq!(holdings.fungible_unconfirmed, 5);
+        assert_eq!(holdings.nfts.len(), 1);
+        assert!(!holdings.nfts[0].confirmed);
+        assert_eq!(capability_str(&holdings.nfts[0].nft), "minting");
+    }
+}
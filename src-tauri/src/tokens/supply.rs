@@ -0,0 +1,336 @@
+//! Supply reports for token categories, walked from the genesis transaction.
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+use bitcoinsuite_core::{
+    hash::{Hashed, Sha256d},
+    script::Script,
+    tx::{Capability, OutPoint, Output, Transaction, TxId},
+};
+use serde_json::{json, Value};
+
+use crate::error::WalletError;
+use crate::network::connection::ElectrumConnection;
+use crate::network::electrum::{get_script_history, scripthash_hex};
+use crate::store::transactions::{decode_transaction, fetch_transaction};
+
+/// sled tree of the last supply report per category, keyed by category hex
+pub static SUPPLY_TREE: &str = "supply";
+/// Upper bound on transactions fetched for one report
+const MAX_WALK_TRANSACTIONS: usize = 10_000;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TokenSupply {
+    pub category: TxId,
+    pub genesis_txid: TxId,
+    /// Fungible tokens can only be created in the genesis transaction
+    pub minted: u128,
+    pub burned: u128,
+    /// Unspent fungible amount held by the wallet scripts
+    pub held_by_us: u128,
+    /// Unspent fungible amount held by everyone else
+    pub circulating: u128,
+    pub minting_batons: Vec<OutPoint>,
+    pub unspent_nfts: usize,
+    /// Tip height of the last transaction seen during the walk
+    pub last_height: i64,
+}
+
+fn supply_err(reason: String) -> WalletError {
+    WalletError::NetworkError { reason }
+}
+
+/// Fetches and decodes transactions, each txid at most once per walk.
+struct TxFetcher<'a> {
+    db: &'a sled::Db,
+    connection: &'a ElectrumConnection,
+    txs: HashMap<TxId, Transaction>,
+    histories: HashMap<String, Vec<(TxId, i64)>>,
+}
+
+impl<'a> TxFetcher<'a> {
+    fn new(db: &'a sled::Db, connection: &'a ElectrumConnection) -> Self {
+        TxFetcher {
+            db,
+            connection,
+            txs: HashMap::new(),
+            histories: HashMap::new(),
+        }
+    }
+
+    async fn tx(&mut self, txid: &TxId) -> Result<Transaction, WalletError> {
+        if let Some(tx) = self.txs.get(txid) {
+            return Ok(tx.clone());
+        }
+        if self.txs.len() >= MAX_WALK_TRANSACTIONS {
+            return Err(WalletError::Generic {
+                reason: format!("supply walk exceeded {} transactions", MAX_WALK_TRANSACTIONS),
+            });
+        }
+        let txid_hex = txid.to_string();
+        let raw = fetch_transaction(self.db, &txid_hex, self.connection).await?;
+        let tx = decode_transaction(&txid_hex, &raw)?;
+        self.txs.insert(*txid, tx.clone());
+        Ok(tx)
+    }
+
+    async fn history(&mut self, script: &Script) -> Result<Vec<(TxId, i64)>, WalletError> {
+        let scripthash = scripthash_hex(script);
+        if let Some(history) = self.histories.get(&scripthash) {
+            return Ok(history.clone());
+        }
+        let history = get_script_history(script, self.connection).await?;
+        let history: Value = serde_json::from_str(&history)?;
+        let mut entries = vec![];
+        for entry in history.as_array().cloned().unwrap_or_default() {
+            let txid = match entry["tx_hash"].as_str().map(Sha256d::from_be_hex) {
+                Some(Ok(txid)) => TxId::from(txid),
+                _ => continue,
+            };
+            entries.push((txid, entry["height"].as_i64().unwrap_or(0)));
+        }
+        self.histories.insert(scripthash, entries.clone());
+        Ok(entries)
+    }
+
+    /// Finds the transaction spending `outpoint` among the history of its locking script.
+    async fn spender(
+        &mut self,
+        outpoint: &OutPoint,
+        output: &Output,
+    ) -> Result<Option<(TxId, i64)>, WalletError> {
+        for (txid, height) in self.history(&output.script).await? {
+            if txid == outpoint.txid {
+                continue;
+            }
+            let tx = self.tx(&txid).await?;
+            if tx.inputs.iter().any(|input| input.prev_out == *outpoint) {
+                return Ok(Some((txid, height)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Walks every output of `category` from genesis, following spends through script histories.
+/// When `genesis_txid` is unknown it is found as the spender of `category:0`.
+pub async fn compute_supply(
+    db: &sled::Db,
+    category: TxId,
+    genesis_txid: Option<TxId>,
+    our_scripts: &[Script],
+    connection: &ElectrumConnection,
+) -> Result<TokenSupply, WalletError> {
+    let mut fetcher = TxFetcher::new(db, connection);
+    let genesis_txid = match genesis_txid {
+        Some(txid) => txid,
+        None => {
+            let category_tx = fetcher.tx(&category).await?;
+            let outpoint = OutPoint {
+                txid: category,
+                outpoint_index: 0,
+            };
+            let output = match category_tx.outputs.first() {
+                Some(output) => output.clone(),
+                None => return Err(supply_err("category transaction has no outputs".to_string())),
+            };
+            match fetcher.spender(&outpoint, &output).await? {
+                Some((txid, _)) => txid,
+                None => return Err(supply_err("category has no genesis yet".to_string())),
+            }
+        }
+    };
+
+    let mut visited: BTreeSet<TxId> = BTreeSet::new();
+    let mut queue: VecDeque<(TxId, i64)> = VecDeque::from([(genesis_txid, 0)]);
+    let mut walked = vec![];
+    let mut spent: BTreeSet<OutPoint> = BTreeSet::new();
+
+    while let Some((txid, height)) = queue.pop_front() {
+        if !visited.insert(txid) {
+            continue;
+        }
+        let tx = fetcher.tx(&txid).await?;
+        for (index, output) in tx.outputs.iter().enumerate() {
+            match output.token.as_ref() {
+                Some(token) if token.category == category => {}
+                _ => continue,
+            }
+            let outpoint = OutPoint {
+                txid,
+                outpoint_index: index as u32,
+            };
+            if let Some(spender) = fetcher.spender(&outpoint, output).await? {
+                spent.insert(outpoint);
+                queue.push_back(spender);
+            }
+        }
+        walked.push((txid, tx, height));
+    }
+    let supply = tally_supply(category, genesis_txid, &walked, &spent, our_scripts);
+    cache_supply(db, &supply)?;
+    Ok(supply)
+}
+
+/// Supply of `category` over the transactions of a walk, given the outputs found spent.
+fn tally_supply(
+    category: TxId,
+    genesis_txid: TxId,
+    walked: &[(TxId, Transaction, i64)],
+    spent: &BTreeSet<OutPoint>,
+    our_scripts: &[Script],
+) -> TokenSupply {
+    let mut supply = TokenSupply {
+        category,
+        genesis_txid,
+        ..Default::default()
+    };
+    let mut unspent: u128 = 0;
+    for (txid, tx, height) in walked {
+        supply.last_height = supply.last_height.max(*height);
+        for (index, output) in tx.outputs.iter().enumerate() {
+            let token = match output.token.as_ref() {
+                Some(token) if token.category == category => token,
+                _ => continue,
+            };
+            if *txid == genesis_txid {
+                supply.minted += token.amount.0 as u128;
+            }
+            let outpoint = OutPoint {
+                txid: *txid,
+                outpoint_index: index as u32,
+            };
+            if spent.contains(&outpoint) {
+                continue;
+            }
+            unspent += token.amount.0 as u128;
+            if our_scripts.contains(&output.script) {
+                supply.held_by_us += token.amount.0 as u128;
+            }
+            if let Some(nft) = token.nft.as_ref() {
+                supply.unspent_nfts += 1;
+                if matches!(nft.capability.0, Capability::Minting) {
+                    supply.minting_batons.push(outpoint);
+                }
+            }
+        }
+    }
+    supply.burned = supply.minted.saturating_sub(unspent);
+    supply.circulating = unspent - supply.held_by_us;
+    supply
+}
+
+fn cache_supply(db: &sled::Db, supply: &TokenSupply) -> Result<(), WalletError> {
+    db.open_tree(SUPPLY_TREE)?.insert(
+        supply.category.to_string().as_bytes(),
+        supply.to_json().to_string().as_bytes(),
+    )?;
+    Ok(())
+}
+
+/// Last report computed for `category`, as json.
+pub fn cached_supply(db: &sled::Db, category: &TxId) -> Result<Option<Value>, WalletError> {
+    match db.open_tree(SUPPLY_TREE)?.get(category.to_string().as_bytes())? {
+        Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        None => Ok(None),
+    }
+}
+
+impl TokenSupply {
+    pub fn to_json(&self) -> Value {
+        let batons: Vec<Value> = self
+            .minting_batons
+            .iter()
+            .map(|o| json!({"txid":o.txid.to_string(),"vout":o.outpoint_index}))
+            .collect();
+        json!({
+            "category": self.category.to_string(),
+            "genesisTxid": self.genesis_txid.to_string(),
+            "minted": self.minted.to_string(),
+            "burned": self.burned.to_string(),
+            "heldByUs": self.held_by_us.to_string(),
+            "circulating": self.circulating.to_string(),
+            "mintingBatons": batons,
+            "unspentNfts": self.unspent_nfts,
+            "lastHeight": self.last_height,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    use bitcoinsuite_core::ser::CompactUint;
+    use bitcoinsuite_core::tx::{CashToken, Commitment, Input, NonFungibleTokenCapability, NFT};
+    use bytes::Bytes;
+
+    fn token_output(script: &Script, amount: u64, capability: Option<Capability>) -> Output {
+        Output {
+            value: 1_000,
+            script: script.clone(),
+            token: Some(CashToken {
+                amount: CompactUint(amount),
+                category: TxId::from_str(&"cc".repeat(32)).unwrap(),
+                nft: capability.map(|capability| NFT {
+                    capability: NonFungibleTokenCapability(capability),
+                    commitment: Commitment(Bytes::new()),
+                }),
+            }),
+        }
+    }
+
+    fn tx(spends: Vec<OutPoint>, outputs: Vec<Output>) -> Transaction {
+        Transaction {
+            version: 2,
+            inputs: spends
+                .into_iter()
+                .map(|prev_out| Input {
+                    prev_out,
+                    script: Script::default(),
+                    sequence: 0,
+                })
+                .collect(),
+            outputs,
+            locktime: 0,
+        }
+    }
+
+    #[test]
+    fn supply_of_a_walk() {
+        let category = TxId::from_str(&"cc".repeat(32)).unwrap();
+        let genesis_txid = TxId::from_str(&"dd".repeat(32)).unwrap();
+        let send_txid = TxId::from_str(&"ee".repeat(32)).unwrap();
+        let ours = Script::new(Bytes::from(vec![0x51]));
+        let theirs = Script::new(Bytes::from(vec![0x52]));
+        let outpoint = |txid, outpoint_index| OutPoint {
+            txid,
+            outpoint_index,
+        };
+
+        let genesis = tx(
+            vec![outpoint(category, 0)],
+            vec![
+                token_output(&ours, 600, None),
+                token_output(&theirs, 400, None),
+                token_output(&ours, 0, Some(Capability::Minting)),
+            ],
+        );
+        // sends 300 of the 400 on, 100 are burned
+        let send = tx(
+            vec![outpoint(genesis_txid, 1)],
+            vec![token_output(&theirs, 300, None)],
+        );
+        let walked = vec![(genesis_txid, genesis, 10), (send_txid, send, 12)];
+        let spent = BTreeSet::from([outpoint(genesis_txid, 1)]);
+
+        let supply = tally_supply(category, genesis_txid, &walked, &spent, &[ours]);
+        assert_eq!(supply.minted, 1_000);
+        assert_eq!(supply.burned, 100);
+        assert_eq!(supply.held_by_us, 600);
+        assert_eq!(supply.circulating, 300);
+        assert_eq!(supply.minting_batons, vec![outpoint(genesis_txid, 2)]);
+        assert_eq!(supply.unspent_nfts, 1);
+        assert_eq!(supply.last_height, 12);
+    }
+}
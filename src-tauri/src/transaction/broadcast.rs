@@ -0,0 +1,661 @@
+//! Persistent queue of outgoing transactions. A transaction is saved before it is sent, so a
+//! broadcast that failed to reach a server is retried, and one the server accepted is sent
+//! again after a reconnect until it confirms. Transactions whose inputs were spent by another
+//! transaction are reported as conflicted, the ones a server refused as rejected.
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bitcoinsuite_core::tx::{Output, Transaction};
+use serde_json::{json, Value};
+
+use crate::encoding::{write_optional_str, write_str, write_varbytes, Reader};
+use crate::error::WalletError;
+use crate::network::connection::ElectrumConnection;
+use crate::network::electrum::{get_script_history, get_script_unspent, send_raw_transaction};
+use crate::network::error::NetworkError;
+use crate::network::task::ServiceTask;
+use crate::store::pending::{drop_pending, mark_pending};
+use crate::store::schema::Record;
+use crate::store::transactions::{
+    cache_transaction, decode_transaction, fetch_transactions, spent_outputs, txid_of,
+};
+
+/// sled tree of `OutgoingTx` records keyed by txid hex
+pub static OUTGOING_TREE: &str = "outgoing";
+pub static EVENT_BROADCAST_STATUS: &str = "broadcast-status";
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(15);
+/// Refusals meaning the transaction is already known, not that it is invalid
+const ALREADY_KNOWN: [&str; 3] = [
+    "already-known",
+    "already-in-mempool",
+    "already in block chain",
+];
+/// Refusals meaning an input is missing or spent, checked for a conflict
+const MISSING_INPUTS: [&str; 4] = [
+    "missingorspent",
+    "missing-inputs",
+    "missing inputs",
+    "mempool-conflict",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BroadcastState {
+    /// Waiting for a server to accept it
+    Queued,
+    /// Accepted, waiting for a block
+    InMempool,
+    Confirmed {
+        height: u32,
+    },
+    /// `outpoint` was spent by `spender`, when it could be found
+    Conflicted {
+        outpoint: String,
+        spender: Option<String>,
+    },
+    Rejected {
+        reason: String,
+    },
+}
+
+impl BroadcastState {
+    /// Confirmed, conflicted and rejected transactions are no longer sent.
+    pub fn is_final(&self) -> bool {
+        !matches!(self, BroadcastState::Queued | BroadcastState::InMempool)
+    }
+
+    pub fn to_json(&self) -> Value {
+        match self {
+            BroadcastState::Queued => json!({"state": "queued"}),
+            BroadcastState::InMempool => json!({"state": "mempool"}),
+            BroadcastState::Confirmed { height } => json!({"state": "confirmed", "height": height}),
+            BroadcastState::Conflicted { outpoint, spender } => {
+                json!({"state": "conflicted", "outpoint": outpoint, "spender": spender})
+            }
+            BroadcastState::Rejected { reason } => json!({"state": "rejected", "reason": reason}),
+        }
+    }
+
+    fn from_json(json: &Value) -> Option<Self> {
+        let text = |key: &str| json[key].as_str().map(String::from);
+        match json["state"].as_str()? {
+            "queued" => Some(BroadcastState::Queued),
+            "mempool" => Some(BroadcastState::InMempool),
+            "confirmed" => Some(BroadcastState::Confirmed {
+                height: json["height"].as_u64()? as u32,
+            }),
+            "conflicted" => Some(BroadcastState::Conflicted {
+                outpoint: text("outpoint")?,
+                spender: text("spender"),
+            }),
+            "rejected" => Some(BroadcastState::Rejected {
+                reason: text("reason")?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutgoingTx {
+    pub txid: String,
+    pub raw_tx: String,
+    pub state: BroadcastState,
+    /// Sends that did not reach a server
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    /// Unix seconds
+    pub queued_at: u64,
+}
+
+impl OutgoingTx {
+    pub fn to_json(&self) -> Value {
+        let mut json = self.state.to_json();
+        json["txid"] = json!(self.txid);
+        json["rawTx"] = json!(self.raw_tx);
+        json["attempts"] = json!(self.attempts);
+        json["lastError"] = json!(self.last_error);
+        json["queuedAt"] = json!(self.queued_at);
+        json
+    }
+
+    /// Entry saved by releases before the queue held records, read by the migration.
+    pub(crate) fn from_json(json: &Value) -> Option<Self> {
+        Some(OutgoingTx {
+            txid: json["txid"].as_str()?.to_string(),
+            raw_tx: json["rawTx"].as_str()?.to_string(),
+            state: BroadcastState::from_json(json)?,
+            attempts: json["attempts"].as_u64().unwrap_or(0) as u32,
+            last_error: json["lastError"].as_str().map(String::from),
+            queued_at: json["queuedAt"].as_u64().unwrap_or(0),
+        })
+    }
+}
+
+impl Record for BroadcastState {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        match self {
+            BroadcastState::Queued => out.push(0),
+            BroadcastState::InMempool => out.push(1),
+            BroadcastState::Confirmed { height } => {
+                out.push(2);
+                out.extend_from_slice(&height.to_le_bytes());
+            }
+            BroadcastState::Conflicted { outpoint, spender } => {
+                out.push(3);
+                write_str(&mut out, outpoint);
+                write_optional_str(&mut out, spender.as_deref());
+            }
+            BroadcastState::Rejected { reason } => {
+                out.push(4);
+                write_str(&mut out, reason);
+            }
+        }
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        read_state(&mut Reader::new(bytes))
+    }
+}
+
+fn read_state(reader: &mut Reader) -> Option<BroadcastState> {
+    match reader.u8()? {
+        0 => Some(BroadcastState::Queued),
+        1 => Some(BroadcastState::InMempool),
+        2 => Some(BroadcastState::Confirmed {
+            height: reader.u32()?,
+        }),
+        3 => Some(BroadcastState::Conflicted {
+            outpoint: reader.string()?,
+            spender: reader.optional_string()?,
+        }),
+        4 => Some(BroadcastState::Rejected {
+            reason: reader.string()?,
+        }),
+        _ => None,
+    }
+}
+
+impl Record for OutgoingTx {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = hex::decode(&self.txid).unwrap_or_default();
+        write_varbytes(&mut out, &hex::decode(&self.raw_tx).unwrap_or_default());
+        out.extend_from_slice(&self.state.encode());
+        out.extend_from_slice(&self.attempts.to_le_bytes());
+        write_optional_str(&mut out, self.last_error.as_deref());
+        out.extend_from_slice(&self.queued_at.to_le_bytes());
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader::new(bytes);
+        Some(OutgoingTx {
+            txid: hex::encode(reader.bytes(32)?),
+            raw_tx: hex::encode(reader.varbytes()?),
+            state: read_state(&mut reader)?,
+            attempts: reader.u32()?,
+            last_error: reader.optional_string()?,
+            queued_at: reader.u64()?,
+        })
+    }
+}
+
+/// How a server answered a broadcast.
+#[derive(Clone, Debug, PartialEq)]
+enum SendOutcome {
+    Accepted,
+    /// No server was reached, or the servers disagreed
+    Retry(String),
+    InputsMissing(String),
+    Refused(String),
+}
+
+fn send_outcome(res: &Result<String, NetworkError>) -> SendOutcome {
+    let e = match res {
+        Ok(_) => return SendOutcome::Accepted,
+        Err(e) => e,
+    };
+    let reason = match e {
+        NetworkError::Server { reason, .. } => reason.clone(),
+        NetworkError::UnexpectedResponse { response, .. } => response.clone(),
+        _ => return SendOutcome::Retry(e.to_string()),
+    };
+    let lower = reason.to_lowercase();
+    if ALREADY_KNOWN.iter().any(|known| lower.contains(known)) {
+        SendOutcome::Accepted
+    } else if MISSING_INPUTS.iter().any(|missing| lower.contains(missing)) {
+        SendOutcome::InputsMissing(reason)
+    } else {
+        SendOutcome::Refused(reason)
+    }
+}
+
+pub fn save_outgoing(db: &sled::Db, tx: &OutgoingTx) -> Result<(), WalletError> {
+    db.open_tree(OUTGOING_TREE)?
+        .insert(tx.txid.as_bytes(), tx.encode())?;
+    Ok(())
+}
+
+/// Every queued transaction, oldest first so parents are sent before their children.
+/// Entries that can't be read are skipped, so one of them doesn't hold up the others.
+pub fn outgoing_transactions(db: &sled::Db) -> Result<Vec<OutgoingTx>, WalletError> {
+    let mut txs = vec![];
+    for entry in db.open_tree(OUTGOING_TREE)?.iter() {
+        let (txid, bytes) = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                log::warn!("outgoing transaction not read {}", e);
+                continue;
+            }
+        };
+        match OutgoingTx::decode(&bytes) {
+            Some(tx) => txs.push(tx),
+            None => log::warn!("malformed outgoing {}", String::from_utf8_lossy(&txid)),
+        }
+    }
+    txs.sort_by_key(|tx| tx.queued_at);
+    Ok(txs)
+}
+
+pub fn get_outgoing(db: &sled::Db, txid: &str) -> Result<Option<OutgoingTx>, WalletError> {
+    match db.open_tree(OUTGOING_TREE)?.get(txid.as_bytes())? {
+        Some(bytes) => match OutgoingTx::decode(&bytes) {
+            Some(tx) => Ok(Some(tx)),
+            None => Err(WalletError::DataBaseError {
+                reason: format!("malformed record in {}", OUTGOING_TREE),
+            }),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Drops a transaction from the queue, its inputs are no longer pending-spent.
+pub fn forget_outgoing(db: &sled::Db, txid: &str) -> Result<bool, WalletError> {
+    drop_pending(db, txid)?;
+    Ok(db
+        .open_tree(OUTGOING_TREE)?
+        .remove(txid.as_bytes())?
+        .is_some())
+}
+
+/// Keeps the pending spend of `tx` in step with its state. A transaction that is queued or
+/// in the mempool holds its inputs, a rejected or conflicted one releases them. Confirmed
+/// ones are settled by the next `listunspent`.
+pub fn track_pending(db: &sled::Db, tx: &OutgoingTx) -> Result<(), WalletError> {
+    match tx.state {
+        BroadcastState::Queued | BroadcastState::InMempool => {
+            mark_pending(db, &tx.txid, &tx.raw_tx)
+        }
+        BroadcastState::Rejected { .. } | BroadcastState::Conflicted { .. } => {
+            drop_pending(db, &tx.txid)?;
+            Ok(())
+        }
+        BroadcastState::Confirmed { .. } => Ok(()),
+    }
+}
+
+/// Saves `raw_tx` as queued, or returns the entry already saved for it. A rejected or
+/// conflicted entry is queued again, sending it anew is asked for. The transaction is cached
+/// too, so queued children can look up the outputs they spend.
+pub fn enqueue(db: &sled::Db, raw_tx: &str) -> Result<OutgoingTx, WalletError> {
+    let raw = match hex::decode(raw_tx) {
+        Ok(raw) => raw,
+        Err(e) => {
+            return Err(WalletError::Generic {
+                reason: format!("transaction is not hex: {}", e),
+            })
+        }
+    };
+    let txid = txid_of(&raw);
+    decode_transaction(&txid, raw_tx)?;
+    if let Some(tx) = get_outgoing(db, &txid)? {
+        match tx.state {
+            BroadcastState::Rejected { .. } | BroadcastState::Conflicted { .. } => {}
+            _ => return Ok(tx),
+        }
+    }
+    cache_transaction(db, &txid, raw_tx)?;
+    let queued_at = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(now) => now.as_secs(),
+        Err(_) => 0,
+    };
+    let tx = OutgoingTx {
+        txid,
+        raw_tx: raw_tx.to_string(),
+        state: BroadcastState::Queued,
+        attempts: 0,
+        last_error: None,
+        queued_at,
+    };
+    save_outgoing(db, &tx)?;
+    Ok(tx)
+}
+
+/// Txids and heights in a `get_history` response.
+fn history_entries(history: &str) -> Result<Vec<(String, i64)>, WalletError> {
+    let history: Value = serde_json::from_str(history)?;
+    Ok(history
+        .as_array()
+        .cloned()
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|entry| {
+            let txid = entry["tx_hash"].as_str()?.to_string();
+            Some((txid, entry["height"].as_i64().unwrap_or(0)))
+        })
+        .collect())
+}
+
+/// First input of `tx` whose outpoint the server no longer lists as unspent, with the
+/// transaction that spent it when it is in the history of the spent script.
+async fn find_conflict(
+    db: &sled::Db,
+    txid: &str,
+    tx: &Transaction,
+    spent: &[Output],
+    connection: &ElectrumConnection,
+) -> Result<Option<BroadcastState>, WalletError> {
+    for (input, output) in tx.inputs.iter().zip(spent) {
+        let prev_txid = input.prev_out.txid.to_string();
+        let prev_index = input.prev_out.outpoint_index;
+        let unspent: Value =
+            serde_json::from_str(&get_script_unspent(&output.script, None, connection).await?)?;
+        let is_unspent = unspent
+            .as_array()
+            .cloned()
+            .unwrap_or_default()
+            .iter()
+            .any(|u| {
+                u["tx_hash"].as_str() == Some(prev_txid.as_str())
+                    && u["tx_pos"].as_u64() == Some(prev_index as u64)
+            });
+        if is_unspent {
+            continue;
+        }
+        let history = get_script_history(&output.script, connection).await?;
+        let others: Vec<String> = history_entries(&history)?
+            .into_iter()
+            .map(|(other, _)| other)
+            .filter(|other| other != txid && *other != prev_txid)
+            .collect();
+        let raw_txs = fetch_transactions(db, &others, connection).await?;
+        let mut spender = None;
+        for (other, raw_tx) in others.iter().zip(raw_txs) {
+            let spends_outpoint = decode_transaction(other, &raw_tx)?.inputs.iter().any(|i| {
+                i.prev_out.txid == input.prev_out.txid && i.prev_out.outpoint_index == prev_index
+            });
+            if spends_outpoint {
+                spender = Some(other.clone());
+                break;
+            }
+        }
+        return Ok(Some(BroadcastState::Conflicted {
+            outpoint: format!("{}:{}", prev_txid, prev_index),
+            spender,
+        }));
+    }
+    Ok(None)
+}
+
+/// Sends `tx` and moves it to its next state. Network failures leave it queued.
+pub async fn broadcast(
+    db: &sled::Db,
+    tx: &mut OutgoingTx,
+    connection: &ElectrumConnection,
+) -> Result<(), WalletError> {
+    let res = send_raw_transaction(&tx.raw_tx, connection).await;
+    record_send(db, tx, &res, connection).await
+}
+
+/// Applies the answer to a broadcast of `tx` made elsewhere, like a cross-checked send.
+/// `connection` is used to look for a conflict when the server reports missing inputs.
+pub async fn record_send(
+    db: &sled::Db,
+    tx: &mut OutgoingTx,
+    res: &Result<String, NetworkError>,
+    connection: &ElectrumConnection,
+) -> Result<(), WalletError> {
+    match send_outcome(res) {
+        SendOutcome::Accepted => {
+            tx.last_error = None;
+            if tx.state == BroadcastState::Queued {
+                tx.state = BroadcastState::InMempool;
+            }
+        }
+        SendOutcome::Retry(reason) => {
+            tx.attempts += 1;
+            tx.last_error = Some(reason);
+        }
+        SendOutcome::InputsMissing(reason) => {
+            let decoded = decode_transaction(&tx.txid, &tx.raw_tx)?;
+            let spent = spent_outputs(db, &decoded, connection).await?;
+            tx.state = match find_conflict(db, &tx.txid, &decoded, &spent, connection).await? {
+                Some(conflict) => conflict,
+                None => BroadcastState::Rejected { reason },
+            };
+        }
+        SendOutcome::Refused(reason) => tx.state = BroadcastState::Rejected { reason },
+    }
+    Ok(())
+}
+
+/// Checks where `tx` stands on the server: confirmed, in the mempool, or gone. A transaction
+/// the server doesn't know is checked for conflicts and sent again when its inputs are
+/// still unspent. `rebroadcast` sends mempool transactions again as well, after a reconnect
+/// to a server that may never have seen them.
+pub async fn refresh(
+    db: &sled::Db,
+    tx: &mut OutgoingTx,
+    rebroadcast: bool,
+    connection: &ElectrumConnection,
+) -> Result<(), WalletError> {
+    let decoded = decode_transaction(&tx.txid, &tx.raw_tx)?;
+    let spent = spent_outputs(db, &decoded, connection).await?;
+    // every spend shows up in the history of the script it spends from
+    let height = match spent.first() {
+        Some(output) => {
+            let history = get_script_history(&output.script, connection).await?;
+            history_entries(&history)?
+                .into_iter()
+                .find(|(txid, _)| *txid == tx.txid)
+                .map(|(_, height)| height)
+        }
+        None => None,
+    };
+    match height {
+        Some(height) if height > 0 => {
+            tx.state = BroadcastState::Confirmed {
+                height: height as u32,
+            };
+            Ok(())
+        }
+        Some(_) => {
+            tx.state = BroadcastState::InMempool;
+            match rebroadcast {
+                true => broadcast(db, tx, connection).await,
+                false => Ok(()),
+            }
+        }
+        None => match find_conflict(db, &tx.txid, &decoded, &spent, connection).await? {
+            Some(conflict) => {
+                tx.state = conflict;
+                Ok(())
+            }
+            None => broadcast(db, tx, connection).await,
+        },
+    }
+}
+
+/// Held in tauri state. `start` spawns the task working through the queue, `stop` ends it
+/// after the current pass.
+#[derive(Clone, Default)]
+pub struct BroadcastQueue {
+    task: ServiceTask,
+}
+
+impl BroadcastQueue {
+    pub fn is_running(&self) -> bool {
+        self.task.is_running()
+    }
+
+    pub fn stop(&self) {
+        self.task.stop();
+    }
+
+    /// Spawns the queue on the wallet database `db` unless it is already running, a running
+    /// queue moves over to `connection` on its next pass. `emit` receives `broadcast-status`
+    /// events with the transaction json whenever a state changes.
+    pub fn start<E>(&self, connection: Arc<ElectrumConnection>, db: sled::Db, emit: E)
+    where
+        E: Fn(&str, Value) + Send + Sync + 'static,
+    {
+        let id = match self.task.start(connection) {
+            Some(id) => id,
+            None => return,
+        };
+        let queue = self.clone();
+        tauri::async_runtime::spawn(async move {
+            queue.run(id, &db, emit).await;
+        });
+    }
+
+    /// One pass over the unfinished transactions. Queued ones are sent, the others refreshed.
+    /// A transaction that fails is left as it is and tried again on the next pass.
+    pub async fn process<E>(
+        &self,
+        db: &sled::Db,
+        rebroadcast: bool,
+        connection: &ElectrumConnection,
+        emit: &E,
+    ) -> Result<(), WalletError>
+    where
+        E: Fn(&str, Value),
+    {
+        for mut tx in outgoing_transactions(db)? {
+            if tx.state.is_final() {
+                continue;
+            }
+            let before = tx.clone();
+            let res = match tx.state {
+                BroadcastState::Queued => broadcast(db, &mut tx, connection).await,
+                _ => refresh(db, &mut tx, rebroadcast, connection).await,
+            };
+            if let Err(e) = res {
+                log::warn!("outgoing {} not updated {}", tx.txid, e);
+                continue;
+            }
+            if tx != before {
+                if let Err(e) = save_outgoing(db, &tx) {
+                    log::warn!("outgoing {} not saved {}", tx.txid, e);
+                    continue;
+                }
+            }
+            if tx.state != before.state {
+                if let Err(e) = track_pending(db, &tx) {
+                    log::warn!("pending spends of {} not updated {}", tx.txid, e);
+                }
+                log::info!("outgoing {} {:?}", tx.txid, tx.state);
+                emit(EVENT_BROADCAST_STATUS, tx.to_json());
+            }
+        }
+        Ok(())
+    }
+
+    async fn run<E>(&self, id: u64, db: &sled::Db, emit: E)
+    where
+        E: Fn(&str, Value),
+    {
+        // connection and generation of the last complete pass, none yet counts as a reconnect
+        let mut sent_on: Option<(Arc<ElectrumConnection>, u64)> = None;
+        while self.task.is_current(id) {
+            let connection = match self.task.connection() {
+                Some(connection) => connection,
+                None => break,
+            };
+            // another server or a reopened client may not have our mempool transactions
+            let rebroadcast = match &sent_on {
+                Some((sent_to, generation)) => {
+                    !Arc::ptr_eq(sent_to, &connection)
+                        || *generation != connection.generation()
+                        || !connection.is_connected()
+                }
+                None => true,
+            };
+            match self.process(db, rebroadcast, &connection, &emit).await {
+                Ok(()) => sent_on = Some((connection.clone(), connection.generation())),
+                // the next pass reads the queue again and still counts as a rebroadcast
+                Err(e) => log::warn!("broadcast queue error {}", e),
+            }
+            tokio::time::sleep(RETRY_INTERVAL).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server_error(reason: &str) -> Result<String, NetworkError> {
+        Err(NetworkError::Server {
+            method: "blockchain.transaction.broadcast".to_string(),
+            reason: reason.to_string(),
+        })
+    }
+
+    #[test]
+    fn classified_answers() {
+        assert_eq!(send_outcome(&Ok("aa".repeat(32))), SendOutcome::Accepted);
+        assert_eq!(
+            send_outcome(&server_error("txn-already-known")),
+            SendOutcome::Accepted
+        );
+        assert!(matches!(
+            send_outcome(&server_error("bad-txns-inputs-missingorspent")),
+            SendOutcome::InputsMissing(_)
+        ));
+        assert!(matches!(
+            send_outcome(&server_error("min relay fee not met")),
+            SendOutcome::Refused(_)
+        ));
+        let timeout = Err(NetworkError::Timeout {
+            method: "blockchain.transaction.broadcast".to_string(),
+            seconds: 30,
+        });
+        assert!(matches!(send_outcome(&timeout), SendOutcome::Retry(_)));
+        let dropped = Err(NetworkError::Request {
+            method: "blockchain.transaction.broadcast".to_string(),
+            reason: "I/O error: broken pipe".to_string(),
+        });
+        assert!(matches!(send_outcome(&dropped), SendOutcome::Retry(_)));
+    }
+
+    #[test]
+    fn record_roundtrip() {
+        let states = vec![
+            BroadcastState::Queued,
+            BroadcastState::InMempool,
+            BroadcastState::Confirmed { height: 800_000 },
+            BroadcastState::Conflicted {
+                outpoint: format!("{}:1", "bb".repeat(32)),
+                spender: None,
+            },
+            BroadcastState::Rejected {
+                reason: "dust".to_string(),
+            },
+        ];
+        for state in states {
+            let tx = OutgoingTx {
+                txid: "aa".repeat(32),
+                raw_tx: "0100".to_string(),
+                state,
+                attempts: 2,
+                last_error: Some("timed out".to_string()),
+                queued_at: 1_700_000_000,
+            };
+            assert_eq!(OutgoingTx::from_json(&tx.to_json()), Some(tx.clone()));
+            assert_eq!(OutgoingTx::decode(&tx.encode()), Some(tx));
+        }
+    }
+}
@@ -4,16 +4,17 @@
 
 use crate::coins::selection::{
     non_token_amount_from_utxo, selection_final_candidates, BranchAndBoundCoinSelection,
-    CoinSelectionAlgorithm, Excess, FeeRate, UtxoCandidates, WeightedUtxo,
+    CoinSelectionAlgorithm, Error as SelectionError, Excess, FeeRate, UtxoCandidates, WeightedUtxo,
 };
-use crate::coins::utxo::UnspentUtxos;
+use crate::coins::utxo::{UnspentUtxos, Utxo};
 use crate::error::WalletError;
-use crate::keys::bip44::{
-    default_testnet_derivation, derive_hd_path_private_key, get_hd_node_from_db_seed,
-};
+use crate::keys::bip44::{default_testnet_derivation, derive_hd_path_private_key};
 
 use bitcoinsuite_core::ser::CompactUint;
-use bitcoinsuite_core::tx::{CashToken, Commitment, NonFungibleTokenCapability, TxId, NFT};
+use bitcoinsuite_core::tx::{
+    Capability, CashToken, Commitment, NonFungibleTokenCapability, TxId, NFT,
+};
+use std::collections::BTreeMap;
 use std::str::FromStr;
 // use bitcoinsuite_core::tx::CashToken;
 use bitcoinsuite_core::{
@@ -23,7 +24,7 @@ use bitcoinsuite_core::{
     tx::{Input, Output, Transaction},
 };
 
-use electrum_client::bitcoin::bip32::DerivationPath;
+use electrum_client::bitcoin::bip32::{DerivationPath, ExtendedPrivKey};
 // use bytes::Bytes;
 use secp256k1_abc::{Message, PublicKey, Secp256k1, SecretKey};
 
@@ -44,6 +45,23 @@ fn calculate_dust(output: &Output) -> u64 {
     output.ser_len() as u64 * 3 + 444 as u64
 }
 
+/// Weight of a p2pkh scriptSig: its length, a 65 byte schnorr signature and a 33 byte
+/// compressed key, each with a push opcode
+const P2PKH_SATISFACTION_WEIGHT: usize = (1 + 1 + 65 + 1 + 33) * 4;
+
+/// `value` left once `fee` is paid from it, it must stay above the dust limit of `output`.
+fn pay_fee(output: &Output, value: u64, fee: u64) -> Result<u64, WalletError> {
+    let dust = calculate_dust(output);
+    match value.checked_sub(fee) {
+        Some(left) if left >= dust => Ok(left),
+        _ => Err(WalletError::InputValueInsufficient {
+            reason: "fee".to_string(),
+            amount_request: fee + dust,
+            actual: value,
+        }),
+    }
+}
+
 pub fn create_nft(commitment: &str, capability: &str) -> Option<NFT> {
     let commitment = match hex::decode(hex::encode(commitment.as_bytes())) {
         Ok(hex) => Ok(hex),
@@ -67,17 +85,12 @@ pub fn create_nft(commitment: &str, capability: &str) -> Option<NFT> {
     }
 }
 
-fn get_private_key(derivation_path: &str) -> Result<[u8; 32], String> {
+fn get_private_key(xpriv: &ExtendedPrivKey, derivation_path: &str) -> Result<[u8; 32], String> {
     match DerivationPath::from_str(derivation_path) {
-        Ok(deriv_path) => {
-            match get_hd_node_from_db_seed(None, electrum_client::bitcoin::Network::Testnet) {
-                Ok(xp) => match derive_hd_path_private_key(deriv_path, xp) {
-                    Ok(k) => Ok(k),
-                    Err(e) => Err(e.to_string()),
-                },
-                Err(e) => Err(e.to_string()),
-            }
-        }
+        Ok(deriv_path) => match derive_hd_path_private_key(deriv_path, *xpriv) {
+            Ok(k) => Ok(k),
+            Err(e) => Err(e.to_string()),
+        },
         Err(e) => Err(e.to_string()),
     }
 }
@@ -89,7 +102,11 @@ pub struct DustAndRawTransactionHex {
     pub dust: u64,
 }
 
+/// Selects coins and builds a transaction paying `amount` to `destination_script`, signed
+/// with the key at `derivation_path` below `xpriv`.
+#[allow(clippy::too_many_arguments)]
 pub fn create_tx_for_destination_output(
+    xpriv: &ExtendedPrivKey,
     derivation_path: &str,
     token_options: Option<TokenOptions>,
     destination_script: &Script,
@@ -97,8 +114,8 @@ pub fn create_tx_for_destination_output(
     amount: u64,
     utxos: UnspentUtxos,
     required_utxos: Option<UnspentUtxos>,
+    fee_rate: FeeRate,
 ) -> Result<DustAndRawTransactionHex, WalletError> {
-    let fee = FeeRate::from_sat_per_vb(0.0);
     let mut w_utxos: Vec<WeightedUtxo> = Vec::new();
     let mut token_genesis_utxos: Vec<WeightedUtxo> = Vec::new();
     let mut token_spend_utxos: Vec<WeightedUtxo> = Vec::new();
@@ -106,7 +123,7 @@ pub fn create_tx_for_destination_output(
     if let Some(r_utxos) = required_utxos.as_ref() {
         r_utxos.non_token.iter().for_each(|utxo| {
             let wtxo = WeightedUtxo {
-                satisfaction_weight: fee.0 as usize,
+                satisfaction_weight: P2PKH_SATISFACTION_WEIGHT,
                 utxo: utxo.0.clone(),
             };
             token_genesis_utxos.push(wtxo);
@@ -115,7 +132,7 @@ pub fn create_tx_for_destination_output(
     if let Some(r_utxos) = required_utxos.as_ref() {
         r_utxos.with_token.iter().for_each(|utxo| {
             let wtxo = WeightedUtxo {
-                satisfaction_weight: fee.0 as usize,
+                satisfaction_weight: P2PKH_SATISFACTION_WEIGHT,
                 utxo: utxo.0.clone(),
             };
             token_spend_utxos.push(wtxo);
@@ -123,7 +140,7 @@ pub fn create_tx_for_destination_output(
     }
     utxos.non_token.iter().for_each(|utxo| {
         let wtxo = WeightedUtxo {
-            satisfaction_weight: fee.0 as usize,
+            satisfaction_weight: P2PKH_SATISFACTION_WEIGHT,
             utxo: utxo.0.clone(),
         };
         w_utxos.push(wtxo);
@@ -187,13 +204,17 @@ pub fn create_tx_for_destination_output(
             selected: av_utxos,
         };
         let tx_size = build_transaction_p2pkh(
+            xpriv,
             derivation_path,
             &mut utxos,
             vec![destination_output.clone()],
-        );
-        destination_output.value = destination_output.value - tx_size?.len() as u64 / 2;
+        )?
+        .len()
+            / 2;
+        destination_output.value = pay_fee(&destination_output, amount, fee_rate.fee_vb(tx_size))?;
 
-        let tx_hex = build_transaction_p2pkh(derivation_path, &mut utxos, vec![destination_output]);
+        let tx_hex =
+            build_transaction_p2pkh(xpriv, derivation_path, &mut utxos, vec![destination_output]);
         Ok(tx_hex?)
     } else {
         let req_utxos = if token_genesis_utxos.is_empty() {
@@ -226,68 +247,74 @@ pub fn create_tx_for_destination_output(
         let coins = match BranchAndBoundCoinSelection::default().coin_select(
             req_utxos.clone(), //required utxos
             w_utxos,           //optional utxos
-            FeeRate::from_sat_per_vb(0.0),
+            fee_rate,
             amount,
             &destination_output,
         ) {
             Ok(selection) => {
                 match selection.excess {
-                    Excess::Change { amount, fee: _ } => {
+                    Excess::Change { .. } => {
                         let tx_size = build_transaction_p2pkh(
+                            xpriv,
                             derivation_path,
                             &mut selection_final_candidates(&selection).unwrap(),
                             vec![maybe_change.clone(), destination_output.clone()],
-                        )
-                        .unwrap()
+                        )?
                         .len()
                             / 2;
-                        let change_amount = amount;
-                        let total_relay_fee = tx_size as u64;
+                        let total_relay_fee = fee_rate.fee_vb(tx_size);
+                        // the selected change is net of the input fees only, the whole fee is
+                        // paid from what the inputs hold beyond the amount
+                        let change_amount = selection
+                            .selected_amount()
+                            .checked_sub(amount)
+                            .and_then(|left| left.checked_sub(total_relay_fee));
                         //Check if change can cover relay fee and leftover is not below dust
-                        if (change_amount - total_relay_fee) > calculate_dust(&maybe_change) {
-                            maybe_change.value += change_amount;
-                            maybe_change.value -= total_relay_fee;
-                            build_transaction_p2pkh(
-                                derivation_path,
-                                &mut selection_final_candidates(&selection).unwrap(),
-                                vec![maybe_change, destination_output],
-                            )
-                        } else {
-                            let tx_size = build_transaction_p2pkh(
-                                derivation_path,
-                                &mut selection_final_candidates(&selection).unwrap(),
-                                vec![maybe_change.clone(), destination_output.clone()],
-                            )
-                            .unwrap()
-                            .len()
-                                / 2;
-                            destination_output.value -= tx_size as u64;
-                            build_transaction_p2pkh(
-                                derivation_path,
-                                &mut selection_final_candidates(&selection).unwrap(),
-                                vec![maybe_change, destination_output],
-                            )
+                        match change_amount {
+                            Some(change) if change > calculate_dust(&maybe_change) => {
+                                maybe_change.value = change;
+                                build_transaction_p2pkh(
+                                    xpriv,
+                                    derivation_path,
+                                    &mut selection_final_candidates(&selection).unwrap(),
+                                    vec![maybe_change, destination_output],
+                                )
+                            }
+                            _ => {
+                                destination_output.value = pay_fee(
+                                    &destination_output,
+                                    destination_output.value,
+                                    total_relay_fee,
+                                )?;
+                                build_transaction_p2pkh(
+                                    xpriv,
+                                    derivation_path,
+                                    &mut selection_final_candidates(&selection).unwrap(),
+                                    vec![maybe_change, destination_output],
+                                )
+                            }
                         }
                     }
-                    Excess::NoChange {
-                        dust_threshold: _,
-                        remaining_amount,
-                        change_fee: _,
-                    } => {
+                    Excess::NoChange { .. } => {
                         if token_change.is_some() {
                             return Err(WalletError::Generic { reason: "Coin Selection: no change outputs creates but token change detected".to_string() });
                         }
                         let tx_size = build_transaction_p2pkh(
+                            xpriv,
                             derivation_path,
                             &mut selection_final_candidates(&selection).unwrap(),
                             vec![destination_output.clone()],
-                        )
-                        .unwrap()
+                        )?
                         .len()
                             / 2;
-                        destination_output.value += remaining_amount;
-                        destination_output.value -= tx_size as u64;
+                        // what is left below the dust limit goes to the destination
+                        destination_output.value = pay_fee(
+                            &destination_output,
+                            selection.selected_amount(),
+                            fee_rate.fee_vb(tx_size),
+                        )?;
                         build_transaction_p2pkh(
+                            xpriv,
                             derivation_path,
                             &mut selection_final_candidates(&selection).unwrap(),
                             vec![destination_output],
@@ -295,24 +322,44 @@ pub fn create_tx_for_destination_output(
                     }
                 }
             }
+            Err(SelectionError::InsufficientFunds { needed, available }) => {
+                Err(WalletError::InputValueInsufficient {
+                    reason: "amount and fee".to_string(),
+                    amount_request: needed,
+                    actual: available,
+                })
+            }
             Err(e) => Err(WalletError::Generic {
                 reason: format!("Coin Selection Error: {:?}", e),
             }),
         };
         coins
     };
-    match raw_transactopn_hex {
-        Ok(tx_hex) => Ok(DustAndRawTransactionHex {
-            dust,
-            raw_tx: tx_hex,
-        }),
-        Err(e) => Err(WalletError::Generic {
-            reason: e.to_string(),
-        }),
-    }
+    Ok(DustAndRawTransactionHex {
+        dust,
+        raw_tx: raw_transactopn_hex?,
+    })
 }
 
+/// Builds and signs a p2pkh transaction, refusing to drop any token carried by the inputs.
+/// Tokens can only be destroyed through [create_burn_transaction].
 pub fn build_transaction_p2pkh(
+    xpriv: &ExtendedPrivKey,
+    derivation_path: &str,
+    selected_outputs: &mut UtxoCandidates,
+    destination_outputs: Vec<Output>,
+) -> Result<RawTransactionHex, WalletError> {
+    check_no_implicit_burn(&selected_outputs.selected, &destination_outputs)?;
+    sign_transaction_p2pkh(
+        xpriv,
+        derivation_path,
+        selected_outputs,
+        destination_outputs,
+    )
+}
+
+fn sign_transaction_p2pkh(
+    xpriv: &ExtendedPrivKey,
     derivation_path: &str,
     selected_outputs: &mut UtxoCandidates,
     destination_outputs: Vec<Output>,
@@ -348,14 +395,10 @@ pub fn build_transaction_p2pkh(
             &mut tx_unsigned,
             &SigHashType::ALL_BIP143_UTXOS,
         );
-        if get_private_key(derivation_path).is_err() {
-            return Err(WalletError::Generic {
-                reason: get_private_key(derivation_path).unwrap_err().to_string(),
-            });
-        }
-
-        let secret_key =
-            SecretKey::from_slice(&mut get_private_key(derivation_path).clone().unwrap());
+        let secret_key = match get_private_key(xpriv, derivation_path) {
+            Ok(key) => SecretKey::from_slice(&key),
+            Err(e) => return Err(WalletError::Generic { reason: e }),
+        };
 
         let secp = Secp256k1::new();
         let sighash = hex::decode(signature_serialized).unwrap();
@@ -395,6 +438,431 @@ pub fn build_transaction_p2pkh(
     Ok(hex::encode(tx_signed.ser()))
 }
 
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TokenTally {
+    pub fungible: u128,
+    pub nfts: usize,
+}
+
+/// Sums fungible amounts and counts NFTs per category.
+pub fn tally_tokens<'a>(outputs: impl Iterator<Item = &'a Output>) -> BTreeMap<TxId, TokenTally> {
+    let mut tally: BTreeMap<TxId, TokenTally> = BTreeMap::new();
+    for token in outputs.filter_map(|output| output.token.as_ref()) {
+        let entry = tally.entry(token.category).or_default();
+        entry.fungible += token.amount.0 as u128;
+        if token.nft.is_some() {
+            entry.nfts += 1;
+        }
+    }
+    tally
+}
+
+/// Counts the NFTs of `outputs` by category, capability and commitment.
+fn count_nfts<'a>(
+    outputs: impl Iterator<Item = &'a Output>,
+) -> BTreeMap<(TxId, u8, Vec<u8>), usize> {
+    let mut nfts = BTreeMap::new();
+    for token in outputs.filter_map(|output| output.token.as_ref()) {
+        if let Some(nft) = token.nft.as_ref() {
+            let capability = match nft.capability.0 {
+                Capability::None => 0,
+                Capability::Mutable => 1,
+                Capability::Minting => 2,
+            };
+            let key = (token.category, capability, nft.commitment.0.to_vec());
+            *nfts.entry(key).or_default() += 1;
+        }
+    }
+    nfts
+}
+
+/// Every fungible amount spent by the inputs must be carried to an output, and every NFT
+/// with the same capability and commitment. An NFT turned immutable or given another
+/// commitment loses its minting or mutable capability, only [create_burn_transaction] may
+/// destroy it.
+fn check_no_implicit_burn(inputs: &[Utxo], outputs: &[Output]) -> Result<(), WalletError> {
+    let spent = tally_tokens(inputs.iter().map(|utxo| &utxo.output));
+    let created = tally_tokens(outputs.iter());
+    let spent_nfts = count_nfts(inputs.iter().map(|utxo| &utxo.output));
+    let created_nfts = count_nfts(outputs.iter());
+    for (category, spent) in spent.iter() {
+        let created = created.get(category).cloned().unwrap_or_default();
+        let nfts_kept = spent_nfts
+            .iter()
+            .filter(|((nft_category, _, _), _)| nft_category == category)
+            .all(|(nft, count)| created_nfts.get(nft).map_or(false, |kept| kept >= count));
+        if created.fungible < spent.fungible || !nfts_kept {
+            return Err(WalletError::ImplicitTokenBurn {
+                category: category.to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// OP_RETURN output carrying an optional burn memo
+fn burn_memo_output(memo: &str) -> Result<Output, WalletError> {
+    let memo = memo.as_bytes();
+    if memo.len() > MAX_BURN_MEMO_SIZE {
+        return Err(WalletError::Generic {
+            reason: format!("burn memo exceeds {} bytes", MAX_BURN_MEMO_SIZE),
+        });
+    }
+    let mut script = ScriptMut::with_capacity(memo.len() + 3);
+    script.put_bytecode(&[OP_RETURN]);
+    if memo.len() <= 75 {
+        script.put_bytecode(&[memo.len() as u8]);
+    } else {
+        script.put_bytecode(&[OP_PUSHDATA1, memo.len() as u8]);
+    }
+    script.put_bytecode(memo);
+    Ok(Output {
+        script: script.freeze(),
+        token: None,
+        value: 0,
+    })
+}
+
+const OP_RETURN: u8 = 0x6a;
+const OP_PUSHDATA1: u8 = 0x4c;
+const MAX_BURN_MEMO_SIZE: usize = 220;
+
+#[derive(Debug)]
+pub struct BurnTransaction {
+    pub raw_tx: RawTransactionHex,
+    pub burned: BTreeMap<TxId, TokenTally>,
+    pub fee: u64,
+}
+
+/// Inputs and outputs of a burn before signing.
+#[derive(Debug)]
+struct BurnPlan {
+    candidates: UtxoCandidates,
+    outputs: Vec<Output>,
+    burned: BTreeMap<TxId, TokenTally>,
+    fee: u64,
+}
+
+/// What the burned utxos hold minus `keep`, erroring when `keep` holds a token they don't.
+fn burned_tokens(
+    burn_utxos: &[Utxo],
+    keep: &[CashToken],
+) -> Result<BTreeMap<TxId, TokenTally>, WalletError> {
+    let mut burned = tally_tokens(burn_utxos.iter().map(|utxo| &utxo.output));
+    for token in keep {
+        let missing = || WalletError::Generic {
+            reason: format!(
+                "token change of {} is not held by the burned utxos",
+                token.category
+            ),
+        };
+        let tally = burned.get_mut(&token.category).ok_or_else(missing)?;
+        tally.fungible = tally
+            .fungible
+            .checked_sub(token.amount.0 as u128)
+            .ok_or_else(missing)?;
+        if token.nft.is_some() {
+            tally.nfts = tally.nfts.checked_sub(1).ok_or_else(missing)?;
+        }
+    }
+    burned.retain(|_, tally| tally.fungible > 0 || tally.nfts > 0);
+    if burned.is_empty() {
+        return Err(WalletError::Generic {
+            reason: "token change keeps every token, nothing is burned".to_string(),
+        });
+    }
+    Ok(burned)
+}
+
+/// Outputs of a burn: the satoshi change first, one dust output per token kept, then the
+/// memo. Inputs are added from `funding_utxos`, largest first, while the burned utxos can't
+/// cover the fee and the change. `tx_size` gives the size of the transaction in bytes.
+fn plan_burn<F>(
+    burn_utxos: Vec<Utxo>,
+    mut funding_utxos: Vec<Utxo>,
+    change_script: &Script,
+    keep: Vec<CashToken>,
+    memo: Option<&str>,
+    fee_rate: FeeRate,
+    tx_size: F,
+) -> Result<BurnPlan, WalletError>
+where
+    F: Fn(&mut UtxoCandidates, Vec<Output>) -> Result<usize, WalletError>,
+{
+    let burned = burned_tokens(&burn_utxos, &keep)?;
+    let mut outputs = vec![Output {
+        script: change_script.clone(),
+        token: None,
+        value: 0,
+    }];
+    for token in keep {
+        let mut output = Output {
+            script: change_script.clone(),
+            token: Some(token),
+            value: 0,
+        };
+        output.value = calculate_dust(&output);
+        outputs.push(output);
+    }
+    if let Some(memo) = memo {
+        outputs.push(burn_memo_output(memo)?);
+    }
+    let token_change: u64 = outputs.iter().map(|output| output.value).sum();
+
+    funding_utxos.sort_by_key(|utxo| std::cmp::Reverse(utxo.output.value));
+    let mut funding_utxos = funding_utxos.into_iter();
+    let mut candidates = UtxoCandidates {
+        selected: burn_utxos,
+        change: None,
+    };
+    loop {
+        let input_value: u64 = candidates.selected.iter().map(|u| u.output.value).sum();
+        let fee = fee_rate.fee_vb(tx_size(&mut candidates, outputs.clone())?);
+        let dust = calculate_dust(&outputs[0]);
+        let needed = fee + dust + token_change;
+        if input_value >= needed {
+            outputs[0].value = input_value - fee - token_change;
+            return Ok(BurnPlan {
+                candidates,
+                outputs,
+                burned,
+                fee,
+            });
+        }
+        match funding_utxos.next() {
+            Some(utxo) => candidates.selected.push(utxo),
+            None => {
+                return Err(WalletError::InputValueInsufficient {
+                    reason: "burn fee and change".to_string(),
+                    amount_request: needed,
+                    actual: input_value,
+                })
+            }
+        }
+    }
+}
+
+/// Spends `burn_utxos` without recreating their tokens, except for the ones in `keep` which
+/// go back to `change_script` as token change. The satoshis they hold, topped up with
+/// `funding_utxos` if needed, go back to `change_script` too. Nothing is built unless
+/// `confirm_burn` is set.
+#[allow(clippy::too_many_arguments)]
+pub fn create_burn_transaction(
+    xpriv: &ExtendedPrivKey,
+    derivation_path: &str,
+    burn_utxos: Vec<Utxo>,
+    funding_utxos: Vec<Utxo>,
+    change_script: &Script,
+    keep: Vec<CashToken>,
+    memo: Option<&str>,
+    fee_rate: FeeRate,
+    confirm_burn: bool,
+) -> Result<BurnTransaction, WalletError> {
+    if burn_utxos.is_empty() || burn_utxos.iter().any(|utxo| utxo.output.token.is_none()) {
+        return Err(WalletError::Generic {
+            reason: "burn requires at least one utxo and every utxo must carry a token".to_string(),
+        });
+    }
+    if !confirm_burn {
+        let summary = burned_tokens(&burn_utxos, &keep)?
+            .iter()
+            .map(|(category, tally)| {
+                format!(
+                    "{}: {} fungible, {} nft",
+                    category, tally.fungible, tally.nfts
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("; ");
+        return Err(WalletError::BurnNotConfirmed { summary });
+    }
+    let mut plan = plan_burn(
+        burn_utxos,
+        funding_utxos,
+        change_script,
+        keep,
+        memo,
+        fee_rate,
+        |candidates, outputs| {
+            Ok(sign_transaction_p2pkh(xpriv, derivation_path, candidates, outputs)?.len() / 2)
+        },
+    )?;
+    let raw_tx =
+        sign_transaction_p2pkh(xpriv, derivation_path, &mut plan.candidates, plan.outputs)?;
+    Ok(BurnTransaction {
+        raw_tx,
+        burned: plan.burned,
+        fee: plan.fee,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoinsuite_core::hash::ShaRmd160;
+    use bitcoinsuite_core::tx::{Capability, OutPoint};
+    use bytes::Bytes;
+
+    fn category() -> TxId {
+        TxId::from_str(&"cc".repeat(32)).unwrap()
+    }
+
+    fn utxo(index: u32, value: u64, token: Option<CashToken>) -> Utxo {
+        Utxo {
+            height: 1,
+            output: Output {
+                script: Script::p2pkh(&ShaRmd160::from_be_slice(&[1; 20]).unwrap()),
+                value,
+                token,
+            },
+            outpoint: OutPoint {
+                txid: TxId::from_str(&"aa".repeat(32)).unwrap(),
+                outpoint_index: index,
+            },
+        }
+    }
+
+    fn fungible(amount: u64) -> CashToken {
+        CashToken {
+            amount: CompactUint(amount),
+            category: category(),
+            nft: None,
+        }
+    }
+
+    /// 10 bytes of version, counts and locktime, 141 per schnorr p2pkh input
+    fn estimate(
+        candidates: &mut UtxoCandidates,
+        outputs: Vec<Output>,
+    ) -> Result<usize, WalletError> {
+        let outputs: usize = outputs.iter().map(|output| output.ser_len()).sum();
+        Ok(10 + 141 * candidates.selected.len() + outputs)
+    }
+
+    fn plan(burn: Vec<Utxo>, funding: Vec<Utxo>, keep: Vec<CashToken>, rate: f32) -> BurnPlan {
+        let change = burn[0].output.script.clone();
+        let fee_rate = FeeRate::from_sat_per_vb(rate);
+        plan_burn(burn, funding, &change, keep, None, fee_rate, estimate).unwrap()
+    }
+
+    fn value_in(plan: &BurnPlan) -> u64 {
+        plan.candidates
+            .selected
+            .iter()
+            .map(|u| u.output.value)
+            .sum()
+    }
+
+    fn value_out(plan: &BurnPlan) -> u64 {
+        plan.outputs.iter().map(|output| output.value).sum()
+    }
+
+    #[test]
+    fn fungible_burn() {
+        let plan = plan(
+            vec![utxo(0, 10_000, Some(fungible(1_000)))],
+            vec![],
+            vec![],
+            2.0,
+        );
+        assert_eq!(
+            plan.burned[&category()],
+            TokenTally {
+                fungible: 1_000,
+                nfts: 0
+            }
+        );
+        assert_eq!(plan.outputs.len(), 1);
+        assert!(plan.outputs.iter().all(|output| output.token.is_none()));
+        let size = estimate(&mut plan.candidates.clone(), plan.outputs.clone()).unwrap();
+        assert_eq!(plan.fee, 2 * size as u64);
+        assert_eq!(value_in(&plan), value_out(&plan) + plan.fee);
+    }
+
+    #[test]
+    fn nft_burn_funded() {
+        let nft = CashToken {
+            amount: CompactUint(0),
+            category: category(),
+            nft: Some(NFT {
+                capability: NonFungibleTokenCapability(Capability::None),
+                commitment: Commitment(Bytes::from_static(b"01")),
+            }),
+        };
+        // 800 sats can't pay fee and dust change, the larger funding utxo is pulled in
+        let plan = plan(
+            vec![utxo(0, 800, Some(nft))],
+            vec![utxo(1, 600, None), utxo(2, 5_000, None)],
+            vec![],
+            1.0,
+        );
+        assert_eq!(
+            plan.burned[&category()],
+            TokenTally {
+                fungible: 0,
+                nfts: 1
+            }
+        );
+        assert_eq!(plan.candidates.selected.len(), 2);
+        assert_eq!(plan.candidates.selected[1].output.value, 5_000);
+        assert_eq!(value_in(&plan), value_out(&plan) + plan.fee);
+    }
+
+    #[test]
+    fn nft_capability_kept() {
+        let nft = |capability, commitment: &'static [u8]| CashToken {
+            amount: CompactUint(0),
+            category: category(),
+            nft: Some(NFT {
+                capability: NonFungibleTokenCapability(capability),
+                commitment: Commitment(Bytes::from_static(commitment)),
+            }),
+        };
+        let output = |token| utxo(1, 1_000, Some(token)).output;
+        let baton = vec![utxo(0, 1_000, Some(nft(Capability::Minting, b"")))];
+        // minting a new NFT keeps the baton
+        let minted = [
+            output(nft(Capability::Minting, b"")),
+            output(nft(Capability::None, b"01")),
+        ];
+        assert!(check_no_implicit_burn(&baton, &minted).is_ok());
+        // same NFT count, but the baton became an immutable NFT
+        let downgraded = [output(nft(Capability::None, b""))];
+        assert!(matches!(
+            check_no_implicit_burn(&baton, &downgraded),
+            Err(WalletError::ImplicitTokenBurn { .. })
+        ));
+        let mutable = vec![utxo(0, 1_000, Some(nft(Capability::Mutable, b"01")))];
+        let recommitted = [output(nft(Capability::Mutable, b"02"))];
+        assert!(check_no_implicit_burn(&mutable, &recommitted).is_err());
+    }
+
+    #[test]
+    fn partial_burn_keeps_change() {
+        let plan = plan(
+            vec![utxo(0, 10_000, Some(fungible(1_000)))],
+            vec![],
+            vec![fungible(400)],
+            1.0,
+        );
+        assert_eq!(
+            plan.burned[&category()],
+            TokenTally {
+                fungible: 600,
+                nfts: 0
+            }
+        );
+        assert_eq!(plan.outputs.len(), 2);
+        assert_eq!(plan.outputs[1].token, Some(fungible(400)));
+        assert_eq!(plan.outputs[1].value, calculate_dust(&plan.outputs[1]));
+        assert_eq!(value_in(&plan), value_out(&plan) + plan.fee);
+
+        let burn = vec![utxo(0, 10_000, Some(fungible(1_000)))];
+        assert!(burned_tokens(&burn, &[fungible(1_001)]).is_err());
+        assert!(burned_tokens(&burn, &[fungible(1_000)]).is_err());
+    }
+}
+
 /*
 
 mod test {
@@ -0,0 +1,399 @@
+//! Double-spend proofs. A proof holds what two conflicting spends of one outpoint signed, both
+//! signatures valid for the same key prove the owner signed a double spend. Proofs only exist
+//! for P2PKH outputs. Signatures using SIGHASH_UTXOS and token outputs can't be checked from
+//! the proof alone and are reported as unverifiable.
+use bitcoin_hashes::{hash160, sha256d, Hash};
+use bitcoinsuite_core::tx::{Output, Transaction};
+use secp256k1_abc::{schnorrsig, Message, PublicKey, Secp256k1, Signature};
+use serde_json::{json, Value};
+
+use crate::encoding::{write_varbytes, Reader};
+use crate::error::WalletError;
+use crate::network::connection::ElectrumConnection;
+use crate::network::p2p::bloom::script_elements;
+use crate::store::transactions::{decode_transaction, fetch_transaction};
+
+const SIGHASH_FORKID: u8 = 0x40;
+const SIGHASH_UTXOS: u8 = 0x20;
+
+/// What one of the conflicting transactions committed to when spending the outpoint.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Spender {
+    pub version: u32,
+    pub sequence: u32,
+    pub locktime: u32,
+    pub hash_prevouts: [u8; 32],
+    pub hash_sequence: [u8; 32],
+    pub hash_outputs: [u8; 32],
+    /// Unlocking script pushes, the signature for P2PKH
+    pub push_data: Vec<Vec<u8>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DsProof {
+    /// Spent outpoint, txid in internal byte order
+    pub prev_txid: [u8; 32],
+    pub prev_index: u32,
+    pub spenders: [Spender; 2],
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DsProofStatus {
+    /// Both signatures check out, the outpoint was signed away twice
+    Verified,
+    Invalid(String),
+    Unverifiable(String),
+}
+
+impl DsProofStatus {
+    pub fn to_json(&self) -> Value {
+        match self {
+            DsProofStatus::Verified => json!({"status": "verified"}),
+            DsProofStatus::Invalid(reason) => json!({"status": "invalid", "reason": reason}),
+            DsProofStatus::Unverifiable(reason) => {
+                json!({"status": "unverifiable", "reason": reason})
+            }
+        }
+    }
+}
+
+fn dsproof_err(reason: String) -> WalletError {
+    WalletError::NetworkError { reason }
+}
+
+fn parse_spender(reader: &mut Reader) -> Option<Spender> {
+    let version = reader.u32()?;
+    let sequence = reader.u32()?;
+    let locktime = reader.u32()?;
+    let hash_prevouts = reader.hash()?;
+    let hash_sequence = reader.hash()?;
+    let hash_outputs = reader.hash()?;
+    let count = reader.varint()?;
+    let mut push_data = vec![];
+    for _ in 0..count {
+        push_data.push(reader.varbytes()?.to_vec());
+    }
+    Some(Spender {
+        version,
+        sequence,
+        locktime,
+        hash_prevouts,
+        hash_sequence,
+        hash_outputs,
+        push_data,
+    })
+}
+
+impl Spender {
+    /// BIP143 style signing serialization of this spend.
+    fn preimage(
+        &self,
+        proof: &DsProof,
+        script_code: &[u8],
+        value: u64,
+        sighash_type: u8,
+    ) -> Vec<u8> {
+        let mut preimage = vec![];
+        preimage.extend_from_slice(&self.version.to_le_bytes());
+        preimage.extend_from_slice(&self.hash_prevouts);
+        preimage.extend_from_slice(&self.hash_sequence);
+        preimage.extend_from_slice(&proof.prev_txid);
+        preimage.extend_from_slice(&proof.prev_index.to_le_bytes());
+        write_varbytes(&mut preimage, script_code);
+        preimage.extend_from_slice(&value.to_le_bytes());
+        preimage.extend_from_slice(&self.sequence.to_le_bytes());
+        preimage.extend_from_slice(&self.hash_outputs);
+        preimage.extend_from_slice(&self.locktime.to_le_bytes());
+        preimage.extend_from_slice(&(sighash_type as u32).to_le_bytes());
+        preimage
+    }
+
+    /// Checks the signature of this spend against `pubkey`.
+    fn verify(&self, proof: &DsProof, spent: &Output, pubkey: &PublicKey) -> DsProofStatus {
+        let signature = match self.push_data.as_slice() {
+            [signature] if !signature.is_empty() => signature,
+            _ => return DsProofStatus::Invalid("spender is not a P2PKH signature".to_string()),
+        };
+        let (signature, sighash_type) = signature.split_at(signature.len() - 1);
+        let sighash_type = sighash_type[0];
+        if sighash_type & SIGHASH_FORKID == 0 {
+            return DsProofStatus::Invalid("signature without SIGHASH_FORKID".to_string());
+        }
+        if sighash_type & SIGHASH_UTXOS != 0 {
+            return DsProofStatus::Unverifiable("signature uses SIGHASH_UTXOS".to_string());
+        }
+        let preimage = self.preimage(proof, spent.script.bytecode(), spent.value, sighash_type);
+        let digest = sha256d::Hash::hash(&preimage).into_inner();
+        let message = match Message::from_slice(&digest) {
+            Ok(message) => message,
+            Err(e) => return DsProofStatus::Invalid(e.to_string()),
+        };
+        let secp = Secp256k1::new();
+        let verified = match signature.len() {
+            64 => match schnorrsig::Signature::from_slice(signature) {
+                Ok(signature) => secp.schnorrabc_verify(&signature, &message, pubkey).is_ok(),
+                Err(_) => false,
+            },
+            _ => match Signature::from_der_lax(signature) {
+                Ok(mut signature) => {
+                    signature.normalize_s();
+                    secp.verify(&message, &signature, pubkey).is_ok()
+                }
+                Err(_) => false,
+            },
+        };
+        match verified {
+            true => DsProofStatus::Verified,
+            false => DsProofStatus::Invalid("bad spender signature".to_string()),
+        }
+    }
+}
+
+impl DsProof {
+    pub fn parse(raw: &[u8]) -> Option<Self> {
+        let mut reader = Reader::new(raw);
+        let prev_txid = reader.hash()?;
+        let prev_index = reader.u32()?;
+        let first = parse_spender(&mut reader)?;
+        let second = parse_spender(&mut reader)?;
+        Some(DsProof {
+            prev_txid,
+            prev_index,
+            spenders: [first, second],
+        })
+    }
+
+    /// Txid of the spent output, as displayed by explorers
+    pub fn prev_txid_hex(&self) -> String {
+        let mut txid = self.prev_txid;
+        txid.reverse();
+        hex::encode(txid)
+    }
+
+    /// Spent outpoint as `txid:vout`
+    pub fn outpoint(&self) -> String {
+        format!("{}:{}", self.prev_txid_hex(), self.prev_index)
+    }
+
+    /// Checks both spenders against the spent output and the key that owns it.
+    pub fn verify(&self, spent: &Output, pubkey: &[u8]) -> DsProofStatus {
+        if spent.token.is_some() {
+            return DsProofStatus::Unverifiable("spent output carries tokens".to_string());
+        }
+        let pubkey_hash = hash160::Hash::hash(pubkey).into_inner();
+        let p2pkh = [&[0x76, 0xa9, 0x14][..], &pubkey_hash, &[0x88, 0xac]].concat();
+        if spent.script.bytecode()[..] != p2pkh[..] {
+            return DsProofStatus::Invalid("key does not own the spent output".to_string());
+        }
+        let pubkey = match PublicKey::from_slice(pubkey) {
+            Ok(pubkey) => pubkey,
+            Err(e) => return DsProofStatus::Invalid(e.to_string()),
+        };
+        let [first, second] = &self.spenders;
+        if first.hash_outputs == second.hash_outputs && first.hash_prevouts == second.hash_prevouts
+        {
+            return DsProofStatus::Invalid("both spenders sign the same transaction".to_string());
+        }
+        for spender in self.spenders.iter() {
+            let status = spender.verify(self, spent, &pubkey);
+            if status != DsProofStatus::Verified {
+                return status;
+            }
+        }
+        DsProofStatus::Verified
+    }
+}
+
+/// Proof as delivered by Fulcrum's `blockchain.transaction.dsproof.*` methods, checked.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DoubleSpendAlert {
+    pub dspid: String,
+    /// Mempool transaction spending the disputed outpoint
+    pub txid: String,
+    pub outpoint: String,
+    /// Mempool transactions depending on `txid`, all of them may be dropped
+    pub descendants: Vec<String>,
+    pub status: DsProofStatus,
+}
+
+impl DoubleSpendAlert {
+    pub fn affects(&self, txid: &str) -> bool {
+        self.txid == txid || self.descendants.iter().any(|d| d == txid)
+    }
+
+    /// `alert` when a verified proof shows the payment being double spent, `warning` when the
+    /// proof could not be checked.
+    pub fn to_json(&self) -> Value {
+        let mut alert = self.status.to_json();
+        alert["severity"] = match self.status {
+            DsProofStatus::Verified => json!("alert"),
+            _ => json!("warning"),
+        };
+        alert["dspid"] = json!(self.dspid);
+        alert["txid"] = json!(self.txid);
+        alert["outpoint"] = json!(self.outpoint);
+        alert["descendants"] = json!(self.descendants);
+        alert
+    }
+}
+
+/// Public key the input of `tx` spending the proof outpoint signed with, the last push of a
+/// P2PKH unlocking script.
+fn spending_pubkey(tx: &Transaction, proof: &DsProof) -> Option<Vec<u8>> {
+    let outpoint = proof.outpoint();
+    let input = tx.inputs.iter().find(|input| {
+        format!("{}:{}", input.prev_out.txid, input.prev_out.outpoint_index) == outpoint
+    })?;
+    script_elements(&input.script).pop()
+}
+
+/// Parses and verifies a server proof. The spending and spent transactions come from the
+/// transaction cache in `db`, so both are checked against their txid.
+pub async fn check_dsproof(
+    db: &sled::Db,
+    proof: &Value,
+    connection: &ElectrumConnection,
+) -> Result<DoubleSpendAlert, WalletError> {
+    let field = |name: &str| match proof[name].as_str() {
+        Some(value) => Ok(value.to_string()),
+        None => Err(dsproof_err(format!("dsproof without {}", name))),
+    };
+    let txid = field("txid")?;
+    let raw = hex::decode(field("hex")?).unwrap_or_default();
+    let parsed = match DsProof::parse(&raw) {
+        Some(parsed) => parsed,
+        None => return Err(dsproof_err(format!("bad dsproof for {}", txid))),
+    };
+    let descendants = proof["descendants"]
+        .as_array()
+        .map(|d| {
+            d.iter()
+                .filter_map(|t| t.as_str().map(String::from))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let tx = decode_transaction(&txid, &fetch_transaction(db, &txid, connection).await?)?;
+    let prev_txid = parsed.prev_txid_hex();
+    let prev_tx = decode_transaction(
+        &prev_txid,
+        &fetch_transaction(db, &prev_txid, connection).await?,
+    )?;
+    let spent = prev_tx.outputs.get(parsed.prev_index as usize);
+    let status = match (spent, spending_pubkey(&tx, &parsed)) {
+        (Some(spent), Some(pubkey)) => parsed.verify(spent, &pubkey),
+        (None, _) => DsProofStatus::Invalid(format!("{} does not exist", parsed.outpoint())),
+        (_, None) => DsProofStatus::Invalid(format!("{} does not spend the outpoint", txid)),
+    };
+    Ok(DoubleSpendAlert {
+        dspid: field("dspid")?,
+        txid,
+        outpoint: parsed.outpoint(),
+        descendants,
+        status,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::write_varint;
+    use bitcoinsuite_core::script::Script;
+    use bytes::Bytes;
+    use secp256k1_abc::SecretKey;
+
+    fn spender(hash_outputs: u8) -> Spender {
+        Spender {
+            version: 2,
+            sequence: 0xffff_ffff,
+            locktime: 0,
+            hash_prevouts: [1; 32],
+            hash_sequence: [2; 32],
+            hash_outputs: [hash_outputs; 32],
+            push_data: vec![],
+        }
+    }
+
+    fn serialize(proof: &DsProof) -> Vec<u8> {
+        let mut raw = proof.prev_txid.to_vec();
+        raw.extend_from_slice(&proof.prev_index.to_le_bytes());
+        for spender in proof.spenders.iter() {
+            raw.extend_from_slice(&spender.version.to_le_bytes());
+            raw.extend_from_slice(&spender.sequence.to_le_bytes());
+            raw.extend_from_slice(&spender.locktime.to_le_bytes());
+            raw.extend_from_slice(&spender.hash_prevouts);
+            raw.extend_from_slice(&spender.hash_sequence);
+            raw.extend_from_slice(&spender.hash_outputs);
+            write_varint(&mut raw, spender.push_data.len() as u64);
+            for push in spender.push_data.iter() {
+                write_varbytes(&mut raw, push);
+            }
+        }
+        raw
+    }
+
+    /// Two spends of one P2PKH output signed with schnorr, as a wallet double spending would.
+    #[test]
+    fn signed_twice() {
+        let secp = Secp256k1::new();
+        let secret = SecretKey::from_slice(&[0x11; 32]).unwrap();
+        let pubkey = PublicKey::from_secret_key(&secp, &secret).serialize();
+        let pubkey_hash = hash160::Hash::hash(&pubkey).into_inner();
+        let spent = Output {
+            script: Script::new(Bytes::from(
+                [&[0x76, 0xa9, 0x14][..], &pubkey_hash, &[0x88, 0xac]].concat(),
+            )),
+            value: 10_000,
+            token: None,
+        };
+        let mut proof = DsProof {
+            prev_txid: [7; 32],
+            prev_index: 1,
+            spenders: [spender(3), spender(4)],
+        };
+        let sighash_type = SIGHASH_FORKID | 1;
+        let signatures: Vec<Vec<u8>> = proof
+            .spenders
+            .iter()
+            .map(|spender| {
+                let preimage =
+                    spender.preimage(&proof, spent.script.bytecode(), spent.value, sighash_type);
+                let digest = sha256d::Hash::hash(&preimage).into_inner();
+                let message = Message::from_slice(&digest).unwrap();
+                let signature = secp.schnorrabc_sign_no_aux_rand(&message, &secret);
+                [signature.as_ref().as_slice(), &[sighash_type]].concat()
+            })
+            .collect();
+        for (spender, signature) in proof.spenders.iter_mut().zip(signatures) {
+            spender.push_data = vec![signature];
+        }
+
+        let parsed = DsProof::parse(&serialize(&proof)).unwrap();
+        assert_eq!(parsed, proof);
+        assert_eq!(parsed.outpoint(), format!("{}:1", "07".repeat(32)));
+        assert_eq!(parsed.verify(&spent, &pubkey), DsProofStatus::Verified);
+
+        let mut tampered = proof.clone();
+        tampered.spenders[1].hash_outputs = [5; 32];
+        assert!(matches!(
+            tampered.verify(&spent, &pubkey),
+            DsProofStatus::Invalid(_)
+        ));
+
+        let mut same = proof.clone();
+        same.spenders[1] = same.spenders[0].clone();
+        assert!(matches!(
+            same.verify(&spent, &pubkey),
+            DsProofStatus::Invalid(_)
+        ));
+
+        let other_value = Output {
+            value: 9_999,
+            ..spent
+        };
+        assert!(matches!(
+            proof.verify(&other_value, &pubkey),
+            DsProofStatus::Invalid(_)
+        ));
+    }
+}
@@ -1 +1,3 @@
+pub mod broadcast;
 pub mod build;
+pub mod dsproof;